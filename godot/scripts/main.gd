@@ -17,11 +17,25 @@ func _ready():
 	var dataset_path = ProjectSettings.globalize_path(path)
 	print("📂 Checking files in:", path)
 
+	core.dataset_load_progress.connect(_on_dataset_load_progress)
+	core.dataset_loaded.connect(_on_dataset_loaded)
+	core.dataset_load_failed.connect(_on_dataset_load_failed)
+	# loads on a worker thread - the graphs show up once dataset_loaded fires
 	core.load_dataset_and_projections("pbmc3k", dataset_path )
-	print("✅ Finished loading, creating 3D objects");
 	add_child(core)
 	core.add_to_group("PrintForgeCore")  # ✅ now discoverable!
 	print("Initializing the XRinterface:")
 	
 	add_child(xr_user)
-	
+
+
+func _on_dataset_load_progress(name: String, fraction: float, stage: String) -> void:
+	print("⏳ %s: %s %d%%" % [name, stage, int(fraction * 100.0)])
+
+
+func _on_dataset_loaded(name: String, summary: Dictionary) -> void:
+	print("✅ Finished loading %s: %s" % [name, summary])
+
+
+func _on_dataset_load_failed(name: String, error: String) -> void:
+	push_error("❌ Loading %s failed: %s" % [name, error])
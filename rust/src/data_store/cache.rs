@@ -0,0 +1,561 @@
+//cache.rs
+use crate::data_store::DataStore;
+use crate::data_store::doctor::{Issue, Severity};
+use crate::data_store::genes::read_feature_records;
+use crate::data_store::manifest::{DatasetManifest, MANIFEST_FILE};
+use crate::data_store::modality::Modality;
+use crate::data_store::source;
+use crate::data_store::MtxProgress;
+use memmap2::Mmap;
+use ndarray::Array2;
+use sprs::CsMat;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::UNIX_EPOCH;
+
+/// File name of the cache inside the dataset folder.
+pub const CACHE_FILE: &str = ".printforge3d.cache";
+const CACHE_MAGIC: &[u8; 8] = b"PF3DSTOR";
+/// Bump this whenever the layout below changes - old caches are then rebuilt.
+const CACHE_VERSION: u32 = 5;
+/// Values read per block by `CacheReader` (a corrupt length must not allocate everything up front).
+const READ_BLOCK: usize = 1 << 16;
+
+/// Size and modification time of one source file the cache was built from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SourceStamp {
+    name: String,
+    size: u64,
+    mtime_ns: u64,
+}
+
+impl DataStore {
+    /// Like `from_cellranger`, but keeps a binary copy of the expensive parts
+    /// (matrix, gene/cell names and projections) in `<dir>/.printforge3d.cache`.
+    ///
+    /// The cache is memory mapped on later loads: the CSR and CSC buffers of the counts are
+    /// aligned little endian sections that are viewed in the mapped file and copied into the
+    /// matrices in one go, without any per value parsing. It is rebuilt automatically if any
+    /// of the source files (or `dataset.toml`) changed size or mtime. The metadata (`meta.tsv`,
+    /// `meta.factors.json`) is small and always read from its text files, so
+    /// edits made to the factors from VR do not invalidate the cache. The load issues of
+    /// the cached files (e.g. projections that did not fit) are part of the cache.
+    /// Folders inside an archive are read without a cache.
+    pub fn from_cellranger_cached<P: AsRef<Path>>(dir: P) -> Result<Self, String> {
+        Self::from_cellranger_cached_with_progress(dir, &|_| {})
+    }
+
+    /// `from_cellranger_cached` reporting the matrix parsing progress (only called without a valid cache).
+    pub fn from_cellranger_cached_with_progress<P: AsRef<Path>>(
+        dir: P,
+        progress: &dyn Fn(&MtxProgress),
+    ) -> Result<Self, String> {
+        let dir = dir.as_ref();
+        if source::in_archive(dir) {
+            // nowhere to put the cache
+            return Self::from_cellranger_with_progress(dir, progress);
+        }
+        let stamps = source_stamps(dir);
+        let cache_path = dir.join(CACHE_FILE);
+
+        match read_cache(&cache_path, &stamps) {
+            Ok(Some(parts)) => {
+                println!("⚡ Using dataset cache {:?}", cache_path);
+                let (cell_meta, factor_numbers) = Self::load_cell_meta(dir, &parts.cell_names)?;
+                let gene_meta = Default::default();
+                let mut ret = Self::from_parts(
+                    parts.counts,
+                    parts.gene_names,
+                    parts.cell_names,
+                    cell_meta,
+                    gene_meta,
+                    parts.drcs,
+                )?;
+                ret.factor_numbers = factor_numbers;
+                ret.counts_csc = OnceLock::from(parts.counts_csc);
+                ret.primary_modality = parts.primary_modality;
+                ret.modalities = parts.modalities;
+                ret.load_issues = parts.load_issues;
+                let manifest = DatasetManifest::load(dir)?;
+                let files = manifest.resolve(dir);
+                match read_feature_records(&files.features) {
+                    Ok(features) => ret.set_gene_table_from_features(&features)?,
+                    Err(e) => println!("⚠️ gene meta not loaded: {}", e),
+                }
+                ret.load_gene_annotation_file(files.gene_annotation.as_deref());
+                ret.manifest = manifest;
+                return Ok(ret);
+            }
+            Ok(None) => {}
+            Err(e) => println!("⚠️ Ignoring broken cache {:?}: {}", cache_path, e),
+        }
+
+        let ret = Self::from_cellranger_with_progress(dir, progress)?;
+        match ret.write_cache(&cache_path, &stamps) {
+            Ok(()) => println!("💾 Wrote dataset cache {:?}", cache_path),
+            Err(e) => println!("⚠️ Could not write cache {:?}: {}", cache_path, e),
+        }
+        Ok(ret)
+    }
+
+    fn write_cache(&self, path: &Path, stamps: &[SourceStamp]) -> Result<(), String> {
+        // write to a temp file first so an interrupted write never leaves a half cache behind
+        let tmp: PathBuf = path.with_extension("tmp");
+        {
+            let f = File::create(&tmp).map_err(|e| e.to_string())?;
+            let mut w = CacheWriter { out: BufWriter::new(f) };
+            w.bytes(CACHE_MAGIC)?;
+            w.u32(CACHE_VERSION)?;
+
+            w.stamps(stamps)?;
+
+            w.matrix(&self.counts)?;
+            w.matrix(self.counts_csc())?;
+
+            w.strings(&self.gene_names)?;
+            w.strings(&self.cell_names)?;
+
+            w.string(&self.primary_modality)?;
+            w.u64(self.modalities.len() as u64)?;
+            for (name, m) in &self.modalities {
+                w.string(name)?;
+                w.matrix(&m.counts)?;
+                w.strings(&m.feature_names)?;
+            }
+
+            w.u64(self.drcs.len() as u64)?;
+            for (name, view) in &self.drcs {
+                w.string(name)?;
+                w.u64(view.nrows() as u64)?;
+                w.u64(view.ncols() as u64)?;
+                let flat: Vec<f32> = view.iter().copied().collect();
+                w.aligned_f32s(&flat)?;
+            }
+
+            // the text files read again on every load report their problems themselves
+            let issues: Vec<&Issue> = self
+                .load_issues
+                .iter()
+                .filter(|i| stamps.iter().any(|s| Path::new(&s.name).file_name().is_some_and(|n| *n == *i.file)))
+                .collect();
+            w.u64(issues.len() as u64)?;
+            for issue in issues {
+                w.u32(match issue.severity {
+                    Severity::Error => 0,
+                    Severity::Warning => 1,
+                })?;
+                w.string(&issue.file)?;
+                // rows are 1-based, 0 is "no row"
+                w.u64(issue.row.unwrap_or(0) as u64)?;
+                w.string(&issue.problem)?;
+            }
+            w.out.flush().map_err(|e| e.to_string())?;
+        }
+        fs::rename(&tmp, path).map_err(|e| e.to_string())
+    }
+}
+
+/// The parts of a DataStore that live in the cache.
+struct CachedParts {
+    counts: CsMat<f32>,
+    counts_csc: CsMat<f32>,
+    gene_names: Vec<String>,
+    cell_names: Vec<String>,
+    primary_modality: String,
+    modalities: HashMap<String, Modality>,
+    drcs: HashMap<String, Array2<f32>>,
+    load_issues: Vec<Issue>,
+}
+
+/// Stamp all files the folder loader reads (except the small metadata tables).
+fn source_stamps(dir: &Path) -> Vec<SourceStamp> {
+    // an unreadable manifest is reported by the loader itself
+    let files = DatasetManifest::load(dir).unwrap_or_default().resolve(dir);
+    let manifest = dir.join(MANIFEST_FILE);
+    let mut paths = files.sources();
+    paths.push(&manifest);
+    file_stamps(dir, &paths)
+}
+
+/// Stamp `paths` (named relative to `dir`, missing files are left out), sorted by name.
+pub(crate) fn file_stamps(dir: &Path, paths: &[&Path]) -> Vec<SourceStamp> {
+    let mut stamps = Vec::new();
+    for &path in paths {
+        let Ok(meta) = fs::metadata(path) else { continue };
+        let name = path.strip_prefix(dir).unwrap_or(path).to_string_lossy().to_string();
+        let mtime_ns = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        stamps.push(SourceStamp { name, size: meta.len(), mtime_ns });
+    }
+    stamps.sort_by(|a, b| a.name.cmp(&b.name));
+    stamps
+}
+
+/// `Ok(None)` if there is no usable cache (missing, old version or stale sources).
+fn read_cache(path: &Path, stamps: &[SourceStamp]) -> Result<Option<CachedParts>, String> {
+    let Ok(f) = File::open(path) else {
+        return Ok(None);
+    };
+    // Safety: the cache is only ever replaced by rename, never modified in place.
+    let mmap = unsafe { Mmap::map(&f) }.map_err(|e| e.to_string())?;
+    let mut r = CacheReader::new(&mmap[..]);
+
+    if r.bytes(CACHE_MAGIC.len())? != CACHE_MAGIC || r.u32()? != CACHE_VERSION {
+        return Ok(None);
+    }
+    if r.stamps()? != stamps {
+        println!("♻️ Dataset files changed - rebuilding the cache");
+        return Ok(None);
+    }
+
+    let counts = r.matrix()?;
+    let counts_csc = r.matrix()?;
+    if !counts.is_csr() || counts_csc.is_csr() || counts_csc.shape() != counts.shape() {
+        return Err("cached counts do not match their CSC copy".to_string());
+    }
+    let gene_names = r.strings()?;
+    let cell_names = r.strings()?;
+
+    let primary_modality = r.string()?;
+    let n_modalities = r.u64()? as usize;
+    let mut modalities = HashMap::with_capacity(n_modalities);
+    for _ in 0..n_modalities {
+        let name = r.string()?;
+        let counts = r.matrix()?;
+        let feature_names = r.strings()?;
+        modalities.insert(name, Modality::new(counts, feature_names));
+    }
+
+    let n_drcs = r.u64()? as usize;
+    let mut drcs = HashMap::with_capacity(n_drcs);
+    for _ in 0..n_drcs {
+        let name = r.string()?;
+        let nrows = r.u64()? as usize;
+        let ncols = r.u64()? as usize;
+        let flat = r.section::<f32>()?.to_vec();
+        let view = Array2::from_shape_vec((nrows, ncols), flat).map_err(|e| e.to_string())?;
+        drcs.insert(name, view);
+    }
+
+    let n_issues = r.u64()? as usize;
+    let mut load_issues = Vec::with_capacity(n_issues.min(READ_BLOCK));
+    for _ in 0..n_issues {
+        let severity = match r.u32()? {
+            0 => Severity::Error,
+            _ => Severity::Warning,
+        };
+        let file = r.string()?;
+        let row = Some(r.u64()? as usize).filter(|row| *row > 0);
+        load_issues.push(Issue { severity, file, row, problem: r.string()? });
+    }
+
+    Ok(Some(CachedParts { counts, counts_csc, gene_names, cell_names, primary_modality, modalities, drcs, load_issues }))
+}
+
+/* ---------- little endian (de)serialization helpers ---------- */
+
+pub(crate) struct CacheWriter {
+    pub(crate) out: BufWriter<File>,
+}
+
+impl CacheWriter {
+    pub(crate) fn bytes(&mut self, b: &[u8]) -> Result<(), String> {
+        self.out.write_all(b).map_err(|e| e.to_string())
+    }
+    pub(crate) fn u32(&mut self, v: u32) -> Result<(), String> {
+        self.bytes(&v.to_le_bytes())
+    }
+    pub(crate) fn u64(&mut self, v: u64) -> Result<(), String> {
+        self.bytes(&v.to_le_bytes())
+    }
+    pub(crate) fn string(&mut self, s: &str) -> Result<(), String> {
+        self.u64(s.len() as u64)?;
+        self.bytes(s.as_bytes())
+    }
+    pub(crate) fn strings(&mut self, v: &[String]) -> Result<(), String> {
+        self.u64(v.len() as u64)?;
+        v.iter().try_for_each(|s| self.string(s))
+    }
+    pub(crate) fn usizes(&mut self, v: &[usize]) -> Result<(), String> {
+        self.u64(v.len() as u64)?;
+        v.iter().try_for_each(|x| self.u64(*x as u64))
+    }
+    pub(crate) fn f32s(&mut self, v: &[f32]) -> Result<(), String> {
+        self.u64(v.len() as u64)?;
+        v.iter().try_for_each(|x| self.bytes(&x.to_le_bytes()))
+    }
+    pub(crate) fn stamps(&mut self, v: &[SourceStamp]) -> Result<(), String> {
+        self.u64(v.len() as u64)?;
+        v.iter().try_for_each(|s| {
+            self.string(&s.name)?;
+            self.u64(s.size)?;
+            self.u64(s.mtime_ns)
+        })
+    }
+    /// Zero bytes up to the next multiple of 8, so the values that follow can be used in place.
+    fn align(&mut self) -> Result<(), String> {
+        let pos = self.out.stream_position().map_err(|e| e.to_string())?;
+        self.bytes(&[0u8; 8][..((8 - pos % 8) % 8) as usize])
+    }
+    /// Like `usizes`, but aligned (see `CacheReader::section`).
+    fn aligned_u64s(&mut self, v: &[usize]) -> Result<(), String> {
+        self.u64(v.len() as u64)?;
+        self.align()?;
+        v.iter().try_for_each(|x| self.u64(*x as u64))
+    }
+    /// Indices below 2^32 as an aligned u32 section.
+    fn aligned_u32s(&mut self, v: &[usize]) -> Result<(), String> {
+        self.u64(v.len() as u64)?;
+        self.align()?;
+        v.iter().try_for_each(|x| match u32::try_from(*x) {
+            Ok(x) => self.u32(x),
+            Err(_) => Err(format!("index {} does not fit the cache", x)),
+        })
+    }
+    /// Like `f32s`, but aligned (see `CacheReader::section`).
+    pub(crate) fn aligned_f32s(&mut self, v: &[f32]) -> Result<(), String> {
+        self.u64(v.len() as u64)?;
+        self.align()?;
+        v.iter().try_for_each(|x| self.bytes(&x.to_le_bytes()))
+    }
+    /// A compressed matrix as it is stored (CSR or CSC): the storage, the shape and
+    /// the indptr (u64), indices (u32) and data (f32) sections.
+    pub(crate) fn matrix(&mut self, m: &CsMat<f32>) -> Result<(), String> {
+        self.u32(if m.is_csr() { 0 } else { 1 })?;
+        self.u64(m.rows() as u64)?;
+        self.u64(m.cols() as u64)?;
+        self.aligned_u64s(&m.proper_indptr())?;
+        self.aligned_u32s(m.indices())?;
+        self.aligned_f32s(m.data())
+    }
+}
+
+/// Reads what `CacheWriter` wrote, from a file or from a mapped slice.
+pub(crate) struct CacheReader<R> {
+    input: R,
+    /// bytes read so far
+    pub(crate) pos: usize,
+}
+
+impl<R: Read> CacheReader<R> {
+    pub(crate) fn new(input: R) -> Self {
+        Self { input, pos: 0 }
+    }
+    fn fill(&mut self, buf: &mut [u8]) -> Result<(), String> {
+        self.input.read_exact(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => "truncated cache".to_string(),
+            _ => e.to_string(),
+        })?;
+        self.pos += buf.len();
+        Ok(())
+    }
+    pub(crate) fn bytes(&mut self, n: usize) -> Result<Vec<u8>, String> {
+        let mut ret = Vec::new();
+        (&mut self.input).take(n as u64).read_to_end(&mut ret).map_err(|e| e.to_string())?;
+        if ret.len() != n {
+            return Err("truncated cache".to_string());
+        }
+        self.pos += n;
+        Ok(ret)
+    }
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let mut b = [0u8; N];
+        self.fill(&mut b)?;
+        Ok(b)
+    }
+    pub(crate) fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+    pub(crate) fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+    pub(crate) fn string(&mut self) -> Result<String, String> {
+        let n = self.u64()? as usize;
+        String::from_utf8(self.bytes(n)?).map_err(|e| e.to_string())
+    }
+    pub(crate) fn strings(&mut self) -> Result<Vec<String>, String> {
+        let n = self.u64()? as usize;
+        (0..n).map(|_| self.string()).collect()
+    }
+    pub(crate) fn stamps(&mut self) -> Result<Vec<SourceStamp>, String> {
+        let n = self.u64()? as usize;
+        (0..n)
+            .map(|_| -> Result<SourceStamp, String> {
+                Ok(SourceStamp { name: self.string()?, size: self.u64()?, mtime_ns: self.u64()? })
+            })
+            .collect()
+    }
+    /// A length followed by that many `N` byte values.
+    fn values<const N: usize, T>(&mut self, convert: impl Fn([u8; N]) -> T) -> Result<Vec<T>, String> {
+        let n = self.u64()? as usize;
+        let mut ret = Vec::with_capacity(n.min(READ_BLOCK));
+        let mut buf = vec![0u8; n.min(READ_BLOCK) * N];
+        let mut left = n;
+        while left > 0 {
+            let block = &mut buf[..left.min(READ_BLOCK) * N];
+            self.fill(block)?;
+            ret.extend(block.chunks_exact(N).map(|c| convert(c.try_into().unwrap())));
+            left -= block.len() / N;
+        }
+        Ok(ret)
+    }
+    pub(crate) fn usizes(&mut self) -> Result<Vec<usize>, String> {
+        self.values(|b| u64::from_le_bytes(b) as usize)
+    }
+    pub(crate) fn f32s(&mut self) -> Result<Vec<f32>, String> {
+        self.values(f32::from_le_bytes)
+    }
+}
+
+impl<'a> CacheReader<&'a [u8]> {
+    /// Step over `n` bytes without reading them (e.g. a block used straight from a mapped file).
+    pub(crate) fn skip(&mut self, n: usize) -> Result<(), String> {
+        self.input = self.input.get(n..).ok_or("truncated cache")?;
+        self.pos += n;
+        Ok(())
+    }
+    /// Step over the padding `CacheWriter::align` wrote (the slice starts at the file start).
+    fn align(&mut self) -> Result<(), String> {
+        self.skip((8 - self.pos % 8) % 8)
+    }
+    /// The values of an aligned section, viewed in place in the mapped file.
+    pub(crate) fn section<T: Plain>(&mut self) -> Result<&'a [T], String> {
+        let n = self.u64()? as usize;
+        self.align()?;
+        let input: &'a [u8] = self.input;
+        let bytes = n
+            .checked_mul(std::mem::size_of::<T>())
+            .and_then(|len| input.get(..len))
+            .ok_or("truncated cache")?;
+        self.skip(bytes.len())?;
+        view(bytes)
+    }
+    /// A matrix written by `CacheWriter::matrix`, copied out of the mapped sections in one go.
+    pub(crate) fn matrix(&mut self) -> Result<CsMat<f32>, String> {
+        let csr = self.u32()? == 0;
+        let shape = (self.u64()? as usize, self.u64()? as usize);
+        let indptr: Vec<usize> = self.section::<u64>()?.iter().map(|&p| p as usize).collect();
+        let indices: Vec<usize> = self.section::<u32>()?.iter().map(|&i| i as usize).collect();
+        let data = self.section::<f32>()?.to_vec();
+        let m = if csr {
+            CsMat::try_new(shape, indptr, indices, data)
+        } else {
+            CsMat::try_new_csc(shape, indptr, indices, data)
+        };
+        m.map_err(|(_, _, _, e)| format!("invalid cached matrix: {}", e))
+    }
+}
+
+/// Numbers for which every bit pattern is a valid value, so mapped bytes can be viewed as them.
+///
+/// # Safety
+/// Only implement this for plain number types.
+pub(crate) unsafe trait Plain: Copy {}
+unsafe impl Plain for u32 {}
+unsafe impl Plain for u64 {}
+unsafe impl Plain for f32 {}
+
+/// View little endian bytes as `T` without copying them.
+fn view<T: Plain>(bytes: &[u8]) -> Result<&[T], String> {
+    if cfg!(target_endian = "big") {
+        return Err("the cache is little endian".to_string());
+    }
+    // Safety: any bits are a valid `T` and the checks below reject a misaligned start or end.
+    let (head, values, tail) = unsafe { bytes.align_to::<T>() };
+    if !head.is_empty() || !tail.is_empty() {
+        return Err("misaligned cache section".to_string());
+    }
+    Ok(values)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_store::data_store::test_store;
+    use sprs::TriMat;
+
+    fn tmp_dir(tag: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("printforge3d_cache_{}_{}", tag, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn cache_roundtrip_and_invalidation() {
+        let dir = tmp_dir("roundtrip");
+        fs::write(dir.join("matrix.mtx.gz"), b"placeholder").unwrap();
+
+        let mut tri = TriMat::<f32>::new((3, 2));
+        tri.add_triplet(0, 0, 1.0);
+        tri.add_triplet(2, 1, 5.5);
+        let mut drcs = HashMap::new();
+        drcs.insert("umap".to_string(), Array2::from_shape_vec((2, 3), vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]).unwrap());
+        let mut ds = test_store(tri.to_csr(), vec!["g1".into(), "g2".into(), "g3".into()], vec!["c1".into(), "c2".into()]);
+        ds.drcs = drcs;
+        let issue = |file: &str, row| Issue { severity: Severity::Warning, file: file.to_string(), row, problem: "odd".into() };
+        // meta.tsv is read again on load and reports its own problems
+        ds.load_issues = vec![issue("matrix.mtx.gz", Some(3)), issue("meta.tsv", None), issue("matrix.mtx.gz", None)];
+
+        let path = dir.join(CACHE_FILE);
+        let stamps = source_stamps(&dir);
+        ds.write_cache(&path, &stamps).unwrap();
+
+        let parts = read_cache(&path, &stamps).unwrap().expect("cache should be valid");
+        assert_eq!(parts.counts, ds.counts);
+        assert_eq!(parts.counts_csc, *ds.counts_csc());
+        assert_eq!(parts.gene_names, ds.gene_names);
+        assert_eq!(parts.cell_names, ds.cell_names);
+        assert_eq!(parts.drcs["umap"], ds.drcs["umap"]);
+        let rows: Vec<(&str, Option<usize>)> = parts.load_issues.iter().map(|i| (i.file.as_str(), i.row)).collect();
+        assert_eq!(rows, vec![("matrix.mtx.gz", Some(3)), ("matrix.mtx.gz", None)]);
+
+        // a changed source file size makes the cache stale
+        fs::write(dir.join("matrix.mtx.gz"), b"a different placeholder").unwrap();
+        assert!(read_cache(&path, &source_stamps(&dir)).unwrap().is_none());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reopened_cache_matches_the_source_load() {
+        let dir = tmp_dir("reopen");
+        fs::write(dir.join("features.tsv"), "ENSG1\tA\tGene Expression\nENSG2\tB\tGene Expression\nCD3\tCD3\tAntibody Capture\n").unwrap();
+        fs::write(dir.join("barcodes.tsv"), "AAA-1\nCCC-1\nGGG-1\n").unwrap();
+        fs::write(
+            dir.join("matrix.mtx"),
+            "%%MatrixMarket matrix coordinate integer general\n3 3 5\n1 1 4\n2 1 1\n2 3 7\n3 2 2\n3 3 9\n",
+        )
+        .unwrap();
+        fs::write(dir.join("umap.drc"), "cell\tx\ty\tz\nAAA-1\t0\t1\t2\nCCC-1\t3\t4\t5\nGGG-1\t6\t7\t8\n").unwrap();
+
+        let source = DataStore::from_cellranger(&dir).unwrap();
+        let first = DataStore::from_cellranger_cached(&dir).unwrap();
+        assert!(dir.join(CACHE_FILE).exists());
+        // the second load maps the cache written by the first
+        let reopened = DataStore::from_cellranger_cached(&dir).unwrap();
+        for ds in [&first, &reopened] {
+            assert_eq!(ds.counts, source.counts);
+            assert_eq!(ds.counts_csc(), source.counts_csc());
+            assert_eq!(ds.gene_names, source.gene_names);
+            assert_eq!(ds.cell_names, source.cell_names);
+            assert_eq!(ds.drcs, source.drcs);
+            assert_eq!(ds.primary_modality, source.primary_modality);
+            assert_eq!(ds.modalities.len(), source.modalities.len());
+            for (name, m) in &source.modalities {
+                assert_eq!(ds.modalities[name].counts, m.counts);
+                assert_eq!(ds.modalities[name].feature_names, m.feature_names);
+            }
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,312 @@
+//cellexal.rs
+//! Folders exported by the original CellexalVR R package (`cellexalvrR::export2cellexalvr`):
+//!
+//! * `database.sqlite` - the expression (tables `genes`, `cells` and `datavalues`)
+//! * `*.mds` - one projection per file (cell name + 2 or 3 coordinates)
+//! * `a.meta.cell` - 0/1 columns named `<group>@<level>`
+//! * `c.meta` - optional per cell annotation table
+//! * `index.facs` - optional per cell FACS intensities
+use crate::data_store::DataStore;
+use crate::data_store::doctor::{Issue, Severity};
+use crate::data_store::meta_table::{MetaColumn, MetaTable};
+use crate::data_store::projection::map_rows_by_barcode;
+use crate::data_store::source;
+use rusqlite::{Connection, OpenFlags};
+use rust_data_table::SurvivalData;
+use sprs::TriMat;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// The expression database of a CellexalVR folder.
+pub const DATABASE_FILE: &str = "database.sqlite";
+/// The 0/1 cell annotation of a CellexalVR folder.
+pub const META_CELL_FILE: &str = "a.meta.cell";
+
+/// Separates group and level in the `a.meta.cell` column names.
+const LEVEL_SEP: char = '@';
+
+/// Is `dir` a folder exported by cellexalvrR (rather than a Cell Ranger like one)?
+pub fn is_cellexal_folder(dir: &Path) -> bool {
+    source::is_file(&dir.join(DATABASE_FILE)) && source::is_file(&dir.join(META_CELL_FILE))
+}
+
+/// A tab separated table with a header line and the cell names in the first column.
+struct CellTable {
+    columns: Vec<String>,
+    cells: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+impl CellTable {
+    fn read(path: &Path) -> Result<Self, String> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .has_headers(true)
+            .flexible(true)
+            .from_reader(source::open_text(path)?);
+        let columns: Vec<String> = rdr
+            .headers()
+            .map_err(|e| format!("❌ Failed to read the header of {:?}: {}", path, e))?
+            .iter()
+            .skip(1)
+            .map(|s| s.trim().to_string())
+            .collect();
+        let mut cells = Vec::new();
+        let mut rows = Vec::new();
+        for record in rdr.records() {
+            let record = record.map_err(|e| format!("❌ Failed to read {:?}: {}", path, e))?;
+            let Some(cell) = record.get(0) else { continue };
+            cells.push(cell.to_string());
+            rows.push(record.iter().skip(1).map(|s| s.trim().to_string()).collect());
+        }
+        Ok(Self { columns, cells, rows })
+    }
+
+    /// The values of column `col` in the order of `cell_names` (missing cells get "").
+    fn column_for(&self, col: usize, index: &HashMap<&str, usize>, n_cells: usize) -> Vec<String> {
+        let mut values = vec![String::new(); n_cells];
+        for (cell, row) in self.cells.iter().zip(&self.rows) {
+            if let (Some(&i), Some(v)) = (index.get(cell.as_str()), row.get(col)) {
+                values[i] = v.clone();
+            }
+        }
+        values
+    }
+}
+
+impl DataStore {
+    /// Load a folder exported by the CellexalVR R package.
+    ///
+    /// The genes and cells are taken from the sqlite database, the `*.mds` files
+    /// become `drcs` (named after the file), the `<group>@<level>` columns of
+    /// `a.meta.cell` are collapsed into one factor per group and the columns of
+    /// `c.meta` and `index.facs` are added to `cell_meta` as they are.
+    pub fn from_cellexal_folder<P: AsRef<Path>>(dir: P) -> Result<Self, String> {
+        let dir = dir.as_ref();
+        if source::in_archive(dir) {
+            return Err(format!("❌ SQLite databases can not be read from an archive - unpack {:?} first", dir));
+        }
+        let Database { counts, gene_names, cell_names } = read_database(&dir.join(DATABASE_FILE))?;
+        let index: HashMap<&str, usize> = cell_names.iter().enumerate().map(|(i, c)| (c.as_str(), i)).collect();
+        let mut issues = Vec::new();
+
+        // --- Metadata ---
+        let mut table = MetaTable::new();
+        table.push(MetaColumn::factor_from_strings("barcode", &cell_names))?;
+        let meta_cell = CellTable::read(&dir.join(META_CELL_FILE))?;
+        for col in meta_cell_groups(&meta_cell, &index, cell_names.len()) {
+            table.push(col)?;
+        }
+        for file in ["c.meta", "index.facs"] {
+            let path = dir.join(file);
+            if !source::is_file(&path) {
+                continue;
+            }
+            let extra = CellTable::read(&path)?;
+            for (c, name) in extra.columns.iter().enumerate() {
+                if table.has_column(name) {
+                    issues.push(Issue {
+                        severity: Severity::Warning,
+                        file: file.to_string(),
+                        row: None,
+                        problem: format!("column '{}' already defined - skipped", name),
+                    });
+                    continue;
+                }
+                table.push(MetaColumn::typed_from_strings(name, &extra.column_for(c, &index, cell_names.len())))?;
+            }
+        }
+        let cell_meta = table.into_survival_data()?;
+
+        let mut ret = Self::from_parts(counts, gene_names, cell_names, cell_meta, SurvivalData::default(), HashMap::new())?;
+        ret.load_issues = issues;
+
+        // --- Projections ---
+        for path in mds_files(dir) {
+            let name = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+            let file = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            if let Err(e) = ret.load_mds(&name, &path) {
+                println!("⚠️ Failed to load projection '{}': {}", name, e);
+                ret.load_issues.push(Issue {
+                    severity: Severity::Error,
+                    file,
+                    row: None,
+                    problem: format!("projection not loaded: {}", e),
+                });
+            }
+        }
+
+        println!(
+            "📂 CellexalVR folder {:?}: {} genes × {} cells, projections {:?}",
+            dir,
+            ret.gene_names.len(),
+            ret.cell_names.len(),
+            ret.drcs.keys().collect::<Vec<_>>()
+        );
+        Ok(ret)
+    }
+
+    /// Read one `.mds` file (cell name and coordinates, with or without a header line).
+    fn load_mds(&mut self, name: &str, path: &Path) -> Result<(), String> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(source::open_text(path)?);
+        let mut barcodes = Vec::new();
+        let mut rows = Vec::new();
+        for (i, record) in rdr.records().enumerate() {
+            let record = record.map_err(|e| format!("❌ Failed to read {:?}: {}", path, e))?;
+            let Some(cell) = record.get(0) else { continue };
+            let row: Vec<f32> = record
+                .iter()
+                .skip(1)
+                .map(|v| v.trim().parse::<f32>().unwrap_or(f32::NAN))
+                .collect();
+            // a header line has no numbers at all
+            if i == 0 && row.iter().all(|v| v.is_nan()) {
+                continue;
+            }
+            barcodes.push(cell.to_string());
+            rows.push(row);
+        }
+        if rows.iter().map(|r| r.len()).max().unwrap_or(0) < 2 {
+            return Err(format!("❌ {:?} needs at least 2 coordinates per cell", path));
+        }
+        let (data, stats) = map_rows_by_barcode(&barcodes, &rows, &self.cell_names);
+        if stats.matched == 0 {
+            return Err(format!("❌ No cell of {:?} is part of the database", path));
+        }
+        println!("📈 Projection '{}': {} cells matched, {} rows dropped", name, stats.matched, stats.dropped);
+        self.drcs.insert(name.to_string(), data);
+        Ok(())
+    }
+}
+
+/// The content of a cellexalvrR `database.sqlite`.
+struct Database {
+    /// genes × cells
+    counts: sprs::CsMat<f32>,
+    gene_names: Vec<String>,
+    cell_names: Vec<String>,
+}
+
+/// Read the expression from a cellexalvrR `database.sqlite`.
+/// Genes and cells are ordered by their id, the values are stored genes × cells.
+fn read_database(path: &Path) -> Result<Database, String> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| format!("❌ Failed to open {:?}: {}", path, e))?;
+    let sql_err = |e: rusqlite::Error| format!("❌ Failed to read {:?}: {}", path, e);
+
+    let read_names = |query: &str| -> Result<(Vec<String>, HashMap<i64, usize>), String> {
+        let mut stmt = conn.prepare(query).map_err(sql_err)?;
+        let mut names = Vec::new();
+        let mut ids = HashMap::new();
+        let rows = stmt
+            .query_map([], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, String>(1)?)))
+            .map_err(sql_err)?;
+        for row in rows {
+            let (id, name) = row.map_err(sql_err)?;
+            ids.insert(id, names.len());
+            names.push(name);
+        }
+        Ok((names, ids))
+    };
+    let (gene_names, gene_ids) = read_names("SELECT id, gname FROM genes ORDER BY id")?;
+    let (cell_names, cell_ids) = read_names("SELECT id, cname FROM cells ORDER BY id")?;
+    if gene_names.is_empty() || cell_names.is_empty() {
+        return Err(format!("❌ No genes or cells in {:?}", path));
+    }
+
+    let mut tri = TriMat::<f32>::new((gene_names.len(), cell_names.len()));
+    let mut stmt = conn
+        .prepare("SELECT gene_id, cell_id, value FROM datavalues")
+        .map_err(sql_err)?;
+    let rows = stmt
+        .query_map([], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, i64>(1)?, r.get::<_, f64>(2)?)))
+        .map_err(sql_err)?;
+    let mut unknown = 0usize;
+    for row in rows {
+        let (g, c, v) = row.map_err(sql_err)?;
+        match (gene_ids.get(&g), cell_ids.get(&c)) {
+            (Some(&g), Some(&c)) if v != 0.0 => tri.add_triplet(g, c, v as f32),
+            (Some(_), Some(_)) => {}
+            _ => unknown += 1,
+        }
+    }
+    if unknown > 0 {
+        println!("⚠️ {} values in {:?} reference unknown genes or cells - skipped", unknown, path);
+    }
+    println!("🗄️ read {} values from {:?}", tri.nnz(), path);
+    Ok(Database { counts: tri.to_csr(), gene_names, cell_names })
+}
+
+/// Collapse the 0/1 `<group>@<level>` columns into one factor per group.
+/// A column without a group is a group of its own; cells without a 1 are missing.
+fn meta_cell_groups(table: &CellTable, index: &HashMap<&str, usize>, n_cells: usize) -> Vec<MetaColumn> {
+    let mut order: Vec<String> = Vec::new();
+    let mut groups: HashMap<String, (Vec<String>, Vec<Option<usize>>)> = HashMap::new();
+    for (c, column) in table.columns.iter().enumerate() {
+        let (group, level) = column.split_once(LEVEL_SEP).unwrap_or((column, column));
+        let (levels, codes) = groups.entry(group.to_string()).or_insert_with(|| {
+            order.push(group.to_string());
+            (Vec::new(), vec![None; n_cells])
+        });
+        levels.push(level.to_string());
+        let id = levels.len() - 1;
+        for (cell, row) in table.cells.iter().zip(&table.rows) {
+            let set = row.get(c).and_then(|v| v.parse::<f64>().ok()).is_some_and(|v| v != 0.0);
+            if let (true, Some(&i)) = (set, index.get(cell.as_str())) {
+                codes[i] = Some(id);
+            }
+        }
+    }
+    order
+        .into_iter()
+        .map(|name| {
+            let (levels, codes) = groups.remove(&name).unwrap_or_default();
+            MetaColumn::Factor { name, levels, codes }
+        })
+        .collect()
+}
+
+/// All `*.mds` files in `dir`, sorted by name.
+fn mds_files(dir: &Path) -> Vec<PathBuf> {
+    source::list_dir(dir)
+        .into_iter()
+        .filter(|p| p.extension().is_some_and(|e| e.eq_ignore_ascii_case("mds")))
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn meta_cell_columns_become_factors() {
+        let table = CellTable {
+            columns: vec!["Type@T".into(), "Type@B".into(), "sorted".into()],
+            cells: vec!["c2".into(), "c1".into(), "c3".into()],
+            rows: vec![
+                vec!["0".into(), "1".into(), "1".into()],
+                vec!["1".into(), "0".into(), "0".into()],
+                vec!["0".into(), "0".into(), "0".into()],
+            ],
+        };
+        let cells = ["c1", "c2", "c3"];
+        let index: HashMap<&str, usize> = cells.iter().enumerate().map(|(i, c)| (*c, i)).collect();
+
+        let cols = meta_cell_groups(&table, &index, 3);
+        assert_eq!(cols.len(), 2);
+        match &cols[0] {
+            MetaColumn::Factor { name, levels, codes } => {
+                assert_eq!(name, "Type");
+                assert_eq!(levels, &vec!["T".to_string(), "B".to_string()]);
+                assert_eq!(codes, &vec![Some(0), Some(1), None]);
+            }
+            _ => panic!("expected a factor column"),
+        }
+        assert_eq!(cols[1].name(), "sorted");
+    }
+}
@@ -1,164 +1,334 @@
 use rust_data_table::SurvivalData;
-use std::collections::HashSet;
-use sprs::io::read_matrix_market_from_bufread;
-use sprs::{CsMat, TriMat};
-use ndarray::{Array2, s,Axis };
-use std::io::BufReader;
-use flate2::read::GzDecoder;
-use std::collections::HashMap;
-use std::path::Path;
-
-use std::fs::{self,File};
+use sprs::CsMat;
+use ndarray::{Array2, Axis };
+use std::cell::Cell;
+use std::sync::OnceLock;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::data_store::cellexal::is_cellexal_folder;
+use crate::data_store::doctor::{Issue, Severity};
+use crate::data_store::genes::{feature_type, read_feature_records, GeneResolver};
+use crate::data_store::knn::NeighborGraph;
+use crate::data_store::manifest::DatasetManifest;
+use crate::data_store::meta_table::{detect_delimiter, factor_codes, factor_numbers_in, survival_data_from, FactorCodes, FactorNumbers, MetaColumn, MetaTable};
+use crate::data_store::orientation::orient;
+use crate::data_store::out_of_core::{OutOfCoreCounts, OUT_OF_CORE_FILE};
+use crate::data_store::spatial::{is_visium_folder, SpatialInfo};
+use crate::data_store::projection::{map_rows_by_barcode, ProjectionAxes, ProjectionMatch};
+use crate::data_store::mtx_reader::{read_matrix_market, MtxProgress};
+use crate::data_store::modality::{split_by_feature_type, Modality, GENE_EXPRESSION};
+use crate::data_store::normalization::{DenseLayer, LayerRow, RAW_LAYER};
+use crate::data_store::source::{self, open_text};
 
 #[derive(Debug)]
 pub struct DataStore {
-    pub counts: CsMat<f32>,      // expression matrix (genes × cells)
+    pub counts: CsMat<f32>,      // expression matrix (genes × cells), empty if out_of_core is set
+    pub(crate) counts_csc: OnceLock<CsMat<f32>>, // the same as CSC, see counts_csc()
     pub gene_names: Vec<String>, // from features.tsv.gz
     pub cell_names: Vec<String>, // from barcodes.tsv.gz
     pub cell_meta: SurvivalData, // all annotations and cluster info
-    pub gene_meta: SurvivalData, // in case we want to store some info there later
-    pub drcs: HashMap<String, Array2<f32>>,
+    pub(crate) factor_numbers: FactorNumbers, // meta.factors.json numbers of the cell_meta factor levels, see meta_factor()
+    pub gene_meta: SurvivalData, // gene annotation (ids, types, chromosome, ...) built from gene_table
+    pub(crate) gene_table: MetaTable, // the columns of gene_meta, see set_gene_table()
+    pub(crate) gene_resolver: OnceLock<GeneResolver>, // symbol/id/alias lookup, see resolve_gene()
+    pub drcs: HashMap<String, Array2<f32>>, // embeddings (cells × all dims), see projection_view()
+    pub axes: HashMap<String, ProjectionAxes>, // displayed dimensions per drcs entry (default: the first 3)
+    pub neighbors: HashMap<String, NeighborGraph>, // kNN/SNN graphs per drcs entry, see build_neighbors()
+    pub layers: HashMap<String, CsMat<f32>>, // additional matrices like counts (e.g. spliced/unspliced, normalized)
+    pub dense_layers: HashMap<String, DenseLayer>, // dense layers for some genes (Pearson residuals, scaled)
+    pub primary_modality: String, // the feature type stored in counts/gene_names
+    pub modalities: HashMap<String, Modality>, // all other feature types (ADT, CRISPR, ...)
+    pub manifest: DatasetManifest, // display settings from dataset.toml (empty without one)
+    pub source: Option<PathBuf>, // the folder or file this dataset was opened from
+    pub spatial: Option<SpatialInfo>, // tissue image of the `spatial` projection (Visium)
+    pub(crate) out_of_core: Option<OutOfCoreCounts>, // counts read from disk on demand, see gene_vector()
+    pub(crate) load_issues: Vec<Issue>, // problems the loaders skipped over (part of the doctor report)
+    pub(crate) filtered_cells: HashSet<String>, // barcodes removed by filter_cells (still in the source files)
     active_group: Option<String>,
     group_id:usize,
 }
 
 impl DataStore {
+    /// Assemble a DataStore from already loaded parts (used by all loaders).
+    ///
+    /// `counts` may be CSR or CSC and genes × cells or cells × genes -
+    /// it is stored as genes × cells CSR. Any other shape is an error.
+    pub(crate) fn from_parts(
+        counts: CsMat<f32>,
+        gene_names: Vec<String>,
+        cell_names: Vec<String>,
+        cell_meta: SurvivalData,
+        gene_meta: SurvivalData,
+        drcs: HashMap<String, Array2<f32>>,
+    ) -> Result<Self, String> {
+        let (counts, counts_csc) = orient(counts, gene_names.len(), cell_names.len())?;
+        let gene_table = MetaTable { columns: vec![MetaColumn::factor_from_strings("gene", &gene_names)], ..Default::default() };
+        Ok(Self {
+            counts,
+            counts_csc: counts_csc.map(Into::into).unwrap_or_default(),
+            gene_names,
+            cell_names,
+            cell_meta,
+            factor_numbers: FactorNumbers::new(),
+            gene_meta,
+            gene_table,
+            gene_resolver: OnceLock::new(),
+            drcs,
+            axes: HashMap::new(),
+            neighbors: HashMap::new(),
+            layers: HashMap::new(),
+            dense_layers: HashMap::new(),
+            primary_modality: GENE_EXPRESSION.to_string(),
+            modalities: HashMap::new(),
+            manifest: DatasetManifest::default(),
+            source: None,
+            spatial: None,
+            out_of_core: None,
+            load_issues: Vec::new(),
+            filtered_cells: HashSet::new(),
+            active_group: None,
+            group_id: 0,
+        })
+    }
+
+    /// Open a dataset and pick the loader from the path:
+    /// a folder is read with `from_cellranger_cached` (or `from_out_of_core` if it has a
+    /// `counts.pf3dgenes`, `from_cellexal_folder` for folders exported by the CellexalVR
+    /// R package and `from_visium` for Space Ranger output folders), a `.h5ad` file with `from_h5ad`,
+    /// a 10x `.h5` matrix with `from_10x_h5` and a `.loom` file with `from_loom`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        Self::open_with_progress(path, &|_, _| {})
+    }
+
+    /// `open` reporting its progress as (stage, fraction of that stage),
+    /// e.g. ("matrix", 0.4) while the MatrixMarket file is parsed.
+    /// The callback is called from the loading thread.
+    pub fn open_with_progress<P: AsRef<Path>>(path: P, progress: &dyn Fn(&str, f32)) -> Result<Self, String> {
+        let path = path.as_ref();
+        progress("reading", 0.0);
+        let is_dir = source::is_dir(path);
+        let mut ret = if is_dir && path.join(OUT_OF_CORE_FILE).is_file() {
+            Self::from_out_of_core_with_progress(path, &|p| progress("matrix", p))?
+        } else if is_dir && is_cellexal_folder(path) {
+            Self::from_cellexal_folder(path)?
+        } else if is_dir && is_visium_folder(path) {
+            Self::from_visium(path)?
+        } else if is_dir {
+            Self::from_cellranger_cached_with_progress(path, &|p| progress("matrix", p.fraction()))?
+        } else {
+            let ext = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .map(|e| e.to_ascii_lowercase())
+                .unwrap_or_default();
+            match ext.as_str() {
+                "h5ad" | "h5" | "loom" if source::in_archive(path) => {
+                    return Err(format!("❌ HDF5 files can not be read from an archive - unpack {:?} first", path));
+                }
+                "h5ad" => Self::from_h5ad(path)?,
+                "h5" => Self::from_10x_h5(path)?,
+                "loom" => Self::from_loom(path)?,
+                _ => return Err(format!("❌ Unsupported dataset path {:?}", path)),
+            }
+        };
+        ret.source = Some(path.to_path_buf());
+        // QC would read the whole matrix from disk - out-of-core datasets get it on request
+        progress("qc", 0.0);
+        let patterns = ret.manifest.qc.clone();
+        if ret.is_out_of_core() {
+            println!("ℹ️ QC metrics are not computed on load for out-of-core datasets");
+        } else if let Err(e) = ret.add_qc_metrics(&patterns) {
+            println!("⚠️ {}", e);
+            ret.load_issues.push(Issue {
+                severity: Severity::Warning,
+                file: path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+                row: None,
+                problem: format!("QC metrics not computed: {}", e),
+            });
+        }
+        progress("reading", 1.0);
+        Ok(ret)
+    }
+
     /// this initializes the data view in VR
     /// at the moment it expects simple text files:
     /// features.tsv.gz, barcodes.tsv.gz and matrix.mtx.gz for the expression data
     /// meta.tsv - a table containing the cell meta info (will be parsed by SurvivalData!)
     /// meta.factors.json a file ulimtately created by the VR process definig how the data in the meat sould be used 
     pub fn from_cellranger<P: AsRef<std::path::Path>>(dir: P) -> Result<Self, String> {
+        Self::from_cellranger_with_progress(dir, &|_| {})
+    }
+
+    /// `from_cellranger` with a callback that receives the bytes and lines read
+    /// from `matrix.mtx.gz` after every parsed block (e.g. to drive a loading bar).
+    pub fn from_cellranger_with_progress<P: AsRef<std::path::Path>>(
+        dir: P,
+        progress: &dyn Fn(&MtxProgress),
+    ) -> Result<Self, String> {
+        let last = Cell::new(-1i32);
+        let progress = |p: &MtxProgress| {
+            // one line per 10% is plenty in the log
+            let step = (p.fraction() * 10.0) as i32;
+            if step != last.get() {
+                last.set(step);
+                println!("📥 matrix: {:.0}% ({} lines)", p.fraction() * 100.0, p.lines);
+            }
+            progress(p);
+        };
         let dir = dir.as_ref();
 
+        let manifest = DatasetManifest::load(dir)?;
+        let files = manifest.resolve(dir);
+
         // --- Gene names ---
-        let features_path = dir.join("features.tsv.gz");
-        let f = File::open(&features_path)
-            .map_err(|e| format!("❌ Failed to open {:?}: {}", features_path, e))?;
-        let decoder = GzDecoder::new(f);
-        let mut rdr = csv::ReaderBuilder::new()
-            .delimiter(b'\t')
-            .has_headers(false)
-            .from_reader(decoder);
-        let gene_names: Vec<String> = rdr
-            .records()
-            .filter_map(|r| r.ok())
-            .map(|r| r[1].to_string())
-            .collect();
+        let features_path = &files.features;
+        let features = read_feature_records(features_path)?;
+        let gene_names: Vec<String> = features.iter().map(|r| r.get(1).unwrap_or(&r[0]).clone()).collect();
+        let feature_types: Vec<String> = features.iter().map(|r| feature_type(r).to_string()).collect();
         if gene_names.is_empty() {
             return Err(format!("❌ No gene names found in {:?}", features_path));
         }
 
         // --- Cell barcodes ---
-        let barcodes_path = dir.join("barcodes.tsv.gz");
-        let f = File::open(&barcodes_path)
-            .map_err(|e| format!("❌ Failed to open {:?}: {}", barcodes_path, e))?;
-        let decoder = GzDecoder::new(f);
-        let mut rdr = csv::ReaderBuilder::new()
-            .has_headers(false)
-            .from_reader(decoder);
-        let cell_names: Vec<String> = rdr
-            .records()
-            .filter_map(|r| r.ok())
-            .map(|r| r[0].to_string())
-            .collect();
-        if cell_names.is_empty() {
-            return Err(format!("❌ No cell barcodes found in {:?}", barcodes_path));
-        }
-
-        // --- Matrix (.mtx.gz) ---
-        let matrix_path = dir.join("matrix.mtx.gz");
-        let tri: TriMat<f32> = {
-            let f = File::open(&matrix_path)
-                .map_err(|e| format!("❌ Failed to open {:?}: {}", matrix_path, e))?;
-            let decoder = GzDecoder::new(f);
-            let mut reader = BufReader::new(decoder);
-
-            match read_matrix_market_from_bufread::<f32, usize, _>(&mut reader) {
-                Ok(tri) => tri,
-                Err(_) => {
-                    // Retry as integer and cast to f32
-                    let f = File::open(&matrix_path)
-                        .map_err(|e| format!("❌ Failed to reopen {:?}: {}", matrix_path, e))?;
-                    let decoder = GzDecoder::new(f);
-                    let mut reader = BufReader::new(decoder);
-                    let int_tri: TriMat<i32> = read_matrix_market_from_bufread::<i32, usize, _>(&mut reader)
-                        .map_err(|e| format!("❌ Failed to parse {:?} as integer MatrixMarket: {}", matrix_path, e))?;
-
-                    let mut tri_f32 = TriMat::<f32>::with_capacity(
-                        (int_tri.rows(), int_tri.cols()),
-                        int_tri.nnz(),
-                    );
-                    for (v, (r, c)) in int_tri.triplet_iter() {
-                        tri_f32.add_triplet(r, c, *v as f32);
-                    }
-                    tri_f32
-                }
-            }
-        };
+        let cell_names = read_barcodes(&files.barcodes)?;
 
-        let counts: CsMat<f32> = tri.to_csr();
+        // --- Matrix (.mtx / .mtx.gz) ---
+        let matrix_path = &files.matrix;
+        let counts: CsMat<f32> = read_matrix_market(matrix_path, &progress)?.to_csr()?;
         if counts.nnz() == 0 {
             return Err(format!("❌ Matrix {:?} appears empty", matrix_path));
         }
+        let (counts, _) = orient(counts, gene_names.len(), cell_names.len())
+            .map_err(|e| format!("{} ({:?})", e, matrix_path))?;
+        let (primary_modality, counts, gene_names, modalities) =
+            split_by_feature_type(counts, gene_names, &feature_types);
 
         // --- Metadata ---
-        let meta_path = dir.join("meta.tsv");
-        let meta_json_path = dir.join("meta.factors.json");
-        let cell_meta = SurvivalData::from_file(
-            &meta_path,
-            b'\t',
-            HashSet::<String>::new(),
-            &meta_json_path,
-        )
-        .map_err(|e| format!("❌ Failed to load metadata: {}", e))?;
+        let (cell_meta, factor_numbers) = Self::load_cell_meta_from(&files.meta, &files.meta_factors, &cell_names)?;
 
-        
-        println!("📈 searching path {} for projections linke '*.drc'",dir.to_string_lossy() );
-        
-        let dataset_path = Path::new(&dir);
-        let dataset_str = dataset_path
-            .file_name()                    // last path component
-            .and_then(|s| s.to_str())       // convert OsStr → &str
-            .unwrap_or("<unknown>");        // fallback if not valid UTF-8
-        
-        let mut projections = Vec::new();
-
-        if let Ok(entries) = fs::read_dir(dataset_path) {
-            for entry in entries.flatten() {
-                if let Some(ext) = entry.path().extension().and_then(|e| e.to_str()) {
-                    if ext.eq_ignore_ascii_case("drc") {
-                        projections.push(entry.path());
-                    }
-                }
-            }
-        }
-        println!("📈 Found projections {:?}", projections);
-
-
-        let mut ret = Self{
+        let mut ret = Self::from_parts(
             counts,
             gene_names,
-            gene_meta: SurvivalData::default(),
             cell_names,
             cell_meta,
-            drcs: HashMap::new(),
-            active_group:None,
-            group_id:0,
-        };
-        for proj_path in projections {
-            let proj_type = proj_path
-            .file_stem()
-            .unwrap_or_default()
-            .to_string_lossy()
-            .to_string();
-            if let Err(e) = ret.load_projection_from_tsv( &proj_type, &proj_path.to_string_lossy() ){
-                println!("⚠️ Failed to load projection '{}': {}", proj_type, e);
+            SurvivalData::default(),
+            HashMap::new(),
+        )?;
+        ret.factor_numbers = factor_numbers;
+        ret.primary_modality = primary_modality;
+        ret.modalities = modalities;
+        if let Some(modality) = &manifest.modality {
+            ret.set_primary_modality(modality)?;
+        }
+        ret.set_gene_table_from_features(&features)?;
+        ret.load_gene_annotation_file(files.gene_annotation.as_deref());
+        ret.load_projection_files(&files.projections);
+        ret.manifest = manifest;
+
+        Ok(ret)
+    }
+
+    /// Read `meta.tsv` + `meta.factors.json` from a dataset folder
+    /// (or the files named in its `dataset.toml`).
+    pub(crate) fn load_cell_meta(dir: &Path, cell_names: &[String]) -> Result<(SurvivalData, FactorNumbers), String> {
+        let files = DatasetManifest::load(dir)?.resolve(dir);
+        Self::load_cell_meta_from(&files.meta, &files.meta_factors, cell_names)
+    }
+
+    /// Read the cell meta table (tab or comma separated).
+    ///
+    /// Without a meta table the cell meta only holds the `barcode` factor. Without
+    /// `meta.factors.json` the factor columns are guessed (see `MetaTable::infer`) and
+    /// written to `meta.factors.json`, so they can be corrected there.
+    /// Also returns the json numbers of the factor levels (see `meta_factor`).
+    pub(crate) fn load_cell_meta_from(
+        meta_path: &Path,
+        meta_json_path: &Path,
+        cell_names: &[String],
+    ) -> Result<(SurvivalData, FactorNumbers), String> {
+        if !source::exists(meta_path) {
+            println!("⚠️ No {:?} - the cell meta data only holds the barcodes", meta_path);
+            let mut table = MetaTable::new();
+            table.push(MetaColumn::factor_from_strings("barcode", cell_names))?;
+            return Ok((table.into_survival_data()?, FactorNumbers::new()));
+        }
+        let delimiter = detect_delimiter(meta_path)?;
+        let mut guessed = None;
+        if !source::exists(meta_json_path) {
+            let table = MetaTable::infer(meta_path, delimiter)?;
+            match table.write_factors_json(meta_json_path) {
+                Ok(()) => println!(
+                    "📝 No {:?} - wrote the guessed factors {:?}",
+                    meta_json_path,
+                    table.factor_names()
+                ),
+                // e.g. a read only folder or an archive
+                Err(e) => {
+                    println!("⚠️ {} - the guessed factors {:?} are not saved", e, table.factor_names());
+                    guessed = Some(table);
+                }
             }
         }
+        let data = survival_data_from(meta_path, delimiter, meta_json_path, guessed.as_ref())?;
+        let numbers = match guessed {
+            // numbered by position
+            Some(_) => FactorNumbers::new(),
+            None => factor_numbers_in(&source::read_to_string(meta_json_path)?),
+        };
+        Ok((data, numbers))
+    }
 
+    /// Load the optional gene annotation of a folder; problems become load issues.
+    pub(crate) fn load_gene_annotation_file(&mut self, path: Option<&Path>) {
+        let Some(path) = path else { return };
+        if let Err(e) = self.load_gene_annotation(path) {
+            println!("⚠️ {}", e);
+            self.load_issues.push(Issue {
+                severity: Severity::Warning,
+                file: path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+                row: None,
+                problem: format!("gene annotation not loaded: {}", e),
+            });
+        }
+    }
 
-        Ok(ret)
+    /// Load all '*.drc' projections found in `dir` into `drcs`.
+    pub(crate) fn load_projections_in(&mut self, dir: &Path) {
+        println!("📈 searching path {} for projections linke '*.drc'",dir.to_string_lossy() );
+        match DatasetManifest::load(dir) {
+            Ok(manifest) => self.load_projection_files(&manifest.resolve(dir).projections),
+            Err(e) => println!("⚠️ {}", e),
+        }
+    }
+
+    /// Load (drcs key, file) pairs, skipping files that do not fit the dataset.
+    pub(crate) fn load_projection_files(&mut self, projections: &[(String, PathBuf)]) {
+        println!("📈 Found projections {:?}", projections);
+        for (proj_type, proj_path) in projections {
+            let file = proj_path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            match self.load_projection_from_tsv( proj_type, &proj_path.to_string_lossy() ){
+                Ok(stats) if !stats.is_complete() => self.load_issues.push(Issue {
+                    severity: Severity::Warning,
+                    file,
+                    row: None,
+                    problem: format!(
+                        "{} cells matched, {} rows dropped, {} cells without coordinates",
+                        stats.matched, stats.dropped, stats.missing
+                    ),
+                }),
+                Ok(_) => {}
+                Err(e) => {
+                    println!("⚠️ Failed to load projection '{}': {}", proj_type, e);
+                    self.load_issues.push(Issue {
+                        severity: Severity::Error,
+                        file,
+                        row: None,
+                        problem: format!("projection not loaded: {}", e),
+                    });
+                }
+            }
+        }
     }
 
 
@@ -171,21 +341,34 @@ impl DataStore {
     /// * `order_row`   - array of ordering values (1×N or N×1)
     /// * `cluster_row` - array of cluster identifiers (1×N or N×1)
     /// * `as_mean`     - if true, take mean per group instead of sum
+    /// * `modality`    - the feature type to aggregate ("" for the primary one)
+    /// * `layer`       - the expression layer of the primary modality ("" or "counts" for the raw counts)
     ///
     /// # Returns
-    /// `(Array2<f64>, Vec<usize>)` — matrix (genes × pseudo_samples) and cluster labels
+    /// `(Array2<f64>, Vec<usize>)` — matrix (features × pseudo_samples) and cluster labels,
+    /// or an error if the modality or layer does not exist. Genes a dense layer has
+    /// no values for are NaN.
     pub fn make_pseudo_samples(
         &self,
         group_name: &str,
         cluster_row: &Array2<f64>,
         as_mean: bool,
-    ) -> (Array2<f64>, Vec<usize>) {
+        modality: &str,
+        layer: &str,
+    ) -> Result<(Array2<f64>, Vec<usize>), String> {
+        let (counts, _) = self
+            .modality(modality)
+            .ok_or_else(|| format!("Modality '{}' not found", modality))?;
+        let raw = layer.is_empty() || layer == RAW_LAYER;
+        if !raw && !std::ptr::eq(counts, &self.counts) {
+            return Err(format!("Layers belong to the primary modality '{}', not '{}'", self.primary_modality, modality));
+        }
 
         let cluster_row = self.cell_meta.as_vec_f64( group_name );
         let order_row = self.cell_meta.as_vec_f64( &format!("{} order", group_name) );
 
         let n_cells = order_row.len();
-        let n_genes = self.counts.rows();
+        let n_genes = counts.rows();
         fn to_usize(v: f64) -> Option<usize> {
             if v.is_finite() && v >= 0.0 {
                 Some(v as usize)
@@ -201,8 +384,9 @@ impl DataStore {
             }
         }
 
-        // 2️⃣ Aggregate per cluster
-        let mut pseudo_data: Vec<Vec<f64>> = Vec::new();
+        // 2️⃣ Assign the cells to pseudo-samples per cluster
+        let mut pseudo_of_cell: Vec<Option<usize>> = vec![None; n_cells];
+        let mut pseudo_sizes: Vec<usize> = Vec::new();
         let mut pseudo_labels: Vec<usize> = Vec::new();
 
         for (cluster_id, mut members) in clusters {
@@ -218,73 +402,97 @@ impl DataStore {
             let chunk_size = (n / 10).max(1);
 
             for chunk in members.chunks(chunk_size) {
-                let mut sum_vec = vec![0.0f64; n_genes];
                 for &(_, cell_idx) in chunk {
-                    if let Some(view) = self.counts.outer_view(cell_idx) {
-                        for (gene_idx, val) in view.iter() {
-                            sum_vec[gene_idx] += *val as f64;
-                        }
-                    }
+                    pseudo_of_cell[cell_idx] = Some(pseudo_sizes.len());
                 }
+                pseudo_sizes.push(chunk.len());
+                pseudo_labels.push(cluster_id);
+            }
+        }
 
-                // Normalize if requested
-                if as_mean && !chunk.is_empty() {
-                    let inv = 1.0 / chunk.len() as f64;
-                    for v in &mut sum_vec {
-                        *v *= inv;
-                    }
+        // 3️⃣ Sum up gene by gene (genes × pseudo_samples) - works for out-of-core counts as well
+        let mut pseudo_mat = Array2::<f64>::zeros((n_genes, pseudo_sizes.len()));
+        let mut add = |gene_idx: usize, values: LayerRow| {
+            values.for_each(|cell_idx, val| {
+                if let Some(Some(p)) = pseudo_of_cell.get(cell_idx) {
+                    pseudo_mat[[gene_idx, *p]] += val as f64;
                 }
-
-                pseudo_data.push(sum_vec);
-                pseudo_labels.push(cluster_id);
+            });
+        };
+        if raw {
+            self.for_each_feature(modality, |gene_idx, values| add(gene_idx, LayerRow::Sparse(values)))?;
+        } else {
+            self.for_each_layer_gene(layer, add)?;
+        }
+        if let Some(genes) = self.layer_genes(layer) {
+            let mut covered = vec![false; n_genes];
+            genes.iter().for_each(|g| covered[*g] = true);
+            for (mut row, _) in pseudo_mat.axis_iter_mut(Axis(0)).zip(&covered).filter(|(_, c)| !**c) {
+                row.fill(f64::NAN);
             }
         }
 
-        // 3️⃣ Build ndarray (genes × pseudo_samples)
-        let n_pseudo = pseudo_data.len();
-        let mut pseudo_mat = Array2::<f64>::zeros((n_genes, n_pseudo));
-        for (j, col) in pseudo_data.iter().enumerate() {
-            let mut view = pseudo_mat.index_axis_mut(Axis(1), j);
-            for (i, &val) in col.iter().enumerate() {
-                view[i] = val;
+        // Normalize if requested
+        if as_mean {
+            for (mut col, &size) in pseudo_mat.axis_iter_mut(Axis(1)).zip(&pseudo_sizes) {
+                col /= size.max(1) as f64;
             }
         }
 
-        (pseudo_mat, pseudo_labels)
+        Ok((pseudo_mat, pseudo_labels))
     }
 
 
-    /// Load one DR coordinate file (UMAP, PCA, etc.) from TSV
-    pub fn load_projection_from_tsv(&mut self, name: &str, path: &str) -> Result<(), String> {
-        let ds = SurvivalData::from_tsv(path, b'\t', HashSet::new(), String::new())
-            .map_err(|e| format!("Failed to read {}: {}", path, e))?;
-
-        let barcode_factor = ds
-            .factors
-            .get(&ds.headers[0])
-            .ok_or_else(|| "No barcode column found".to_string())?;
-
-        if let Some(our_barcodes) = self.cell_meta.factors.get("barcode"){
-            if let Err(e) = our_barcodes.compare_levels(barcode_factor) {
-                return Err(format!("Barcode mismatch in projection '{}': {}", name, e));
-            }
-        } else {
-            return Err("Current dataset has no 'barcode' factor in cellmeta".into());
+    /// Load one DR coordinate file (UMAP, PCA, etc.) from TSV.
+    ///
+    /// The first column holds the barcodes, all other columns are dimensions.
+    /// Rows are matched to the cells by barcode, so the file may cover a subset
+    /// of the cells or list them in another order: cells without a row get NaN
+    /// coordinates and rows with unknown barcodes are dropped.
+    pub fn load_projection_from_tsv(&mut self, name: &str, path: &str) -> Result<ProjectionMatch, String> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .has_headers(true)
+            .flexible(true)
+            .from_reader(open_text(Path::new(path))?);
+
+        let mut barcodes = Vec::new();
+        let mut rows = Vec::new();
+        for record in rdr.records() {
+            let record = record.map_err(|e| format!("Failed to read {}: {}", path, e))?;
+            let Some(barcode) = record.get(0) else { continue };
+            barcodes.push(barcode.to_string());
+            rows.push(
+                record
+                    .iter()
+                    .skip(1)
+                    .map(|v| v.trim().parse::<f32>().unwrap_or(f32::NAN))
+                    .collect::<Vec<f32>>(),
+            );
         }
-
-        let n_cols = ds.numeric_data.shape()[1];
-        if n_cols < 4 {
-            return Err("Dataset must have at least 3 numeric columns (x, y, z) + rownames".into());
+        if rows.iter().map(|r| r.len()).max().unwrap_or(0) < 2 {
+            return Err("Dataset must have at least 2 numeric columns (x, y) + rownames".into());
         }
 
-        let view = ds.numeric_data.slice(s![.., 1..4]).mapv(|v| v as f32).to_owned();
-
-        self.drcs.insert(name.to_string(), view);
-        Ok(())
+        // keep all dimensions - projection_view() picks the displayed ones
+        let (data, stats) = map_rows_by_barcode(&barcodes, &rows, &self.cell_names);
+        if stats.matched == 0 {
+            return Err(format!("No barcode of projection '{}' is part of the dataset", name));
+        }
+        println!(
+            "📈 Projection '{}': {} cells matched, {} rows dropped, {} cells without coordinates",
+            name, stats.matched, stats.dropped, stats.missing
+        );
+
+        self.drcs.insert(name.to_string(), data);
+        self.axes.remove(name);
+        self.forget_neighbors_of(name);
+        Ok(stats)
     }
 
-    pub fn get_projection(&self, name: &str) -> Option<&Array2<f32>> {
-        self.drcs.get(name)
+    /// The displayed (cells × 3) coordinates of a projection.
+    pub fn get_projection(&self, name: &str) -> Option<Array2<f32>> {
+        self.projection_view(name).ok()
     }
 
     /// Select cells in a projection by 3D position + radius (VR-space),
@@ -296,9 +504,10 @@ impl DataStore {
         position: &[f32], // 3D position from VR
         radius: f32,      // VR radius (same scale as positions)
     ) -> anyhow::Result<Vec<i32>> {
-        // ─── ensure projection exists
-        let Some(view) = self.drcs.get(projection_name) else {
-            anyhow::bail!("Projection '{}' not found", projection_name);
+        // ─── ensure projection exists (in the displayed dimensions)
+        let view = match self.projection_view(projection_name) {
+            Ok(view) => view,
+            Err(e) => anyhow::bail!("{}", e),
         };
 
         // ─── initialize active_group if needed
@@ -344,4 +553,56 @@ impl DataStore {
         Ok(ret)
     }
 
+    /// The levels of a cell_meta factor and the level of every cell (`None`: missing).
+    /// The numbers meta.factors.json gives the levels are mapped to level positions.
+    pub fn meta_factor(&self, column: &str) -> Option<FactorCodes> {
+        factor_codes(&self.cell_meta, &self.factor_numbers, column)
+    }
+
+    /// The cells where the cell_meta factor `column` is `level` (any level if it is empty),
+    /// e.g. the cells of a selection group.
+    pub fn cells_in_group(&self, column: &str, level: &str) -> Result<Vec<usize>, String> {
+        let (levels, codes) = self
+            .meta_factor(column)
+            .ok_or_else(|| format!("❌ '{}' is not a cell_meta factor", column))?;
+        let wanted = match level {
+            "" => None,
+            _ => Some(
+                levels
+                    .iter()
+                    .position(|l| l == level)
+                    .ok_or_else(|| format!("❌ '{}' is not a level of '{}'", level, column))?,
+            ),
+        };
+        Ok(codes
+            .iter()
+            .enumerate()
+            .filter(|(_, code)| code.is_some_and(|c| wanted.is_none_or(|w| c == w)))
+            .map(|(i, _)| i)
+            .collect())
+    }
+
+}
+
+/// The cell barcodes of a `barcodes.tsv(.gz)` file (its first column).
+pub(crate) fn read_barcodes(path: &Path) -> Result<Vec<String>, String> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(open_text(path)?);
+    let cell_names: Vec<String> = rdr
+        .records()
+        .filter_map(|r| r.ok())
+        .map(|r| r[0].to_string())
+        .collect();
+    if cell_names.is_empty() {
+        return Err(format!("❌ No cell barcodes found in {:?}", path));
+    }
+    Ok(cell_names)
+}
+
+/// A dataset with only counts, gene and cell names - the starting point of most tests.
+#[cfg(test)]
+pub(crate) fn test_store(counts: CsMat<f32>, gene_names: Vec<String>, cell_names: Vec<String>) -> DataStore {
+    DataStore::from_parts(counts, gene_names, cell_names, SurvivalData::default(), SurvivalData::default(), HashMap::new())
+        .unwrap()
 }
@@ -0,0 +1,396 @@
+//doctor.rs
+//! Consistency checks for a loaded dataset.
+//!
+//! Mismatching files (a meta.tsv from another run, a .drc for a subset of the cells, ...)
+//! otherwise only show up later as wrong colors or panics in VR.
+use crate::data_store::DataStore;
+use crate::data_store::cellexal::{is_cellexal_folder, DATABASE_FILE, META_CELL_FILE};
+use crate::data_store::source::{self, open_text};
+use crate::data_store::manifest::DatasetFiles;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+
+/// Row level problems of one kind reported before they are summarized.
+const MAX_ROW_ISSUES: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Issue {
+    pub severity: Severity,
+    /// the file the problem was found in (or the dataset part for in-memory data)
+    pub file: String,
+    /// 1-based line in that file (the header is line 1), if the problem is row specific
+    pub row: Option<usize>,
+    pub problem: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let icon = if self.severity == Severity::Error { "❌" } else { "⚠️" };
+        match self.row {
+            Some(row) => write!(f, "{} {}:{}: {}", icon, self.file, row, self.problem),
+            None => write!(f, "{} {}: {}", icon, self.file, self.problem),
+        }
+    }
+}
+
+/// The result of `DataStore::doctor`.
+#[derive(Debug, Clone, Default)]
+pub struct DoctorReport {
+    pub issues: Vec<Issue>,
+}
+
+impl DoctorReport {
+    pub fn errors(&self) -> impl Iterator<Item = &Issue> {
+        self.issues.iter().filter(|i| i.severity == Severity::Error)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Issue> {
+        self.issues.iter().filter(|i| i.severity == Severity::Warning)
+    }
+
+    /// true if there are no errors (warnings are fine)
+    pub fn is_ok(&self) -> bool {
+        self.errors().next().is_none()
+    }
+
+    fn push(&mut self, severity: Severity, file: &str, row: Option<usize>, problem: String) {
+        self.issues.push(Issue { severity, file: file.to_string(), row, problem });
+    }
+
+    fn error(&mut self, file: &str, problem: String) {
+        self.push(Severity::Error, file, None, problem);
+    }
+
+    fn warning(&mut self, file: &str, problem: String) {
+        self.push(Severity::Warning, file, None, problem);
+    }
+
+    /// Report row specific problems, but only the first MAX_ROW_ISSUES of them.
+    fn rows(&mut self, severity: Severity, file: &str, rows: Vec<(usize, String)>) {
+        let n = rows.len();
+        for (row, problem) in rows.into_iter().take(MAX_ROW_ISSUES) {
+            self.push(severity, file, Some(row), problem);
+        }
+        if n > MAX_ROW_ISSUES {
+            self.push(severity, file, None, format!("... and {} more rows like that", n - MAX_ROW_ISSUES));
+        }
+    }
+}
+
+impl fmt::Display for DoctorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "🩺 {} errors, {} warnings",
+            self.errors().count(),
+            self.warnings().count()
+        )?;
+        for issue in &self.issues {
+            writeln!(f, "  {}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+/// File names used in the report for the parts of a dataset.
+struct SourceNames {
+    features: String,
+    barcodes: String,
+    matrix: String,
+    meta: String,
+    projections: HashMap<String, String>,
+}
+
+impl SourceNames {
+    fn new(source: Option<&Path>, files: Option<&DatasetFiles>) -> Self {
+        let name = |p: &Path| p.file_name().unwrap_or_default().to_string_lossy().to_string();
+        match (files, source) {
+            (Some(files), _) => Self {
+                features: name(&files.features),
+                barcodes: name(&files.barcodes),
+                matrix: name(&files.matrix),
+                meta: name(&files.meta),
+                projections: files.projections.iter().map(|(k, p)| (k.clone(), name(p))).collect(),
+            },
+            (None, Some(dir)) if is_cellexal_folder(dir) => Self {
+                features: DATABASE_FILE.to_string(),
+                barcodes: DATABASE_FILE.to_string(),
+                matrix: DATABASE_FILE.to_string(),
+                meta: META_CELL_FILE.to_string(),
+                projections: HashMap::new(),
+            },
+            (None, Some(file)) => {
+                let file = name(file);
+                Self {
+                    features: file.clone(),
+                    barcodes: file.clone(),
+                    matrix: file.clone(),
+                    meta: file,
+                    projections: HashMap::new(),
+                }
+            }
+            (None, None) => Self {
+                features: "features".to_string(),
+                barcodes: "barcodes".to_string(),
+                matrix: "matrix".to_string(),
+                meta: "cell_meta".to_string(),
+                projections: HashMap::new(),
+            },
+        }
+    }
+
+    fn projection(&self, key: &str) -> String {
+        self.projections.get(key).cloned().unwrap_or_else(|| format!("projection '{}'", key))
+    }
+}
+
+impl DataStore {
+    /// Check that all parts of the dataset fit together:
+    /// matrix dimensions vs. features and barcodes, one meta row per barcode (in the same order),
+    /// projections covering all cells with known barcodes, unique barcodes, ...
+    ///
+    /// Problems found while loading (e.g. a skipped .drc file) are part of the report as well.
+    pub fn doctor(&self) -> DoctorReport {
+        let mut report = DoctorReport { issues: self.load_issues.clone() };
+        let files = self
+            .source
+            .as_deref()
+            .filter(|p| source::is_dir(p) && !is_cellexal_folder(p))
+            .map(|dir| self.manifest.resolve(dir));
+        let names = SourceNames::new(self.source.as_deref(), files.as_ref());
+        let n_genes = self.gene_names.len();
+        let n_cells = self.cell_names.len();
+
+        // --- matrix vs. features and barcodes ---
+        if self.counts.rows() != n_genes {
+            report.error(
+                &names.matrix,
+                format!("matrix has {} rows but there are {} features ({})", self.counts.rows(), n_genes, names.features),
+            );
+        }
+        if self.counts.cols() != n_cells {
+            report.error(
+                &names.matrix,
+                format!("matrix has {} columns but there are {} barcodes ({})", self.counts.cols(), n_cells, names.barcodes),
+            );
+        }
+        for (name, modality) in &self.modalities {
+            if modality.counts.cols() != n_cells {
+                report.error(
+                    &names.matrix,
+                    format!("modality '{}' has {} cells, expected {}", name, modality.counts.cols(), n_cells),
+                );
+            }
+        }
+        for (name, layer) in &self.layers {
+            if layer.shape() != self.counts.shape() {
+                report.error(
+                    &names.matrix,
+                    format!("layer '{}' has shape {:?}, expected {:?}", name, layer.shape(), self.counts.shape()),
+                );
+            }
+        }
+        // (out-of-core counts would have to be read completely)
+        let empty_cells = if self.is_out_of_core() { 0 } else { count_empty_cells(&self.counts) };
+        if empty_cells > 0 {
+            report.warning(&names.matrix, format!("{} cells have no counts at all", empty_cells));
+        }
+
+        // --- duplicated names ---
+        report.rows(Severity::Error, &names.barcodes, duplicates(&self.cell_names, 1, "barcode"));
+        report.rows(Severity::Warning, &names.features, duplicates(&self.gene_names, 1, "feature"));
+
+        // --- cell meta ---
+        let n_meta = self.cell_meta.numeric_data.nrows();
+        if self.cell_meta.headers.is_empty() {
+            report.error(&names.meta, "no cell meta data loaded".to_string());
+        } else if n_meta != n_cells {
+            report.error(&names.meta, format!("{} rows but {} barcodes", n_meta, n_cells));
+        } else if let Some((levels, codes)) = self.meta_factor("barcode") {
+            let rows = codes
+                .iter()
+                .enumerate()
+                .filter_map(|(i, code)| {
+                    let found = code.map(|c| &levels[c]);
+                    (found != Some(&self.cell_names[i])).then(|| {
+                        (i + 2, format!("barcode {:?} - expected '{}'", found, self.cell_names[i]))
+                    })
+                })
+                .collect();
+            report.rows(Severity::Error, &names.meta, rows);
+        } else {
+            report.error(&names.meta, "no 'barcode' column".to_string());
+        }
+
+        // --- projections ---
+        let mut keys: Vec<&String> = self.drcs.keys().collect();
+        keys.sort();
+        for key in keys {
+            let view = &self.drcs[key];
+            let file = names.projection(key);
+            if view.nrows() != n_cells {
+                report.error(&file, format!("{} rows but {} cells", view.nrows(), n_cells));
+                continue;
+            }
+            // cells missing in a .drc file are reported by check_projection_file
+            if files.as_ref().is_some_and(|f| f.projections.iter().any(|(k, _)| k == key)) {
+                continue;
+            }
+            let nan_rows: Vec<(usize, String)> = view
+                .outer_iter()
+                .enumerate()
+                .filter(|(_, row)| row.iter().any(|v| !v.is_finite()))
+                .map(|(i, _)| (i + 2, format!("cell '{}' has no valid coordinates", self.cell_names[i])))
+                .collect();
+            report.rows(Severity::Warning, &file, nan_rows);
+        }
+        if let Some(files) = &files {
+            for (key, path) in &files.projections {
+                self.check_projection_file(&mut report, &names.projection(key), path);
+            }
+        }
+
+        report
+    }
+
+    /// Check the barcodes (first column) of a .drc file against the dataset.
+    /// Rows are matched by barcode, so other orders and subsets are fine, but
+    /// unknown or repeated barcodes are reported.
+    fn check_projection_file(&self, report: &mut DoctorReport, file: &str, path: &Path) {
+        let reader = match open_text(path) {
+            Ok(r) => r,
+            Err(e) => {
+                report.error(file, e);
+                return;
+            }
+        };
+        let mut rdr = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .has_headers(true)
+            .flexible(true)
+            .from_reader(reader);
+        let barcodes: Vec<String> = rdr
+            .records()
+            .filter_map(|r| r.ok())
+            .map(|r| r.get(0).unwrap_or_default().to_string())
+            .collect();
+
+        let known: HashSet<&str> = self.cell_names.iter().map(|s| s.as_str()).collect();
+        let unknown = barcodes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| !known.contains(b.as_str()) && !self.filtered_cells.contains(b.as_str()))
+            .map(|(i, b)| (i + 2, format!("barcode '{}' is not part of the dataset - row dropped", b)))
+            .collect();
+        report.rows(Severity::Warning, file, unknown);
+        let filtered = barcodes.iter().filter(|b| self.filtered_cells.contains(b.as_str())).count();
+        if filtered > 0 {
+            report.warning(file, format!("{} rows belong to cells removed by the QC filter", filtered));
+        }
+        report.rows(Severity::Error, file, duplicates(&barcodes, 2, "barcode"));
+
+        let covered: HashSet<&str> = barcodes.iter().map(|s| s.as_str()).collect();
+        let missing = self.cell_names.iter().filter(|c| !covered.contains(c.as_str())).count();
+        if missing > 0 {
+            report.warning(file, format!("{} of {} cells have no coordinates", missing, self.cell_names.len()));
+        }
+    }
+}
+
+fn count_empty_cells(mat: &sprs::CsMat<f32>) -> usize {
+    if mat.is_csc() {
+        return mat.outer_iterator().filter(|col| col.nnz() == 0).count();
+    }
+    let mut seen = vec![false; mat.cols()];
+    for &c in mat.indices() {
+        seen[c] = true;
+    }
+    seen.iter().filter(|s| !**s).count()
+}
+
+/// (1-based line, problem) for every repeated name; `offset` is the line of the first entry.
+fn duplicates(names: &[String], offset: usize, what: &str) -> Vec<(usize, String)> {
+    let mut first: HashMap<&str, usize> = HashMap::new();
+    let mut ret = Vec::new();
+    for (i, n) in names.iter().enumerate() {
+        if let Some(prev) = first.get(n.as_str()) {
+            ret.push((i + offset, format!("{} '{}' already used in line {}", what, n, prev + offset)));
+        } else {
+            first.insert(n, i);
+        }
+    }
+    ret
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_store::data_store::test_store;
+    use ndarray::Array2;
+    use sprs::TriMat;
+
+    #[test]
+    fn doctor_reports_mismatches() {
+        let mut tri = TriMat::<f32>::new((2, 3));
+        tri.add_triplet(0, 0, 1.0);
+        tri.add_triplet(1, 1, 2.0);
+        let mut drcs = HashMap::new();
+        let mut umap = Array2::<f32>::zeros((3, 3));
+        umap[[1, 0]] = f32::NAN;
+        drcs.insert("umap".to_string(), umap);
+        drcs.insert("tsne".to_string(), Array2::<f32>::zeros((2, 3)));
+
+        let mut ds = test_store(tri.to_csr(), vec!["g1".into(), "g2".into()], vec!["c1".into(), "c2".into(), "c1".into()]);
+        ds.drcs = drcs;
+        // e.g. a feature added after loading
+        ds.gene_names.push("g3".into());
+        let report = ds.doctor();
+        let problems: Vec<String> = report.issues.iter().map(|i| i.to_string()).collect();
+
+        assert!(!report.is_ok());
+        assert!(problems.iter().any(|p| p.contains("matrix has 2 rows but there are 3 features")));
+        assert!(problems.iter().any(|p| p.contains("barcodes:3: barcode 'c1' already used in line 1")));
+        assert!(problems.iter().any(|p| p.contains("1 cells have no counts")));
+        assert!(problems.iter().any(|p| p.contains("projection 'tsne': 2 rows but 3 cells")));
+        let nan = report.warnings().find(|i| i.file == "projection 'umap'").unwrap();
+        assert_eq!(nan.row, Some(3));
+    }
+
+    #[test]
+    fn cells_removed_by_qc_are_not_unknown() {
+        let mut tri = TriMat::<f32>::new((1, 3));
+        tri.add_triplet(0, 0, 1.0);
+        tri.add_triplet(0, 2, 2.0);
+        let cells: Vec<String> = ["c1", "c2", "c3"].iter().map(|s| s.to_string()).collect();
+        let mut ds = test_store(tri.to_csr(), vec!["g1".into()], cells);
+        ds.filter_cells(&[true, false, true]).unwrap();
+
+        let path = std::env::temp_dir().join(format!("printforge3d_doctor_{}.drc", std::process::id()));
+        std::fs::write(&path, "cell\tx\ty\nc1\t0\t0\nc2\t1\t1\nc3\t2\t2\nc9\t3\t3\n").unwrap();
+        let mut report = DoctorReport::default();
+        ds.check_projection_file(&mut report, "umap.drc", &path);
+        let _ = std::fs::remove_file(&path);
+
+        let problems: Vec<String> = report.issues.iter().map(|i| i.to_string()).collect();
+        assert_eq!(problems.iter().filter(|p| p.contains("is not part of the dataset")).count(), 1);
+        assert!(problems.iter().any(|p| p.contains("barcode 'c9'")));
+        assert!(problems.iter().any(|p| p.contains("1 rows belong to cells removed by the QC filter")));
+    }
+}
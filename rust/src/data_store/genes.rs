@@ -0,0 +1,277 @@
+//genes.rs
+//! Gene annotation (`gene_meta`) and finding genes by symbol, Ensembl id or alias.
+use crate::data_store::DataStore;
+use crate::data_store::source::open_text;
+use crate::data_store::meta_table::{MetaColumn, MetaTable};
+use crate::data_store::modality::GENE_EXPRESSION;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// `gene_meta` columns that hold gene ids (Ensembl ids for Cell Ranger, `Accession` for loom).
+const ID_COLUMNS: &[&str] = &["gene_id", "gene_ids", "id", "Accession"];
+/// The `gene_meta` column with alternative symbols (separated by `,`, `|` or `;`).
+pub const ALIAS_COLUMN: &str = "aliases";
+/// Names for the columns features.tsv files have after id, name and type (10x ARC/ATAC).
+const EXTRA_FEATURE_COLUMNS: &[&str] = &["chromosome", "start", "end"];
+
+/// Looks genes up by id, symbol, case-insensitive symbol or alias (in that order).
+#[derive(Debug, Default)]
+pub struct GeneResolver {
+    ids: HashMap<String, Vec<usize>>,
+    symbols: HashMap<String, Vec<usize>>,
+    lower: HashMap<String, Vec<usize>>,
+    aliases: HashMap<String, Vec<usize>>,
+}
+
+/// `ENSG00000141510.17` → `ENSG00000141510`
+fn strip_version(id: &str) -> &str {
+    match id.rsplit_once('.') {
+        Some((base, v))
+            if id.get(..3).is_some_and(|p| p.eq_ignore_ascii_case("ENS")) && v.chars().all(|c| c.is_ascii_digit()) =>
+        {
+            base
+        }
+        _ => id,
+    }
+}
+
+impl GeneResolver {
+    pub fn new(symbols: &[String], ids: &[String], aliases: &[String]) -> Self {
+        let mut ret = Self::default();
+        for (i, s) in symbols.iter().enumerate() {
+            ret.symbols.entry(s.clone()).or_default().push(i);
+            ret.lower.entry(s.to_lowercase()).or_default().push(i);
+        }
+        for (i, id) in ids.iter().enumerate().filter(|(_, id)| !id.is_empty()) {
+            ret.ids.entry(strip_version(id).to_string()).or_default().push(i);
+            ret.lower.entry(strip_version(id).to_lowercase()).or_default().push(i);
+        }
+        for (i, list) in aliases.iter().enumerate() {
+            for alias in list.split([',', '|', ';']).map(str::trim).filter(|a| !a.is_empty()) {
+                let entry = ret.aliases.entry(alias.to_lowercase()).or_default();
+                if !entry.contains(&i) {
+                    entry.push(i);
+                }
+            }
+        }
+        ret
+    }
+
+    /// All genes `query` could mean - more than one if a symbol is duplicated.
+    pub fn resolve(&self, query: &str) -> Vec<usize> {
+        let query = query.trim();
+        let lower = query.to_lowercase();
+        [
+            self.ids.get(strip_version(query)),
+            self.symbols.get(query),
+            self.lower.get(strip_version(&lower)),
+            self.aliases.get(&lower),
+        ]
+        .into_iter()
+        .flatten()
+        .find(|hits| !hits.is_empty())
+        .map(|hits| {
+            let mut hits = hits.clone();
+            hits.sort_unstable();
+            hits.dedup();
+            hits
+        })
+        .unwrap_or_default()
+    }
+}
+
+/// Read all columns of a features.tsv(.gz) file (id, name, type, ...).
+pub(crate) fn read_feature_records(path: &Path) -> Result<Vec<Vec<String>>, String> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(open_text(path)?);
+    Ok(rdr
+        .records()
+        .filter_map(|r| r.ok())
+        .map(|r| r.iter().map(|s| s.to_string()).collect())
+        .collect())
+}
+
+/// The feature type of a features.tsv record (CellRanger v2 files have none).
+pub(crate) fn feature_type(record: &[String]) -> &str {
+    match record.get(2).map(|s| s.as_str()) {
+        Some(t) if !t.is_empty() => t,
+        _ => GENE_EXPRESSION,
+    }
+}
+
+/// The `gene_meta` table for the features.tsv records of one modality:
+/// `gene_id`, `gene`, `feature_type` and any further columns.
+pub(crate) fn feature_table(records: &[&Vec<String>]) -> Result<MetaTable, String> {
+    let n_cols = records.iter().map(|r| r.len()).max().unwrap_or(0);
+    let column = |c: usize| -> Vec<String> { records.iter().map(|r| r.get(c).cloned().unwrap_or_default()).collect() };
+    let mut table = MetaTable::new();
+    table.push(MetaColumn::factor_from_strings("gene_id", &column(0)))?;
+    let names: Vec<String> = records.iter().map(|r| r.get(1).unwrap_or(&r[0]).clone()).collect();
+    table.push(MetaColumn::factor_from_strings("gene", &names))?;
+    let types: Vec<String> = records.iter().map(|r| feature_type(r).to_string()).collect();
+    table.push(MetaColumn::factor_from_strings("feature_type", &types))?;
+    for c in 3..n_cols {
+        let name = EXTRA_FEATURE_COLUMNS
+            .get(c - 3)
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| format!("column_{}", c + 1));
+        table.push(MetaColumn::typed_from_strings(&name, &column(c)))?;
+    }
+    Ok(table)
+}
+
+impl DataStore {
+    /// Replace the gene annotation (one row per gene in `gene_names`) and rebuild `gene_meta`.
+    pub fn set_gene_table(&mut self, table: MetaTable) -> Result<(), String> {
+        if table.n_rows() != self.gene_names.len() {
+            return Err(format!(
+                "❌ gene annotation has {} rows for {} genes",
+                table.n_rows(),
+                self.gene_names.len()
+            ));
+        }
+        self.gene_meta = table.clone().into_survival_data()?;
+        self.gene_table = table;
+        self.gene_resolver = OnceLock::new();
+        Ok(())
+    }
+
+    /// Use the features.tsv records of the primary modality as gene annotation.
+    pub(crate) fn set_gene_table_from_features(&mut self, records: &[Vec<String>]) -> Result<(), String> {
+        let primary: Vec<&Vec<String>> = records
+            .iter()
+            .filter(|r| feature_type(r) == self.primary_modality)
+            .collect();
+        self.set_gene_table(feature_table(&primary)?)
+    }
+
+    /// Add the columns of a gene annotation table (e.g. chromosome, start, biotype, aliases).
+    ///
+    /// The file is tab separated with a header; rows are matched to the genes by the first
+    /// column, which may hold Ensembl ids or symbols. Returns the number of matched genes.
+    pub fn load_gene_annotation(&mut self, path: &Path) -> Result<usize, String> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .has_headers(true)
+            .flexible(true)
+            .from_reader(open_text(path)?);
+        let headers: Vec<String> = rdr
+            .headers()
+            .map_err(|e| format!("❌ Failed to read the header of {:?}: {}", path, e))?
+            .iter()
+            .map(|s| s.trim().to_string())
+            .collect();
+        let mut columns: Vec<Vec<String>> = vec![vec![String::new(); self.gene_names.len()]; headers.len()];
+        let mut matched = vec![false; self.gene_names.len()];
+        for record in rdr.records() {
+            let record = record.map_err(|e| format!("❌ Failed to read {:?}: {}", path, e))?;
+            let key = record.get(0).unwrap_or_default();
+            // ids are unique, a duplicated symbol gets the annotation for all its genes
+            for gene in self.resolve_exact(key) {
+                matched[gene] = true;
+                for (c, v) in record.iter().enumerate().take(headers.len()).skip(1) {
+                    columns[c][gene] = v.trim().to_string();
+                }
+            }
+        }
+
+        let mut table = self.gene_table.clone();
+        for (name, values) in headers.iter().zip(&columns).skip(1) {
+            if table.has_column(name) {
+                println!("⚠️ gene annotation column '{}' already exists - skipped", name);
+                continue;
+            }
+            table.push(MetaColumn::typed_from_strings(name, values))?;
+        }
+        self.set_gene_table(table)?;
+        let n = matched.iter().filter(|m| **m).count();
+        println!("🧬 annotated {} of {} genes from {:?}", n, self.gene_names.len(), path);
+        Ok(n)
+    }
+
+    /// The gene ids in the order of `gene_names` (empty strings if the source had none).
+    pub fn gene_ids(&self) -> Vec<String> {
+        ID_COLUMNS
+            .iter()
+            .find_map(|n| self.gene_table.column(n))
+            .map(|c| c.strings())
+            .unwrap_or_else(|| vec![String::new(); self.gene_names.len()])
+    }
+
+    fn resolver(&self) -> &GeneResolver {
+        self.gene_resolver.get_or_init(|| {
+            let aliases = self.gene_table.column(ALIAS_COLUMN).map(|c| c.strings()).unwrap_or_default();
+            GeneResolver::new(&self.gene_names, &self.gene_ids(), &aliases)
+        })
+    }
+
+    /// Genes with exactly this id or symbol.
+    fn resolve_exact(&self, key: &str) -> Vec<usize> {
+        let r = self.resolver();
+        r.ids
+            .get(strip_version(key))
+            .or_else(|| r.symbols.get(key))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// All genes of the primary modality `query` can refer to: an Ensembl id, a symbol,
+    /// a case-insensitive symbol or an alias. Duplicated symbols give all candidates.
+    pub fn resolve_gene(&self, query: &str) -> Vec<usize> {
+        self.resolver().resolve(query)
+    }
+
+    /// The one gene `query` refers to - an error if there is none or it is ambiguous.
+    pub fn gene_index(&self, query: &str) -> Result<usize, String> {
+        match self.resolve_gene(query).as_slice() {
+            [] => Err(format!("❌ Gene '{}' not found", query)),
+            [one] => Ok(*one),
+            many => {
+                let ids = self.gene_ids();
+                let candidates: Vec<String> = many
+                    .iter()
+                    .map(|&g| match ids.get(g).filter(|id| !id.is_empty()) {
+                        Some(id) => format!("{} ({})", self.gene_names[g], id),
+                        None => self.gene_names[g].clone(),
+                    })
+                    .collect();
+                Err(format!("❌ Gene '{}' is ambiguous: {}", query, candidates.join(", ")))
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_by_id_symbol_case_and_alias() {
+        let symbols: Vec<String> = ["TP53", "MATR3", "MATR3", "CD4"].iter().map(|s| s.to_string()).collect();
+        let ids: Vec<String> = ["ENSG00000141510.17", "ENSG00000015479", "ENSG00000280987", ""]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let aliases: Vec<String> = ["p53, LFS1", "", "", "T4|Leu-3"].iter().map(|s| s.to_string()).collect();
+        let r = GeneResolver::new(&symbols, &ids, &aliases);
+
+        assert_eq!(r.resolve("ENSG00000141510"), vec![0]);
+        assert_eq!(r.resolve("ENSG00000141510.5"), vec![0]);
+        assert_eq!(r.resolve("tp53"), vec![0]);
+        assert_eq!(r.resolve("MATR3"), vec![1, 2]);
+        assert_eq!(r.resolve("ENSG00000280987"), vec![2]);
+        assert_eq!(r.resolve("leu-3"), vec![3]);
+        assert_eq!(r.resolve("ensg00000141510.16"), vec![0]);
+        assert!(r.resolve("XYZ").is_empty());
+
+        // gene 0 is found by its symbol and its id - but listed once
+        let names = |v: &[&str]| -> Vec<String> { v.iter().map(|s| s.to_string()).collect() };
+        let r = GeneResolver::new(&names(&["Ab", "aB"]), &names(&["AB", ""]), &names(&["", ""]));
+        assert_eq!(r.resolve("ab"), vec![0, 1]);
+    }
+}
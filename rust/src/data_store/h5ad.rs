@@ -0,0 +1,421 @@
+//h5ad.rs
+use crate::data_store::DataStore;
+use crate::data_store::hdf5_utils::{
+    attr_string, attr_strings, attr_usizes, is_string_dataset, read_compressed_group, read_f32,
+    read_f64, read_strings, read_usize,
+};
+use crate::data_store::doctor::{Issue, Severity};
+use crate::data_store::meta_table::{MetaColumn, MetaTable};
+use hdf5::{File as H5File, Group};
+use rust_data_table::SurvivalData;
+use sprs::{CsMat, TriMat};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// The layer `X` is kept in when `raw/X` is loaded as the counts.
+pub const X_LAYER: &str = "X";
+
+impl DataStore {
+    /// Load an AnnData `.h5ad` file directly.
+    ///
+    /// * `raw/X` + `raw/var` are used for the expression if present (like anndata_exporter does),
+    ///   otherwise `X` + `var`. Sparse (CSR/CSC) and dense, float and int matrices are supported.
+    /// * with `raw/X` present, `X` is kept as the layer `X` (rows matched to `raw/var` by name);
+    ///   anything of it that can not be kept is reported in the load issues.
+    /// * `obs` becomes `cell_meta` (categoricals as factors, the index as `barcode`).
+    /// * `var` becomes `gene_meta` (the index as `gene`).
+    /// * every `obsm` entry with at least two columns becomes a projection in `drcs`
+    ///   (the `X_` prefix is dropped: `X_umap` -> `umap`).
+    ///
+    /// Skipped `obs`/`var` columns and `obsm` entries are reported in the load issues.
+    pub fn from_h5ad<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let file = H5File::open(path).map_err(|e| format!("❌ Failed to open {:?}: {}", path, e))?;
+
+        // --- Cells ---
+        let obs = file
+            .group("obs")
+            .map_err(|e| format!("❌ No 'obs' group in {:?}: {}", path, e))?;
+        let mut skipped = Vec::new();
+        let (cell_names, mut obs_table) = read_dataframe(&obs, &mut skipped)?;
+        if cell_names.is_empty() {
+            return Err(format!("❌ No cells found in {:?}", path));
+        }
+        if !obs_table.has_column("barcode") {
+            obs_table.push(MetaColumn::factor_from_strings("barcode", &cell_names))?;
+        }
+
+        // --- Genes + matrix ---
+        let has_raw = file.link_exists("raw") && file.link_exists("raw/X");
+        let (x_root, var_group) = if has_raw {
+            ("raw/X", "raw/var")
+        } else {
+            ("X", "var")
+        };
+        let var = file
+            .group(var_group)
+            .map_err(|e| format!("❌ No '{}' group in {:?}: {}", var_group, path, e))?;
+        let (gene_names, mut var_table) = read_dataframe(&var, &mut skipped)?;
+        if gene_names.is_empty() {
+            return Err(format!("❌ No gene names found in {:?}", path));
+        }
+        if !var_table.has_column("gene") {
+            var_table.push(MetaColumn::factor_from_strings("gene", &gene_names))?;
+        }
+
+        let counts = read_x(&file, x_root, gene_names.len(), cell_names.len())?;
+        if counts.nnz() == 0 {
+            return Err(format!("❌ Matrix '{}' in {:?} appears empty", x_root, path));
+        }
+
+        // --- Projections ---
+        let mut drcs = HashMap::new();
+        if let Ok(obsm) = file.group("obsm") {
+            for key in obsm.member_names().unwrap_or_default() {
+                let Ok(ds) = obsm.dataset(&key) else {
+                    skipped.push(format!("obsm '{}' is not a plain array - skipped", key));
+                    continue;
+                };
+                let shape = ds.shape();
+                if shape.len() != 2 || shape[1] < 2 || shape[0] != cell_names.len() {
+                    skipped.push(format!("obsm '{}' has shape {:?} - skipped", key, shape));
+                    continue;
+                }
+                let values = read_f32(&ds)?;
+                let n_dims = shape[1];
+                let view = Self::embedding_to_array(&values, shape[0], n_dims);
+                let name = key.strip_prefix("X_").unwrap_or(&key).to_string();
+                println!("📈 Found projection '{}' ({} dims)", name, n_dims);
+                drcs.insert(name, view);
+            }
+        }
+
+        let cell_meta = obs_table.into_survival_data()?;
+
+        let mut ret = Self::from_parts(counts, gene_names, cell_names, cell_meta, SurvivalData::default(), drcs)?;
+        ret.set_gene_table(var_table)?;
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        for problem in skipped {
+            println!("⚠️ {}", problem);
+            ret.load_issues.push(Issue { severity: Severity::Warning, file: file_name.clone(), row: None, problem });
+        }
+        if has_raw {
+            ret.add_x_layer(&file, path);
+        }
+        Ok(ret)
+    }
+
+    /// Keep `X` (usually normalized or scaled values) next to the `raw/X` counts.
+    fn add_x_layer(&mut self, file: &H5File, path: &Path) {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let mut warn = |problem: String| {
+            println!("⚠️ {}", problem);
+            self.load_issues.push(Issue { severity: Severity::Warning, file: file_name.clone(), row: None, problem });
+        };
+        let layer = file
+            .group("var")
+            .map_err(|e| format!("no 'var' group: {}", e))
+            // the columns of this var are not used
+            .and_then(|var| read_dataframe(&var, &mut Vec::new()))
+            .and_then(|(x_genes, _)| {
+                let x = read_x(file, "X", x_genes.len(), self.cell_names.len())?;
+                Ok((x_genes, x))
+            });
+        let (x_genes, x) = match layer {
+            Ok(v) => v,
+            Err(e) => {
+                warn(format!("'X' not loaded (the counts are 'raw/X'): {}", e.trim_start_matches("❌ ")));
+                return;
+            }
+        };
+        if x_genes == self.gene_names {
+            self.layers.insert(X_LAYER.to_string(), x);
+            return;
+        }
+
+        // X often holds a subset of the raw genes (e.g. only the variable ones)
+        let mut row_of: HashMap<&str, usize> = HashMap::new();
+        for (i, g) in self.gene_names.iter().enumerate() {
+            row_of.entry(g.as_str()).or_insert(i);
+        }
+        let mut tri = TriMat::<f32>::new((self.gene_names.len(), self.cell_names.len()));
+        let mut in_x = vec![false; self.gene_names.len()];
+        let mut not_in_raw = 0;
+        for (gene, row) in x_genes.iter().zip(x.outer_iterator()) {
+            let Some(&r) = row_of.get(gene.as_str()) else {
+                not_in_raw += 1;
+                continue;
+            };
+            in_x[r] = true;
+            for (cell, &v) in row.iter() {
+                tri.add_triplet(r, cell, v);
+            }
+        }
+        if not_in_raw > 0 {
+            warn(format!("{} genes of 'X' are not in 'raw/var' - left out of the layer '{}'", not_in_raw, X_LAYER));
+        }
+        let only_raw = in_x.iter().filter(|b| !**b).count();
+        if only_raw > 0 {
+            warn(format!("{} genes of 'raw/var' are not in 'X' - they are empty in the layer '{}'", only_raw, X_LAYER));
+        }
+        self.layers.insert(X_LAYER.to_string(), tri.to_csr());
+    }
+}
+
+/// Read the expression matrix stored at `root` and return it as genes × cells CSR.
+fn read_x(file: &H5File, root: &str, n_genes: usize, n_cells: usize) -> Result<CsMat<f32>, String> {
+    // dense matrix: a plain (cells × genes) dataset
+    if let Ok(ds) = file.dataset(root) {
+        let shape = ds.shape();
+        if shape != [n_cells, n_genes] {
+            return Err(format!(
+                "❌ '{}' has shape {:?} - expected [{}, {}] (cells × genes)",
+                root, shape, n_cells, n_genes
+            ));
+        }
+        let values = read_f32(&ds)?;
+        let mut tri = TriMat::<f32>::new((n_genes, n_cells));
+        for (idx, v) in values.iter().enumerate() {
+            if *v != 0.0 {
+                tri.add_triplet(idx % n_genes, idx / n_genes, *v);
+            }
+        }
+        return Ok(tri.to_csr());
+    }
+
+    let group = file
+        .group(root)
+        .map_err(|e| format!("❌ No '{}' in h5ad file: {}", root, e))?;
+    let encoding = attr_string(&group, "encoding-type")
+        .or_else(|| attr_string(&group, "h5sparse_format").map(|f| format!("{}_matrix", f)))
+        .unwrap_or_default();
+    let shape = attr_usizes(&group, "shape")
+        .or_else(|| attr_usizes(&group, "h5sparse_shape"))
+        .unwrap_or_else(|| vec![n_cells, n_genes]);
+    if shape != [n_cells, n_genes] {
+        return Err(format!(
+            "❌ '{}' has shape {:?} - expected [{}, {}] (cells × genes)",
+            root, shape, n_cells, n_genes
+        ));
+    }
+
+    // AnnData stores cells × genes: a CSR matrix on disk is our genes × cells matrix in CSC.
+    match encoding.as_str() {
+        "csr_matrix" => read_compressed_group(&group, (n_genes, n_cells), false),
+        "csc_matrix" => read_compressed_group(&group, (n_genes, n_cells), true),
+        other => Err(format!("❌ Unsupported encoding '{}' for '{}'", other, root)),
+    }
+}
+
+/// Read an AnnData dataframe group: returns the index and all other columns.
+/// Columns that can not be read are added to `skipped`.
+fn read_dataframe(group: &Group, skipped: &mut Vec<String>) -> Result<(Vec<String>, MetaTable), String> {
+    let index_name = attr_string(group, "_index").unwrap_or_else(|| "_index".to_string());
+    let index = group
+        .dataset(&index_name)
+        .map_err(|e| format!("❌ No index '{}' in {}: {}", index_name, group.name(), e))
+        .and_then(|ds| read_strings(&ds))?;
+
+    let columns = attr_strings(group, "column-order").unwrap_or_else(|| {
+        group
+            .member_names()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|m| *m != index_name && m != "__categories")
+            .collect()
+    });
+
+    let mut table = MetaTable::new();
+    for col in columns {
+        match read_column(group, &col, index.len()) {
+            Ok(Some(c)) => table.push(c)?,
+            Ok(None) => skipped.push(format!("column '{}' in {} has an unsupported type - skipped", col, group.name())),
+            Err(e) => skipped.push(format!("column '{}' in {} not loaded: {}", col, group.name(), e.trim_start_matches("❌ "))),
+        }
+    }
+    Ok((index, table))
+}
+
+/// Read one dataframe column (numeric, string, categorical or nullable).
+fn read_column(group: &Group, name: &str, n_rows: usize) -> Result<Option<MetaColumn>, String> {
+    let col = if let Ok(ds) = group.dataset(name) {
+        if is_string_dataset(&ds) {
+            MetaColumn::factor_from_strings(name, &read_strings(&ds)?)
+        } else {
+            MetaColumn::Numeric { name: name.to_string(), values: read_f64(&ds)? }
+        }
+    } else if let Ok(sub) = group.group(name) {
+        match attr_string(&sub, "encoding-type").as_deref() {
+            Some("categorical") => {
+                let cat_ds = sub.dataset("categories").map_err(|e| e.to_string())?;
+                let levels = read_strings(&cat_ds)?;
+                let raw_codes = read_f64(&sub.dataset("codes").map_err(|e| e.to_string())?)?;
+                let codes = raw_codes
+                    .into_iter()
+                    .map(|c| if c >= 0.0 && (c as usize) < levels.len() { Some(c as usize) } else { None })
+                    .collect();
+                MetaColumn::Factor { name: name.to_string(), levels, codes }
+            }
+            Some("nullable-integer") | Some("nullable-boolean") => {
+                let mut values = read_f64(&sub.dataset("values").map_err(|e| e.to_string())?)?;
+                if let Ok(mask_ds) = sub.dataset("mask") {
+                    let mask = read_usize(&mask_ds)?;
+                    for (v, m) in values.iter_mut().zip(mask) {
+                        if m != 0 {
+                            *v = f64::NAN;
+                        }
+                    }
+                }
+                MetaColumn::Numeric { name: name.to_string(), values }
+            }
+            _ => return Ok(None),
+        }
+    } else {
+        return Ok(None);
+    };
+
+    if col.len() != n_rows {
+        return Err(format!("{} rows - expected {}", col.len(), n_rows));
+    }
+    Ok(Some(col))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_store::hdf5_utils::fixtures::{write_attr_values, write_compressed, write_str_attr, write_strings, write_values};
+    use ndarray::Array2;
+    use std::path::PathBuf;
+
+    /// cells × genes, as AnnData stores it
+    const X: [[f32; 2]; 3] = [[1.0, 0.0], [0.0, 2.0], [3.0, 4.0]];
+
+    fn h5ad_path(tag: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("printforge3d_h5ad_{}_{}.h5ad", tag, std::process::id()))
+    }
+
+    fn write_dataframe(parent: &Group, name: &str, index: &[&str]) -> Group {
+        let group = parent.create_group(name).unwrap();
+        write_str_attr(&group, "_index", "_index");
+        write_strings(&group, "_index", index);
+        group
+    }
+
+    /// `X` as a sparse group, cells as the rows (`csr_matrix`) or genes as the columns (`csc_matrix`).
+    fn write_sparse_x(parent: &Group, name: &str, encoding: &str) {
+        let group = parent.create_group(name).unwrap();
+        write_str_attr(&group, "encoding-type", encoding);
+        write_attr_values(&group, "shape", &[3i64, 2]);
+        match encoding {
+            "csr_matrix" => write_compressed(&group, &[1.0, 2.0, 3.0, 4.0], &[0, 1, 0, 1], &[0, 1, 2, 4]),
+            _ => write_compressed(&group, &[1.0, 3.0, 2.0, 4.0], &[0, 2, 1, 2], &[0, 2, 4]),
+        }
+    }
+
+    /// Three cells (with a categorical and a numeric obs column), two genes and the `X` `write_x` adds.
+    fn write_h5ad(path: &Path, write_x: impl Fn(&H5File)) {
+        let file = H5File::create(path).unwrap();
+        let obs = write_dataframe(&file, "obs", &["c0", "c1", "c2"]);
+        let cluster = obs.create_group("cluster").unwrap();
+        write_str_attr(&cluster, "encoding-type", "categorical");
+        write_strings(&cluster, "categories", &["a", "b"]);
+        write_values(&cluster, "codes", &[1i8, 0, 1]);
+        write_values(&obs, "n_umi", &[1.0f64, 2.0, 7.0]);
+        write_dataframe(&file, "var", &["g0", "g1"]);
+        write_x(&file);
+    }
+
+    fn assert_counts(ds: &DataStore) {
+        assert_eq!(ds.gene_names, vec!["g0", "g1"]);
+        assert_eq!(ds.cell_names, vec!["c0", "c1", "c2"]);
+        for (cell, row) in X.iter().enumerate() {
+            for (gene, v) in row.iter().enumerate() {
+                assert_eq!(ds.counts.get(gene, cell).copied().unwrap_or(0.0), *v, "gene {} cell {}", gene, cell);
+            }
+        }
+    }
+
+    #[test]
+    fn sparse_and_dense_x_become_genes_x_cells() {
+        for encoding in ["csr_matrix", "csc_matrix", "dense"] {
+            let path = h5ad_path(encoding);
+            write_h5ad(&path, |file| match encoding {
+                "dense" => {
+                    let x = Array2::from_shape_fn((3, 2), |(c, g)| X[c][g]);
+                    file.new_dataset_builder().with_data(&x).create("X").unwrap();
+                }
+                _ => write_sparse_x(file, "X", encoding),
+            });
+            let ds = DataStore::from_h5ad(&path).unwrap();
+            let _ = std::fs::remove_file(&path);
+            assert_counts(&ds);
+        }
+    }
+
+    #[test]
+    fn obs_columns_become_cell_meta() {
+        let path = h5ad_path("obs");
+        write_h5ad(&path, |file| write_sparse_x(file, "X", "csr_matrix"));
+        let ds = DataStore::from_h5ad(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let (levels, codes) = ds.meta_factor("cluster").unwrap();
+        assert_eq!(levels, vec!["a", "b"]);
+        assert_eq!(codes, vec![Some(1), Some(0), Some(1)]);
+        assert_eq!(ds.cell_meta.as_vec_f64("n_umi"), vec![1.0, 2.0, 7.0]);
+        let (levels, _) = ds.meta_factor("barcode").unwrap();
+        assert_eq!(levels, vec!["c0", "c1", "c2"]);
+        assert!(ds.load_issues.is_empty());
+    }
+
+    #[test]
+    fn skipped_columns_and_embeddings_are_load_issues() {
+        let path = h5ad_path("skipped");
+        write_h5ad(&path, |file| {
+            write_sparse_x(file, "X", "csr_matrix");
+            let obs = file.group("obs").unwrap();
+            // a group without a known encoding and a column of the wrong length
+            obs.create_group("odd").unwrap();
+            write_values(&obs, "short", &[1.0f64]);
+            let obsm = file.create_group("obsm").unwrap();
+            let one_dim = Array2::from_shape_vec((3, 1), vec![0.0f32, 1.0, 2.0]).unwrap();
+            obsm.new_dataset_builder().with_data(&one_dim).create("X_line").unwrap();
+        });
+        let ds = DataStore::from_h5ad(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let problems: Vec<&str> = ds.load_issues.iter().map(|i| i.problem.as_str()).collect();
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems.iter().any(|p| p.contains("'odd'") && p.contains("unsupported type")));
+        assert!(problems.iter().any(|p| p.contains("'short'") && p.contains("1 rows")));
+        assert!(problems.iter().any(|p| p.contains("obsm 'X_line'")));
+        assert!(ds.load_issues.iter().all(|i| i.severity == Severity::Warning));
+        assert!(ds.drcs.is_empty());
+    }
+
+    #[test]
+    fn x_is_kept_as_layer_next_to_raw_counts() {
+        let path = h5ad_path("raw");
+        write_h5ad(&path, |file| {
+            let raw = file.create_group("raw").unwrap();
+            write_dataframe(&raw, "var", &["g0", "g1"]);
+            write_sparse_x(&raw, "X", "csr_matrix");
+            // X only holds g1 (e.g. the variable genes), overwriting the var group of write_h5ad
+            file.unlink("var").unwrap();
+            write_dataframe(file, "var", &["g1"]);
+            let x = Array2::from_shape_vec((3, 1), vec![0.5f32, 0.0, 1.5]).unwrap();
+            file.new_dataset_builder().with_data(&x).create("X").unwrap();
+        });
+        let ds = DataStore::from_h5ad(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_counts(&ds);
+        let layer = &ds.layers[X_LAYER];
+        assert_eq!(layer.shape(), (2, 3));
+        assert_eq!(layer.get(1, 0), Some(&0.5));
+        assert_eq!(layer.get(1, 2), Some(&1.5));
+        assert_eq!(layer.outer_view(0).unwrap().nnz(), 0);
+        // g0 is missing in X
+        assert_eq!(ds.load_issues.len(), 1);
+    }
+}
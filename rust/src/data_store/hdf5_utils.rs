@@ -0,0 +1,188 @@
+//hdf5_utils.rs
+//! Small helpers shared by the HDF5 based loaders (h5ad, 10x h5, loom).
+use hdf5::types::{FixedAscii, FixedUnicode, TypeDescriptor, VarLenAscii, VarLenUnicode};
+use hdf5::{Conversion, Dataset, Group, Location};
+use sprs::CsMat;
+
+/// Read any numeric dataset as f64 (ints and floats of all sizes).
+pub(crate) fn read_f64(ds: &Dataset) -> Result<Vec<f64>, String> {
+    ds.as_reader()
+        .conversion(Conversion::Hard)
+        .read_raw::<f64>()
+        .map_err(|e| format!("❌ Failed to read {}: {}", ds.name(), e))
+}
+
+/// Read any numeric dataset as f32 (ints and floats of all sizes).
+pub(crate) fn read_f32(ds: &Dataset) -> Result<Vec<f32>, String> {
+    ds.as_reader()
+        .conversion(Conversion::Hard)
+        .read_raw::<f32>()
+        .map_err(|e| format!("❌ Failed to read {}: {}", ds.name(), e))
+}
+
+/// Read an integer index dataset (indices, indptr, codes) as usize.
+pub(crate) fn read_usize(ds: &Dataset) -> Result<Vec<usize>, String> {
+    let raw = ds
+        .as_reader()
+        .conversion(Conversion::Hard)
+        .read_raw::<i64>()
+        .map_err(|e| format!("❌ Failed to read {}: {}", ds.name(), e))?;
+    raw.into_iter()
+        .map(|v| usize::try_from(v).map_err(|_| format!("❌ Negative index {} in {}", v, ds.name())))
+        .collect()
+}
+
+/// Read a string dataset - variable or fixed length, ASCII or UTF-8.
+pub(crate) fn read_strings(ds: &Dataset) -> Result<Vec<String>, String> {
+    let descr = ds
+        .dtype()
+        .and_then(|t| t.to_descriptor())
+        .map_err(|e| format!("❌ Failed to get the type of {}: {}", ds.name(), e))?;
+    let err = |e: hdf5::Error| format!("❌ Failed to read strings from {}: {}", ds.name(), e);
+    let ret = match descr {
+        TypeDescriptor::VarLenUnicode => ds
+            .read_raw::<VarLenUnicode>()
+            .map_err(err)?
+            .iter()
+            .map(|s| s.as_str().to_string())
+            .collect(),
+        TypeDescriptor::VarLenAscii => ds
+            .read_raw::<VarLenAscii>()
+            .map_err(err)?
+            .iter()
+            .map(|s| s.as_str().to_string())
+            .collect(),
+        TypeDescriptor::FixedAscii(_) => ds
+            .as_reader()
+            .conversion(Conversion::Hard)
+            .read_raw::<FixedAscii<1024>>()
+            .map_err(err)?
+            .iter()
+            .map(|s| s.as_str().to_string())
+            .collect(),
+        TypeDescriptor::FixedUnicode(_) => ds
+            .as_reader()
+            .conversion(Conversion::Hard)
+            .read_raw::<FixedUnicode<1024>>()
+            .map_err(err)?
+            .iter()
+            .map(|s| s.as_str().to_string())
+            .collect(),
+        // numeric ids (e.g. an integer obs index) - keep them as text
+        _ => read_f64(ds)?.iter().map(|v| v.to_string()).collect(),
+    };
+    Ok(ret)
+}
+
+/// True if the dataset stores text rather than numbers.
+pub(crate) fn is_string_dataset(ds: &Dataset) -> bool {
+    matches!(
+        ds.dtype().and_then(|t| t.to_descriptor()),
+        Ok(TypeDescriptor::VarLenUnicode)
+            | Ok(TypeDescriptor::VarLenAscii)
+            | Ok(TypeDescriptor::FixedAscii(_))
+            | Ok(TypeDescriptor::FixedUnicode(_))
+    )
+}
+
+/// Read a scalar string attribute, `None` if it does not exist.
+pub(crate) fn attr_string(loc: &Location, name: &str) -> Option<String> {
+    let attr = loc.attr(name).ok()?;
+    if let Ok(s) = attr.read_scalar::<VarLenUnicode>() {
+        return Some(s.as_str().to_string());
+    }
+    if let Ok(s) = attr.read_scalar::<VarLenAscii>() {
+        return Some(s.as_str().to_string());
+    }
+    attr.as_reader()
+        .conversion(Conversion::Hard)
+        .read_scalar::<FixedAscii<1024>>()
+        .ok()
+        .map(|s| s.as_str().to_string())
+}
+
+/// Read a string array attribute (e.g. anndata's `column-order`).
+pub(crate) fn attr_strings(loc: &Location, name: &str) -> Option<Vec<String>> {
+    let attr = loc.attr(name).ok()?;
+    if let Ok(v) = attr.read_raw::<VarLenUnicode>() {
+        return Some(v.iter().map(|s| s.as_str().to_string()).collect());
+    }
+    attr.read_raw::<VarLenAscii>()
+        .ok()
+        .map(|v| v.iter().map(|s| s.as_str().to_string()).collect())
+}
+
+/// Read an integer array attribute (e.g. a sparse matrix `shape`).
+pub(crate) fn attr_usizes(loc: &Location, name: &str) -> Option<Vec<usize>> {
+    let attr = loc.attr(name).ok()?;
+    attr.as_reader()
+        .conversion(Conversion::Hard)
+        .read_raw::<i64>()
+        .ok()
+        .map(|v| v.into_iter().map(|x| x.max(0) as usize).collect())
+}
+
+/// Read a compressed sparse matrix stored as a `data`/`indices`/`indptr` group.
+///
+/// `csr` tells how `indptr`/`indices` are read for a matrix of `shape` (rows, cols):
+/// true if `indptr` runs over the rows, false if it runs over the columns. A matrix
+/// stored transposed on disk (e.g. anndata's cells × genes `csr_matrix` read as
+/// genes × cells) is therefore read with `false`. The result is always CSR.
+pub(crate) fn read_compressed_group(
+    group: &Group,
+    shape: (usize, usize),
+    csr: bool,
+) -> Result<CsMat<f32>, String> {
+    let data = read_f32(&group.dataset("data").map_err(|e| e.to_string())?)?;
+    let indices = read_usize(&group.dataset("indices").map_err(|e| e.to_string())?)?;
+    let indptr = read_usize(&group.dataset("indptr").map_err(|e| e.to_string())?)?;
+
+    let mat = if csr {
+        CsMat::new_from_unsorted(shape, indptr, indices, data)
+    } else {
+        CsMat::new_from_unsorted_csc(shape, indptr, indices, data)
+    }
+    .map_err(|(_, _, _, e)| format!("❌ Invalid sparse matrix in {}: {}", group.name(), e))?;
+
+    Ok(if mat.is_csr() { mat } else { mat.to_other_storage() })
+}
+
+/// Writers for the small HDF5 files the loader tests build.
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use hdf5::types::VarLenUnicode;
+    use hdf5::{Group, H5Type, Location};
+
+    fn text(value: &str) -> VarLenUnicode {
+        value.parse().unwrap()
+    }
+
+    /// A 1D numeric dataset.
+    pub(crate) fn write_values<T: H5Type>(group: &Group, name: &str, values: &[T]) {
+        group.new_dataset_builder().with_data(values).create(name).unwrap();
+    }
+
+    /// The `data`/`indices`/`indptr` datasets of a compressed sparse matrix.
+    pub(crate) fn write_compressed(group: &Group, data: &[f32], indices: &[i32], indptr: &[i64]) {
+        write_values(group, "data", data);
+        write_values(group, "indices", indices);
+        write_values(group, "indptr", indptr);
+    }
+
+    /// A 1D variable length UTF-8 string dataset.
+    pub(crate) fn write_strings(group: &Group, name: &str, values: &[&str]) {
+        let values: Vec<VarLenUnicode> = values.iter().map(|v| text(v)).collect();
+        group.new_dataset_builder().with_data(values.as_slice()).create(name).unwrap();
+    }
+
+    /// A scalar string attribute (e.g. `encoding-type`).
+    pub(crate) fn write_str_attr(loc: &Location, name: &str, value: &str) {
+        let attr = loc.new_attr::<VarLenUnicode>().shape(()).create(name).unwrap();
+        attr.write_scalar(&text(value)).unwrap();
+    }
+
+    /// A 1D numeric attribute (e.g. a sparse matrix `shape`).
+    pub(crate) fn write_attr_values<T: H5Type>(loc: &Location, name: &str, values: &[T]) {
+        loc.new_attr_builder().with_data(values).create(name).unwrap();
+    }
+}
@@ -0,0 +1,449 @@
+//hvg.rs
+//! Highly variable gene selection on the raw counts of the primary modality.
+//!
+//! Two methods are available:
+//! - `SeuratV3`: a loess fit of log10(variance) over log10(mean) of the counts; the
+//!   variance of the counts standardized by the fit (clipped at sqrt(n_cells)) ranks the genes
+//!   (Stuart et al. 2019, `FindVariableFeatures(selection.method = "vst")`).
+//! - `Dispersion`: genes are binned by the mean of the library size normalized counts and
+//!   their log dispersion is z-scored within the bin (Seurat v1 / Cell Ranger).
+//!
+//! With a batch factor the statistics are computed per batch and the genes ranked high
+//! in most batches are taken first. The results are `gene_meta` columns.
+use crate::data_store::DataStore;
+use crate::data_store::meta_table::MetaColumn;
+
+pub const HVG_MEAN: &str = "hvg_mean";
+pub const HVG_VARIANCE: &str = "hvg_variance";
+pub const HVG_VARIANCE_STANDARDIZED: &str = "hvg_variance_standardized";
+pub const IS_HVG: &str = "is_hvg";
+
+/// Counts per cell the `Dispersion` method normalizes to.
+const DISPERSION_TARGET: f64 = 1e4;
+/// Mean bins of the `Dispersion` method.
+const DISPERSION_BINS: usize = 20;
+/// The loess span of the `SeuratV3` fit (as in Seurat).
+const LOESS_SPAN: f64 = 0.3;
+/// The loess fit is evaluated at this many points and interpolated in between.
+const LOESS_ANCHORS: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HvgFlavor {
+    SeuratV3,
+    Dispersion,
+}
+
+impl HvgFlavor {
+    /// `seurat_v3` (or `vst`) and `dispersion` (or `seurat`, `mean_var`).
+    pub fn parse(text: &str) -> Result<Self, String> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "seurat_v3" | "vst" | "" => Ok(HvgFlavor::SeuratV3),
+            "dispersion" | "seurat" | "mean_var" => Ok(HvgFlavor::Dispersion),
+            _ => Err(format!("❌ Unknown HVG method '{}' - use seurat_v3 or dispersion", text)),
+        }
+    }
+}
+
+/// Per gene statistics of one batch.
+struct BatchStats {
+    n: usize,
+    /// sum and sum of squares of the (SeuratV3: raw, Dispersion: normalized) values
+    sum: Vec<f64>,
+    sum_sq: Vec<f64>,
+    /// standardized variance (SeuratV3) or normalized dispersion; NaN for genes that can't be ranked
+    score: Vec<f64>,
+}
+
+/// Sums of the values of every gene in every batch.
+struct Sums {
+    n: Vec<usize>,
+    sum: Vec<Vec<f64>>,
+    sum_sq: Vec<Vec<f64>>,
+}
+
+/// Locally weighted quadratic regression of `y` on `x` (tricube weights over the
+/// `span` fraction of nearest points), evaluated at every `x`.
+pub(crate) fn loess(x: &[f64], y: &[f64], span: f64) -> Vec<f64> {
+    let n = x.len();
+    if n < 3 {
+        let mean = y.iter().sum::<f64>() / n.max(1) as f64;
+        return vec![mean; n];
+    }
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|a, b| x[*a].total_cmp(&x[*b]));
+    let xs: Vec<f64> = order.iter().map(|i| x[*i]).collect();
+    let ys: Vec<f64> = order.iter().map(|i| y[*i]).collect();
+    let k = ((span * n as f64).ceil() as usize).clamp(3, n);
+
+    let fit_at = |pos: usize| -> f64 {
+        let x0 = xs[pos];
+        // the k nearest points form a window around pos
+        let (mut lo, mut hi) = (pos, pos + 1);
+        while hi - lo < k {
+            if lo == 0 {
+                hi += 1;
+            } else if hi == n || x0 - xs[lo - 1] <= xs[hi] - x0 {
+                lo -= 1;
+            } else {
+                hi += 1;
+            }
+        }
+        let radius = (x0 - xs[lo]).max(xs[hi - 1] - x0).max(1e-12) * 1.0001;
+        // weighted least squares for a + b·d + c·d² with d = x - x0
+        let mut m = [[0.0f64; 3]; 3];
+        let mut v = [0.0f64; 3];
+        for i in lo..hi {
+            let d = xs[i] - x0;
+            let w = (1.0 - (d.abs() / radius).powi(3)).powi(3);
+            let p = [1.0, d, d * d];
+            for r in 0..3 {
+                v[r] += w * p[r] * ys[i];
+                for c in 0..3 {
+                    m[r][c] += w * p[r] * p[c];
+                }
+            }
+        }
+        solve3(m, v).unwrap_or_else(|| v[0] / m[0][0].max(1e-300))
+    };
+
+    let anchors: Vec<usize> = if n <= LOESS_ANCHORS {
+        (0..n).collect()
+    } else {
+        let mut a: Vec<usize> = (0..LOESS_ANCHORS).map(|i| i * (n - 1) / (LOESS_ANCHORS - 1)).collect();
+        a.dedup();
+        a
+    };
+    let fitted: Vec<f64> = anchors.iter().map(|p| fit_at(*p)).collect();
+
+    let mut ret = vec![0.0; n];
+    let mut a = 0;
+    for (pos, orig) in order.iter().enumerate() {
+        while a + 1 < anchors.len() - 1 && anchors[a + 1] <= pos {
+            a += 1;
+        }
+        let (p0, p1) = (anchors[a], anchors[(a + 1).min(anchors.len() - 1)]);
+        let (x0, x1) = (xs[p0], xs[p1]);
+        ret[*orig] = if x1 > x0 {
+            let t = ((xs[pos] - x0) / (x1 - x0)).clamp(0.0, 1.0);
+            fitted[a] + t * (fitted[(a + 1).min(anchors.len() - 1)] - fitted[a])
+        } else {
+            fitted[a]
+        };
+    }
+    ret
+}
+
+/// The intercept of the 3×3 system `m · coef = v` (Gaussian elimination), `None` if singular.
+fn solve3(mut m: [[f64; 3]; 3], mut v: [f64; 3]) -> Option<f64> {
+    for col in 0..3 {
+        let pivot = (col..3).max_by(|a, b| m[*a][col].abs().total_cmp(&m[*b][col].abs()))?;
+        if m[pivot][col].abs() < 1e-12 * m[0][0].abs().max(1e-300) {
+            return None;
+        }
+        m.swap(col, pivot);
+        v.swap(col, pivot);
+        for r in col + 1..3 {
+            let f = m[r][col] / m[col][col];
+            let pivot_row = m[col];
+            for (x, p) in m[r].iter_mut().zip(pivot_row).skip(col) {
+                *x -= f * p;
+            }
+            v[r] -= f * v[col];
+        }
+    }
+    let mut coef = [0.0; 3];
+    for r in (0..3).rev() {
+        let rest: f64 = (r + 1..3).map(|c| m[r][c] * coef[c]).sum();
+        coef[r] = (v[r] - rest) / m[r][r];
+    }
+    Some(coef[0])
+}
+
+fn mean_and_variance(n: usize, sum: f64, sum_sq: f64) -> (f64, f64) {
+    let mean = sum / n.max(1) as f64;
+    let variance = if n > 1 { ((sum_sq - n as f64 * mean * mean) / (n as f64 - 1.0)).max(0.0) } else { 0.0 };
+    (mean, variance)
+}
+
+impl DataStore {
+    /// Select the `n_top` most variable genes and store `hvg_mean`, `hvg_variance`,
+    /// `hvg_variance_standardized` and `is_hvg` in `gene_meta`.
+    /// `batch` names a cell_meta factor; cells without a batch are left out.
+    /// Returns the selected genes, most variable first.
+    pub fn select_hvgs(&mut self, flavor: HvgFlavor, n_top: usize, batch: Option<&str>) -> Result<Vec<usize>, String> {
+        let n_genes = self.gene_names.len();
+        let (n_batches, batch_of) = self.batches(batch)?;
+        let stats: Vec<BatchStats> = match flavor {
+            HvgFlavor::SeuratV3 => self.seurat_v3_stats(n_batches, &batch_of)?,
+            HvgFlavor::Dispersion => self.dispersion_stats(n_batches, &batch_of)?,
+        };
+        let stats: Vec<&BatchStats> = stats.iter().filter(|s| s.score.iter().any(|v| v.is_finite())).collect();
+        if stats.is_empty() {
+            return Err("❌ No batch with enough cells to rank the genes".to_string());
+        }
+
+        // in how many batches is a gene among the top n_top and how high is it ranked there
+        let mut n_top_batches = vec![0usize; n_genes];
+        let mut ranks: Vec<Vec<usize>> = vec![Vec::new(); n_genes];
+        for s in &stats {
+            let mut order: Vec<usize> = (0..n_genes).filter(|g| s.score[*g].is_finite()).collect();
+            order.sort_by(|a, b| s.score[*b].total_cmp(&s.score[*a]));
+            for (rank, g) in order.iter().take(n_top).enumerate() {
+                n_top_batches[*g] += 1;
+                ranks[*g].push(rank);
+            }
+        }
+        let score: Vec<f64> = (0..n_genes)
+            .map(|g| {
+                let finite: Vec<f64> = stats.iter().map(|s| s.score[g]).filter(|v| v.is_finite()).collect();
+                if finite.is_empty() { f64::NAN } else { finite.iter().sum::<f64>() / finite.len() as f64 }
+            })
+            .collect();
+        let median_rank: Vec<f64> = ranks
+            .iter_mut()
+            .map(|r| {
+                r.sort_unstable();
+                match r.len() {
+                    0 => f64::INFINITY,
+                    n if n % 2 == 1 => r[n / 2] as f64,
+                    n => (r[n / 2 - 1] + r[n / 2]) as f64 / 2.0,
+                }
+            })
+            .collect();
+        let mut order: Vec<usize> = (0..n_genes).filter(|g| score[*g].is_finite()).collect();
+        order.sort_by(|a, b| {
+            n_top_batches[*b]
+                .cmp(&n_top_batches[*a])
+                .then(median_rank[*a].total_cmp(&median_rank[*b]))
+                .then(score[*b].total_cmp(&score[*a]))
+        });
+        order.truncate(n_top);
+
+        // mean and variance over the cells of all batches
+        let n_cells: usize = stats.iter().map(|s| s.n).sum();
+        let (mean, variance): (Vec<f64>, Vec<f64>) = (0..n_genes)
+            .map(|g| {
+                let sum = stats.iter().map(|s| s.sum[g]).sum();
+                let sum_sq = stats.iter().map(|s| s.sum_sq[g]).sum();
+                mean_and_variance(n_cells, sum, sum_sq)
+            })
+            .unzip();
+        let mut is_hvg = vec![false; n_genes];
+        order.iter().for_each(|g| is_hvg[*g] = true);
+        let numeric = |name: &str, values: Vec<f64>| MetaColumn::Numeric { name: name.to_string(), values };
+        let mut table = self.gene_table.clone();
+        table.set(numeric(HVG_MEAN, mean))?;
+        table.set(numeric(HVG_VARIANCE, variance))?;
+        table.set(numeric(HVG_VARIANCE_STANDARDIZED, score))?;
+        table.set(MetaColumn::Factor {
+            name: IS_HVG.to_string(),
+            levels: vec!["false".to_string(), "true".to_string()],
+            codes: is_hvg.iter().map(|h| Some(*h as usize)).collect(),
+        })?;
+        self.set_gene_table(table)?;
+        println!("🧬 {} highly variable genes ({:?}, {} batch(es))", order.len(), flavor, stats.len());
+        Ok(order)
+    }
+
+    /// The genes flagged by the last `select_hvgs` (in gene order; empty without one).
+    pub fn hvg_genes(&self) -> Vec<usize> {
+        match self.gene_table.column(IS_HVG) {
+            Some(MetaColumn::Factor { codes, .. }) => {
+                (0..codes.len()).filter(|g| codes[*g] == Some(1)).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// The batch of every cell (`None`: not part of any) from a cell_meta factor.
+    fn batches(&self, batch: Option<&str>) -> Result<(usize, Vec<Option<usize>>), String> {
+        let n_cells = self.cell_names.len();
+        let Some(column) = batch.filter(|b| !b.is_empty()) else {
+            return Ok((1, vec![Some(0); n_cells]));
+        };
+        let (levels, batch_of) = self
+            .meta_factor(column)
+            .ok_or_else(|| format!("❌ '{}' is not a cell_meta factor", column))?;
+        if batch_of.len() != n_cells {
+            return Err(format!("❌ cell_meta column '{}' has {} rows for {} cells", column, batch_of.len(), n_cells));
+        }
+        Ok((levels.len(), batch_of))
+    }
+
+    /// Per batch sums of the counts and squared counts of every gene; with `clip`
+    /// (per batch and gene) the values are clipped first.
+    fn seurat_v3_sums(
+        &self,
+        n_batches: usize,
+        batch_of: &[Option<usize>],
+        clip: Option<&[Vec<f64>]>,
+    ) -> Result<Sums, String> {
+        let n_genes = self.gene_names.len();
+        let mut n = vec![0usize; n_batches];
+        batch_of.iter().flatten().for_each(|b| n[*b] += 1);
+        let mut sum = vec![vec![0.0f64; n_genes]; n_batches];
+        let mut sum_sq = vec![vec![0.0f64; n_genes]; n_batches];
+        self.for_each_feature("", |g, v| {
+            for (c, x) in v.iter() {
+                let Some(b) = batch_of[c] else { continue };
+                let x = match clip {
+                    Some(clip) => (*x as f64).min(clip[b][g]),
+                    None => *x as f64,
+                };
+                sum[b][g] += x;
+                sum_sq[b][g] += x * x;
+            }
+        })?;
+        Ok(Sums { n, sum, sum_sq })
+    }
+
+    fn seurat_v3_stats(&self, n_batches: usize, batch_of: &[Option<usize>]) -> Result<Vec<BatchStats>, String> {
+        let n_genes = self.gene_names.len();
+        let raw = self.seurat_v3_sums(n_batches, batch_of, None)?;
+        let mut means = Vec::with_capacity(n_batches);
+        let mut clip = vec![vec![f64::INFINITY; n_genes]; n_batches];
+        let mut reg_std = vec![vec![f64::NAN; n_genes]; n_batches];
+        for b in 0..n_batches {
+            let (mean, variance): (Vec<f64>, Vec<f64>) =
+                (0..n_genes).map(|g| mean_and_variance(raw.n[b], raw.sum[b][g], raw.sum_sq[b][g])).unzip();
+            let fit_genes: Vec<usize> = (0..n_genes).filter(|g| variance[*g] > 0.0).collect();
+            if raw.n[b] > 1 && fit_genes.len() >= 3 {
+                let x: Vec<f64> = fit_genes.iter().map(|g| mean[*g].log10()).collect();
+                let y: Vec<f64> = fit_genes.iter().map(|g| variance[*g].log10()).collect();
+                for (g, fitted) in fit_genes.iter().zip(loess(&x, &y, LOESS_SPAN)) {
+                    let sd = 10f64.powf(fitted).sqrt();
+                    reg_std[b][*g] = sd;
+                    clip[b][*g] = mean[*g] + sd * (raw.n[b] as f64).sqrt();
+                }
+            }
+            means.push(mean);
+        }
+        let clipped = self.seurat_v3_sums(n_batches, batch_of, Some(&clip))?;
+        let Sums { n, sum, sum_sq } = raw;
+        let stats = sum
+            .into_iter()
+            .zip(sum_sq)
+            .enumerate()
+            .map(|(b, (sum, sum_sq))| {
+                let cells = n[b] as f64;
+                let score = (0..n_genes)
+                    .map(|g| {
+                        let (sd, m) = (reg_std[b][g], means[b][g]);
+                        if !sd.is_finite() || sd <= 0.0 {
+                            return f64::NAN;
+                        }
+                        let ss = cells * m * m + clipped.sum_sq[b][g] - 2.0 * m * clipped.sum[b][g];
+                        ss / ((cells - 1.0) * sd * sd)
+                    })
+                    .collect();
+                BatchStats { n: n[b], sum, sum_sq, score }
+            })
+            .collect();
+        Ok(stats)
+    }
+
+    fn dispersion_stats(&self, n_batches: usize, batch_of: &[Option<usize>]) -> Result<Vec<BatchStats>, String> {
+        let n_genes = self.gene_names.len();
+        let factors: Vec<f64> = self
+            .library_sizes()?
+            .iter()
+            .map(|t| if *t > 0.0 { DISPERSION_TARGET / *t as f64 } else { 0.0 })
+            .collect();
+        let mut n = vec![0usize; n_batches];
+        batch_of.iter().flatten().for_each(|b| n[*b] += 1);
+        let mut sum = vec![vec![0.0f64; n_genes]; n_batches];
+        let mut sum_sq = vec![vec![0.0f64; n_genes]; n_batches];
+        self.for_each_feature("", |g, v| {
+            for (c, x) in v.iter() {
+                let Some(b) = batch_of[c] else { continue };
+                let x = *x as f64 * factors[c];
+                sum[b][g] += x;
+                sum_sq[b][g] += x * x;
+            }
+        })?;
+
+        let mut stats = Vec::with_capacity(n_batches);
+        for (b, (sum, sum_sq)) in sum.into_iter().zip(sum_sq).enumerate() {
+            let (mean, variance): (Vec<f64>, Vec<f64>) =
+                (0..n_genes).map(|g| mean_and_variance(n[b], sum[g], sum_sq[g])).unzip();
+            let mut score = vec![f64::NAN; n_genes];
+            if n[b] > 1 {
+                let log_mean: Vec<f64> = mean.iter().map(|m| m.ln_1p()).collect();
+                let log_disp: Vec<f64> = mean
+                    .iter()
+                    .zip(&variance)
+                    .map(|(m, v)| if *m > 0.0 && *v > 0.0 { (v / m).ln() } else { f64::NAN })
+                    .collect();
+                let ranked: Vec<usize> = (0..n_genes).filter(|g| log_disp[*g].is_finite()).collect();
+                let (lo, hi) = ranked
+                    .iter()
+                    .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), g| (lo.min(log_mean[*g]), hi.max(log_mean[*g])));
+                let width = ((hi - lo) / DISPERSION_BINS as f64).max(1e-12);
+                let bin_of = |g: usize| (((log_mean[g] - lo) / width) as usize).min(DISPERSION_BINS - 1);
+                let mut bins: Vec<Vec<usize>> = vec![Vec::new(); DISPERSION_BINS];
+                ranked.iter().for_each(|g| bins[bin_of(*g)].push(*g));
+                for genes in bins.iter().filter(|genes| !genes.is_empty()) {
+                    let k = genes.len() as f64;
+                    let bin_mean = genes.iter().map(|g| log_disp[*g]).sum::<f64>() / k;
+                    let bin_sd = if genes.len() > 1 {
+                        (genes.iter().map(|g| (log_disp[*g] - bin_mean).powi(2)).sum::<f64>() / (k - 1.0)).sqrt()
+                    } else {
+                        0.0
+                    };
+                    for g in genes {
+                        // a gene alone in its bin (or a bin without spread) is not scaled
+                        score[*g] = if bin_sd > 0.0 { (log_disp[*g] - bin_mean) / bin_sd } else { 1.0 };
+                    }
+                }
+            }
+            stats.push(BatchStats { n: n[b], sum, sum_sq, score });
+        }
+        Ok(stats)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_store::data_store::test_store;
+    use sprs::TriMat;
+
+    #[test]
+    fn loess_follows_a_parabola() {
+        let x: Vec<f64> = (0..500).map(|i| i as f64 / 50.0).collect();
+        let y: Vec<f64> = x.iter().map(|x| 1.0 + 0.5 * x * x).collect();
+        for (fit, y) in loess(&x, &y, 0.3).iter().zip(&y) {
+            assert!((fit - y).abs() < 1e-3, "{} vs {}", fit, y);
+        }
+    }
+
+    #[test]
+    fn variable_genes_are_selected() {
+        // 400 genes with counts around 40 different means; gene 27 has the mean
+        // of its group but all its counts in every 10th cell
+        let n_cells = 200;
+        let mut tri = TriMat::<f32>::new((400, n_cells));
+        for g in 0..400 {
+            for c in 0..n_cells {
+                let level = 1 + g % 40;
+                let x = if g == 27 { if c % 10 == 0 { 280 } else { 0 } } else { level + (c * 7 + g) % 3 };
+                if x > 0 {
+                    tri.add_triplet(g, c, x as f32);
+                }
+            }
+        }
+        let genes: Vec<String> = (0..400).map(|g| format!("G{}", g)).collect();
+        let cells: Vec<String> = (0..n_cells).map(|c| format!("c{}", c)).collect();
+        let mut ds = test_store(tri.to_csr(), genes, cells);
+
+        for flavor in [HvgFlavor::SeuratV3, HvgFlavor::Dispersion] {
+            let top = ds.select_hvgs(flavor, 5, None).unwrap();
+            assert_eq!(top.len(), 5);
+            assert_eq!(top[0], 27, "{:?}", flavor);
+            assert_eq!(ds.hvg_genes().len(), 5);
+        }
+        assert!(ds.gene_table.has_column(HVG_VARIANCE_STANDARDIZED));
+        assert!(HvgFlavor::parse("vst").is_ok() && HvgFlavor::parse("pca").is_err());
+    }
+}
@@ -0,0 +1,327 @@
+//knn.rs
+//! Approximate k nearest neighbors over a `drcs` entry (a projection or PCA scores).
+//!
+//! A random projection forest proposes candidates: every tree splits the cells
+//! recursively by the hyperplane between two random cells, and the cells sharing a
+//! leaf are compared. Some rounds of "neighbors of neighbors" then refine the lists.
+//! From the kNN graph a shared nearest neighbor (SNN) graph is derived with Jaccard
+//! weights of the neighborhoods (as Seurat's `FindNeighbors`).
+use crate::data_store::DataStore;
+use crate::data_store::pca::Gaussian;
+use rayon::prelude::*;
+use sprs::CsMat;
+
+/// Settings of `DataStore::build_neighbors`.
+#[derive(Debug, Clone)]
+pub struct KnnOptions {
+    /// neighbors per cell (the cell itself not counted)
+    pub k: usize,
+    /// use the first `n_dims` dimensions only (`None`: all)
+    pub n_dims: Option<usize>,
+    pub n_trees: usize,
+    /// cells per tree leaf (at least k + 1)
+    pub leaf_size: usize,
+    /// rounds of neighbors-of-neighbors refinement
+    pub refine_rounds: usize,
+    /// SNN edges with a smaller Jaccard weight are dropped (Seurat: 1/15)
+    pub snn_prune: f32,
+    pub seed: u64,
+}
+
+impl Default for KnnOptions {
+    fn default() -> Self {
+        Self { k: 15, n_dims: None, n_trees: 8, leaf_size: 32, refine_rounds: 2, snn_prune: 1.0 / 15.0, seed: 0 }
+    }
+}
+
+/// The neighbor graphs of a dataset over one `drcs` entry. Both are cells × cells
+/// (CSR); cells without coordinates have empty rows.
+#[derive(Debug, Clone)]
+pub struct NeighborGraph {
+    /// the `drcs` entry the graph was built on
+    pub projection: String,
+    pub k: usize,
+    /// Euclidean distance to each of the k nearest neighbors
+    pub knn: CsMat<f32>,
+    /// Jaccard index of the (self including) neighborhoods
+    pub snn: CsMat<f32>,
+}
+
+/// (squared distance, point) pairs, nearest first.
+type Candidates = Vec<(f32, u32)>;
+
+fn dist2(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+/// Add `q` to the k nearest list if it is closer than the farthest one.
+fn offer(list: &mut Candidates, k: usize, d: f32, q: u32) {
+    if list.len() == k && d >= list[k - 1].0 {
+        return;
+    }
+    if list.iter().any(|(_, i)| *i == q) {
+        return;
+    }
+    let pos = list.partition_point(|(x, _)| *x <= d);
+    list.insert(pos, (d, q));
+    list.truncate(k);
+}
+
+/// The leaves of one random projection tree over `n` points of dimension `d`.
+fn tree_leaves(points: &[f32], n: usize, d: usize, leaf_size: usize, rng: &mut Gaussian) -> Vec<Vec<u32>> {
+    let point = |i: u32| &points[i as usize * d..(i as usize + 1) * d];
+    let mut leaves = Vec::new();
+    let mut stack = vec![(0..n as u32).collect::<Vec<u32>>()];
+    while let Some(ids) = stack.pop() {
+        if ids.len() <= leaf_size {
+            leaves.push(ids);
+            continue;
+        }
+        let a = ids[(rng.next_u64() % ids.len() as u64) as usize];
+        let b = ids[(rng.next_u64() % ids.len() as u64) as usize];
+        let normal: Vec<f32> = point(a).iter().zip(point(b)).map(|(x, y)| x - y).collect();
+        let offset: f32 = normal.iter().zip(point(a).iter().zip(point(b))).map(|(w, (x, y))| w * (x + y) / 2.0).sum();
+        let (mut left, mut right): (Vec<u32>, Vec<u32>) = ids
+            .iter()
+            .partition(|i| point(**i).iter().zip(&normal).map(|(x, w)| x * w).sum::<f32>() < offset);
+        if left.is_empty() || right.is_empty() {
+            // identical points (or a == b) - split at random
+            let mut all = if left.is_empty() { right } else { left };
+            for i in (1..all.len()).rev() {
+                all.swap(i, (rng.next_u64() % (i as u64 + 1)) as usize);
+            }
+            right = all.split_off(all.len() / 2);
+            left = all;
+        }
+        stack.push(left);
+        stack.push(right);
+    }
+    leaves
+}
+
+/// Approximate k nearest neighbors (without the point itself) of `n` points of dimension `d`.
+fn approximate_knn(points: &[f32], n: usize, d: usize, opts: &KnnOptions) -> Vec<Candidates> {
+    let k = opts.k.min(n.saturating_sub(1));
+    if k == 0 {
+        return vec![Vec::new(); n];
+    }
+    let leaf_size = opts.leaf_size.max(k + 1);
+    let point = |i: u32| &points[i as usize * d..(i as usize + 1) * d];
+
+    let forest: Vec<(Vec<Vec<u32>>, Vec<u32>)> = (0..opts.n_trees.max(1))
+        .into_par_iter()
+        .map(|t| {
+            let mut rng = Gaussian::new(opts.seed.wrapping_add(t as u64).wrapping_mul(0x2545_F491_4F6C_DD1D));
+            let leaves = tree_leaves(points, n, d, leaf_size, &mut rng);
+            let mut leaf_of = vec![0u32; n];
+            for (l, ids) in leaves.iter().enumerate() {
+                ids.iter().for_each(|i| leaf_of[*i as usize] = l as u32);
+            }
+            (leaves, leaf_of)
+        })
+        .collect();
+
+    let mut knn: Vec<Candidates> = (0..n as u32)
+        .into_par_iter()
+        .map(|p| {
+            let mut list = Candidates::with_capacity(k + 1);
+            for (leaves, leaf_of) in &forest {
+                for q in &leaves[leaf_of[p as usize] as usize] {
+                    if *q != p {
+                        offer(&mut list, k, dist2(point(p), point(*q)), *q);
+                    }
+                }
+            }
+            list
+        })
+        .collect();
+
+    for _ in 0..opts.refine_rounds {
+        knn = (0..n as u32)
+            .into_par_iter()
+            .map(|p| {
+                let mut list = knn[p as usize].clone();
+                for (_, m) in &knn[p as usize] {
+                    for (_, q) in &knn[*m as usize] {
+                        if *q != p {
+                            offer(&mut list, k, dist2(point(p), point(*q)), *q);
+                        }
+                    }
+                }
+                list
+            })
+            .collect();
+    }
+    knn
+}
+
+/// Jaccard weights of the neighborhoods (each point with its kNN) of all point pairs
+/// that share a neighbor, without the pairs of a point with itself.
+fn shared_neighbors(knn: &[Candidates], prune: f32) -> Vec<Vec<(u32, f32)>> {
+    let n = knn.len();
+    let hood = |p: usize| std::iter::once(p as u32).chain(knn[p].iter().map(|(_, q)| *q));
+    let mut members_of: Vec<Vec<u32>> = vec![Vec::new(); n];
+    for p in 0..n {
+        hood(p).for_each(|m| members_of[m as usize].push(p as u32));
+    }
+    (0..n)
+        .into_par_iter()
+        .map(|p| {
+            let size_p = knn[p].len() + 1;
+            let mut others: Vec<u32> = hood(p).flat_map(|m| members_of[m as usize].iter().copied()).collect();
+            others.sort_unstable();
+            let mut ret = Vec::new();
+            for run in others.chunk_by(|a, b| a == b) {
+                let q = run[0];
+                if q as usize == p {
+                    continue;
+                }
+                let shared = run.len() as f32;
+                let union = (size_p + knn[q as usize].len() + 1) as f32 - shared;
+                let jaccard = shared / union;
+                if jaccard >= prune {
+                    ret.push((q, jaccard));
+                }
+            }
+            ret
+        })
+        .collect()
+}
+
+/// A cells × cells CSR matrix from the (column, value) entries of the rows of `cells`.
+fn cell_graph(n_cells: usize, cells: &[usize], rows: Vec<Vec<(usize, f32)>>) -> CsMat<f32> {
+    let mut by_cell: Vec<Vec<(usize, f32)>> = vec![Vec::new(); n_cells];
+    for (c, mut row) in cells.iter().zip(rows) {
+        row.sort_unstable_by_key(|(j, _)| *j);
+        by_cell[*c] = row;
+    }
+    let mut indptr = vec![0];
+    let (mut indices, mut data) = (Vec::new(), Vec::new());
+    for row in by_cell {
+        for (j, v) in row {
+            indices.push(j);
+            data.push(v);
+        }
+        indptr.push(indices.len());
+    }
+    CsMat::new((n_cells, n_cells), indptr, indices, data)
+}
+
+impl DataStore {
+    /// Build the kNN and SNN graphs over the `drcs` entry `projection` and store them as
+    /// `neighbors[projection]`. Cells with a NaN coordinate are left out.
+    pub fn build_neighbors(&mut self, projection: &str, opts: &KnnOptions) -> Result<&NeighborGraph, String> {
+        let data = self
+            .drcs
+            .get(projection)
+            .ok_or_else(|| format!("❌ Projection '{}' not found - available: {:?}", projection, self.drcs.keys()))?;
+        let d = opts.n_dims.unwrap_or(data.ncols()).min(data.ncols());
+        if d == 0 {
+            return Err(format!("❌ Projection '{}' has no dimensions", projection));
+        }
+        let cells: Vec<usize> = (0..data.nrows())
+            .filter(|c| data.row(*c).iter().take(d).all(|v| v.is_finite()))
+            .collect();
+        let mut points = Vec::with_capacity(cells.len() * d);
+        cells.iter().for_each(|c| points.extend(data.row(*c).iter().take(d)));
+
+        let knn = approximate_knn(&points, cells.len(), d, opts);
+        let snn = shared_neighbors(&knn, opts.snn_prune);
+        let to_cells = |rows: Vec<Vec<(u32, f32)>>| -> Vec<Vec<(usize, f32)>> {
+            rows.into_iter().map(|r| r.into_iter().map(|(q, v)| (cells[q as usize], v)).collect()).collect()
+        };
+        let knn_rows = knn.into_iter().map(|r| r.into_iter().map(|(d2, q)| (q, d2.sqrt())).collect()).collect();
+        let n_cells = self.cell_names.len();
+        let graph = NeighborGraph {
+            projection: projection.to_string(),
+            k: opts.k,
+            knn: cell_graph(n_cells, &cells, to_cells(knn_rows)),
+            snn: cell_graph(n_cells, &cells, to_cells(snn)),
+        };
+        println!(
+            "🕸️ neighbors of '{}': k = {} over {} dims for {} cells, {} SNN edges",
+            projection,
+            opts.k,
+            d,
+            cells.len(),
+            graph.snn.nnz()
+        );
+        self.neighbors.insert(projection.to_string(), graph);
+        Ok(&self.neighbors[projection])
+    }
+
+    /// The nearest neighbors of a cell as (cell, distance), nearest first.
+    pub fn knn_of(&self, projection: &str, cell: usize) -> Result<Vec<(usize, f32)>, String> {
+        let mut ret = self.neighbor_row(projection, cell, |g| &g.knn)?;
+        ret.sort_by(|a, b| a.1.total_cmp(&b.1));
+        Ok(ret)
+    }
+
+    /// The SNN neighbors of a cell as (cell, Jaccard weight), strongest first.
+    pub fn snn_of(&self, projection: &str, cell: usize) -> Result<Vec<(usize, f32)>, String> {
+        let mut ret = self.neighbor_row(projection, cell, |g| &g.snn)?;
+        ret.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Ok(ret)
+    }
+
+    fn neighbor_row(
+        &self,
+        projection: &str,
+        cell: usize,
+        which: impl Fn(&NeighborGraph) -> &CsMat<f32>,
+    ) -> Result<Vec<(usize, f32)>, String> {
+        let graph = self
+            .neighbors
+            .get(projection)
+            .ok_or_else(|| format!("❌ No neighbor graph for '{}' - build it first", projection))?;
+        let row = which(graph)
+            .outer_view(cell)
+            .ok_or_else(|| format!("❌ Cell {} out of range ({} cells)", cell, self.cell_names.len()))?;
+        Ok(row.iter().map(|(j, v)| (j, *v)).collect())
+    }
+
+    /// Drop the neighbor graphs built on a `drcs` entry that changed.
+    pub(crate) fn forget_neighbors_of(&mut self, projection: &str) {
+        self.neighbors.retain(|_, g| g.projection != projection);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn approximate_neighbors_match_brute_force() {
+        // 3 clusters of 400 points in 10 dimensions
+        let (n, d) = (1200, 10);
+        let mut rng = Gaussian::new(3);
+        let points: Vec<f32> = (0..n * d)
+            .map(|i| ((i / d) % 3) as f32 * 20.0 + rng.sample() as f32)
+            .collect();
+        let opts = KnnOptions::default();
+        let knn = approximate_knn(&points, n, d, &opts);
+
+        let mut found = 0;
+        for p in 0..n {
+            let mut exact: Vec<(f32, usize)> = (0..n)
+                .filter(|q| *q != p)
+                .map(|q| (dist2(&points[p * d..(p + 1) * d], &points[q * d..(q + 1) * d]), q))
+                .collect();
+            exact.sort_by(|a, b| a.0.total_cmp(&b.0));
+            assert_eq!(knn[p].len(), opts.k);
+            found += knn[p].iter().filter(|(_, q)| exact[..opts.k].iter().any(|(_, e)| *e == *q as usize)).count();
+        }
+        let recall = found as f64 / (n * opts.k) as f64;
+        assert!(recall > 0.95, "recall {}", recall);
+
+        let snn = shared_neighbors(&knn, opts.snn_prune);
+        for (p, row) in snn.iter().enumerate() {
+            for (q, w) in row {
+                // symmetric and within the cluster
+                assert!(snn[*q as usize].iter().any(|(r, v)| *r as usize == p && v == w));
+                assert_eq!(*q as usize % 3, p % 3);
+            }
+        }
+    }
+}
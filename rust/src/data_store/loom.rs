@@ -0,0 +1,249 @@
+//loom.rs
+use crate::data_store::DataStore;
+use crate::data_store::doctor::{Issue, Severity};
+use crate::data_store::hdf5_utils::{is_string_dataset, read_f32, read_f64, read_strings};
+use crate::data_store::meta_table::{MetaColumn, MetaTable};
+use hdf5::{Conversion, Dataset, File as H5File, Group};
+use sprs::CsMat;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Number of gene rows read from the dense loom matrices at once.
+const ROW_CHUNK: usize = 512;
+
+impl DataStore {
+    /// Load a `.loom` file.
+    ///
+    /// * `/matrix` (genes × cells) becomes `counts`
+    /// * every entry in `/layers` (e.g. `spliced`, `unspliced`) becomes a `layers` entry
+    ///   (one named like the raw counts is renamed, see `insert_loaded_layer`)
+    /// * 1D `col_attrs` become `cell_meta` columns, 2D `col_attrs` (embeddings) become `drcs`
+    /// * `row_attrs` become `gene_meta`
+    ///
+    /// Attributes that can not be read are skipped and reported in the load issues.
+    ///
+    /// Cell names are taken from `col_attrs/CellID` and gene names from `row_attrs/Gene`.
+    pub fn from_loom<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let file = H5File::open(path).map_err(|e| format!("❌ Failed to open {:?}: {}", path, e))?;
+
+        let col_attrs = file
+            .group("col_attrs")
+            .map_err(|e| format!("❌ No 'col_attrs' in {:?}: {}", path, e))?;
+        let row_attrs = file
+            .group("row_attrs")
+            .map_err(|e| format!("❌ No 'row_attrs' in {:?}: {}", path, e))?;
+
+        // --- Names ---
+        let cell_names = read_name_attr(&col_attrs, &["CellID", "obs_names", "cell_names"])?;
+        if cell_names.is_empty() {
+            return Err(format!("❌ No cell names found in {:?}", path));
+        }
+        let gene_names = read_name_attr(&row_attrs, &["Gene", "var_names", "gene_names"])?;
+        if gene_names.is_empty() {
+            return Err(format!("❌ No gene names found in {:?}", path));
+        }
+        let shape = (gene_names.len(), cell_names.len());
+
+        // --- Main matrix + layers ---
+        let matrix = file
+            .dataset("matrix")
+            .map_err(|e| format!("❌ No 'matrix' in {:?}: {}", path, e))?;
+        let counts = read_dense_as_csr(&matrix, shape)?;
+        if counts.nnz() == 0 {
+            return Err(format!("❌ Matrix in {:?} appears empty", path));
+        }
+
+        let mut layers = Vec::new();
+        if let Ok(group) = file.group("layers") {
+            for name in group.member_names().unwrap_or_default() {
+                if name.is_empty() {
+                    continue;
+                }
+                let Ok(ds) = group.dataset(&name) else { continue };
+                println!("📦 reading loom layer '{}'", name);
+                layers.push((name, read_dense_as_csr(&ds, shape)?));
+            }
+        }
+
+        // --- Cell meta + embeddings ---
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let mut issues = Vec::new();
+        let mut skip = |group: &str, name: &str, e: String| {
+            let problem = format!("{} '{}' not loaded: {}", group, name, e.trim_start_matches("❌ "));
+            println!("⚠️ {}", problem);
+            issues.push(Issue { severity: Severity::Warning, file: file_name.clone(), row: None, problem });
+        };
+        let mut cell_table = MetaTable::new();
+        let mut drcs = HashMap::new();
+        for name in col_attrs.member_names().unwrap_or_default() {
+            let Ok(ds) = col_attrs.dataset(&name) else { continue };
+            let ds_shape = ds.shape();
+            match ds_shape.as_slice() {
+                [n] if *n == cell_names.len() => match attr_column(&name, &ds) {
+                    Ok(col) => cell_table.push(col)?,
+                    Err(e) => skip("col_attrs", &name, e),
+                },
+                [n, d] if *n == cell_names.len() && *d >= 2 => match read_f32(&ds) {
+                    Ok(values) => {
+                        let proj = name.strip_prefix("X_").unwrap_or(&name).to_string();
+                        println!("📈 Found projection '{}' ({} dims)", proj, d);
+                        drcs.insert(proj, Self::embedding_to_array(&values, *n, *d));
+                    }
+                    Err(e) => skip("col_attrs", &name, e),
+                },
+                _ => println!("⚠️ col_attrs '{}' has shape {:?} - skipped", name, ds_shape),
+            }
+        }
+        if !cell_table.has_column("barcode") {
+            cell_table.push(MetaColumn::factor_from_strings("barcode", &cell_names))?;
+        }
+
+        // --- Gene meta ---
+        let mut gene_table = MetaTable::new();
+        for name in row_attrs.member_names().unwrap_or_default() {
+            let Ok(ds) = row_attrs.dataset(&name) else { continue };
+            if ds.shape() == [gene_names.len()] {
+                match attr_column(&name, &ds) {
+                    Ok(col) => gene_table.push(col)?,
+                    Err(e) => skip("row_attrs", &name, e),
+                }
+            }
+        }
+        if !gene_table.has_column("gene") {
+            gene_table.push(MetaColumn::factor_from_strings("gene", &gene_names))?;
+        }
+
+        let cell_meta = cell_table.into_survival_data()?;
+
+        let mut ret = Self::from_parts(counts, gene_names, cell_names, cell_meta, Default::default(), drcs)?;
+        ret.set_gene_table(gene_table)?;
+        ret.load_issues = issues;
+        for (name, layer) in layers {
+            ret.insert_loaded_layer(name, layer, &file_name);
+        }
+        Ok(ret)
+    }
+}
+
+/// The first existing attribute out of `candidates` as a list of names.
+fn read_name_attr(group: &Group, candidates: &[&str]) -> Result<Vec<String>, String> {
+    for c in candidates {
+        if let Ok(ds) = group.dataset(c) {
+            return read_strings(&ds);
+        }
+    }
+    Err(format!("❌ None of {:?} found in {}", candidates, group.name()))
+}
+
+/// A 1D loom attribute as meta column (text -> factor, numbers -> numeric).
+fn attr_column(name: &str, ds: &Dataset) -> Result<MetaColumn, String> {
+    if is_string_dataset(ds) {
+        Ok(MetaColumn::factor_from_strings(name, &read_strings(ds)?))
+    } else {
+        Ok(MetaColumn::Numeric { name: name.to_string(), values: read_f64(ds)? })
+    }
+}
+
+/// Read a dense (genes × cells) loom matrix in row chunks and keep only the non zero entries.
+fn read_dense_as_csr(ds: &Dataset, shape: (usize, usize)) -> Result<CsMat<f32>, String> {
+    if ds.shape() != [shape.0, shape.1] {
+        return Err(format!(
+            "❌ {} has shape {:?} - expected [{}, {}] (genes × cells)",
+            ds.name(),
+            ds.shape(),
+            shape.0,
+            shape.1
+        ));
+    }
+    let mut indptr = Vec::with_capacity(shape.0 + 1);
+    let mut indices = Vec::new();
+    let mut data = Vec::new();
+    indptr.push(0);
+
+    let mut start = 0;
+    while start < shape.0 {
+        let end = (start + ROW_CHUNK).min(shape.0);
+        let chunk = ds
+            .as_reader()
+            .conversion(Conversion::Hard)
+            .read_slice_2d::<f32, _>((start..end, ..))
+            .map_err(|e| format!("❌ Failed to read rows {}..{} of {}: {}", start, end, ds.name(), e))?;
+        for (idx, v) in chunk.iter().enumerate() {
+            if *v != 0.0 {
+                indices.push(idx % shape.1);
+                data.push(*v);
+            }
+            if (idx + 1) % shape.1 == 0 {
+                indptr.push(indices.len());
+            }
+        }
+        start = end;
+    }
+
+    CsMat::try_new(shape, indptr, indices, data)
+        .map_err(|(_, _, _, e)| format!("❌ Invalid matrix in {}: {}", ds.name(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_store::hdf5_utils::fixtures::{write_strings, write_values};
+    use ndarray::Array2;
+
+    /// More genes than one `ROW_CHUNK`, so the matrix is read in two chunks.
+    const N_GENES: usize = ROW_CHUNK + 88;
+
+    fn value(gene: usize, cell: usize) -> f32 {
+        if (gene + cell) % 3 == 0 { (gene + 1) as f32 } else { 0.0 }
+    }
+
+    #[test]
+    fn matrix_layers_and_attributes_are_read() {
+        let path = std::env::temp_dir().join(format!("printforge3d_loom_{}.loom", std::process::id()));
+        {
+            let file = H5File::create(&path).unwrap();
+            let matrix = Array2::from_shape_fn((N_GENES, 3), |(g, c)| value(g, c));
+            file.new_dataset_builder().with_data(&matrix).create("matrix").unwrap();
+            let layers = file.create_group("layers").unwrap();
+            let spliced = matrix.mapv(|v| v * 2.0);
+            layers.new_dataset_builder().with_data(&spliced).create("spliced").unwrap();
+
+            let col_attrs = file.create_group("col_attrs").unwrap();
+            write_strings(&col_attrs, "CellID", &["c0", "c1", "c2"]);
+            write_strings(&col_attrs, "cluster", &["T", "B", "T"]);
+            let umap = Array2::from_shape_vec((3, 2), vec![0.0f32, 1.0, 2.0, 3.0, 4.0, 5.0]).unwrap();
+            col_attrs.new_dataset_builder().with_data(&umap).create("X_umap").unwrap();
+            // no meta column can hold pairs of numbers
+            write_values(&col_attrs, "pairs", &[[1.0f32, 2.0], [3.0, 4.0], [5.0, 6.0]]);
+
+            let row_attrs = file.create_group("row_attrs").unwrap();
+            let genes: Vec<String> = (0..N_GENES).map(|g| format!("G{}", g)).collect();
+            let genes: Vec<&str> = genes.iter().map(|g| g.as_str()).collect();
+            write_strings(&row_attrs, "Gene", &genes);
+        }
+        let ds = DataStore::from_loom(&path);
+        let _ = std::fs::remove_file(&path);
+        let ds = ds.unwrap();
+
+        assert_eq!(ds.counts.shape(), (N_GENES, 3));
+        let spliced = &ds.layers["spliced"];
+        for g in [0, 1, ROW_CHUNK - 1, ROW_CHUNK, N_GENES - 1] {
+            for c in 0..3 {
+                assert_eq!(ds.counts.get(g, c).copied().unwrap_or(0.0), value(g, c), "gene {} cell {}", g, c);
+                assert_eq!(spliced.get(g, c).copied().unwrap_or(0.0), 2.0 * value(g, c));
+            }
+        }
+        assert_eq!(ds.gene_names[ROW_CHUNK], format!("G{}", ROW_CHUNK));
+        assert_eq!(ds.cell_names, vec!["c0", "c1", "c2"]);
+        let (levels, codes) = ds.meta_factor("cluster").unwrap();
+        assert_eq!(levels, vec!["T", "B"]);
+        assert_eq!(codes, vec![Some(0), Some(1), Some(0)]);
+        assert_eq!(ds.drcs["umap"].row(2).to_vec(), vec![4.0, 5.0]);
+
+        // the unreadable attribute is skipped and reported
+        assert!(!ds.cell_meta.headers.iter().any(|h| h == "pairs"));
+        assert_eq!(ds.load_issues.len(), 1);
+        assert!(ds.load_issues[0].problem.contains("pairs"));
+    }
+}
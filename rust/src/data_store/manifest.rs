@@ -0,0 +1,211 @@
+//manifest.rs
+//! The optional `dataset.toml` describing a dataset folder.
+//!
+//! ```toml
+//! name = "PBMC 3k"
+//! modality = "Gene Expression"
+//! color_by = "cluster"
+//!
+//! [files]
+//! features = "genes.tsv"        # CellRanger v2 / uncompressed files are fine
+//! barcodes = "barcodes.tsv"
+//! matrix = "matrix.mtx"
+//! meta = "meta.tsv"
+//! meta_factors = "meta.factors.json"
+//! gene_annotation = "genes.annotation.tsv"   # gene_id/symbol, chromosome, start, biotype, aliases, ...
+//!
+//! [[projections]]
+//! file = "umap.drc"
+//! title = "UMAP (30 PCs)"
+//! color = "#cccccc"
+//!
+//! [qc]
+//! mito = ["mt-"]                # gene name prefixes (ignoring case), default ["MT-"]
+//! ribo = ["Rps", "Rpl"]         # default ["RPS", "RPL"]
+//! ```
+//!
+//! Every entry is optional. Without a manifest (or for missing entries) the
+//! usual CellRanger names are used.
+use crate::data_store::qc::QcPatterns;
+use crate::data_store::source;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+pub const MANIFEST_FILE: &str = "dataset.toml";
+
+const FEATURES: &[&str] = &["features.tsv.gz", "features.tsv", "genes.tsv.gz", "genes.tsv"];
+const BARCODES: &[&str] = &["barcodes.tsv.gz", "barcodes.tsv"];
+const MATRIX: &[&str] = &["matrix.mtx.gz", "matrix.mtx"];
+const META: &[&str] = &["meta.tsv"];
+const META_FACTORS: &[&str] = &["meta.factors.json"];
+const GENE_ANNOTATION: &[&str] = &["genes.annotation.tsv", "genes.annotation.tsv.gz"];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatasetManifest {
+    /// name shown in VR instead of the folder name
+    pub name: Option<String>,
+    /// the feature type to show by default (e.g. "Antibody Capture")
+    pub modality: Option<String>,
+    /// the cell_meta column the graphs are colored by after loading
+    pub color_by: Option<String>,
+    pub files: ManifestFiles,
+    pub projections: Vec<ManifestProjection>,
+    /// the genes counted for percent_mt/percent_ribo
+    pub qc: QcPatterns,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ManifestFiles {
+    pub features: Option<String>,
+    pub barcodes: Option<String>,
+    pub matrix: Option<String>,
+    pub meta: Option<String>,
+    pub meta_factors: Option<String>,
+    pub gene_annotation: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ManifestProjection {
+    pub file: String,
+    /// the drcs key, defaults to the file stem
+    pub name: Option<String>,
+    pub title: Option<String>,
+    /// default point color ("#rrggbb")
+    pub color: Option<String>,
+}
+
+/// The files of a dataset folder after applying manifest and conventions.
+#[derive(Debug, Clone)]
+pub struct DatasetFiles {
+    pub features: PathBuf,
+    pub barcodes: PathBuf,
+    pub matrix: PathBuf,
+    pub meta: PathBuf,
+    pub meta_factors: PathBuf,
+    /// the optional gene annotation table (only set if it exists)
+    pub gene_annotation: Option<PathBuf>,
+    /// (drcs key, file)
+    pub projections: Vec<(String, PathBuf)>,
+}
+
+impl DatasetFiles {
+    /// All files the expression data and projections are built from (for cache stamps).
+    pub fn sources(&self) -> Vec<&Path> {
+        let mut ret = vec![self.features.as_path(), self.barcodes.as_path(), self.matrix.as_path()];
+        ret.extend(self.projections.iter().map(|(_, p)| p.as_path()));
+        ret
+    }
+}
+
+impl DatasetManifest {
+    /// Read `<dir>/dataset.toml`; a missing file gives the default (empty) manifest.
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        let path = dir.join(MANIFEST_FILE);
+        if !source::exists(&path) {
+            return Ok(Self::default());
+        }
+        let text = source::read_to_string(&path)?;
+        toml::from_str(&text).map_err(|e| format!("❌ Invalid manifest {:?}: {}", path, e))
+    }
+
+    /// Resolve all file paths relative to `dir`.
+    pub fn resolve(&self, dir: &Path) -> DatasetFiles {
+        let pick = |given: &Option<String>, candidates: &[&str]| -> PathBuf {
+            match given {
+                Some(p) => dir.join(p),
+                None => candidates
+                    .iter()
+                    .map(|c| dir.join(c))
+                    .find(|p| source::exists(p))
+                    .unwrap_or_else(|| dir.join(candidates[0])),
+            }
+        };
+
+        let projections = if self.projections.is_empty() {
+            scan_drc_files(dir)
+                .into_iter()
+                .map(|p| (file_stem(&p), p))
+                .collect()
+        } else {
+            self.projections
+                .iter()
+                .map(|p| {
+                    let path = dir.join(&p.file);
+                    (p.name.clone().unwrap_or_else(|| file_stem(&path)), path)
+                })
+                .collect()
+        };
+
+        DatasetFiles {
+            features: pick(&self.files.features, FEATURES),
+            barcodes: pick(&self.files.barcodes, BARCODES),
+            matrix: pick(&self.files.matrix, MATRIX),
+            meta: pick(&self.files.meta, META),
+            meta_factors: pick(&self.files.meta_factors, META_FACTORS),
+            gene_annotation: Some(pick(&self.files.gene_annotation, GENE_ANNOTATION)).filter(|p| source::exists(p)),
+            projections,
+        }
+    }
+
+    /// The manifest entry for a projection key (if any).
+    pub fn projection(&self, key: &str) -> Option<&ManifestProjection> {
+        self.projections
+            .iter()
+            .find(|p| p.name.as_deref().unwrap_or(&file_stem(Path::new(&p.file))) == key)
+    }
+}
+
+fn file_stem(path: &Path) -> String {
+    path.file_stem().unwrap_or_default().to_string_lossy().to_string()
+}
+
+fn scan_drc_files(dir: &Path) -> Vec<PathBuf> {
+    source::list_dir(dir)
+        .into_iter()
+        .filter(|p| {
+            p.extension()
+                .and_then(|e| e.to_str())
+                .map(|e| e.eq_ignore_ascii_case("drc"))
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn manifest_overrides_and_fallbacks() {
+        let dir = std::env::temp_dir().join(format!("pf3d_manifest_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("barcodes.tsv"), "AAAC-1\n").unwrap();
+        fs::write(dir.join("tsne.drc"), "").unwrap();
+        fs::write(
+            dir.join(MANIFEST_FILE),
+            "name = \"test\"\ncolor_by = \"cluster\"\n[files]\nfeatures = \"genes.tsv\"\n\
+             [[projections]]\nfile = \"umap_3d.drc\"\nname = \"umap\"\ntitle = \"UMAP\"\ncolor = \"#ff0000\"\n",
+        )
+        .unwrap();
+
+        let manifest = DatasetManifest::load(&dir).unwrap();
+        let files = manifest.resolve(&dir);
+        assert_eq!(manifest.name.as_deref(), Some("test"));
+        assert_eq!(files.features, dir.join("genes.tsv"));
+        assert_eq!(files.barcodes, dir.join("barcodes.tsv"));
+        assert_eq!(files.matrix, dir.join("matrix.mtx.gz"));
+        // listed projections replace the *.drc scan
+        assert_eq!(files.projections, vec![("umap".to_string(), dir.join("umap_3d.drc"))]);
+        assert_eq!(manifest.projection("umap").and_then(|p| p.title.as_deref()), Some("UMAP"));
+
+        fs::remove_file(dir.join(MANIFEST_FILE)).unwrap();
+        let files = DatasetManifest::load(&dir).unwrap().resolve(&dir);
+        assert_eq!(files.projections, vec![("tsne".to_string(), dir.join("tsne.drc"))]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
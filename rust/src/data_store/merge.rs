@@ -0,0 +1,259 @@
+//merge.rs
+//! Combine several samples (e.g. one Cell Ranger run each) into one DataStore.
+use crate::data_store::DataStore;
+use crate::data_store::meta_table::{FactorCodes, MetaColumn, MetaTable};
+use crate::data_store::modality::Modality;
+use ndarray::{concatenate, Array2, Axis};
+use rust_data_table::SurvivalData;
+use sprs::CsMat;
+use std::collections::{HashMap, HashSet};
+
+/// Which features a merged dataset keeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeneJoin {
+    /// all features of all samples (missing ones are 0 in the other samples)
+    Union,
+    /// only the features every sample has
+    Intersect,
+}
+
+impl DataStore {
+    /// Merge several samples into one dataset.
+    ///
+    /// * the features are the union or the intersection of all samples (in order of first appearance)
+    /// * barcodes become `<sample>_<barcode>` and a `sample` factor is added to `cell_meta`
+    /// * `cell_meta` columns all samples have are kept, as are layers and modalities
+    /// * projections are stacked if every sample has them with the same number of dimensions;
+    ///   a projection calculated on the merged data can be added afterwards with
+    ///   `load_projection_from_tsv` (rows are matched by the prefixed barcodes)
+    pub fn merge(samples: &[(&str, &DataStore)], genes: GeneJoin) -> Result<Self, String> {
+        if samples.is_empty() {
+            return Err("❌ Nothing to merge".to_string());
+        }
+        if let Some((name, _)) = samples.iter().find(|(_, ds)| ds.is_out_of_core()) {
+            return Err(format!("❌ Sample '{}' is out of core - merge the in-memory datasets instead", name));
+        }
+        let mut seen = HashSet::new();
+        if let Some((dup, _)) = samples.iter().find(|(name, _)| !seen.insert(*name)) {
+            return Err(format!("❌ Sample name '{}' used twice", dup));
+        }
+
+        // --- Expression ---
+        let names: Vec<&[String]> = samples.iter().map(|(_, ds)| ds.gene_names.as_slice()).collect();
+        let gene_names = feature_space(&names, genes);
+        if gene_names.is_empty() {
+            return Err("❌ The samples have no genes in common".to_string());
+        }
+        let mats: Vec<&CsMat<f32>> = samples.iter().map(|(_, ds)| ds.counts_csc()).collect();
+        let counts = stack_cells(&mats, &names, &gene_names);
+
+        let cell_names: Vec<String> = samples
+            .iter()
+            .flat_map(|(sample, ds)| ds.cell_names.iter().map(move |c| format!("{}_{}", sample, c)))
+            .collect();
+
+        // --- Metadata ---
+        let mut table = MetaTable::new();
+        table.push(MetaColumn::factor_from_strings("barcode", &cell_names))?;
+        let sample_ids: Vec<String> = samples
+            .iter()
+            .flat_map(|(sample, ds)| std::iter::repeat_n(sample.to_string(), ds.cell_names.len()))
+            .collect();
+        table.push(MetaColumn::factor_from_strings("sample", &sample_ids))?;
+        for col in merge_cell_meta(samples) {
+            if !table.has_column(col.name()) {
+                table.push(col)?;
+            }
+        }
+
+        // --- Projections ---
+        let mut drcs = HashMap::new();
+        let (_, first) = samples[0];
+        for (key, data) in &first.drcs {
+            let parts: Option<Vec<&Array2<f32>>> = samples
+                .iter()
+                .map(|(_, ds)| ds.drcs.get(key).filter(|d| d.ncols() == data.ncols()))
+                .collect();
+            match parts {
+                Some(parts) => {
+                    let views: Vec<_> = parts.iter().map(|p| p.view()).collect();
+                    let stacked = concatenate(Axis(0), &views).map_err(|e| e.to_string())?;
+                    drcs.insert(key.clone(), stacked);
+                }
+                None => println!("⚠️ projection '{}' is not part of all samples - not merged", key),
+            }
+        }
+
+        let mut ret = Self::from_parts(
+            counts,
+            gene_names,
+            cell_names,
+            table.into_survival_data()?,
+            SurvivalData::default(),
+            drcs,
+        )?;
+
+        // --- Layers and modalities (only those all samples have) ---
+        for key in first.layers.keys() {
+            let Some(mats) = samples.iter().map(|(_, ds)| ds.layers.get(key)).collect::<Option<Vec<_>>>() else {
+                println!("⚠️ layer '{}' is not part of all samples - not merged", key);
+                continue;
+            };
+            let csc: Vec<CsMat<f32>> = mats.iter().map(|m| m.to_csc()).collect();
+            let csc: Vec<&CsMat<f32>> = csc.iter().collect();
+            ret.layers.insert(key.clone(), stack_cells(&csc, &names, &ret.gene_names).to_csr());
+        }
+        ret.primary_modality = first.primary_modality.clone();
+        for key in first.modalities.keys() {
+            let Some(mods) = samples.iter().map(|(_, ds)| ds.modalities.get(key)).collect::<Option<Vec<_>>>() else {
+                println!("⚠️ modality '{}' is not part of all samples - not merged", key);
+                continue;
+            };
+            let names: Vec<&[String]> = mods.iter().map(|m| m.feature_names.as_slice()).collect();
+            let features = feature_space(&names, genes);
+            let csc: Vec<&CsMat<f32>> = mods.iter().map(|m| m.counts_csc()).collect();
+            let counts = stack_cells(&csc, &names, &features).to_csr();
+            ret.modalities.insert(key.clone(), Modality::new(counts, features));
+        }
+
+        println!(
+            "🧩 merged {} samples: {} genes × {} cells",
+            samples.len(),
+            ret.gene_names.len(),
+            ret.cell_names.len()
+        );
+        Ok(ret)
+    }
+}
+
+/// The merged feature names, in order of first appearance.
+fn feature_space(names: &[&[String]], join: GeneJoin) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let all = names.iter().flat_map(|n| n.iter()).filter(|n| seen.insert(n.as_str()));
+    match join {
+        GeneJoin::Union => all.cloned().collect(),
+        GeneJoin::Intersect => {
+            let sets: Vec<HashSet<&str>> = names.iter().map(|n| n.iter().map(|s| s.as_str()).collect()).collect();
+            all.filter(|n| sets.iter().all(|s| s.contains(n.as_str()))).cloned().collect()
+        }
+    }
+}
+
+/// Put the cells (columns) of all matrices next to each other, with the rows
+/// re-ordered to `space`. Features not in `space` are dropped, repeated names summed up.
+/// Returns a CSC matrix (features × all cells).
+fn stack_cells(mats: &[&CsMat<f32>], names: &[&[String]], space: &[String]) -> CsMat<f32> {
+    let index: HashMap<&str, usize> = space.iter().enumerate().map(|(i, n)| (n.as_str(), i)).collect();
+    let n_cells: usize = mats.iter().map(|m| m.cols()).sum();
+    let mut indptr = Vec::with_capacity(n_cells + 1);
+    let mut indices = Vec::new();
+    let mut data = Vec::new();
+    indptr.push(0);
+    let mut column: Vec<(usize, f32)> = Vec::new();
+    for (mat, names) in mats.iter().zip(names) {
+        let rows: Vec<Option<usize>> = names.iter().map(|n| index.get(n.as_str()).copied()).collect();
+        for cell in mat.outer_iterator() {
+            column.clear();
+            column.extend(cell.iter().filter_map(|(g, v)| rows[g].map(|r| (r, *v))));
+            column.sort_unstable_by_key(|(r, _)| *r);
+            for &(r, v) in &column {
+                if indices.len() > *indptr.last().unwrap() && indices.last() == Some(&r) {
+                    *data.last_mut().unwrap() += v;
+                } else {
+                    indices.push(r);
+                    data.push(v);
+                }
+            }
+            indptr.push(indices.len());
+        }
+    }
+    CsMat::new_csc((space.len(), n_cells), indptr, indices, data)
+}
+
+/// The `cell_meta` columns (other than `barcode`) all samples have, one after the other.
+/// Factors get the union of all levels; columns that are a factor in one sample and
+/// numeric in another are skipped.
+fn merge_cell_meta(samples: &[(&str, &DataStore)]) -> Vec<MetaColumn> {
+    let (_, first) = samples[0];
+    let mut columns = Vec::new();
+    for col in &first.cell_meta.headers {
+        if col == "barcode" || !samples.iter().all(|(_, ds)| ds.cell_meta.headers.contains(col)) {
+            continue;
+        }
+        let factors: Vec<Option<FactorCodes>> = samples.iter().map(|(_, ds)| ds.meta_factor(col)).collect();
+        if factors.iter().all(|f| f.is_some()) {
+            let mut levels: Vec<String> = Vec::new();
+            let mut codes = Vec::new();
+            for (sample_levels, sample_codes) in factors.into_iter().flatten() {
+                let remap: Vec<usize> = sample_levels
+                    .iter()
+                    .map(|l| match levels.iter().position(|x| x == l) {
+                        Some(i) => i,
+                        None => {
+                            levels.push(l.clone());
+                            levels.len() - 1
+                        }
+                    })
+                    .collect();
+                codes.extend(sample_codes.iter().map(|c| c.map(|c| remap[c])));
+            }
+            columns.push(MetaColumn::Factor { name: col.clone(), levels, codes });
+        } else if factors.iter().all(|f| f.is_none()) {
+            let values = samples.iter().flat_map(|(_, ds)| ds.cell_meta.as_vec_f64(col)).collect();
+            columns.push(MetaColumn::Numeric { name: col.clone(), values });
+        } else {
+            println!("⚠️ cell meta column '{}' is a factor in some samples only - not merged", col);
+        }
+    }
+    columns
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_store::data_store::test_store;
+    use sprs::TriMat;
+
+    fn sample(genes: &[&str], cells: &[&str], values: &[(usize, usize, f32)]) -> DataStore {
+        let mut tri = TriMat::<f32>::new((genes.len(), cells.len()));
+        for &(g, c, v) in values {
+            tri.add_triplet(g, c, v);
+        }
+        let names = |v: &[&str]| -> Vec<String> { v.iter().map(|s| s.to_string()).collect() };
+        let mut ds = test_store(tri.to_csr(), names(genes), names(cells));
+        ds.drcs.insert("umap".to_string(), Array2::from_elem((cells.len(), 2), 1.0));
+        ds
+    }
+
+    #[test]
+    fn union_and_intersection_of_genes() {
+        let a = sample(&["G1", "G2"], &["AAA"], &[(0, 0, 1.0), (1, 0, 2.0)]);
+        let b = sample(&["G3", "G1"], &["AAA", "CCC"], &[(0, 1, 3.0), (1, 0, 4.0)]);
+
+        let merged = DataStore::merge(&[("s1", &a), ("s2", &b)], GeneJoin::Union).unwrap();
+        assert_eq!(merged.gene_names, vec!["G1", "G2", "G3"]);
+        assert_eq!(merged.cell_names, vec!["s1_AAA", "s2_AAA", "s2_CCC"]);
+        assert_eq!(merged.counts.get(0, 1), Some(&4.0));
+        assert_eq!(merged.counts.get(2, 2), Some(&3.0));
+        assert_eq!(merged.drcs["umap"].nrows(), 3);
+        let (levels, codes) = merged.meta_factor("sample").unwrap();
+        assert_eq!(levels, vec!["s1", "s2"]);
+        assert_eq!(codes, vec![Some(0), Some(1), Some(1)]);
+        let (levels, codes) = merged.meta_factor("barcode").unwrap();
+        let barcodes: Vec<&str> = codes.iter().map(|c| levels[c.unwrap()].as_str()).collect();
+        assert_eq!(barcodes, vec!["s1_AAA", "s2_AAA", "s2_CCC"]);
+
+        let mut c = sample(&["G1"], &["GGG"], &[(0, 0, 5.0)]);
+        c.drcs.clear();
+        let merged = DataStore::merge(&[("s1", &a), ("s3", &c)], GeneJoin::Union).unwrap();
+        assert_eq!(merged.cell_names, vec!["s1_AAA", "s3_GGG"]);
+        assert!(merged.drcs.is_empty());
+
+        let merged = DataStore::merge(&[("s1", &a), ("s2", &b)], GeneJoin::Intersect).unwrap();
+        assert_eq!(merged.gene_names, vec!["G1"]);
+        assert_eq!(merged.counts.get(0, 0), Some(&1.0));
+
+        assert!(DataStore::merge(&[("s1", &a), ("s1", &b)], GeneJoin::Union).is_err());
+    }
+}
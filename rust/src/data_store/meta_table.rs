@@ -0,0 +1,517 @@
+//meta_table.rs
+use rust_data_table::SurvivalData;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use crate::data_store::source::{self, open_text};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Integer columns with at most this many different values are guessed to be factors (cluster ids).
+const MAX_INFERRED_LEVELS: usize = 64;
+
+/// Tab or comma - whichever the header line of a table contains more of.
+pub(crate) fn detect_delimiter(path: &Path) -> Result<u8, String> {
+    let mut header = String::new();
+    BufReader::new(open_text(path)?)
+        .read_line(&mut header)
+        .map_err(|e| format!("❌ Failed to read {:?}: {}", path, e))?;
+    let tabs = header.matches('\t').count();
+    let commas = header.matches(',').count();
+    Ok(if commas > tabs { b',' } else { b'\t' })
+}
+
+/// A fresh temporary folder for meta.tsv/meta.factors.json pairs handed to `SurvivalData`.
+fn temp_meta_dir() -> Result<PathBuf, String> {
+    let dir = std::env::temp_dir().join(format!(
+        "printforge3d_meta_{}_{}",
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&dir).map_err(|e| format!("❌ Failed to create {:?}: {}", dir, e))?;
+    Ok(dir)
+}
+
+/// The levels of a factor and the level of every row (`None`: missing).
+pub type FactorCodes = (Vec<String>, Vec<Option<usize>>);
+
+/// The number meta.factors.json gives each level of a factor column, for the
+/// factors whose levels are not simply numbered 0, 1, ... by position.
+pub type FactorNumbers = HashMap<String, Vec<f64>>;
+
+/// The level numbers of a meta.factors.json text (see `FactorNumbers`).
+/// Files that are no list of factors are left to `SurvivalData` to complain about.
+pub(crate) fn factor_numbers_in(text: &str) -> FactorNumbers {
+    let factors: Vec<serde_json::Value> = serde_json::from_str(text).unwrap_or_default();
+    let mut ret = FactorNumbers::new();
+    for factor in &factors {
+        let (Some(column), Some(numbers)) = (
+            factor.get("column").and_then(|c| c.as_str()),
+            factor.get("numeric").and_then(|v| v.as_array()),
+        ) else {
+            continue;
+        };
+        let numbers: Vec<f64> = numbers.iter().filter_map(|x| x.as_f64()).collect();
+        if numbers.iter().enumerate().any(|(i, n)| *n != i as f64) {
+            ret.insert(column.to_string(), numbers);
+        }
+    }
+    ret
+}
+
+/// The levels of a `SurvivalData` factor and the level of every row (`None`: missing).
+///
+/// `SurvivalData` stores the number meta.factors.json gives a level; `numbers` maps those
+/// back to level positions (factors without an entry are numbered by position).
+pub(crate) fn factor_codes(data: &SurvivalData, numbers: &FactorNumbers, column: &str) -> Option<FactorCodes> {
+    let levels = data.factors.get(column)?.get_levels().clone();
+    // -0.0 and 0.0 are the same number
+    let key = |x: f64| (x + 0.0).to_bits();
+    let position: HashMap<u64, usize> = match numbers.get(column).filter(|n| n.len() == levels.len()) {
+        Some(numbers) => numbers.iter().enumerate().map(|(i, n)| (key(*n), i)).collect(),
+        None => (0..levels.len()).map(|i| (key(i as f64), i)).collect(),
+    };
+    let codes = data.as_vec_f64(column).iter().map(|c| position.get(&key(*c)).copied()).collect();
+    Some((levels, codes))
+}
+
+/// Load a cell meta table with its factor definitions (`guessed` replaces the json file).
+///
+/// `SurvivalData` reads plain files from disk only, so compressed tables, tables inside
+/// an archive and guessed factors go through a temporary copy.
+pub(crate) fn survival_data_from(
+    meta_path: &Path,
+    delimiter: u8,
+    json_path: &Path,
+    guessed: Option<&MetaTable>,
+) -> Result<SurvivalData, String> {
+    let load = |meta: &Path, json: &Path| {
+        SurvivalData::from_file(meta, delimiter, HashSet::<String>::new(), json)
+            .map_err(|e| format!("❌ Failed to load metadata: {}", e))
+    };
+    if guessed.is_none() && source::is_plain_file(meta_path) && source::is_plain_file(json_path) {
+        return load(meta_path, json_path);
+    }
+    let dir = temp_meta_dir()?;
+    let (meta_copy, json_copy) = (dir.join("meta.tsv"), dir.join("meta.factors.json"));
+    let copy = |from: &Path, to: &Path| -> Result<(), String> {
+        let mut out = File::create(to).map_err(|e| format!("❌ Failed to create {:?}: {}", to, e))?;
+        std::io::copy(&mut open_text(from)?, &mut out)
+            .map(|_| ())
+            .map_err(|e| format!("❌ Failed to read {:?}: {}", from, e))
+    };
+    let ret = copy(meta_path, &meta_copy)
+        .and_then(|_| match guessed {
+            Some(table) => table.write_factors_json(&json_copy),
+            None => copy(json_path, &json_copy),
+        })
+        .and_then(|_| load(&meta_copy, &json_copy));
+    let _ = fs::remove_dir_all(&dir);
+    ret
+}
+
+/// One column of a [`MetaTable`].
+#[derive(Clone, Debug)]
+pub enum MetaColumn {
+    /// Plain numbers; NaN marks a missing value.
+    Numeric { name: String, values: Vec<f64> },
+    /// A categorical column: `codes[i]` indexes into `levels`, `None` is missing.
+    Factor { name: String, levels: Vec<String>, codes: Vec<Option<usize>> },
+}
+
+impl MetaColumn {
+    pub fn name(&self) -> &str {
+        match self {
+            MetaColumn::Numeric { name, .. } | MetaColumn::Factor { name, .. } => name,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            MetaColumn::Numeric { values, .. } => values.len(),
+            MetaColumn::Factor { codes, .. } => codes.len(),
+        }
+    }
+
+    /// Build a factor column from raw strings, keeping the levels in order of first appearance.
+    pub fn factor_from_strings(name: &str, values: &[String]) -> Self {
+        let mut levels: Vec<String> = Vec::new();
+        let mut lookup: HashMap<&str, usize> = HashMap::new();
+        let mut codes = Vec::with_capacity(values.len());
+        for v in values {
+            let id = *lookup.entry(v.as_str()).or_insert_with(|| {
+                levels.push(v.clone());
+                levels.len() - 1
+            });
+            codes.push(Some(id));
+        }
+        MetaColumn::Factor { name: name.to_string(), levels, codes }
+    }
+
+    /// A numeric column if all non empty values are numbers, a factor otherwise.
+    pub fn typed_from_strings(name: &str, values: &[String]) -> Self {
+        let numbers: Option<Vec<f64>> = values
+            .iter()
+            .map(|v| if v.is_empty() || v == "NA" { Some(f64::NAN) } else { v.parse().ok() })
+            .collect();
+        match numbers {
+            Some(values) => MetaColumn::Numeric { name: name.to_string(), values },
+            None => MetaColumn::factor_from_strings(name, values),
+        }
+    }
+
+    /// Like `typed_from_strings`, but integer columns with few different values
+    /// (e.g. cluster ids) become factors as well, with the levels sorted numerically.
+    pub fn infer_from_strings(name: &str, values: &[String]) -> Self {
+        let col = Self::typed_from_strings(name, values);
+        let MetaColumn::Numeric { values: numbers, .. } = &col else {
+            return col;
+        };
+        let mut levels: Vec<i64> = Vec::new();
+        for v in numbers.iter().filter(|v| !v.is_nan()) {
+            if v.fract() != 0.0 {
+                return col;
+            }
+            if !levels.contains(&(*v as i64)) {
+                if levels.len() == MAX_INFERRED_LEVELS {
+                    return col;
+                }
+                levels.push(*v as i64);
+            }
+        }
+        // every value different - an id or count rather than a group
+        if levels.is_empty() || levels.len() * 2 > numbers.len() {
+            return col;
+        }
+        levels.sort_unstable();
+        let codes = numbers
+            .iter()
+            .map(|v| if v.is_nan() { None } else { levels.iter().position(|l| *l == *v as i64) })
+            .collect();
+        MetaColumn::Factor { name: name.to_string(), levels: levels.iter().map(|l| l.to_string()).collect(), codes }
+    }
+
+    /// All values as text (missing values are empty).
+    pub fn strings(&self) -> Vec<String> {
+        (0..self.len())
+            .map(|row| match self {
+                MetaColumn::Factor { codes, .. } if codes[row].is_none() => String::new(),
+                MetaColumn::Numeric { values, .. } if !values[row].is_finite() => String::new(),
+                _ => self.cell(row),
+            })
+            .collect()
+    }
+
+    fn cell(&self, row: usize) -> String {
+        match self {
+            MetaColumn::Numeric { values, .. } => {
+                let v = values[row];
+                if v.is_finite() { v.to_string() } else { "NaN".to_string() }
+            }
+            MetaColumn::Factor { levels, codes, .. } => match codes[row] {
+                Some(id) => levels[id].clone(),
+                None => "NA".to_string(),
+            },
+        }
+    }
+}
+
+/// A column oriented, in-memory table that can be turned into a `SurvivalData`.
+///
+/// The loaders that do not start from a `meta.tsv` (h5ad, loom, ...) collect their
+/// annotations here first. Factor columns are written to a `meta.factors.json`
+/// so SurvivalData never has to guess if a column of cluster ids is numeric.
+#[derive(Clone, Debug, Default)]
+pub struct MetaTable {
+    pub columns: Vec<MetaColumn>,
+    /// json numbers of factor levels kept from the `SurvivalData` the table was read from
+    pub(crate) numbers: FactorNumbers,
+}
+
+impl MetaTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn n_rows(&self) -> usize {
+        self.columns.first().map(|c| c.len()).unwrap_or(0)
+    }
+
+    pub fn has_column(&self, name: &str) -> bool {
+        self.columns.iter().any(|c| c.name() == name)
+    }
+
+    pub fn column(&self, name: &str) -> Option<&MetaColumn> {
+        self.columns.iter().find(|c| c.name() == name)
+    }
+
+    /// Add a column; all columns need to have the same length.
+    pub fn push(&mut self, col: MetaColumn) -> Result<(), String> {
+        if !self.columns.is_empty() && col.len() != self.n_rows() {
+            return Err(format!(
+                "❌ meta column '{}' has {} rows - expected {}",
+                col.name(),
+                col.len(),
+                self.n_rows()
+            ));
+        }
+        if self.has_column(col.name()) {
+            return Err(format!("❌ meta column '{}' defined twice", col.name()));
+        }
+        self.columns.push(col);
+        Ok(())
+    }
+
+    /// Read a delimited table with a header line and guess the type of every column
+    /// (`barcode` and text columns are factors, see `MetaColumn::infer_from_strings`).
+    pub fn infer(path: &Path, delimiter: u8) -> Result<Self, String> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .delimiter(delimiter)
+            .has_headers(true)
+            .flexible(true)
+            .from_reader(open_text(path)?);
+        let headers: Vec<String> = rdr
+            .headers()
+            .map_err(|e| format!("❌ Failed to read the header of {:?}: {}", path, e))?
+            .iter()
+            .map(|s| s.trim().to_string())
+            .collect();
+        let mut columns: Vec<Vec<String>> = vec![Vec::new(); headers.len()];
+        for record in rdr.records() {
+            let record = record.map_err(|e| format!("❌ Failed to read {:?}: {}", path, e))?;
+            for (c, col) in columns.iter_mut().enumerate() {
+                col.push(record.get(c).unwrap_or_default().trim().to_string());
+            }
+        }
+        let mut table = Self::new();
+        for (name, values) in headers.iter().zip(&columns) {
+            let col = if name == "barcode" {
+                MetaColumn::factor_from_strings(name, values)
+            } else {
+                MetaColumn::infer_from_strings(name, values)
+            };
+            table.push(col)?;
+        }
+        Ok(table)
+    }
+
+    /// The columns of a loaded `SurvivalData` (factors with their levels) for `cell_names`,
+    /// starting with a `barcode` factor if it has none.
+    pub fn from_survival_data(data: &SurvivalData, numbers: &FactorNumbers, cell_names: &[String]) -> Result<Self, String> {
+        let mut table = Self::new();
+        if !data.headers.iter().any(|h| h == "barcode") {
+            table.push(MetaColumn::factor_from_strings("barcode", cell_names))?;
+        }
+        for name in &data.headers {
+            let values = data.as_vec_f64(name);
+            if values.len() != cell_names.len() || table.has_column(name) {
+                continue;
+            }
+            let col = match factor_codes(data, numbers, name) {
+                Some((levels, codes)) => {
+                    if let Some(n) = numbers.get(name) {
+                        table.numbers.insert(name.clone(), n.clone());
+                    }
+                    MetaColumn::Factor { name: name.clone(), levels, codes }
+                }
+                None => MetaColumn::Numeric { name: name.clone(), values },
+            };
+            table.push(col)?;
+        }
+        Ok(table)
+    }
+
+    /// Add a column or replace the one with the same name (keeping its position).
+    /// A replaced factor is numbered by level position.
+    pub fn set(&mut self, col: MetaColumn) -> Result<(), String> {
+        match self.columns.iter().position(|c| c.name() == col.name()) {
+            Some(i) if col.len() == self.n_rows() => {
+                self.numbers.remove(col.name());
+                self.columns[i] = col;
+                Ok(())
+            }
+            Some(_) => Err(format!(
+                "❌ meta column '{}' has {} rows - expected {}",
+                col.name(),
+                col.len(),
+                self.n_rows()
+            )),
+            None => self.push(col),
+        }
+    }
+
+    /// Keep the rows where `keep` is true (factor levels stay as they are).
+    pub fn select_rows(&self, keep: &[bool]) -> Self {
+        let pick = |n: usize| -> Vec<usize> { (0..n).filter(|r| keep.get(*r).copied().unwrap_or(false)).collect() };
+        let columns = self
+            .columns
+            .iter()
+            .map(|c| match c {
+                MetaColumn::Numeric { name, values } => MetaColumn::Numeric {
+                    name: name.clone(),
+                    values: pick(values.len()).into_iter().map(|r| values[r]).collect(),
+                },
+                MetaColumn::Factor { name, levels, codes } => MetaColumn::Factor {
+                    name: name.clone(),
+                    levels: levels.clone(),
+                    codes: pick(codes.len()).into_iter().map(|r| codes[r]).collect(),
+                },
+            })
+            .collect();
+        Self { columns, numbers: self.numbers.clone() }
+    }
+
+    /// Names of the factor columns.
+    pub fn factor_names(&self) -> Vec<&str> {
+        self.columns
+            .iter()
+            .filter(|c| matches!(c, MetaColumn::Factor { .. }))
+            .map(|c| c.name())
+            .collect()
+    }
+
+    /// Write the table as a tab separated file with a header line.
+    pub fn write_tsv(&self, path: &Path) -> Result<(), String> {
+        let f = File::create(path).map_err(|e| format!("❌ Failed to create {:?}: {}", path, e))?;
+        let mut out = BufWriter::new(f);
+        let header: Vec<&str> = self.columns.iter().map(|c| c.name()).collect();
+        writeln!(out, "{}", header.join("\t")).map_err(|e| e.to_string())?;
+        for row in 0..self.n_rows() {
+            let line: Vec<String> = self.columns.iter().map(|c| c.cell(row)).collect();
+            writeln!(out, "{}", line.join("\t")).map_err(|e| e.to_string())?;
+        }
+        out.flush().map_err(|e| e.to_string())
+    }
+
+    /// Write the factor definitions in the `meta.factors.json` format SurvivalData reads
+    /// (levels are numbered by position unless `numbers` has their json numbers).
+    pub fn write_factors_json(&self, path: &Path) -> Result<(), String> {
+        let factors: Vec<serde_json::Value> = self
+            .columns
+            .iter()
+            .filter_map(|c| match c {
+                MetaColumn::Factor { name, levels, .. } => Some(serde_json::json!({
+                    "column": name,
+                    "levels": levels,
+                    "numeric": self
+                        .numbers
+                        .get(name)
+                        .filter(|n| n.len() == levels.len())
+                        .cloned()
+                        .unwrap_or_else(|| (0..levels.len()).map(|i| i as f64).collect()),
+                    "matching": null,
+                    "one_hot": false,
+                })),
+                MetaColumn::Numeric { .. } => None,
+            })
+            .collect();
+        let text = serde_json::to_string_pretty(&factors).map_err(|e| e.to_string())?;
+        fs::write(path, text).map_err(|e| format!("❌ Failed to write {:?}: {}", path, e))
+    }
+
+    /// Materialize the table as a `SurvivalData` (via a temporary meta.tsv/meta.factors.json pair).
+    pub fn into_survival_data(self) -> Result<SurvivalData, String> {
+        let dir = temp_meta_dir()?;
+        let meta_path = dir.join("meta.tsv");
+        let json_path = dir.join("meta.factors.json");
+
+        let ret = self
+            .write_tsv(&meta_path)
+            .and_then(|_| self.write_factors_json(&json_path))
+            .and_then(|_| {
+                SurvivalData::from_file(&meta_path, b'\t', HashSet::<String>::new(), &json_path)
+                    .map_err(|e| format!("❌ Failed to load metadata: {}", e))
+            });
+        let _ = fs::remove_dir_all(&dir);
+        ret
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn factor_levels_keep_first_appearance_order() {
+        let values: Vec<String> = ["b", "a", "b", "c"].iter().map(|s| s.to_string()).collect();
+        let col = MetaColumn::factor_from_strings("cluster", &values);
+        match col {
+            MetaColumn::Factor { levels, codes, .. } => {
+                assert_eq!(levels, vec!["b", "a", "c"]);
+                assert_eq!(codes, vec![Some(0), Some(1), Some(0), Some(2)]);
+            }
+            _ => panic!("expected a factor column"),
+        }
+    }
+
+    #[test]
+    fn inferred_column_types() {
+        let strings = |v: &[&str]| -> Vec<String> { v.iter().map(|s| s.to_string()).collect() };
+        let clusters = MetaColumn::infer_from_strings("cluster", &strings(&["2", "10", "2", "1", "10", ""]));
+        match clusters {
+            MetaColumn::Factor { levels, codes, .. } => {
+                assert_eq!(levels, vec!["1", "2", "10"]);
+                assert_eq!(codes, vec![Some(1), Some(2), Some(1), Some(0), Some(2), None]);
+            }
+            _ => panic!("expected a factor column"),
+        }
+        let counts = MetaColumn::infer_from_strings("nCount", &strings(&["5", "7", "9", "11"]));
+        assert!(matches!(counts, MetaColumn::Numeric { .. }));
+        let score = MetaColumn::infer_from_strings("score", &strings(&["0.5", "1", "0.5", "1"]));
+        assert!(matches!(score, MetaColumn::Numeric { .. }));
+        let kind = MetaColumn::infer_from_strings("type", &strings(&["T", "B"]));
+        assert!(matches!(kind, MetaColumn::Factor { .. }));
+
+        let path = std::env::temp_dir().join(format!("printforge3d_delim_{}.csv", std::process::id()));
+        fs::write(&path, "barcode,cluster\nAAA,1\n").unwrap();
+        assert_eq!(detect_delimiter(&path).unwrap(), b',');
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn factor_numbers_are_mapped_to_level_positions() {
+        let json = r#"[
+            {"column": "type", "levels": ["T", "B", "NK"], "numeric": [5, 2, 9], "matching": null, "one_hot": false},
+            {"column": "cluster", "levels": ["1", "2"], "numeric": [0, 1], "matching": null, "one_hot": false}
+        ]"#;
+        let numbers = factor_numbers_in(json);
+        // numbered by position
+        assert!(!numbers.contains_key("cluster"));
+        assert_eq!(numbers["type"], vec![5.0, 2.0, 9.0]);
+
+        let dir = temp_meta_dir().unwrap();
+        let (meta, json_path) = (dir.join("meta.tsv"), dir.join("meta.factors.json"));
+        fs::write(&meta, "barcode\ttype\tcluster\nAAA\tNK\t2\nCCC\tT\t1\n").unwrap();
+        fs::write(&json_path, json).unwrap();
+        let data = survival_data_from(&meta, b'\t', &json_path, None).unwrap();
+        // the stored numbers and the file are left as they are
+        assert_eq!(data.as_vec_f64("type"), vec![9.0, 5.0]);
+        assert_eq!(fs::read_to_string(&json_path).unwrap(), json);
+        let _ = fs::remove_dir_all(&dir);
+
+        let (levels, codes) = factor_codes(&data, &numbers, "type").unwrap();
+        assert_eq!(levels, vec!["T", "B", "NK"]);
+        assert_eq!(codes, vec![Some(2), Some(0)]);
+        assert_eq!(factor_codes(&data, &numbers, "cluster").unwrap().1, vec![Some(1), Some(0)]);
+
+        // a table read from the data writes the same numbers back
+        let cells = vec!["AAA".to_string(), "CCC".to_string()];
+        let table = MetaTable::from_survival_data(&data, &numbers, &cells).unwrap();
+        let again = table.into_survival_data().unwrap();
+        assert_eq!(again.as_vec_f64("type"), vec![9.0, 5.0]);
+        assert_eq!(factor_codes(&again, &numbers, "type").unwrap().1, vec![Some(2), Some(0)]);
+    }
+
+    #[test]
+    fn push_rejects_wrong_length() {
+        let mut table = MetaTable::new();
+        table
+            .push(MetaColumn::Numeric { name: "a".into(), values: vec![1.0, 2.0] })
+            .unwrap();
+        let err = table.push(MetaColumn::Numeric { name: "b".into(), values: vec![1.0] });
+        assert!(err.is_err());
+    }
+}
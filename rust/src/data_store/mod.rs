@@ -1,4 +1,43 @@
 mod data_store;
 mod dense_mini_matrix;
+mod doctor;
+mod hdf5_utils;
+mod cache;
+mod cellexal;
+mod genes;
+mod h5ad;
+mod hvg;
+mod knn;
+mod loom;
+mod manifest;
+mod merge;
+mod meta_table;
+mod modality;
+mod normalization;
+mod mtx_reader;
+mod orientation;
+mod pca;
+mod out_of_core;
+mod projection;
+mod qc;
+mod source;
+mod spatial;
+mod tenx_h5;
 
 pub use data_store::DataStore;
+pub use doctor::{DoctorReport, Issue, Severity};
+pub use genes::GeneResolver;
+pub use h5ad::X_LAYER;
+pub use knn::{KnnOptions, NeighborGraph};
+pub use hvg::{HvgFlavor, HVG_MEAN, HVG_VARIANCE, HVG_VARIANCE_STANDARDIZED, IS_HVG};
+pub use manifest::DatasetManifest;
+pub use merge::GeneJoin;
+pub use mtx_reader::MtxProgress;
+pub use normalization::{DenseLayer, LayerRow, Normalization, RAW_LAYER};
+pub use out_of_core::{OutOfCoreCounts, OUT_OF_CORE_FILE};
+pub use pca::{PcaOptions, PcaResult};
+pub use projection::{select_dims, ProjectionAxes, ProjectionMatch, ZAxis};
+pub use qc::{QcMetrics, QcPatterns, QcThresholds, N_COUNT, N_FEATURE, PERCENT_MT, PERCENT_RIBO};
+pub use spatial::{SpatialInfo, SPATIAL_PROJECTION};
+#[cfg(test)]
+pub(crate) use data_store::test_store;
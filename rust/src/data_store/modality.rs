@@ -0,0 +1,267 @@
+//modality.rs
+use crate::data_store::DataStore;
+use crate::data_store::meta_table::{MetaColumn, MetaTable};
+use crate::data_store::qc::N_COUNT;
+use sprs::CsMat;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// The feature type Cell Ranger uses for plain RNA counts.
+pub const GENE_EXPRESSION: &str = "Gene Expression";
+
+/// One additional feature type (e.g. "Antibody Capture", "CRISPR Guide Capture")
+/// with its own (features × cells) matrix.
+#[derive(Debug, Clone)]
+pub struct Modality {
+    pub counts: CsMat<f32>,
+    pub feature_names: Vec<String>,
+    counts_csc: OnceLock<CsMat<f32>>,
+}
+
+impl Modality {
+    pub fn new(counts: CsMat<f32>, feature_names: Vec<String>) -> Self {
+        Self { counts, feature_names, counts_csc: OnceLock::new() }
+    }
+
+    /// The counts as CSC (features × cells), built on first use.
+    pub fn counts_csc(&self) -> &CsMat<f32> {
+        self.counts_csc.get_or_init(|| self.counts.to_csc())
+    }
+}
+
+/// Split a (features × cells) matrix into one matrix per feature type.
+///
+/// Returns the name of the primary modality ("Gene Expression" if present,
+/// otherwise the first type found) together with its matrix and feature names,
+/// plus all other modalities.
+pub(crate) fn split_by_feature_type(
+    counts: CsMat<f32>,
+    names: Vec<String>,
+    feature_types: &[String],
+) -> (String, CsMat<f32>, Vec<String>, HashMap<String, Modality>) {
+    let mut order: Vec<&str> = Vec::new();
+    let mut rows: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, t) in feature_types.iter().enumerate() {
+        let t = if t.is_empty() { GENE_EXPRESSION } else { t.as_str() };
+        rows.entry(t)
+            .or_insert_with(|| {
+                order.push(t);
+                Vec::new()
+            })
+            .push(i);
+    }
+
+    if order.len() <= 1 {
+        let primary = order.first().copied().unwrap_or(GENE_EXPRESSION).to_string();
+        return (primary, counts, names, HashMap::new());
+    }
+
+    let primary = primary_feature_type(&order);
+    let counts = if counts.is_csr() { counts } else { counts.to_csr() };
+
+    let mut others = HashMap::new();
+    for t in &order {
+        if *t == primary {
+            continue;
+        }
+        let idx = &rows[t];
+        others.insert(
+            t.to_string(),
+            Modality::new(
+                select_rows(&counts, idx),
+                idx.iter().map(|&i| names[i].clone()).collect(),
+            ),
+        );
+    }
+    let idx = &rows[primary];
+    let primary_counts = select_rows(&counts, idx);
+    let primary_names = idx.iter().map(|&i| names[i].clone()).collect();
+
+    println!("🧬 Found modalities {:?} - '{}' is the primary one", order, primary);
+    (primary.to_string(), primary_counts, primary_names, others)
+}
+
+/// The feature type of the primary modality: "Gene Expression" if present, otherwise the first one.
+pub(crate) fn primary_feature_type<'a>(types: &[&'a str]) -> &'a str {
+    if types.contains(&GENE_EXPRESSION) {
+        GENE_EXPRESSION
+    } else {
+        types.first().copied().unwrap_or(GENE_EXPRESSION)
+    }
+}
+
+/// Copy the given rows of a CSR matrix into a new CSR matrix.
+pub(crate) fn select_rows(mat: &CsMat<f32>, rows: &[usize]) -> CsMat<f32> {
+    let mut indptr = Vec::with_capacity(rows.len() + 1);
+    let mut indices = Vec::new();
+    let mut data = Vec::new();
+    indptr.push(0);
+    for &r in rows {
+        if let Some(row) = mat.outer_view(r) {
+            indices.extend_from_slice(row.indices());
+            data.extend_from_slice(row.data());
+        }
+        indptr.push(indices.len());
+    }
+    CsMat::new((rows.len(), mat.cols()), indptr, indices, data)
+}
+
+impl DataStore {
+    /// Names of all modalities in this dataset, the primary one first.
+    pub fn modality_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.modalities.keys().cloned().collect();
+        names.sort();
+        names.insert(0, self.primary_modality.clone());
+        names
+    }
+
+    /// The (features × cells) matrix and feature names of a modality.
+    /// An empty name selects the primary modality (`counts`/`gene_names`).
+    pub fn modality(&self, name: &str) -> Option<(&CsMat<f32>, &[String])> {
+        if name.is_empty() || name == self.primary_modality {
+            return Some((&self.counts, &self.gene_names));
+        }
+        self.modalities
+            .get(name)
+            .map(|m| (&m.counts, m.feature_names.as_slice()))
+    }
+
+    /// Make another modality the primary one (swapping it with `counts`/`gene_names`).
+    ///
+    /// Everything computed from the old primary counts goes: the layers, the neighbor
+    /// graphs and the gene annotation. QC metrics in `cell_meta` are recomputed.
+    /// Out-of-core datasets only have their primary modality on disk and can not switch.
+    pub fn set_primary_modality(&mut self, name: &str) -> Result<(), String> {
+        if name == self.primary_modality {
+            return Ok(());
+        }
+        if self.is_out_of_core() {
+            return Err("❌ The primary modality of out-of-core counts can not be changed".to_string());
+        }
+        let other = self
+            .modalities
+            .remove(name)
+            .ok_or_else(|| format!("❌ Modality '{}' not found - available: {:?}", name, self.modality_names()))?;
+        let old = Modality {
+            counts: std::mem::replace(&mut self.counts, other.counts),
+            feature_names: std::mem::replace(&mut self.gene_names, other.feature_names),
+            counts_csc: std::mem::replace(&mut self.counts_csc, other.counts_csc),
+        };
+        let old_name = std::mem::replace(&mut self.primary_modality, name.to_string());
+        self.modalities.insert(old_name.clone(), old);
+        // the gene annotation belonged to the old primary modality
+        let names = MetaTable { columns: vec![MetaColumn::factor_from_strings("gene", &self.gene_names)], ..Default::default() };
+        self.set_gene_table(names)?;
+        // layers are indexed by the old features, the graphs may come from a PCA of them
+        if !self.layers.is_empty() || !self.dense_layers.is_empty() {
+            println!("⚠️ the layers of '{}' were removed", old_name);
+        }
+        self.layers.clear();
+        self.dense_layers.clear();
+        self.neighbors.clear();
+        if MetaTable::from_survival_data(&self.cell_meta, &self.factor_numbers, &self.cell_names)?.has_column(N_COUNT) {
+            let patterns = self.manifest.qc.clone();
+            self.add_qc_metrics(&patterns)?;
+        }
+        Ok(())
+    }
+
+    /// The (features × cells) matrix of a modality as CSC (one column per cell).
+    /// `None` for the primary modality of an out-of-core dataset (its counts are on disk).
+    pub fn modality_csc(&self, name: &str) -> Option<&CsMat<f32>> {
+        if name.is_empty() || name == self.primary_modality {
+            return self.out_of_core.is_none().then(|| self.counts_csc());
+        }
+        self.modalities.get(name).map(|m| m.counts_csc())
+    }
+
+    /// Per cell values of one feature (e.g. for coloring).
+    pub fn feature_values(&self, modality: &str, feature: &str) -> Result<Vec<f32>, String> {
+        let (mat, names) = self
+            .modality(modality)
+            .ok_or_else(|| format!("Modality '{}' not found", modality))?;
+        let primary = std::ptr::eq(mat, &self.counts);
+        let row = match names.iter().position(|n| n == feature) {
+            Some(row) => row,
+            // ids, other cases and aliases of genes
+            None if primary => self.gene_index(feature)?,
+            None => return Err(format!("Feature '{}' not found in modality '{}'", feature, modality)),
+        };
+        let mut values = vec![0.0f32; mat.cols()];
+        // the primary modality may be out of core
+        let row = if primary {
+            self.gene_vector(row)?
+        } else {
+            mat.outer_view(row).map(|v| v.to_owned())
+        };
+        if let Some(row) = row {
+            for (cell, v) in row.iter() {
+                values[cell] = *v;
+            }
+        }
+        Ok(values)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_store::data_store::test_store;
+    use sprs::TriMat;
+
+    #[test]
+    fn split_keeps_gene_expression_primary() {
+        // rows: gene, adt, gene, adt  × 2 cells
+        let mut tri = TriMat::<f32>::new((4, 2));
+        tri.add_triplet(0, 0, 1.0);
+        tri.add_triplet(1, 1, 2.0);
+        tri.add_triplet(2, 1, 3.0);
+        tri.add_triplet(3, 0, 4.0);
+        let names: Vec<String> = ["G1", "CD3", "G2", "CD4"].iter().map(|s| s.to_string()).collect();
+        let types: Vec<String> = [GENE_EXPRESSION, "Antibody Capture", GENE_EXPRESSION, "Antibody Capture"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+
+        let (primary, counts, genes, others) = split_by_feature_type(tri.to_csr(), names, &types);
+        assert_eq!(primary, GENE_EXPRESSION);
+        assert_eq!(genes, vec!["G1", "G2"]);
+        assert_eq!(counts.get(1, 1), Some(&3.0));
+        let adt = &others["Antibody Capture"];
+        assert_eq!(adt.feature_names, vec!["CD3", "CD4"]);
+        assert_eq!(adt.counts.get(0, 1), Some(&2.0));
+        assert_eq!(adt.counts.get(1, 0), Some(&4.0));
+    }
+
+    #[test]
+    fn switching_the_primary_modality_drops_what_belonged_to_the_old_one() {
+        let mut tri = TriMat::<f32>::new((4, 2));
+        tri.add_triplet(0, 0, 1.0);
+        tri.add_triplet(1, 1, 2.0);
+        tri.add_triplet(2, 1, 3.0);
+        tri.add_triplet(3, 0, 4.0);
+        let names: Vec<String> = ["G1", "CD3", "G2", "CD4"].iter().map(|s| s.to_string()).collect();
+        let types: Vec<String> = [GENE_EXPRESSION, "Antibody Capture", GENE_EXPRESSION, "Antibody Capture"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let (primary, counts, genes, others) = split_by_feature_type(tri.to_csr(), names, &types);
+        let cells = vec!["c0".to_string(), "c1".to_string()];
+        let mut ds = test_store(counts.clone(), genes, cells);
+        ds.primary_modality = primary;
+        ds.modalities = others;
+        ds.layers.insert("spliced".to_string(), counts);
+        ds.add_qc_metrics(&Default::default()).unwrap();
+
+        ds.set_primary_modality("Antibody Capture").unwrap();
+        assert_eq!(ds.gene_names, vec!["CD3", "CD4"]);
+        assert!(ds.layers.is_empty());
+        let meta = MetaTable::from_survival_data(&ds.cell_meta, &ds.factor_numbers, &ds.cell_names).unwrap();
+        match meta.column(N_COUNT) {
+            Some(MetaColumn::Numeric { values, .. }) => assert_eq!(values, &vec![4.0, 2.0]),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(ds.modality(GENE_EXPRESSION).unwrap().1, ["G1", "G2"]);
+    }
+}
@@ -0,0 +1,480 @@
+//mtx_reader.rs
+//! A single pass MatrixMarket reader.
+//!
+//! The header decides how the values are parsed (integer / real / pattern and
+//! general / symmetric), the body is read in large blocks that are parsed in
+//! parallel with rayon. CSR and CSC matrices are built directly from the triplets,
+//! or the entries are passed on block by block to an `MtxSink`.
+use crate::data_store::source;
+use rayon::prelude::*;
+use sprs::CsMat;
+use std::cell::Cell;
+use std::io::Read;
+use std::path::Path;
+use std::rc::Rc;
+
+/// Bytes read from the (possibly compressed) input per parsing round.
+const BLOCK_SIZE: usize = 8 << 20;
+
+/// Progress of a running MatrixMarket read.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MtxProgress {
+    /// bytes read from the file on disk (compressed bytes for compressed files)
+    pub bytes_read: u64,
+    /// size of the file on disk
+    pub total_bytes: u64,
+    /// data lines parsed so far
+    pub lines: u64,
+}
+
+impl MtxProgress {
+    pub fn fraction(&self) -> f32 {
+        if self.total_bytes == 0 {
+            0.0
+        } else {
+            (self.bytes_read as f64 / self.total_bytes as f64).min(1.0) as f32
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Real,
+    Integer,
+    Pattern,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Symmetry {
+    General,
+    Symmetric,
+    SkewSymmetric,
+}
+
+/// The entries of a MatrixMarket file (0-based), ready to be compressed.
+#[derive(Debug, Default)]
+pub struct MtxTriplets {
+    pub rows: usize,
+    pub cols: usize,
+    row_idx: Vec<u32>,
+    col_idx: Vec<u32>,
+    vals: Vec<f32>,
+}
+
+impl MtxTriplets {
+    /// Build the CSR matrix (duplicate entries are summed).
+    pub fn to_csr(&self) -> Result<CsMat<f32>, String> {
+        let (indptr, indices, data) = compress(self.rows, &self.row_idx, &self.col_idx, &self.vals);
+        CsMat::try_new((self.rows, self.cols), indptr, indices, data)
+            .map_err(|(_, _, _, e)| format!("❌ Failed to build CSR matrix: {}", e))
+    }
+
+    /// Build the CSC matrix (duplicate entries are summed).
+    pub fn to_csc(&self) -> Result<CsMat<f32>, String> {
+        let (indptr, indices, data) = compress(self.cols, &self.col_idx, &self.row_idx, &self.vals);
+        CsMat::try_new_csc((self.rows, self.cols), indptr, indices, data)
+            .map_err(|(_, _, _, e)| format!("❌ Failed to build CSC matrix: {}", e))
+    }
+}
+
+/// Counts how many bytes were pulled from the underlying reader
+/// (shared, as the reader disappears into a decoder).
+struct CountingReader<R: Read> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+/// The size line of a MatrixMarket file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MtxShape {
+    pub rows: usize,
+    pub cols: usize,
+    /// entries listed in the file (symmetric matrices hold more)
+    pub nnz: usize,
+}
+
+/// Receives the entries of a MatrixMarket file while it is parsed.
+pub trait MtxSink {
+    /// Called once with the size line, before any entries.
+    fn shape(&mut self, shape: MtxShape);
+    /// The (0-based) entries of one parsed block; symmetric entries are already mirrored.
+    fn entries(&mut self, rows: &[u32], cols: &[u32], vals: &[f32]);
+}
+
+impl MtxSink for MtxTriplets {
+    fn shape(&mut self, shape: MtxShape) {
+        self.rows = shape.rows;
+        self.cols = shape.cols;
+        self.row_idx.reserve(shape.nnz);
+        self.col_idx.reserve(shape.nnz);
+        self.vals.reserve(shape.nnz);
+    }
+
+    fn entries(&mut self, rows: &[u32], cols: &[u32], vals: &[f32]) {
+        self.row_idx.extend_from_slice(rows);
+        self.col_idx.extend_from_slice(cols);
+        self.vals.extend_from_slice(vals);
+    }
+}
+
+/// Read a plain or compressed `.mtx` file (see `source::open_text`),
+/// reporting progress after every parsed block.
+pub fn read_matrix_market<P: AsRef<Path>>(
+    path: P,
+    progress: &dyn Fn(&MtxProgress),
+) -> Result<MtxTriplets, String> {
+    let mut ret = MtxTriplets::default();
+    stream_matrix_market(path, progress, &mut ret)?;
+    Ok(ret)
+}
+
+/// Pass the entries of a plain or compressed `.mtx` file to `sink` block by block,
+/// without keeping them (e.g. to convert matrices larger than the RAM).
+pub fn stream_matrix_market<P: AsRef<Path>>(
+    path: P,
+    progress: &dyn Fn(&MtxProgress),
+    sink: &mut dyn MtxSink,
+) -> Result<(), String> {
+    let path = path.as_ref();
+    let total_bytes = source::len(path);
+    let count = Rc::new(Cell::new(0));
+    let raw = CountingReader { inner: source::open_raw(path)?, count: count.clone() };
+    let mut reader = source::decompress(raw).map_err(|e| format!("❌ Failed to open {:?}: {}", path, e))?;
+    parse_matrix_market(&mut reader, total_bytes, &|_| count.get(), progress, sink)
+        .map_err(|e| format!("❌ Failed to parse {:?}: {}", path, e))
+}
+
+/// Parse MatrixMarket text from `reader`; `bytes_read` reports the position on disk.
+fn parse_matrix_market<R: Read>(
+    reader: &mut R,
+    total_bytes: u64,
+    bytes_read: &dyn Fn(&R) -> u64,
+    progress: &dyn Fn(&MtxProgress),
+    sink: &mut dyn MtxSink,
+) -> Result<(), String> {
+    let mut buf: Vec<u8> = Vec::new();
+    let mut eof = fill_block(reader, &mut buf)?;
+
+    // --- header + size line ---
+    let mut pos = 0;
+    let next_line = |buf: &[u8], pos: &mut usize| -> Option<String> {
+        if *pos >= buf.len() {
+            return None;
+        }
+        let end = buf[*pos..].iter().position(|&b| b == b'\n').map(|e| *pos + e).unwrap_or(buf.len());
+        let line = String::from_utf8_lossy(&buf[*pos..end]).trim().to_string();
+        *pos = end + 1;
+        Some(line)
+    };
+    let header = next_line(&buf, &mut pos).ok_or("empty file")?;
+    let (field, symmetry) = parse_header(&header)?;
+
+    let size_line = loop {
+        let line = next_line(&buf, &mut pos).ok_or("missing size line")?;
+        if !line.is_empty() && !line.starts_with('%') {
+            break line;
+        }
+    };
+    let dims: Vec<usize> = size_line
+        .split_ascii_whitespace()
+        .map(|v| v.parse::<usize>().map_err(|_| format!("invalid size line '{}'", size_line)))
+        .collect::<Result<_, _>>()?;
+    let [rows, cols, nnz] = dims[..] else {
+        return Err(format!("invalid size line '{}'", size_line));
+    };
+    if rows > u32::MAX as usize || cols > u32::MAX as usize {
+        return Err(format!("matrix {}×{} is too large", rows, cols));
+    }
+    sink.shape(MtxShape { rows, cols, nnz });
+    buf.drain(..pos.min(buf.len()));
+
+    // --- body ---
+    let mut state = MtxProgress { bytes_read: 0, total_bytes, lines: 0 };
+    loop {
+        // keep an incomplete last line for the next round
+        let carry = if eof {
+            Vec::new()
+        } else {
+            match buf.iter().rposition(|&b| b == b'\n') {
+                Some(p) => buf.split_off(p + 1),
+                None => std::mem::take(&mut buf),
+            }
+        };
+
+        let parsed: Vec<Result<Chunk, String>> = split_at_newlines(&buf, rayon::current_num_threads())
+            .into_par_iter()
+            .map(|piece| parse_chunk(piece, field, rows, cols))
+            .collect();
+        for chunk in parsed {
+            let mut chunk = chunk?;
+            state.lines += chunk.lines;
+            if symmetry != Symmetry::General {
+                chunk.mirror(symmetry == Symmetry::SkewSymmetric);
+            }
+            sink.entries(&chunk.rows, &chunk.cols, &chunk.vals);
+        }
+        state.bytes_read = bytes_read(reader);
+        progress(&state);
+
+        if eof {
+            break;
+        }
+        buf = carry;
+        eof = fill_block(reader, &mut buf)?;
+    }
+
+    if state.lines != nnz as u64 {
+        return Err(format!("expected {} entries but found {}", nnz, state.lines));
+    }
+    Ok(())
+}
+
+fn parse_header(header: &str) -> Result<(Field, Symmetry), String> {
+    let parts: Vec<String> = header.split_ascii_whitespace().map(|s| s.to_ascii_lowercase()).collect();
+    if parts.len() < 5 || parts[0] != "%%matrixmarket" || parts[1] != "matrix" {
+        return Err(format!("not a MatrixMarket matrix header: '{}'", header));
+    }
+    if parts[2] != "coordinate" {
+        return Err(format!("only the 'coordinate' format is supported, not '{}'", parts[2]));
+    }
+    let field = match parts[3].as_str() {
+        "real" | "double" => Field::Real,
+        "integer" => Field::Integer,
+        "pattern" => Field::Pattern,
+        other => return Err(format!("unsupported field type '{}'", other)),
+    };
+    let symmetry = match parts[4].as_str() {
+        "general" => Symmetry::General,
+        "symmetric" => Symmetry::Symmetric,
+        "skew-symmetric" => Symmetry::SkewSymmetric,
+        other => return Err(format!("unsupported symmetry '{}'", other)),
+    };
+    Ok((field, symmetry))
+}
+
+/// Append up to BLOCK_SIZE bytes to `buf`; returns true at the end of the input.
+fn fill_block<R: Read>(reader: &mut R, buf: &mut Vec<u8>) -> Result<bool, String> {
+    let start = buf.len();
+    buf.resize(start + BLOCK_SIZE, 0);
+    let mut filled = start;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => {
+                buf.truncate(filled);
+                return Ok(true);
+            }
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    Ok(false)
+}
+
+/// Split `buf` into about `n` pieces that all end at a line break.
+fn split_at_newlines(buf: &[u8], n: usize) -> Vec<&[u8]> {
+    let target = (buf.len() / n.max(1)).max(1);
+    let mut pieces = Vec::with_capacity(n);
+    let mut start = 0;
+    while start < buf.len() {
+        let mut end = (start + target).min(buf.len());
+        while end < buf.len() && buf[end - 1] != b'\n' {
+            end += 1;
+        }
+        pieces.push(&buf[start..end]);
+        start = end;
+    }
+    pieces
+}
+
+struct Chunk {
+    rows: Vec<u32>,
+    cols: Vec<u32>,
+    vals: Vec<f32>,
+    lines: u64,
+}
+
+impl Chunk {
+    /// Add the entries above the diagonal of a (skew-)symmetric matrix.
+    fn mirror(&mut self, skew: bool) {
+        let sign = if skew { -1.0 } else { 1.0 };
+        for i in 0..self.vals.len() {
+            let (r, c) = (self.rows[i], self.cols[i]);
+            if r != c {
+                self.rows.push(c);
+                self.cols.push(r);
+                self.vals.push(sign * self.vals[i]);
+            }
+        }
+    }
+}
+
+fn parse_chunk(piece: &[u8], field: Field, n_rows: usize, n_cols: usize) -> Result<Chunk, String> {
+    let text = std::str::from_utf8(piece).map_err(|e| e.to_string())?;
+    let mut chunk = Chunk { rows: Vec::new(), cols: Vec::new(), vals: Vec::new(), lines: 0 };
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('%') {
+            continue;
+        }
+        let mut it = line.split_ascii_whitespace();
+        let bad = || format!("invalid data line '{}'", line);
+        let r: usize = it.next().and_then(|v| v.parse().ok()).ok_or_else(bad)?;
+        let c: usize = it.next().and_then(|v| v.parse().ok()).ok_or_else(bad)?;
+        let v: f32 = match field {
+            Field::Pattern => 1.0,
+            Field::Real | Field::Integer => it.next().and_then(|v| v.parse().ok()).ok_or_else(bad)?,
+        };
+        if r == 0 || c == 0 || r > n_rows || c > n_cols {
+            return Err(format!("entry ({}, {}) outside of the {}×{} matrix", r, c, n_rows, n_cols));
+        }
+        chunk.rows.push((r - 1) as u32);
+        chunk.cols.push((c - 1) as u32);
+        chunk.vals.push(v);
+        chunk.lines += 1;
+    }
+    Ok(chunk)
+}
+
+/// Counting sort of the triplets along `outer`; returns (indptr, indices, data)
+/// with sorted inner indices and summed duplicates.
+fn compress(n_outer: usize, outer: &[u32], inner: &[u32], vals: &[f32]) -> (Vec<usize>, Vec<usize>, Vec<f32>) {
+    let mut indptr = vec![0usize; n_outer + 1];
+    for &o in outer {
+        indptr[o as usize + 1] += 1;
+    }
+    for i in 0..n_outer {
+        indptr[i + 1] += indptr[i];
+    }
+
+    let nnz = vals.len();
+    let mut indices = vec![0usize; nnz];
+    let mut data = vec![0f32; nnz];
+    let mut next = indptr.clone();
+    for k in 0..nnz {
+        let o = outer[k] as usize;
+        indices[next[o]] = inner[k] as usize;
+        data[next[o]] = vals[k];
+        next[o] += 1;
+    }
+
+    // sort every outer segment by its inner index (in parallel)
+    let mut segments = Vec::with_capacity(n_outer);
+    let (mut rest_i, mut rest_d) = (indices.as_mut_slice(), data.as_mut_slice());
+    for o in 0..n_outer {
+        let len = indptr[o + 1] - indptr[o];
+        let (seg_i, tail_i) = rest_i.split_at_mut(len);
+        let (seg_d, tail_d) = rest_d.split_at_mut(len);
+        segments.push((seg_i, seg_d));
+        rest_i = tail_i;
+        rest_d = tail_d;
+    }
+    let has_duplicates = segments
+        .into_par_iter()
+        .map(|(seg_i, seg_d)| {
+            if !seg_i.windows(2).all(|w| w[0] <= w[1]) {
+                let mut pairs: Vec<(usize, f32)> = seg_i.iter().copied().zip(seg_d.iter().copied()).collect();
+                pairs.sort_by_key(|p| p.0);
+                for (k, (i, d)) in pairs.into_iter().enumerate() {
+                    seg_i[k] = i;
+                    seg_d[k] = d;
+                }
+            }
+            seg_i.windows(2).any(|w| w[0] == w[1])
+        })
+        .reduce(|| false, |a, b| a || b);
+
+    if has_duplicates {
+        let mut new_indptr = vec![0usize; n_outer + 1];
+        let mut new_indices = Vec::with_capacity(nnz);
+        let mut new_data = Vec::with_capacity(nnz);
+        for o in 0..n_outer {
+            for k in indptr[o]..indptr[o + 1] {
+                if k > indptr[o] && indices[k] == indices[k - 1] {
+                    *new_data.last_mut().unwrap() += data[k];
+                } else {
+                    new_indices.push(indices[k]);
+                    new_data.push(data[k]);
+                }
+            }
+            new_indptr[o + 1] = new_indices.len();
+        }
+        return (new_indptr, new_indices, new_data);
+    }
+    (indptr, indices, data)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sprs::TriMat;
+
+    fn parse(text: &str) -> Result<MtxTriplets, String> {
+        let mut reader = text.as_bytes();
+        let mut ret = MtxTriplets::default();
+        parse_matrix_market(&mut reader, text.len() as u64, &|_| 0, &|_| {}, &mut ret)?;
+        Ok(ret)
+    }
+
+    #[test]
+    fn integer_general_matches_trimat() {
+        let text = "%%MatrixMarket matrix coordinate integer general\n\
+                    % a comment\n\
+                    3 4 5\n\
+                    1 1 3\n\
+                    3 1 1\n\
+                    2 2 7\n\
+                    1 4 2\n\
+                    3 3 5\n";
+        let trip = parse(text).unwrap();
+
+        let mut tri = TriMat::<f32>::new((3, 4));
+        for (r, c, v) in [(0, 0, 3.0), (2, 0, 1.0), (1, 1, 7.0), (0, 3, 2.0), (2, 2, 5.0)] {
+            tri.add_triplet(r, c, v);
+        }
+        assert_eq!(trip.to_csr().unwrap(), tri.to_csr());
+        assert_eq!(trip.to_csc().unwrap(), tri.to_csc());
+    }
+
+    #[test]
+    fn real_pattern_and_symmetric() {
+        let real = parse("%%MatrixMarket matrix coordinate real general\n2 2 1\n2 1 0.5\n").unwrap();
+        assert_eq!(real.to_csr().unwrap().get(1, 0), Some(&0.5));
+
+        let pattern = parse("%%MatrixMarket matrix coordinate pattern general\n2 2 1\n1 2\n").unwrap();
+        assert_eq!(pattern.to_csr().unwrap().get(0, 1), Some(&1.0));
+
+        let sym = parse("%%MatrixMarket matrix coordinate real symmetric\n3 3 2\n1 1 1.0\n3 1 2.0\n").unwrap();
+        let csr = sym.to_csr().unwrap();
+        assert_eq!(csr.nnz(), 3);
+        assert_eq!(csr.get(0, 2), Some(&2.0));
+        assert_eq!(csr.get(2, 0), Some(&2.0));
+    }
+
+    #[test]
+    fn duplicates_are_summed() {
+        let trip = parse("%%MatrixMarket matrix coordinate integer general\n2 2 3\n1 2 1\n1 1 4\n1 2 2\n").unwrap();
+        let csr = trip.to_csr().unwrap();
+        assert_eq!(csr.nnz(), 2);
+        assert_eq!(csr.get(0, 1), Some(&3.0));
+    }
+
+    #[test]
+    fn errors_are_reported() {
+        assert!(parse("not a header\n1 1 1\n").is_err());
+        assert!(parse("%%MatrixMarket matrix array real general\n1 1\n1\n").is_err());
+        // nnz does not match
+        assert!(parse("%%MatrixMarket matrix coordinate integer general\n2 2 2\n1 1 1\n").is_err());
+        // out of bounds
+        assert!(parse("%%MatrixMarket matrix coordinate integer general\n2 2 1\n3 1 1\n").is_err());
+    }
+}
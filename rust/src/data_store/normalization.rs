@@ -0,0 +1,360 @@
+//normalization.rs
+//! Expression layers computed from the raw counts of the primary modality.
+//!
+//! Library size, CPM and log1p layers are sparse and stored in `layers`, next to the
+//! layers a loader found (e.g. spliced/unspliced). Pearson residuals and scaled values are
+//! dense by nature and only kept for the genes asked for, in `dense_layers`.
+//! The raw counts are the layer `RAW_LAYER` (an empty layer name means the same).
+use crate::data_store::DataStore;
+use crate::data_store::doctor::{Issue, Severity};
+use ndarray::{Array2, ArrayView1, Axis};
+use sprs::{CsMat, CsVecView};
+
+/// The name of the raw counts (`counts`, possibly out of core) as a layer.
+pub const RAW_LAYER: &str = "counts";
+
+/// A dense (genes × cells) layer for some of the genes.
+#[derive(Debug, Clone)]
+pub struct DenseLayer {
+    /// the genes (rows of `counts`) in the order of the rows of `values`
+    pub genes: Vec<usize>,
+    pub values: Array2<f32>,
+}
+
+/// How a layer is computed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Normalization {
+    /// Every cell scaled to the same total count (`None`: the median total of all cells).
+    LibrarySize(Option<f32>),
+    /// Counts per million: `LibrarySize(Some(1e6))`.
+    Cpm,
+    /// ln(1 + x) of a sparse layer (e.g. a library size normalized one).
+    Log1p(String),
+    /// Analytic Pearson residuals of a negative binomial model with overdispersion `theta`
+    /// (Lause et al. 2021), clipped to ±sqrt(n_cells). Dense.
+    PearsonResiduals { theta: f32 },
+    /// Per gene z-scores of another layer, clipped to ±`max_value`. Dense.
+    Scaled { source: String, max_value: Option<f32> },
+}
+
+impl Normalization {
+    /// `library_size[:target]`, `cpm`, `log1p[:source]`, `pearson[:theta]` or `scaled[:source[:max]]`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parts = text.trim().split(':');
+        let method = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let args: Vec<&str> = parts.collect();
+        let number = |i: usize| -> Result<Option<f32>, String> {
+            args.get(i)
+                .map(|a| a.parse::<f32>().map_err(|_| format!("❌ '{}' is not a number in '{}'", a, text)))
+                .transpose()
+        };
+        let layer = |i: usize| args.get(i).map(|s| s.to_string()).unwrap_or_else(|| RAW_LAYER.to_string());
+        match method.as_str() {
+            "library_size" | "normalize" => Ok(Normalization::LibrarySize(number(0)?)),
+            "cpm" => Ok(Normalization::Cpm),
+            "log1p" => Ok(Normalization::Log1p(layer(0))),
+            "pearson" | "pearson_residuals" => Ok(Normalization::PearsonResiduals { theta: number(0)?.unwrap_or(100.0) }),
+            "scaled" | "scale" => Ok(Normalization::Scaled { source: layer(0), max_value: number(1)? }),
+            _ => Err(format!(
+                "❌ Unknown normalization '{}' - use library_size, cpm, log1p, pearson or scaled",
+                text
+            )),
+        }
+    }
+}
+
+/// One gene of a layer: the sparse values or all cells.
+pub enum LayerRow<'a> {
+    Sparse(CsVecView<'a, f32>),
+    Dense(ArrayView1<'a, f32>),
+}
+
+impl LayerRow<'_> {
+    /// Visit (cell, value) - only the stored values of sparse rows.
+    pub fn for_each(&self, mut f: impl FnMut(usize, f32)) {
+        match self {
+            LayerRow::Sparse(v) => v.iter().for_each(|(c, x)| f(c, *x)),
+            LayerRow::Dense(v) => v.iter().enumerate().for_each(|(c, x)| f(c, *x)),
+        }
+    }
+
+    pub fn to_dense(&self, n_cells: usize) -> Vec<f32> {
+        let mut ret = vec![0.0; n_cells];
+        self.for_each(|c, x| ret[c] = x);
+        ret
+    }
+}
+
+fn is_raw(layer: &str) -> bool {
+    layer.is_empty() || layer == RAW_LAYER
+}
+
+pub(crate) fn median(values: &mut [f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) { (values[mid - 1] + values[mid]) / 2.0 } else { values[mid] }
+}
+
+impl DataStore {
+    /// The raw counts, the loaded layers and the computed ones.
+    pub fn layer_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.layers.keys().chain(self.dense_layers.keys()).cloned().collect();
+        names.sort();
+        names.insert(0, RAW_LAYER.to_string());
+        names
+    }
+
+    /// The genes a layer has values for (`None`: all genes).
+    pub fn layer_genes(&self, layer: &str) -> Option<&[usize]> {
+        self.dense_layers.get(layer).map(|l| l.genes.as_slice())
+    }
+
+    /// Visit the values of every gene of a layer (dense layers: only their genes).
+    pub fn for_each_layer_gene(&self, layer: &str, mut f: impl FnMut(usize, LayerRow)) -> Result<(), String> {
+        if is_raw(layer) {
+            return self.for_each_feature("", |g, v| f(g, LayerRow::Sparse(v)));
+        }
+        if let Some(mat) = self.layers.get(layer) {
+            mat.outer_iterator().enumerate().for_each(|(g, v)| f(g, LayerRow::Sparse(v)));
+        } else if let Some(dense) = self.dense_layers.get(layer) {
+            for (g, row) in dense.genes.iter().zip(dense.values.axis_iter(Axis(0))) {
+                f(*g, LayerRow::Dense(row));
+            }
+        } else {
+            return Err(format!("❌ Layer '{}' not found - available: {:?}", layer, self.layer_names()));
+        }
+        Ok(())
+    }
+
+    /// The values of one gene (row of `counts`) in a layer, for all cells.
+    pub fn layer_gene_values(&self, layer: &str, gene: usize) -> Result<Vec<f32>, String> {
+        let n_cells = self.cell_names.len();
+        let out_of_range = || format!("❌ Gene {} out of range ({} genes)", gene, self.gene_names.len());
+        if is_raw(layer) {
+            let row = self.gene_vector(gene)?.ok_or_else(out_of_range)?;
+            return Ok(LayerRow::Sparse(row.view()).to_dense(n_cells));
+        }
+        if let Some(mat) = self.layers.get(layer) {
+            let row = mat.outer_view(gene).ok_or_else(out_of_range)?;
+            return Ok(LayerRow::Sparse(row).to_dense(n_cells));
+        }
+        let dense = self
+            .dense_layers
+            .get(layer)
+            .ok_or_else(|| format!("❌ Layer '{}' not found - available: {:?}", layer, self.layer_names()))?;
+        let row = dense
+            .genes
+            .iter()
+            .position(|g| *g == gene)
+            .ok_or_else(|| format!("❌ Gene '{}' is not part of layer '{}'", self.gene_names[gene], layer))?;
+        Ok(dense.values.row(row).to_vec())
+    }
+
+    /// Per cell values of a gene (symbol, id or alias) in a layer (e.g. for coloring).
+    pub fn layer_values(&self, layer: &str, gene: &str) -> Result<Vec<f32>, String> {
+        if is_raw(layer) {
+            return self.feature_values("", gene);
+        }
+        self.layer_gene_values(layer, self.gene_index(gene)?)
+    }
+
+    /// Total counts per cell.
+    pub fn library_sizes(&self) -> Result<Vec<f32>, String> {
+        let mut totals = vec![0.0f32; self.cell_names.len()];
+        self.for_each_feature("", |_, v| v.iter().for_each(|(c, x)| totals[c] += *x))?;
+        Ok(totals)
+    }
+
+    /// Compute a layer from the counts (or another layer) and store it as `name`.
+    /// `genes` limits the dense layers (Pearson residuals, scaled) to some genes;
+    /// `None` makes them for all genes, which needs genes × cells floats of memory.
+    pub fn add_layer(&mut self, name: &str, how: &Normalization, genes: Option<&[usize]>) -> Result<(), String> {
+        if is_raw(name) {
+            return Err(format!("❌ '{}' is the name of the raw counts", RAW_LAYER));
+        }
+        if let Some(g) = genes.and_then(|genes| genes.iter().find(|g| **g >= self.gene_names.len())) {
+            return Err(format!("❌ Gene {} out of range ({} genes)", g, self.gene_names.len()));
+        }
+        let all: Vec<usize>;
+        let genes = match genes {
+            Some(genes) => genes,
+            None => {
+                all = (0..self.gene_names.len()).collect();
+                &all
+            }
+        };
+        match how {
+            Normalization::LibrarySize(target) => self.insert_sparse(name, self.library_size_layer(*target)?),
+            Normalization::Cpm => self.insert_sparse(name, self.library_size_layer(Some(1e6))?),
+            Normalization::Log1p(source) => self.insert_sparse(name, self.log1p_layer(source)?),
+            Normalization::PearsonResiduals { theta } => self.insert_dense(name, self.pearson_residuals(genes, *theta)?),
+            Normalization::Scaled { source, max_value } => {
+                self.insert_dense(name, self.scaled_layer(source, genes, *max_value)?)
+            }
+        }
+        println!("🧮 layer '{}' = {:?}", name, how);
+        Ok(())
+    }
+
+    /// Keep a layer a loader found in `file`. A layer named like the raw counts would be
+    /// hidden by them, it is renamed (and the new name reported in the load issues).
+    pub(crate) fn insert_loaded_layer(&mut self, name: String, layer: CsMat<f32>, file: &str) {
+        let name = if is_raw(&name) {
+            let mut renamed = format!("{}_loaded", RAW_LAYER);
+            while self.layers.contains_key(&renamed) {
+                renamed.push('_');
+            }
+            let problem = format!("layer '{}' is the name of the raw counts - loaded as '{}'", name, renamed);
+            println!("⚠️ {}", problem);
+            self.load_issues.push(Issue { severity: Severity::Warning, file: file.to_string(), row: None, problem });
+            renamed
+        } else {
+            name
+        };
+        self.insert_sparse(&name, layer);
+    }
+
+    fn insert_sparse(&mut self, name: &str, layer: CsMat<f32>) {
+        self.dense_layers.remove(name);
+        self.layers.insert(name.to_string(), layer);
+    }
+
+    fn insert_dense(&mut self, name: &str, layer: DenseLayer) {
+        self.layers.remove(name);
+        self.dense_layers.insert(name.to_string(), layer);
+    }
+
+    /// The counts with every cell scaled to `target` total counts (default: the median total).
+    fn library_size_layer(&self, target: Option<f32>) -> Result<CsMat<f32>, String> {
+        let totals = self.library_sizes()?;
+        let target = target.unwrap_or_else(|| {
+            let mut non_empty: Vec<f32> = totals.iter().copied().filter(|t| *t > 0.0).collect();
+            median(&mut non_empty)
+        });
+        let factors: Vec<f32> = totals.iter().map(|t| if *t > 0.0 { target / t } else { 0.0 }).collect();
+        self.map_sparse(RAW_LAYER, |c, x| x * factors[c])
+    }
+
+    fn log1p_layer(&self, source: &str) -> Result<CsMat<f32>, String> {
+        if self.dense_layers.contains_key(source) {
+            return Err(format!("❌ log1p needs a sparse layer - '{}' is dense", source));
+        }
+        self.map_sparse(source, |_, x| x.ln_1p())
+    }
+
+    /// A sparse layer with `f(cell, value)` applied to the stored values of `source`.
+    fn map_sparse(&self, source: &str, f: impl Fn(usize, f32) -> f32) -> Result<CsMat<f32>, String> {
+        let mut indptr = vec![0];
+        let (mut indices, mut data) = (Vec::new(), Vec::new());
+        self.for_each_layer_gene(source, |_, row| {
+            row.for_each(|c, x| {
+                indices.push(c);
+                data.push(f(c, x));
+            });
+            indptr.push(indices.len());
+        })?;
+        Ok(CsMat::new((self.gene_names.len(), self.cell_names.len()), indptr, indices, data))
+    }
+
+    /// (x - mu) / sqrt(mu + mu² / theta) with mu = cell total × gene total / grand total.
+    fn pearson_residuals(&self, genes: &[usize], theta: f32) -> Result<DenseLayer, String> {
+        if theta <= 0.0 {
+            return Err(format!("❌ theta must be positive (got {})", theta));
+        }
+        let n_cells = self.cell_names.len();
+        let cell_totals: Vec<f64> = self.library_sizes()?.iter().map(|t| *t as f64).collect();
+        let grand_total: f64 = cell_totals.iter().sum();
+        if grand_total <= 0.0 {
+            return Err("❌ All counts are zero".to_string());
+        }
+        let clip = (n_cells as f64).sqrt();
+        let theta = theta as f64;
+
+        let mut values = Array2::<f32>::zeros((genes.len(), n_cells));
+        for (r, &g) in genes.iter().enumerate() {
+            let counts = self.layer_gene_values(RAW_LAYER, g)?;
+            let gene_total: f64 = counts.iter().map(|x| *x as f64).sum();
+            if gene_total == 0.0 {
+                continue;
+            }
+            for (c, (out, x)) in values.row_mut(r).iter_mut().zip(&counts).enumerate() {
+                let mu = cell_totals[c] * gene_total / grand_total;
+                if mu > 0.0 {
+                    let residual = (*x as f64 - mu) / (mu + mu * mu / theta).sqrt();
+                    *out = residual.clamp(-clip, clip) as f32;
+                }
+            }
+        }
+        Ok(DenseLayer { genes: genes.to_vec(), values })
+    }
+
+    /// Per gene z-scores of `source` (genes without variance are 0).
+    fn scaled_layer(&self, source: &str, genes: &[usize], max_value: Option<f32>) -> Result<DenseLayer, String> {
+        let n_cells = self.cell_names.len();
+        let mut values = Array2::<f32>::zeros((genes.len(), n_cells));
+        for (r, &g) in genes.iter().enumerate() {
+            let x = self.layer_gene_values(source, g)?;
+            let n = n_cells.max(2) as f64;
+            let mean = x.iter().map(|v| *v as f64).sum::<f64>() / n_cells.max(1) as f64;
+            let var = x.iter().map(|v| (*v as f64 - mean).powi(2)).sum::<f64>() / (n - 1.0);
+            if var <= 0.0 {
+                continue;
+            }
+            let sd = var.sqrt();
+            let max = max_value.map(|m| m as f64).unwrap_or(f64::INFINITY);
+            for (out, v) in values.row_mut(r).iter_mut().zip(&x) {
+                *out = ((*v as f64 - mean) / sd).clamp(-max, max) as f32;
+            }
+        }
+        Ok(DenseLayer { genes: genes.to_vec(), values })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_store::data_store::test_store;
+    use sprs::TriMat;
+
+    #[test]
+    fn normalized_layers() {
+        // 2 genes × 3 cells with library sizes 2, 4 and 0
+        let mut tri = TriMat::<f32>::new((2, 3));
+        tri.add_triplet(0, 0, 1.0);
+        tri.add_triplet(1, 0, 1.0);
+        tri.add_triplet(0, 1, 4.0);
+        let genes = vec!["A".to_string(), "B".to_string()];
+        let cells: Vec<String> = ["c1", "c2", "c3"].iter().map(|s| s.to_string()).collect();
+        let mut ds = test_store(tri.to_csr(), genes, cells);
+
+        ds.add_layer("cpm", &Normalization::parse("cpm").unwrap(), None).unwrap();
+        assert_eq!(ds.layer_values("cpm", "A").unwrap(), vec![5e5, 1e6, 0.0]);
+        ds.add_layer("norm", &Normalization::LibrarySize(None), None).unwrap();
+        ds.add_layer("log", &Normalization::Log1p("norm".to_string()), None).unwrap();
+        // median library size 3: 1 * 3/2
+        assert!((ds.layer_values("log", "B").unwrap()[0] - 2.5f32.ln()).abs() < 1e-6);
+
+        ds.add_layer("pr", &Normalization::PearsonResiduals { theta: 100.0 }, Some(&[1])).unwrap();
+        assert_eq!(ds.layer_genes("pr"), Some(&[1][..]));
+        let mu = 2.0 * 1.0 / 6.0f64;
+        let expected = (1.0 - mu) / (mu + mu * mu / 100.0).sqrt();
+        assert!((ds.layer_values("pr", "B").unwrap()[0] as f64 - expected).abs() < 1e-5);
+        assert!(ds.layer_values("pr", "A").is_err());
+
+        ds.add_layer("z", &Normalization::parse("scaled:log").unwrap(), Some(&[0])).unwrap();
+        let z = ds.layer_gene_values("z", 0).unwrap();
+        assert!(z.iter().sum::<f32>().abs() < 1e-5);
+        assert_eq!(ds.layer_names(), vec!["counts", "cpm", "log", "norm", "pr", "z"]);
+
+        // a loaded layer must not be hidden by the raw counts
+        let spliced = ds.layers["cpm"].clone();
+        ds.insert_loaded_layer(RAW_LAYER.to_string(), spliced.clone(), "x.loom");
+        assert_eq!(ds.layers.get("counts_loaded"), Some(&spliced));
+        assert_eq!(ds.layer_values("counts", "A").unwrap(), vec![1.0, 4.0, 0.0]);
+        assert_eq!(ds.load_issues.len(), 1);
+    }
+}
@@ -0,0 +1,106 @@
+//orientation.rs
+//! `counts` is always genes × cells and stored CSR (fast per gene access).
+//! The CSC copy (fast per cell access) is built on first use.
+use crate::data_store::DataStore;
+use sprs::{CsMat, CsVec};
+
+/// Bring a loaded matrix into genes × cells orientation.
+///
+/// Returns the CSR matrix plus the CSC copy if it was available for free
+/// (the matrix was stored CSC or had to be transposed).
+pub(crate) fn orient(
+    counts: CsMat<f32>,
+    n_genes: usize,
+    n_cells: usize,
+) -> Result<(CsMat<f32>, Option<CsMat<f32>>), String> {
+    let shape = counts.shape();
+    let counts = if shape == (n_genes, n_cells) {
+        counts
+    } else if shape == (n_cells, n_genes) {
+        println!("🔄 matrix is cells × genes ({} × {}) - transposing", shape.0, shape.1);
+        counts.transpose_into()
+    } else {
+        return Err(format!(
+            "❌ Matrix shape {:?} fits neither {} genes × {} cells nor the transposed layout",
+            shape, n_genes, n_cells
+        ));
+    };
+    if counts.is_csr() {
+        Ok((counts, None))
+    } else {
+        Ok((counts.to_csr(), Some(counts)))
+    }
+}
+
+impl DataStore {
+    /// The counts as CSC (genes × cells), built from `counts` on first use.
+    pub fn counts_csc(&self) -> &CsMat<f32> {
+        self.counts_csc.get_or_init(|| self.counts.to_csc())
+    }
+
+    /// The expression of all genes in one cell (sparse, indexed by gene).
+    /// Out-of-core counts are stored per gene, so this reads the whole matrix there.
+    pub fn cell_vector(&self, cell: usize) -> Result<Option<CsVec<f32>>, String> {
+        if let Some(on_disk) = &self.out_of_core {
+            if cell >= self.cell_names.len() {
+                return Ok(None);
+            }
+            let (mut genes, mut values) = (Vec::new(), Vec::new());
+            on_disk.for_each_gene(|g, v| {
+                if let Ok(i) = v.indices().binary_search(&cell) {
+                    genes.push(g);
+                    values.push(v.data()[i]);
+                }
+            })?;
+            return Ok(Some(CsVec::new(self.gene_names.len(), genes, values)));
+        }
+        Ok(self.counts_csc().outer_view(cell).map(|v| v.to_owned()))
+    }
+
+    /// The expression of one gene in all cells (sparse, indexed by cell).
+    /// Reading a corrupt out-of-core file is an error.
+    pub fn gene_vector(&self, gene: usize) -> Result<Option<CsVec<f32>>, String> {
+        if let Some(on_disk) = &self.out_of_core {
+            return on_disk.gene(gene);
+        }
+        Ok(self.counts.outer_view(gene).map(|v| v.to_owned()))
+    }
+
+    /// Replace `counts` (genes × cells) and drop the CSC copy of the old matrix
+    /// (and the out-of-core file the counts were read from).
+    pub fn set_counts(&mut self, counts: CsMat<f32>) -> Result<(), String> {
+        let (csr, csc) = orient(counts, self.gene_names.len(), self.cell_names.len())?;
+        self.counts = csr;
+        self.out_of_core = None;
+        self.counts_csc = csc.map(Into::into).unwrap_or_default();
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_store::data_store::test_store;
+    use sprs::TriMat;
+
+    #[test]
+    fn cells_by_genes_input_is_transposed() {
+        // 2 cells × 3 genes
+        let mut tri = TriMat::<f32>::new((2, 3));
+        tri.add_triplet(0, 2, 4.0);
+        tri.add_triplet(1, 0, 1.0);
+        let ds = test_store(tri.to_csr(), vec!["g1".into(), "g2".into(), "g3".into()], vec!["c1".into(), "c2".into()]);
+
+        assert_eq!(ds.counts.shape(), (3, 2));
+        assert!(ds.counts.is_csr());
+        let cell = ds.cell_vector(0).unwrap().unwrap();
+        assert_eq!(cell.indices(), &[2]);
+        assert_eq!(cell.data(), &[4.0]);
+        let gene = ds.gene_vector(0).unwrap().unwrap();
+        assert_eq!(gene.indices(), &[1]);
+
+        let wrong = TriMat::<f32>::new((4, 4)).to_csr();
+        assert!(orient(wrong, 3, 2).is_err());
+    }
+}
@@ -0,0 +1,745 @@
+//out_of_core.rs
+//! Expression matrices too large for the RAM of the VR machine.
+//!
+//! The counts are written once into `counts.pf3dgenes` next to the other dataset files:
+//! one sparse column per gene (the cells it is expressed in), grouped into chunks of
+//! `CHUNK_GENES` genes. Opening the file only reads the names and the column offsets;
+//! gene columns are read chunk wise on demand and the last `CACHE_CHUNKS` chunks are kept
+//! in an LRU cache. Metadata and projections are loaded into memory as usual.
+//!
+//! The file can be written from a loaded dataset or converted straight from a
+//! MatrixMarket file that does not fit into memory (see `convert_to_out_of_core`).
+//! Its header stamps the matrix, feature and barcode files it was made from; a file
+//! whose sources changed since is converted again on load.
+use crate::data_store::DataStore;
+use crate::data_store::cache::{file_stamps, CacheReader, CacheWriter, SourceStamp};
+use crate::data_store::data_store::read_barcodes;
+use crate::data_store::doctor::{Issue, Severity};
+use crate::data_store::genes::{feature_type, read_feature_records};
+use crate::data_store::manifest::{DatasetManifest, MANIFEST_FILE};
+use crate::data_store::modality::primary_feature_type;
+use crate::data_store::mtx_reader::{stream_matrix_market, MtxProgress, MtxShape, MtxSink};
+use rayon::prelude::*;
+use memmap2::Mmap;
+use rust_data_table::SurvivalData;
+use sprs::{CsMat, CsVec, CsVecView};
+use std::collections::HashMap;
+use std::cell::Cell;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// File name of the out-of-core matrix inside the dataset folder.
+pub const OUT_OF_CORE_FILE: &str = "counts.pf3dgenes";
+const MAGIC: &[u8; 8] = b"PF3DGENE";
+const VERSION: u32 = 2;
+/// Genes read from disk at once.
+const CHUNK_GENES: usize = 64;
+/// Chunks kept in memory (CACHE_CHUNKS × CHUNK_GENES gene columns).
+const CACHE_CHUNKS: usize = 256;
+/// Entries collected in memory per pass when converting a MatrixMarket file (8 bytes each).
+const CONVERT_BATCH_ENTRIES: usize = 64 << 20;
+
+type Chunk = Arc<Vec<CsVec<f32>>>;
+
+/// A least recently used cache of decoded chunks.
+struct ChunkCache {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<usize, (u64, Chunk)>,
+}
+
+impl ChunkCache {
+    fn new(capacity: usize) -> Self {
+        Self { capacity, tick: 0, entries: HashMap::new() }
+    }
+
+    fn get(&mut self, chunk: usize) -> Option<Chunk> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.entries.get_mut(&chunk).map(|(used, data)| {
+            *used = tick;
+            data.clone()
+        })
+    }
+
+    fn insert(&mut self, chunk: usize, data: Chunk) {
+        if self.entries.len() >= self.capacity {
+            let oldest = self.entries.iter().min_by_key(|(_, (used, _))| *used).map(|(k, _)| *k);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        self.entries.insert(chunk, (self.tick, data));
+    }
+}
+
+/// The genes × cells counts of a dataset, stored gene wise on disk.
+pub struct OutOfCoreCounts {
+    path: PathBuf,
+    mmap: Mmap,
+    n_cells: usize,
+    /// start of each gene column (n_genes + 1 entries, in values)
+    gene_ptr: Vec<usize>,
+    /// byte offsets of the cell indices (u32) and values (f32) blocks
+    indices_at: usize,
+    values_at: usize,
+    /// the source files the matrix was written from
+    sources: Vec<SourceStamp>,
+    cache: Mutex<ChunkCache>,
+}
+
+impl fmt::Debug for OutOfCoreCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "OutOfCoreCounts({:?}, {} genes × {} cells)", self.path, self.n_genes(), self.n_cells)
+    }
+}
+
+impl OutOfCoreCounts {
+    /// Write a genes × cells matrix (CSR, one row per gene) with its names and the
+    /// stamps of the files it was read from.
+    pub fn write(
+        path: &Path,
+        counts: &CsMat<f32>,
+        gene_names: &[String],
+        cell_names: &[String],
+        sources: &[SourceStamp],
+    ) -> Result<(), String> {
+        let csr = if counts.is_csr() { counts.view() } else { return Err("❌ counts need to be CSR".into()) };
+        let tmp = path.with_extension("tmp");
+        let f = File::create(&tmp).map_err(|e| format!("❌ Failed to create {:?}: {}", tmp, e))?;
+        let mut w = CacheWriter { out: BufWriter::new(f) };
+        w.bytes(MAGIC)?;
+        w.u32(VERSION)?;
+        w.u64(CHUNK_GENES as u64)?;
+        w.stamps(sources)?;
+        w.strings(gene_names)?;
+        w.strings(cell_names)?;
+        w.usizes(&csr.proper_indptr())?;
+        w.u64(csr.nnz() as u64)?;
+        for &i in csr.indices() {
+            w.u32(i as u32)?;
+        }
+        w.f32s(csr.data())?;
+        w.out.flush().map_err(|e| format!("❌ Failed to write {:?}: {}", tmp, e))?;
+        drop(w);
+        fs::rename(&tmp, path).map_err(|e| format!("❌ Failed to write {:?}: {}", path, e))
+    }
+
+    /// Convert a MatrixMarket file without loading it: a first pass counts the entries
+    /// of every gene, then each further pass collects the columns of as many whole chunks
+    /// as fit into `max_entries` entries. `gene_of` maps the features of the file
+    /// to the genes written (features without one are skipped); the file may list
+    /// genes × cells or cells × genes. `progress` gets the fraction of all passes.
+    #[allow(clippy::too_many_arguments)]
+    pub fn write_from_mtx(
+        path: &Path,
+        matrix: &Path,
+        gene_of: &[Option<u32>],
+        gene_names: &[String],
+        cell_names: &[String],
+        sources: &[SourceStamp],
+        max_entries: usize,
+        progress: &dyn Fn(f32),
+    ) -> Result<(), String> {
+        let mut sink = GeneColumns::new(gene_of, gene_names.len(), cell_names.len());
+        let n_passes = Cell::new(2usize);
+        let pass = |i: usize| {
+            let n_passes = &n_passes;
+            move |p: &MtxProgress| progress((i as f32 + p.fraction()) / n_passes.get() as f32)
+        };
+        stream_matrix_market(matrix, &pass(0), &mut sink)?;
+        if sink.genes_are_rows.is_none() {
+            return Err(format!(
+                "❌ Matrix shape {:?} fits neither {} features × {} cells nor the transposed layout ({:?})",
+                sink.shape.map(|s| (s.rows, s.cols)),
+                gene_of.len(),
+                cell_names.len(),
+                matrix
+            ));
+        }
+
+        // genes first..last per pass, in whole chunks
+        let mut batches = Vec::new();
+        let mut first = 0;
+        let mut entries = 0;
+        for chunk in 0..gene_names.len().div_ceil(CHUNK_GENES) {
+            let genes = chunk * CHUNK_GENES..((chunk + 1) * CHUNK_GENES).min(gene_names.len());
+            let n: usize = sink.counts[genes.clone()].iter().sum();
+            if entries > 0 && entries + n > max_entries {
+                batches.push(first..genes.start);
+                first = genes.start;
+                entries = 0;
+            }
+            entries += n;
+        }
+        batches.push(first..gene_names.len());
+        n_passes.set(1 + batches.len());
+
+        let indices_tmp = path.with_extension("indices.tmp");
+        let values_tmp = path.with_extension("values.tmp");
+        let create = |p: &Path| {
+            File::create(p).map(BufWriter::new).map_err(|e| format!("❌ Failed to create {:?}: {}", p, e))
+        };
+        let (mut indices_out, mut values_out) = (create(&indices_tmp)?, create(&values_tmp)?);
+        let write_err = |e: io::Error| format!("❌ Failed to write {:?}: {}", path, e);
+        let mut gene_ptr = vec![0usize];
+        for (i, genes) in batches.into_iter().enumerate() {
+            sink.collect(genes.clone());
+            stream_matrix_market(matrix, &pass(i + 1), &mut sink)?;
+            sink.columns.par_iter_mut().for_each(merge_duplicates);
+            for col in &sink.columns {
+                for &(cell, value) in col {
+                    indices_out.write_all(&cell.to_le_bytes()).map_err(write_err)?;
+                    values_out.write_all(&value.to_le_bytes()).map_err(write_err)?;
+                }
+                gene_ptr.push(gene_ptr[gene_ptr.len() - 1] + col.len());
+            }
+            println!("📦 {:?}: genes {}..{} of {} converted", path, genes.start, genes.end, gene_names.len());
+        }
+        indices_out.flush().map_err(write_err)?;
+        values_out.flush().map_err(write_err)?;
+        drop((indices_out, values_out));
+
+        let nnz = gene_ptr[gene_ptr.len() - 1];
+        let tmp = path.with_extension("tmp");
+        let f = File::create(&tmp).map_err(|e| format!("❌ Failed to create {:?}: {}", tmp, e))?;
+        let mut w = CacheWriter { out: BufWriter::new(f) };
+        w.bytes(MAGIC)?;
+        w.u32(VERSION)?;
+        w.u64(CHUNK_GENES as u64)?;
+        w.stamps(sources)?;
+        w.strings(gene_names)?;
+        w.strings(cell_names)?;
+        w.usizes(&gene_ptr)?;
+        w.u64(nnz as u64)?;
+        let append = |w: &mut CacheWriter, part: &Path| -> Result<(), String> {
+            let mut f = File::open(part).map_err(|e| format!("❌ Failed to open {:?}: {}", part, e))?;
+            io::copy(&mut f, &mut w.out).map_err(write_err)?;
+            Ok(())
+        };
+        append(&mut w, &indices_tmp)?;
+        w.u64(nnz as u64)?;
+        append(&mut w, &values_tmp)?;
+        w.out.flush().map_err(|e| format!("❌ Failed to write {:?}: {}", tmp, e))?;
+        drop(w);
+        let _ = fs::remove_file(&indices_tmp);
+        let _ = fs::remove_file(&values_tmp);
+        fs::rename(&tmp, path).map_err(|e| format!("❌ Failed to write {:?}: {}", path, e))
+    }
+
+    /// Open the file and read its header; returns the gene and cell names as well.
+    pub fn open(path: &Path) -> Result<(Self, Vec<String>, Vec<String>), String> {
+        let file = File::open(path).map_err(|e| format!("❌ Failed to open {:?}: {}", path, e))?;
+        // SAFETY: the file is only written through a temporary file + rename
+        let mmap = unsafe { Mmap::map(&file) }.map_err(|e| format!("❌ Failed to map {:?}: {}", path, e))?;
+        let invalid = |e: String| format!("❌ {:?} is not a valid out-of-core matrix: {}", path, e);
+
+        let mut r = CacheReader::new(&mmap[..]);
+        if r.bytes(MAGIC.len()).map_err(invalid)? != MAGIC || r.u32().map_err(invalid)? != VERSION {
+            return Err(invalid("unknown format".to_string()));
+        }
+        if r.u64().map_err(invalid)? as usize != CHUNK_GENES {
+            return Err(invalid("other chunk size".to_string()));
+        }
+        let sources = r.stamps().map_err(invalid)?;
+        let gene_names = r.strings().map_err(invalid)?;
+        let cell_names = r.strings().map_err(invalid)?;
+        let gene_ptr = r.usizes().map_err(invalid)?;
+        if gene_ptr.len() != gene_names.len() + 1 {
+            return Err(invalid("gene offsets do not fit the gene names".to_string()));
+        }
+        let nnz = r.u64().map_err(invalid)? as usize;
+        if gene_ptr[0] != 0 || gene_ptr.windows(2).any(|w| w[0] > w[1]) || gene_ptr[gene_ptr.len() - 1] != nnz {
+            return Err(invalid("gene offsets are not increasing up to the number of values".to_string()));
+        }
+        let block = nnz.checked_mul(4).ok_or_else(|| invalid("too many values".to_string()))?;
+        let indices_at = r.pos;
+        r.skip(block).map_err(invalid)?;
+        if r.u64().map_err(invalid)? as usize != nnz {
+            return Err(invalid("values do not fit the indices".to_string()));
+        }
+        let values_at = r.pos;
+        r.skip(block).map_err(invalid)?;
+
+        let n_cells = cell_names.len();
+        let ret = Self {
+            path: path.to_path_buf(),
+            mmap,
+            n_cells,
+            gene_ptr,
+            indices_at,
+            values_at,
+            sources,
+            cache: Mutex::new(ChunkCache::new(CACHE_CHUNKS)),
+        };
+        Ok((ret, gene_names, cell_names))
+    }
+
+    pub fn n_genes(&self) -> usize {
+        self.gene_ptr.len() - 1
+    }
+
+    /// Decode the gene columns of one chunk from the file.
+    /// A gene with invalid cell indices (a corrupt file) is an error.
+    fn read_chunk(&self, chunk: usize) -> Result<Vec<CsVec<f32>>, String> {
+        let first = chunk * CHUNK_GENES;
+        let last = (first + CHUNK_GENES).min(self.n_genes());
+        (first..last)
+            .map(|g| {
+                let (start, end) = (self.gene_ptr[g], self.gene_ptr[g + 1]);
+                let indices = self.mmap[self.indices_at + start * 4..self.indices_at + end * 4]
+                    .chunks_exact(4)
+                    .map(|c| u32::from_le_bytes(c.try_into().unwrap()) as usize)
+                    .collect();
+                let values = self.mmap[self.values_at + start * 4..self.values_at + end * 4]
+                    .chunks_exact(4)
+                    .map(|c| f32::from_le_bytes(c.try_into().unwrap()))
+                    .collect();
+                CsVec::try_new(self.n_cells, indices, values).map_err(|_| {
+                    format!("❌ {:?}: gene {} has invalid cell indices - the file is corrupt", self.path, g)
+                })
+            })
+            .collect()
+    }
+
+    /// The expression of one gene in all cells (through the chunk cache).
+    pub fn gene(&self, gene: usize) -> Result<Option<CsVec<f32>>, String> {
+        if gene >= self.n_genes() {
+            return Ok(None);
+        }
+        let chunk = gene / CHUNK_GENES;
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        let data = match cache.get(chunk) {
+            Some(data) => data,
+            None => {
+                let data = Arc::new(self.read_chunk(chunk)?);
+                cache.insert(chunk, data.clone());
+                data
+            }
+        };
+        Ok(Some(data[gene % CHUNK_GENES].clone()))
+    }
+
+    /// Visit all genes in order without filling the cache (for whole matrix passes).
+    pub fn for_each_gene(&self, mut f: impl FnMut(usize, CsVecView<f32>)) -> Result<(), String> {
+        for chunk in 0..self.n_genes().div_ceil(CHUNK_GENES) {
+            for (i, v) in self.read_chunk(chunk)?.iter().enumerate() {
+                f(chunk * CHUNK_GENES + i, v.view());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Stamp the files a `counts.pf3dgenes` is converted from: the matrix, its feature
+/// and barcode files and `dataset.toml` (which picks the modality written).
+fn matrix_stamps(dir: &Path) -> Vec<SourceStamp> {
+    // an unreadable manifest is reported by the loader itself
+    let files = DatasetManifest::load(dir).unwrap_or_default().resolve(dir);
+    let manifest = dir.join(MANIFEST_FILE);
+    file_stamps(dir, &[files.matrix.as_path(), files.features.as_path(), files.barcodes.as_path(), manifest.as_path()])
+}
+
+/// Sorts the entries of a gene by cell and sums duplicates (like `MtxTriplets::to_csr`).
+fn merge_duplicates(col: &mut Vec<(u32, f32)>) {
+    col.sort_unstable_by_key(|e| e.0);
+    col.dedup_by(|e, kept| {
+        let same = e.0 == kept.0;
+        if same {
+            kept.1 += e.1;
+        }
+        same
+    });
+}
+
+/// Collects the MatrixMarket entries of some genes (and counts those of all genes).
+struct GeneColumns<'a> {
+    gene_of: &'a [Option<u32>],
+    n_cells: usize,
+    shape: Option<MtxShape>,
+    genes_are_rows: Option<bool>,
+    counts: Vec<usize>,
+    /// the entries (cell, value) of the genes first..first + columns.len()
+    first: usize,
+    columns: Vec<Vec<(u32, f32)>>,
+}
+
+impl<'a> GeneColumns<'a> {
+    fn new(gene_of: &'a [Option<u32>], n_genes: usize, n_cells: usize) -> Self {
+        Self { gene_of, n_cells, shape: None, genes_are_rows: None, counts: vec![0; n_genes], first: 0, columns: Vec::new() }
+    }
+
+    /// Collect the entries of `genes` in the next pass.
+    fn collect(&mut self, genes: std::ops::Range<usize>) {
+        self.first = genes.start;
+        self.columns = genes.map(|g| Vec::with_capacity(self.counts[g])).collect();
+        self.counts.iter_mut().for_each(|c| *c = 0);
+    }
+}
+
+impl MtxSink for GeneColumns<'_> {
+    fn shape(&mut self, shape: MtxShape) {
+        let n_features = self.gene_of.len();
+        self.shape = Some(shape);
+        self.genes_are_rows = if (shape.rows, shape.cols) == (n_features, self.n_cells) {
+            Some(true)
+        } else if (shape.rows, shape.cols) == (self.n_cells, n_features) {
+            println!("🔄 matrix is cells × genes ({} × {}) - transposing", shape.rows, shape.cols);
+            Some(false)
+        } else {
+            None
+        };
+    }
+
+    fn entries(&mut self, rows: &[u32], cols: &[u32], vals: &[f32]) {
+        let Some(genes_are_rows) = self.genes_are_rows else { return };
+        let (features, cells) = if genes_are_rows { (rows, cols) } else { (cols, rows) };
+        for ((&feature, &cell), &value) in features.iter().zip(cells).zip(vals) {
+            let Some(gene) = self.gene_of[feature as usize] else { continue };
+            let gene = gene as usize;
+            self.counts[gene] += 1;
+            if let Some(col) = gene.checked_sub(self.first).and_then(|i| self.columns.get_mut(i)) {
+                col.push((cell, value));
+            }
+        }
+    }
+}
+
+impl DataStore {
+    /// Convert the matrix of a dataset folder (features, barcodes and `matrix.mtx(.gz)`,
+    /// or the files named in its `dataset.toml`) into `<dir>/counts.pf3dgenes` without
+    /// loading it into memory. Only the primary modality is written (as `write_out_of_core` does).
+    pub fn convert_to_out_of_core(dir: &Path, progress: &dyn Fn(f32)) -> Result<PathBuf, String> {
+        let manifest = DatasetManifest::load(dir)?;
+        let files = manifest.resolve(dir);
+        let features = read_feature_records(&files.features)?;
+        let types: Vec<&str> = features.iter().map(|r| feature_type(r)).collect();
+        let primary = manifest.modality.as_deref().unwrap_or_else(|| primary_feature_type(&types));
+
+        let mut gene_names = Vec::new();
+        let gene_of: Vec<Option<u32>> = features
+            .iter()
+            .zip(&types)
+            .map(|(r, t)| {
+                (*t == primary).then(|| {
+                    gene_names.push(r.get(1).unwrap_or(&r[0]).clone());
+                    (gene_names.len() - 1) as u32
+                })
+            })
+            .collect();
+        if gene_names.is_empty() {
+            return Err(format!("❌ No '{}' features found in {:?}", primary, files.features));
+        }
+        let cell_names = read_barcodes(&files.barcodes)?;
+
+        // stamped before reading, so a file changed during the conversion counts as changed
+        let sources = matrix_stamps(dir);
+        let path = dir.join(OUT_OF_CORE_FILE);
+        OutOfCoreCounts::write_from_mtx(
+            &path,
+            &files.matrix,
+            &gene_of,
+            &gene_names,
+            &cell_names,
+            &sources,
+            CONVERT_BATCH_ENTRIES,
+            progress,
+        )?;
+        println!("💾 converted {} genes × {} cells to {:?}", gene_names.len(), cell_names.len(), path);
+        Ok(path)
+    }
+
+    /// Write `counts` into `<dir>/counts.pf3dgenes` so the dataset can later be
+    /// opened with `from_out_of_core` (only the primary modality is written).
+    pub fn write_out_of_core(&self, dir: &Path) -> Result<PathBuf, String> {
+        if self.is_out_of_core() {
+            return Err("❌ The dataset is already out of core".to_string());
+        }
+        let path = dir.join(OUT_OF_CORE_FILE);
+        OutOfCoreCounts::write(&path, &self.counts, &self.gene_names, &self.cell_names, &matrix_stamps(dir))?;
+        println!("💾 wrote {} genes × {} cells to {:?}", self.gene_names.len(), self.cell_names.len(), path);
+        Ok(path)
+    }
+
+    /// Open a dataset folder with a `counts.pf3dgenes` file. Only the names, `meta.tsv`
+    /// and the projections are read now - gene columns are read from disk when used.
+    ///
+    /// If the matrix, feature or barcode files changed since the file was written (or it
+    /// is unreadable), it is converted again from them and a load issue says so. Without
+    /// the matrix file there is nothing to convert from and the file is used as it is.
+    pub fn from_out_of_core<P: AsRef<Path>>(dir: P) -> Result<Self, String> {
+        Self::from_out_of_core_with_progress(dir, &|_| {})
+    }
+
+    /// `from_out_of_core` reporting the progress of a conversion (only called if the file is rebuilt).
+    pub fn from_out_of_core_with_progress<P: AsRef<Path>>(dir: P, progress: &dyn Fn(f32)) -> Result<Self, String> {
+        let dir = dir.as_ref();
+        let manifest = DatasetManifest::load(dir)?;
+        let files = manifest.resolve(dir);
+        let path = dir.join(OUT_OF_CORE_FILE);
+        let can_rebuild = files.matrix.is_file();
+        let opened = match OutOfCoreCounts::open(&path) {
+            Ok(opened) if !can_rebuild || opened.0.sources == matrix_stamps(dir) => Ok(opened),
+            Ok(_) => Err("the matrix, feature or barcode files changed since it was written".to_string()),
+            Err(e) => Err(e),
+        };
+        let mut rebuilt = None;
+        let (on_disk, gene_names, cell_names) = match opened {
+            Ok(opened) => opened,
+            Err(e) if can_rebuild => {
+                println!("♻️ Converting {:?} again: {}", path, e);
+                Self::convert_to_out_of_core(dir, progress)?;
+                rebuilt = Some(format!("converted again from {:?}: {}", files.matrix, e));
+                OutOfCoreCounts::open(&path)?
+            }
+            Err(e) => return Err(e),
+        };
+
+        let (cell_meta, factor_numbers) = Self::load_cell_meta_from(&files.meta, &files.meta_factors, &cell_names)?;
+        let empty = CsMat::zero((gene_names.len(), cell_names.len()));
+        let mut ret = Self::from_parts(empty, gene_names, cell_names, cell_meta, SurvivalData::default(), HashMap::new())?;
+        ret.factor_numbers = factor_numbers;
+        ret.out_of_core = Some(on_disk);
+        ret.load_projection_files(&files.projections);
+        // the file holds the primary modality only, picked as convert_to_out_of_core does
+        let gene_meta = read_feature_records(&files.features).and_then(|features| {
+            let types: Vec<&str> = features.iter().map(|r| feature_type(r)).collect();
+            let primary = manifest.modality.as_deref().unwrap_or_else(|| primary_feature_type(&types));
+            ret.primary_modality = primary.to_string();
+            ret.set_gene_table_from_features(&features)
+        });
+        if let Err(e) = gene_meta {
+            println!("⚠️ gene meta not loaded: {}", e);
+        }
+        ret.load_gene_annotation_file(files.gene_annotation.as_deref());
+        ret.manifest = manifest;
+        if let Some(problem) = rebuilt {
+            ret.load_issues.push(Issue {
+                severity: Severity::Warning,
+                file: OUT_OF_CORE_FILE.to_string(),
+                row: None,
+                problem,
+            });
+        }
+        println!(
+            "🗃️ out-of-core dataset {:?}: {} genes × {} cells",
+            dir,
+            ret.gene_names.len(),
+            ret.cell_names.len()
+        );
+        Ok(ret)
+    }
+
+    /// Are the counts read from disk on demand (`counts` is empty then)?
+    pub fn is_out_of_core(&self) -> bool {
+        self.out_of_core.is_some()
+    }
+
+    /// Visit the (sparse, indexed by cell) values of every feature of a modality in order.
+    /// Works the same for in-memory and out-of-core counts.
+    pub fn for_each_feature(&self, modality: &str, mut f: impl FnMut(usize, CsVecView<f32>)) -> Result<(), String> {
+        let (mat, _) = self
+            .modality(modality)
+            .ok_or_else(|| format!("Modality '{}' not found", modality))?;
+        match &self.out_of_core {
+            Some(on_disk) if std::ptr::eq(mat, &self.counts) => on_disk.for_each_gene(f)?,
+            _ => {
+                for (g, row) in mat.outer_iterator().enumerate() {
+                    f(g, row);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sprs::TriMat;
+
+    #[test]
+    fn genes_are_read_back_through_the_cache() {
+        let n_genes = CHUNK_GENES + 3;
+        let mut tri = TriMat::<f32>::new((n_genes, 4));
+        for g in 0..n_genes {
+            tri.add_triplet(g, g % 4, g as f32 + 1.0);
+        }
+        let counts = tri.to_csr();
+        let genes: Vec<String> = (0..n_genes).map(|g| format!("g{}", g)).collect();
+        let cells: Vec<String> = (0..4).map(|c| format!("c{}", c)).collect();
+
+        let path = std::env::temp_dir().join(format!("printforge3d_ooc_{}.pf3dgenes", std::process::id()));
+        OutOfCoreCounts::write(&path, &counts, &genes, &cells, &[]).unwrap();
+        let (on_disk, gene_names, cell_names) = OutOfCoreCounts::open(&path).unwrap();
+        assert_eq!(gene_names, genes);
+        assert_eq!(cell_names, cells);
+
+        let last = on_disk.gene(n_genes - 1).unwrap().unwrap();
+        assert_eq!(last.indices(), &[(n_genes - 1) % 4]);
+        assert_eq!(last.data(), &[n_genes as f32]);
+        assert_eq!(on_disk.gene(1).unwrap().unwrap().data(), &[2.0]);
+        assert!(on_disk.gene(n_genes).unwrap().is_none());
+
+        let mut total = 0.0;
+        on_disk.for_each_gene(|_, v| total += v.data().iter().sum::<f32>()).unwrap();
+        assert_eq!(total, counts.data().iter().sum::<f32>());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn corrupt_cell_indices_are_errors() {
+        let mut tri = TriMat::<f32>::new((2, 3));
+        tri.add_triplet(0, 1, 1.0);
+        tri.add_triplet(1, 2, 2.0);
+        let genes = vec!["a".to_string(), "b".to_string()];
+        let cells: Vec<String> = (0..3).map(|c| format!("c{}", c)).collect();
+        let path = std::env::temp_dir().join(format!("printforge3d_ooc_cells_{}.pf3dgenes", std::process::id()));
+        OutOfCoreCounts::write(&path, &tri.to_csr(), &genes, &cells, &[]).unwrap();
+        let (on_disk, _, _) = OutOfCoreCounts::open(&path).unwrap();
+        let mut bad = fs::read(&path).unwrap();
+        // the cell of gene 'b' points past the 3 cells
+        let at = on_disk.indices_at + 4;
+        bad[at..at + 4].copy_from_slice(&7u32.to_le_bytes());
+        drop(on_disk);
+        fs::write(&path, &bad).unwrap();
+
+        let (on_disk, _, _) = OutOfCoreCounts::open(&path).unwrap();
+        assert!(on_disk.gene(1).unwrap_err().contains("gene 1 has invalid cell indices"));
+        assert!(on_disk.for_each_gene(|_, _| {}).is_err());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn mtx_conversion_matches_the_loaded_matrix() {
+        let dir = std::env::temp_dir().join(format!("printforge3d_ooc_mtx_{}", std::process::id()));
+        let loaded = dir.join("loaded");
+        fs::create_dir_all(&loaded).unwrap();
+
+        // 2 chunks of genes and 2 antibodies, written cells × features with a duplicate
+        let n_genes = CHUNK_GENES + 5;
+        let mut features = String::new();
+        for g in 0..n_genes {
+            features += &format!("ENSG{}\tG{}\tGene Expression\n", g, g);
+        }
+        features += "CD3\tCD3\tAntibody Capture\nCD4\tCD4\tAntibody Capture\n";
+        let barcodes = "AAA-1\nCCC-1\nGGG-1\n";
+        let mut entries = Vec::new();
+        for f in 0..n_genes + 2 {
+            entries.push(format!("{} {} {}", f % 3 + 1, f + 1, f + 1));
+        }
+        entries.push("2 7 1".to_string());
+        entries.push("1 7 2".to_string());
+        let mtx = format!(
+            "%%MatrixMarket matrix coordinate integer general\n3 {} {}\n{}\n",
+            n_genes + 2,
+            entries.len(),
+            entries.join("\n")
+        );
+        for d in [&dir, &loaded] {
+            fs::write(d.join("features.tsv"), &features).unwrap();
+            fs::write(d.join("barcodes.tsv"), barcodes).unwrap();
+            fs::write(d.join("matrix.mtx"), &mtx).unwrap();
+        }
+
+        let ds = DataStore::from_cellranger(&loaded).unwrap();
+        // stamped with the files of `dir`, as the conversions below are
+        let sources = matrix_stamps(&dir);
+        let expected_path = loaded.join(OUT_OF_CORE_FILE);
+        OutOfCoreCounts::write(&expected_path, &ds.counts, &ds.gene_names, &ds.cell_names, &sources).unwrap();
+        let expected = fs::read(&expected_path).unwrap();
+
+        let files = DatasetManifest::default().resolve(&dir);
+        let features = read_feature_records(&files.features).unwrap();
+        let gene_of: Vec<Option<u32>> = (0..features.len()).map(|f| (f < n_genes).then_some(f as u32)).collect();
+        let path = dir.join(OUT_OF_CORE_FILE);
+        // a few entries per pass, so the genes are collected in several passes
+        let fractions = Mutex::new(Vec::new());
+        OutOfCoreCounts::write_from_mtx(
+            &path,
+            &files.matrix,
+            &gene_of,
+            &ds.gene_names,
+            &ds.cell_names,
+            &sources,
+            10,
+            &|f| fractions.lock().unwrap().push(f),
+        )
+        .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), expected);
+        assert_eq!(fractions.into_inner().unwrap().last(), Some(&1.0));
+
+        assert_eq!(DataStore::convert_to_out_of_core(&dir, &|_| {}).unwrap(), path);
+        assert_eq!(fs::read(&path).unwrap(), expected);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn changed_sources_are_converted_again() {
+        let dir = std::env::temp_dir().join(format!("printforge3d_ooc_stale_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("features.tsv"), "ENSG1\tG1\tGene Expression\nENSG2\tG2\tGene Expression\n").unwrap();
+        fs::write(dir.join("barcodes.tsv"), "AAA-1\nCCC-1\n").unwrap();
+        let mtx = |value: &str| format!("%%MatrixMarket matrix coordinate integer general\n2 2 1\n1 2 {}\n", value);
+        fs::write(dir.join("matrix.mtx"), mtx("5")).unwrap();
+        DataStore::convert_to_out_of_core(&dir, &|_| {}).unwrap();
+        let first_gene = |ds: &DataStore| ds.out_of_core.as_ref().unwrap().gene(0).unwrap().unwrap().data().to_vec();
+
+        let ds = DataStore::from_out_of_core(&dir).unwrap();
+        assert!(ds.load_issues.is_empty());
+        assert_eq!(first_gene(&ds), vec![5.0]);
+        // the gene meta comes from the features, so ids resolve as for in-memory loads
+        assert!(ds.gene_table.has_column("gene_id"));
+        assert_eq!(ds.gene_index("ENSG2"), Ok(1));
+
+        fs::write(dir.join("matrix.mtx"), mtx("17")).unwrap();
+        let ds = DataStore::from_out_of_core(&dir).unwrap();
+        assert_eq!(ds.load_issues.len(), 1);
+        assert_eq!(ds.load_issues[0].file, OUT_OF_CORE_FILE);
+        assert_eq!(first_gene(&ds), vec![17.0]);
+        drop(ds);
+        assert!(DataStore::from_out_of_core(&dir).unwrap().load_issues.is_empty());
+
+        // without the matrix there is nothing to convert from
+        fs::remove_file(dir.join("matrix.mtx")).unwrap();
+        let ds = DataStore::from_out_of_core(&dir).unwrap();
+        assert!(ds.load_issues.is_empty());
+        assert_eq!(first_gene(&ds), vec![17.0]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn corrupt_headers_are_rejected() {
+        let mut tri = TriMat::<f32>::new((2, 3));
+        tri.add_triplet(0, 1, 1.0);
+        tri.add_triplet(1, 2, 2.0);
+        let genes = vec!["a".to_string(), "b".to_string()];
+        let cells: Vec<String> = (0..3).map(|c| format!("c{}", c)).collect();
+        let path = std::env::temp_dir().join(format!("printforge3d_ooc_bad_{}.pf3dgenes", std::process::id()));
+        OutOfCoreCounts::write(&path, &tri.to_csr(), &genes, &cells, &[]).unwrap();
+        let good = fs::read(&path).unwrap();
+
+        // the gene offsets follow magic, version, chunk size, the (no) stamps, the names and their own length
+        let ptr_at = 8 + 4 + 8 + 8 + (8 + 2 * 9) + (8 + 3 * 10) + 8;
+        let mut bad = good.clone();
+        bad[ptr_at + 8..ptr_at + 16].copy_from_slice(&5u64.to_le_bytes());
+        fs::write(&path, &bad).unwrap();
+        assert!(OutOfCoreCounts::open(&path).unwrap_err().contains("gene offsets"));
+
+        let mut bad = good.clone();
+        let huge = (u64::MAX / 2).to_le_bytes();
+        bad[ptr_at + 16..ptr_at + 24].copy_from_slice(&huge);
+        bad[ptr_at + 24..ptr_at + 32].copy_from_slice(&huge);
+        fs::write(&path, &bad).unwrap();
+        assert!(OutOfCoreCounts::open(&path).unwrap_err().contains("too many values"));
+
+        fs::write(&path, &good[..good.len() - 3]).unwrap();
+        assert!(OutOfCoreCounts::open(&path).is_err());
+        let _ = fs::remove_file(&path);
+    }
+}
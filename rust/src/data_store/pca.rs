@@ -0,0 +1,433 @@
+//pca.rs
+//! Principal components of the (log normalized) expression of the highly variable genes.
+//!
+//! A randomized truncated SVD (Halko, Martinsson & Tropp 2011) of the sparse cells × genes
+//! matrix. The gene means are subtracted inside every product, so the centered matrix is
+//! never densified; the sparse and the tall dense products run in parallel with rayon.
+use crate::data_store::DataStore;
+use crate::data_store::meta_table::MetaColumn;
+use crate::data_store::normalization::{median, RAW_LAYER};
+use ndarray::{concatenate, s, Array1, Array2, ArrayView2, Axis};
+use rayon::prelude::*;
+use sprs::CsMat;
+
+/// Rows per rayon task in the tall dense products.
+const ROW_CHUNK: usize = 4096;
+
+/// Settings of `DataStore::run_pca`.
+#[derive(Debug, Clone)]
+pub struct PcaOptions {
+    pub n_components: usize,
+    /// the sparse layer to use; `None`: ln(1 + counts scaled to the median library size)
+    pub layer: Option<String>,
+    /// the genes to use; `None`: the highly variable genes (see `select_hvgs`)
+    pub genes: Option<Vec<usize>>,
+    /// the cells to use (e.g. a selection); the other cells get NaN scores
+    pub cells: Option<Vec<usize>>,
+    /// random vectors beyond `n_components` and power iterations of the randomized SVD
+    pub oversample: usize,
+    pub n_iter: usize,
+    pub seed: u64,
+}
+
+impl Default for PcaOptions {
+    fn default() -> Self {
+        Self { n_components: 50, layer: None, genes: None, cells: None, oversample: 10, n_iter: 4, seed: 0 }
+    }
+}
+
+/// The variance of every component (and its share of the total variance of the genes used).
+#[derive(Debug, Clone)]
+pub struct PcaResult {
+    pub variance: Vec<f64>,
+    pub variance_ratio: Vec<f64>,
+}
+
+/// The matrix X - 1·μᵀ of the chosen cells and genes, stored as the sparse X.
+struct CenteredSparse {
+    /// cells × genes (CSR)
+    x: CsMat<f32>,
+    mean: Array1<f64>,
+    total_variance: f64,
+}
+
+impl CenteredSparse {
+    fn n_cells(&self) -> usize {
+        self.x.rows()
+    }
+
+    fn n_genes(&self) -> usize {
+        self.x.cols()
+    }
+
+    /// (X - 1·μᵀ) · m for a genes × l matrix m, in parallel over the cells.
+    fn dot(&self, m: &Array2<f64>) -> Array2<f64> {
+        let l = m.ncols();
+        let shift = self.mean.dot(m);
+        let m = m.as_standard_layout();
+        let m = m.as_slice().expect("standard layout");
+        let mut out = vec![0.0f64; self.n_cells() * l];
+        out.par_chunks_mut(l.max(1)).enumerate().for_each(|(cell, row)| {
+            row.iter_mut().zip(&shift).for_each(|(o, s)| *o = -s);
+            if let Some(v) = self.x.outer_view(cell) {
+                for (g, x) in v.iter() {
+                    let x = *x as f64;
+                    row.iter_mut().zip(&m[g * l..(g + 1) * l]).for_each(|(o, y)| *o += x * y);
+                }
+            }
+        });
+        Array2::from_shape_vec((self.n_cells(), l), out).expect("cells × l values")
+    }
+
+    /// (X - 1·μᵀ)ᵀ · m for a cells × l matrix m. Every task adds the rows of a block
+    /// of cells into its own genes × l sums, which keeps the reads of m sequential.
+    fn t_dot(&self, m: &Array2<f64>) -> Array2<f64> {
+        let (l, n_genes) = (m.ncols(), self.n_genes());
+        let col_sums = m.sum_axis(Axis(0));
+        let m = m.as_standard_layout();
+        let m = m.as_slice().expect("standard layout");
+        let n_blocks = self.n_cells().div_ceil(ROW_CHUNK);
+        let sums = (0..n_blocks)
+            .into_par_iter()
+            .map(|block| {
+                let mut acc = vec![0.0f64; n_genes * l];
+                for cell in block * ROW_CHUNK..((block + 1) * ROW_CHUNK).min(self.n_cells()) {
+                    let Some(v) = self.x.outer_view(cell) else { continue };
+                    let src = &m[cell * l..(cell + 1) * l];
+                    for (g, x) in v.iter() {
+                        let x = *x as f64;
+                        acc[g * l..(g + 1) * l].iter_mut().zip(src).for_each(|(o, y)| *o += x * y);
+                    }
+                }
+                acc
+            })
+            .reduce(
+                || vec![0.0f64; n_genes * l],
+                |mut a, b| {
+                    a.iter_mut().zip(&b).for_each(|(a, b)| *a += b);
+                    a
+                },
+            );
+        let mut ret = Array2::from_shape_vec((n_genes, l), sums).expect("genes × l values");
+        ret.axis_iter_mut(Axis(0)).zip(&self.mean).for_each(|(mut row, mu)| row.scaled_add(-mu, &col_sums));
+        ret
+    }
+}
+
+/// Row chunks of a tall matrix, for the parallel dense products.
+fn row_chunks(a: &Array2<f64>) -> Vec<ArrayView2<'_, f64>> {
+    a.axis_chunks_iter(Axis(0), ROW_CHUNK).collect()
+}
+
+/// aᵀ · a for a tall matrix a.
+fn gram(a: &Array2<f64>) -> Array2<f64> {
+    let l = a.ncols();
+    row_chunks(a)
+        .par_iter()
+        .map(|c| c.t().dot(c))
+        .reduce(|| Array2::zeros((l, l)), |x, y| x + y)
+}
+
+/// a · b for a tall matrix a and a small matrix b.
+fn tall_dot(a: &Array2<f64>, b: &Array2<f64>) -> Array2<f64> {
+    let parts: Vec<Array2<f64>> = row_chunks(a).par_iter().map(|c| c.dot(b)).collect();
+    let views: Vec<ArrayView2<f64>> = parts.iter().map(|p| p.view()).collect();
+    if views.is_empty() {
+        return Array2::zeros((0, b.ncols()));
+    }
+    concatenate(Axis(0), &views).expect("chunks with the same columns")
+}
+
+/// An orthonormal basis of the column space of a tall matrix: y · V · Λ^-1/2 from the
+/// eigen decomposition of yᵀy (directions without weight become zero columns).
+/// Done twice, as the first pass loses accuracy for ill-conditioned y.
+fn orthonormalize(mut y: Array2<f64>) -> Array2<f64> {
+    for _ in 0..2 {
+        let (values, mut vectors) = symmetric_eigen(gram(&y));
+        let max = values.first().copied().unwrap_or(0.0);
+        for (j, v) in values.iter().enumerate() {
+            let f = if *v > 0.0 && *v > max * 1e-12 { 1.0 / v.sqrt() } else { 0.0 };
+            vectors.column_mut(j).mapv_inplace(|x| x * f);
+        }
+        y = tall_dot(&y, &vectors);
+    }
+    y
+}
+
+/// Eigenvalues (descending) and eigenvectors (columns) of a small symmetric matrix (cyclic Jacobi).
+pub(crate) fn symmetric_eigen(mut a: Array2<f64>) -> (Vec<f64>, Array2<f64>) {
+    let n = a.nrows();
+    let mut v = Array2::<f64>::eye(n);
+    let total: f64 = a.iter().map(|x| x * x).sum();
+    for _sweep in 0..100 {
+        let diagonal: f64 = (0..n).map(|i| a[[i, i]] * a[[i, i]]).sum();
+        if total - diagonal <= 1e-28 * total.max(f64::MIN_POSITIVE) {
+            break;
+        }
+        for p in 0..n {
+            for q in p + 1..n {
+                let apq = a[[p, q]];
+                if apq == 0.0 {
+                    continue;
+                }
+                let theta = (a[[q, q]] - a[[p, p]]) / (2.0 * apq);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+                for k in 0..n {
+                    let (akp, akq) = (a[[k, p]], a[[k, q]]);
+                    a[[k, p]] = c * akp - s * akq;
+                    a[[k, q]] = s * akp + c * akq;
+                }
+                for k in 0..n {
+                    let (apk, aqk) = (a[[p, k]], a[[q, k]]);
+                    a[[p, k]] = c * apk - s * aqk;
+                    a[[q, k]] = s * apk + c * aqk;
+                }
+                for k in 0..n {
+                    let (vkp, vkq) = (v[[k, p]], v[[k, q]]);
+                    v[[k, p]] = c * vkp - s * vkq;
+                    v[[k, q]] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|i, j| a[[*j, *j]].total_cmp(&a[[*i, *i]]));
+    let values = order.iter().map(|i| a[[*i, *i]]).collect();
+    (values, v.select(Axis(1), &order))
+}
+
+/// Standard normal numbers from a SplitMix64 stream (Box-Muller) - the same for the same seed.
+pub(crate) struct Gaussian {
+    state: u64,
+}
+
+impl Gaussian {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// uniform in (0, 1)
+    fn uniform(&mut self) -> f64 {
+        ((self.next_u64() >> 11) as f64 + 0.5) / (1u64 << 53) as f64
+    }
+
+    pub(crate) fn sample(&mut self) -> f64 {
+        let (u, v) = (self.uniform(), self.uniform());
+        (-2.0 * u.ln()).sqrt() * (std::f64::consts::TAU * v).cos()
+    }
+}
+
+impl DataStore {
+    /// Compute a PCA and store the scores as the projection `name` (cells × components;
+    /// cells outside `opts.cells` are NaN) and the loadings as the gene_meta columns
+    /// `<name>_PC1`, `<name>_PC2`, ... (NaN for genes not used).
+    pub fn run_pca(&mut self, name: &str, opts: &PcaOptions) -> Result<PcaResult, String> {
+        let (n_genes, n_cells) = (self.gene_names.len(), self.cell_names.len());
+        let mut genes = opts.genes.clone().unwrap_or_else(|| self.hvg_genes());
+        genes.sort_unstable();
+        genes.dedup();
+        if genes.is_empty() {
+            return Err("❌ No genes for the PCA - select the highly variable genes first".to_string());
+        }
+        if let Some(g) = genes.iter().find(|g| **g >= n_genes) {
+            return Err(format!("❌ Gene {} out of range ({} genes)", g, n_genes));
+        }
+        let mut cells = opts.cells.clone().unwrap_or_else(|| (0..n_cells).collect());
+        cells.sort_unstable();
+        cells.dedup();
+        if let Some(c) = cells.iter().find(|c| **c >= n_cells) {
+            return Err(format!("❌ Cell {} out of range ({} cells)", c, n_cells));
+        }
+
+        let x = self.centered_input(opts.layer.as_deref(), &genes, &cells)?;
+        let k = opts.n_components.min(x.n_genes()).min(x.n_cells().saturating_sub(1));
+        if k == 0 {
+            return Err(format!("❌ A PCA needs at least 2 cells ({} selected)", x.n_cells()));
+        }
+        let l = (k + opts.oversample).min(x.n_genes()).min(x.n_cells());
+
+        // range finder with power iterations: q spans the top left singular vectors
+        let mut rng = Gaussian::new(opts.seed);
+        let omega = Array2::from_shape_simple_fn((x.n_genes(), l), || rng.sample());
+        let mut q = orthonormalize(x.dot(&omega));
+        for _ in 0..opts.n_iter {
+            let z = orthonormalize(x.t_dot(&q));
+            q = orthonormalize(x.dot(&z));
+        }
+        // SVD of the small B = qᵀX (l × genes) from the eigen decomposition of B·Bᵀ
+        let bt = x.t_dot(&q);
+        let (values, u) = symmetric_eigen(bt.t().dot(&bt));
+        let u = u.slice(s![.., ..k]).to_owned();
+        let sigma: Vec<f64> = values[..k].iter().map(|v| v.max(0.0).sqrt()).collect();
+        let mut scores = tall_dot(&q, &u);
+        let mut loadings = bt.dot(&u);
+        for (j, s) in sigma.iter().enumerate() {
+            loadings.column_mut(j).mapv_inplace(|v| if *s > 0.0 { v / s } else { 0.0 });
+            // the sign of a component is arbitrary - make its largest loading positive
+            let largest = loadings.column(j).iter().fold(0.0f64, |m, v| if v.abs() > m.abs() { *v } else { m });
+            let sign = if largest < 0.0 { -1.0 } else { 1.0 };
+            loadings.column_mut(j).mapv_inplace(|v| sign * v);
+            scores.column_mut(j).mapv_inplace(|v| sign * s * v);
+        }
+
+        let dof = (x.n_cells() as f64 - 1.0).max(1.0);
+        let variance: Vec<f64> = sigma.iter().map(|s| s * s / dof).collect();
+        let variance_ratio = variance
+            .iter()
+            .map(|v| if x.total_variance > 0.0 { v / x.total_variance } else { 0.0 })
+            .collect();
+
+        let mut drc = Array2::from_elem((n_cells, k), f32::NAN);
+        for (r, c) in cells.iter().enumerate() {
+            drc.row_mut(*c).iter_mut().zip(scores.row(r)).for_each(|(d, s)| *d = *s as f32);
+        }
+        self.drcs.insert(name.to_string(), drc);
+        self.axes.remove(name);
+        self.forget_neighbors_of(name);
+
+        let prefix = format!("{}_PC", name);
+        let mut table = self.gene_table.clone();
+        table.columns.retain(|c| !c.name().starts_with(&prefix));
+        for j in 0..k {
+            let mut values = vec![f64::NAN; n_genes];
+            genes.iter().zip(loadings.column(j)).for_each(|(g, v)| values[*g] = *v);
+            table.push(MetaColumn::Numeric { name: format!("{}{}", prefix, j + 1), values })?;
+        }
+        self.set_gene_table(table)?;
+        println!(
+            "🧮 PCA '{}': {} components of {} cells × {} genes",
+            name,
+            k,
+            cells.len(),
+            genes.len()
+        );
+        Ok(PcaResult { variance, variance_ratio })
+    }
+
+    /// The values of `genes` (sorted) in `cells` (sorted) from a layer, or log normalized
+    /// counts without one, with the gene means and their total variance.
+    fn centered_input(&self, layer: Option<&str>, genes: &[usize], cells: &[usize]) -> Result<CenteredSparse, String> {
+        let mut row_of = vec![None; self.gene_names.len()];
+        genes.iter().enumerate().for_each(|(r, g)| row_of[*g] = Some(r));
+        let mut col_of = vec![None; self.cell_names.len()];
+        cells.iter().enumerate().for_each(|(col, c)| col_of[*c] = Some(col));
+        let factors: Option<Vec<f32>> = match layer {
+            Some(_) => None,
+            None => {
+                let totals = self.library_sizes()?;
+                let mut non_empty: Vec<f32> = totals.iter().copied().filter(|t| *t > 0.0).collect();
+                let target = median(&mut non_empty);
+                Some(totals.iter().map(|t| if *t > 0.0 { target / t } else { 0.0 }).collect())
+            }
+        };
+
+        let mut rows: Vec<(Vec<usize>, Vec<f32>)> = vec![(Vec::new(), Vec::new()); genes.len()];
+        self.for_each_layer_gene(layer.unwrap_or(RAW_LAYER), |g, values| {
+            let Some(r) = row_of[g] else { return };
+            let (indices, data) = &mut rows[r];
+            values.for_each(|c, x| {
+                let Some(col) = col_of[c] else { return };
+                let x = match &factors {
+                    Some(f) => (x * f[c]).ln_1p(),
+                    None => x,
+                };
+                if x != 0.0 {
+                    indices.push(col);
+                    data.push(x);
+                }
+            });
+        })?;
+
+        let n = cells.len() as f64;
+        let mut indptr = Vec::with_capacity(genes.len() + 1);
+        indptr.push(0);
+        let (mut indices, mut data) = (Vec::new(), Vec::new());
+        let mut mean = Array1::<f64>::zeros(genes.len());
+        let mut total_variance = 0.0;
+        for (r, (i, d)) in rows.into_iter().enumerate() {
+            let sum: f64 = d.iter().map(|x| *x as f64).sum();
+            let sum_sq: f64 = d.iter().map(|x| (*x as f64).powi(2)).sum();
+            mean[r] = sum / n;
+            if n > 1.0 {
+                total_variance += ((sum_sq - n * mean[r] * mean[r]) / (n - 1.0)).max(0.0);
+            }
+            indices.extend(i);
+            data.extend(d);
+            indptr.push(indices.len());
+        }
+        let by_gene = CsMat::new((genes.len(), cells.len()), indptr, indices, data);
+        Ok(CenteredSparse { x: by_gene.to_csc().transpose_into(), mean, total_variance })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_store::data_store::test_store;
+    use sprs::TriMat;
+
+    #[test]
+    fn randomized_pca_matches_the_exact_one() {
+        // 300 cells in overlapping groups that shift genes 0-4, 5-9 and 10-14, plus noise in all 30 genes
+        let (n_cells, n_genes) = (300, 30);
+        let mut rng = Gaussian { state: 7 };
+        let mut dense = Array2::<f64>::zeros((n_cells, n_genes));
+        let mut tri = TriMat::<f32>::new((n_genes, n_cells));
+        for c in 0..n_cells {
+            for g in 0..n_genes {
+                let shift = match g {
+                    0..5 if c % 2 == 0 => 4.0,
+                    5..10 if c % 3 == 0 => 2.5,
+                    10..15 if c % 5 == 0 => 1.5,
+                    _ => 0.0,
+                };
+                let x = (1.0 + shift + 0.3 * rng.sample()).max(0.0);
+                if x > 0.0 {
+                    tri.add_triplet(g, c, x as f32);
+                    dense[[c, g]] = x as f32 as f64;
+                }
+            }
+        }
+        let genes: Vec<String> = (0..n_genes).map(|g| format!("G{}", g)).collect();
+        let cells: Vec<String> = (0..n_cells).map(|c| format!("c{}", c)).collect();
+        let mut ds = test_store(tri.to_csr(), genes, cells);
+        ds.layers.insert("x".to_string(), ds.counts.clone());
+
+        let opts = PcaOptions {
+            n_components: 3,
+            layer: Some("x".to_string()),
+            genes: Some((0..n_genes).collect()),
+            ..Default::default()
+        };
+        let result = ds.run_pca("pca", &opts).unwrap();
+
+        // exact: eigen decomposition of the covariance matrix
+        let centered = &dense - &dense.mean_axis(Axis(0)).unwrap();
+        let (exact, vectors) = symmetric_eigen(centered.t().dot(&centered) / (n_cells as f64 - 1.0));
+        for j in 0..3 {
+            assert!((result.variance[j] - exact[j]).abs() < 1e-6 * exact[0], "{} vs {}", result.variance[j], exact[j]);
+            let Some(MetaColumn::Numeric { values: loading, .. }) = ds.gene_table.column(&format!("pca_PC{}", j + 1))
+            else {
+                panic!("no loadings of PC{}", j + 1);
+            };
+            let dot: f64 = loading.iter().zip(vectors.column(j)).map(|(a, b)| a * b).sum();
+            assert!((dot.abs() - 1.0).abs() < 1e-6, "component {}: |cos| = {}", j, dot.abs());
+        }
+        assert_eq!(ds.drcs["pca"].dim(), (n_cells, 3));
+        // the first component separates the two groups
+        let pc1 = ds.drcs["pca"].column(0).to_vec();
+        assert!(pc1.iter().step_by(2).all(|v| v.signum() == pc1[0].signum()));
+        assert!(pc1.iter().skip(1).step_by(2).all(|v| v.signum() != pc1[0].signum()));
+    }
+}
@@ -0,0 +1,275 @@
+//projection.rs
+//! `drcs` keep every dimension of an embedding (cells × n_dims).
+//! What is shown in VR is a cells × 3 view of it: two embedding dimensions for x/y
+//! and either a third dimension or a padding value for z.
+use crate::data_store::DataStore;
+use ndarray::Array2;
+use std::collections::HashMap;
+
+/// Where the z axis of a displayed projection comes from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ZAxis {
+    /// another embedding dimension
+    Dim(usize),
+    /// the same value for all cells (2D embeddings default to 0.0)
+    Constant(f32),
+    /// a numeric or factor column of `cell_meta`
+    Meta(String),
+    /// a feature of the primary modality (e.g. a gene)
+    Feature(String),
+}
+
+impl ZAxis {
+    /// Parse "dim:3", "meta:<column>", "gene:<name>" or a plain number (constant).
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        if let Some(dim) = text.strip_prefix("dim:") {
+            return dim
+                .trim()
+                .parse()
+                .map(ZAxis::Dim)
+                .map_err(|_| format!("❌ Invalid dimension '{}'", dim));
+        }
+        if let Some(col) = text.strip_prefix("meta:") {
+            return Ok(ZAxis::Meta(col.to_string()));
+        }
+        if let Some(gene) = text.strip_prefix("gene:") {
+            return Ok(ZAxis::Feature(gene.to_string()));
+        }
+        text.parse()
+            .map(ZAxis::Constant)
+            .map_err(|_| format!("❌ Unknown z axis '{}' - use a number, dim:<i>, meta:<column> or gene:<name>", text))
+    }
+}
+
+/// The dimensions of an embedding shown as x, y and z.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProjectionAxes {
+    pub x: usize,
+    pub y: usize,
+    pub z: ZAxis,
+}
+
+impl ProjectionAxes {
+    /// The first three dimensions, or the first two with z = 0 for 2D embeddings.
+    pub fn default_for(n_dims: usize) -> Self {
+        let z = if n_dims >= 3 { ZAxis::Dim(2) } else { ZAxis::Constant(0.0) };
+        Self { x: 0, y: 1, z }
+    }
+}
+
+/// Pick the x/y columns of `data` and add `z` as the third column (cells × 3, z = 0 without it).
+pub fn select_dims(data: &Array2<f32>, x: usize, y: usize, z: Option<&[f32]>) -> Array2<f32> {
+    let n = data.nrows();
+    let mut view = Array2::<f32>::zeros((n, 3));
+    view.column_mut(0).assign(&data.column(x));
+    view.column_mut(1).assign(&data.column(y));
+    if let Some(z) = z {
+        view.column_mut(2).assign(&ndarray::ArrayView1::from(z));
+    }
+    view
+}
+
+/// How the rows of a projection file were matched to the cells of a dataset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProjectionMatch {
+    /// cells that got coordinates
+    pub matched: usize,
+    /// rows whose barcode is not part of the dataset (or repeated)
+    pub dropped: usize,
+    /// cells without a row in the file (their coordinates are NaN)
+    pub missing: usize,
+}
+
+impl ProjectionMatch {
+    pub fn is_complete(&self) -> bool {
+        self.dropped == 0 && self.missing == 0
+    }
+}
+
+/// Place the rows of an embedding at the position of their barcode in `cell_names`.
+/// Cells without a row get NaN coordinates (hidden by the renderer).
+pub fn map_rows_by_barcode(
+    barcodes: &[String],
+    rows: &[Vec<f32>],
+    cell_names: &[String],
+) -> (Array2<f32>, ProjectionMatch) {
+    let n_dims = rows.iter().map(|r| r.len()).max().unwrap_or(0);
+    let index: HashMap<&str, usize> = cell_names.iter().enumerate().map(|(i, c)| (c.as_str(), i)).collect();
+
+    let mut data = Array2::<f32>::from_elem((cell_names.len(), n_dims), f32::NAN);
+    let mut seen = vec![false; cell_names.len()];
+    let mut stats = ProjectionMatch::default();
+    for (barcode, row) in barcodes.iter().zip(rows) {
+        match index.get(barcode.as_str()) {
+            Some(&i) if !seen[i] => {
+                seen[i] = true;
+                stats.matched += 1;
+                for (d, v) in row.iter().enumerate() {
+                    data[[i, d]] = *v;
+                }
+            }
+            _ => stats.dropped += 1,
+        }
+    }
+    stats.missing = cell_names.len() - stats.matched;
+    (data, stats)
+}
+
+/// (min, max) of the finite values.
+fn finite_range<'a>(values: impl Iterator<Item = &'a f32>) -> Option<(f32, f32)> {
+    values
+        .filter(|v| v.is_finite())
+        .fold(None, |acc, &v| match acc {
+            None => Some((v, v)),
+            Some((lo, hi)) => Some((lo.min(v), hi.max(v))),
+        })
+}
+
+impl DataStore {
+    /// Turn a row major (cells × n_dims) embedding into a `drcs` entry.
+    pub(crate) fn embedding_to_array(values: &[f32], n_cells: usize, n_dims: usize) -> Array2<f32> {
+        Array2::from_shape_vec((n_cells, n_dims), values.to_vec())
+            .unwrap_or_else(|_| Array2::from_elem((n_cells, n_dims), f32::NAN))
+    }
+
+    /// The axes a projection is displayed with (the defaults if none were set).
+    pub fn projection_axes(&self, name: &str) -> Option<ProjectionAxes> {
+        let data = self.drcs.get(name)?;
+        Some(
+            self.axes
+                .get(name)
+                .cloned()
+                .unwrap_or_else(|| ProjectionAxes::default_for(data.ncols())),
+        )
+    }
+
+    /// Choose which dimensions of a projection are displayed.
+    pub fn set_projection_axes(&mut self, name: &str, axes: ProjectionAxes) -> Result<(), String> {
+        let data = self
+            .drcs
+            .get(name)
+            .ok_or_else(|| format!("❌ Projection '{}' not found", name))?;
+        let n_dims = data.ncols();
+        for d in [Some(axes.x), Some(axes.y), if let ZAxis::Dim(d) = axes.z { Some(d) } else { None }]
+            .into_iter()
+            .flatten()
+        {
+            if d >= n_dims {
+                return Err(format!("❌ Projection '{}' has only {} dimensions (asked for {})", name, n_dims, d));
+            }
+        }
+        // check the z source now rather than on every redraw
+        self.z_values(&axes, data)?;
+        self.axes.insert(name.to_string(), axes);
+        Ok(())
+    }
+
+    /// The displayed (cells × 3) coordinates of a projection.
+    pub fn projection_view(&self, name: &str) -> Result<Array2<f32>, String> {
+        let data = self
+            .drcs
+            .get(name)
+            .ok_or_else(|| format!("❌ Projection '{}' not found", name))?;
+        if data.ncols() < 2 {
+            return Err(format!("❌ Projection '{}' has less than 2 dimensions", name));
+        }
+        let axes = self.projection_axes(name).unwrap_or_else(|| ProjectionAxes::default_for(data.ncols()));
+        let z = self.z_values(&axes, data)?;
+        Ok(select_dims(data, axes.x, axes.y, Some(&z)))
+    }
+
+    /// The z coordinates. Per cell values from cell_meta or a feature are rescaled to the
+    /// range of the displayed x/y dimensions, so that they neither flatten nor stretch the graph.
+    fn z_values(&self, axes: &ProjectionAxes, data: &Array2<f32>) -> Result<Vec<f32>, String> {
+        let n = data.nrows();
+        let raw: Vec<f32> = match &axes.z {
+            ZAxis::Dim(d) => {
+                if *d >= data.ncols() {
+                    return Err(format!("❌ z dimension {} out of range ({} dims)", d, data.ncols()));
+                }
+                return Ok(data.column(*d).to_vec());
+            }
+            ZAxis::Constant(c) => return Ok(vec![*c; n]),
+            ZAxis::Meta(col) => {
+                if !self.cell_meta.headers.contains(col) {
+                    return Err(format!("❌ Meta column '{}' not found", col));
+                }
+                self.cell_meta.as_vec_f64(col).iter().map(|v| *v as f32).collect()
+            }
+            ZAxis::Feature(feature) => self.feature_values("", feature)?,
+        };
+        if raw.len() != n {
+            return Err(format!("❌ z axis has {} values for {} cells", raw.len(), n));
+        }
+
+        // scale into the x/y range
+        let span = [axes.x, axes.y]
+            .iter()
+            .filter_map(|d| finite_range(data.column(*d).iter()))
+            .map(|(lo, hi)| hi - lo)
+            .fold(0.0f32, f32::max);
+        let Some((lo, hi)) = finite_range(raw.iter()) else {
+            return Ok(raw);
+        };
+        let scale = if hi > lo { span / (hi - lo) } else { 0.0 };
+        Ok(raw.iter().map(|v| (v - lo) * scale).collect())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_store::data_store::test_store;
+    use sprs::TriMat;
+    use std::collections::HashMap;
+
+    #[test]
+    fn dims_padding_and_parsing() {
+        let mut tri = TriMat::<f32>::new((1, 2));
+        tri.add_triplet(0, 0, 2.0);
+        tri.add_triplet(0, 1, 6.0);
+        let mut drcs = HashMap::new();
+        drcs.insert("umap".to_string(), Array2::from_shape_vec((2, 2), vec![0.0, 0.0, 4.0, 2.0]).unwrap());
+        drcs.insert("pca".to_string(), Array2::from_shape_vec((2, 4), vec![1., 2., 3., 4., 5., 6., 7., 8.]).unwrap());
+        drcs.insert("wide".to_string(), Array2::from_shape_vec((2, 3), vec![0., 0., 0., 1., 1., 10.]).unwrap());
+        let mut ds = test_store(tri.to_csr(), vec!["g1".into()], vec!["c1".into(), "c2".into()]);
+        ds.drcs = drcs;
+
+        // 2D is padded with z = 0
+        let umap = ds.projection_view("umap").unwrap();
+        assert_eq!(umap.row(1).to_vec(), vec![4.0, 2.0, 0.0]);
+
+        // a gene as z is scaled to the x/y span (4.0)
+        ds.set_projection_axes("umap", ProjectionAxes { x: 0, y: 1, z: ZAxis::parse("gene:g1").unwrap() }).unwrap();
+        let umap = ds.projection_view("umap").unwrap();
+        assert_eq!(umap.column(2).to_vec(), vec![0.0, 4.0]);
+
+        // ... the span of the displayed dimensions, not of the first two
+        ds.set_projection_axes("wide", ProjectionAxes { x: 2, y: 0, z: ZAxis::parse("gene:g1").unwrap() }).unwrap();
+        assert_eq!(ds.projection_view("wide").unwrap().column(2).to_vec(), vec![0.0, 10.0]);
+
+        // PC2/PC3/PC4
+        ds.set_projection_axes("pca", ProjectionAxes { x: 1, y: 2, z: ZAxis::Dim(3) }).unwrap();
+        assert_eq!(ds.projection_view("pca").unwrap().row(0).to_vec(), vec![2.0, 3.0, 4.0]);
+        assert!(ds.set_projection_axes("pca", ProjectionAxes { x: 0, y: 1, z: ZAxis::Dim(4) }).is_err());
+
+        assert_eq!(ZAxis::parse("0.5").unwrap(), ZAxis::Constant(0.5));
+        assert_eq!(ZAxis::parse("meta:nCount").unwrap(), ZAxis::Meta("nCount".into()));
+        assert!(ZAxis::parse("something").is_err());
+    }
+
+    #[test]
+    fn rows_are_mapped_by_barcode() {
+        let cells: Vec<String> = ["A", "B", "C"].iter().map(|s| s.to_string()).collect();
+        let barcodes: Vec<String> = ["C", "X", "A", "A"].iter().map(|s| s.to_string()).collect();
+        let rows = vec![vec![3.0, 3.5], vec![9.0, 9.0], vec![1.0, 1.5], vec![7.0, 7.0]];
+
+        let (data, stats) = map_rows_by_barcode(&barcodes, &rows, &cells);
+        assert_eq!(stats, ProjectionMatch { matched: 2, dropped: 2, missing: 1 });
+        assert_eq!(data.row(0).to_vec(), vec![1.0, 1.5]);
+        assert!(data[[1, 0]].is_nan());
+        assert_eq!(data.row(2).to_vec(), vec![3.0, 3.5]);
+    }
+}
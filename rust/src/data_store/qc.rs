@@ -0,0 +1,259 @@
+//qc.rs
+//! Per cell quality metrics (`nCount`, `nFeature`, `percent_mt`, `percent_ribo`)
+//! and removing the cells that fail QC thresholds.
+use crate::data_store::DataStore;
+use crate::data_store::meta_table::{MetaColumn, MetaTable};
+use crate::data_store::modality::Modality;
+use serde::Deserialize;
+use sprs::CsMat;
+use std::sync::OnceLock;
+
+pub const N_COUNT: &str = "nCount";
+pub const N_FEATURE: &str = "nFeature";
+pub const PERCENT_MT: &str = "percent_mt";
+pub const PERCENT_RIBO: &str = "percent_ribo";
+
+/// The `[qc]` section of dataset.toml: gene name prefixes of the mitochondrial and
+/// ribosomal genes (matched ignoring case, e.g. "mt-" for mouse data).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QcPatterns {
+    pub mito: Vec<String>,
+    pub ribo: Vec<String>,
+}
+
+impl Default for QcPatterns {
+    fn default() -> Self {
+        Self { mito: vec!["MT-".to_string()], ribo: vec!["RPS".to_string(), "RPL".to_string()] }
+    }
+}
+
+impl QcPatterns {
+    fn matches(prefixes: &[String], gene: &str) -> bool {
+        prefixes.iter().any(|p| {
+            gene.len() >= p.len() && gene.is_char_boundary(p.len()) && gene[..p.len()].eq_ignore_ascii_case(p)
+        })
+    }
+
+    pub fn is_mito(&self, gene: &str) -> bool {
+        Self::matches(&self.mito, gene)
+    }
+
+    pub fn is_ribo(&self, gene: &str) -> bool {
+        Self::matches(&self.ribo, gene)
+    }
+}
+
+/// The QC metrics of all cells (percentages are 0 for cells without counts).
+#[derive(Debug, Clone, Default)]
+pub struct QcMetrics {
+    pub n_count: Vec<f32>,
+    pub n_feature: Vec<u32>,
+    pub percent_mt: Vec<f32>,
+    pub percent_ribo: Vec<f32>,
+}
+
+/// Inclusive limits a cell has to meet to pass QC; `None` does not check that metric.
+#[derive(Debug, Clone, Default)]
+pub struct QcThresholds {
+    pub min_count: Option<f32>,
+    pub max_count: Option<f32>,
+    pub min_features: Option<u32>,
+    pub max_features: Option<u32>,
+    pub max_percent_mt: Option<f32>,
+    pub max_percent_ribo: Option<f32>,
+}
+
+impl QcMetrics {
+    /// One flag per cell: does it pass all `thresholds`?
+    pub fn pass(&self, thresholds: &QcThresholds) -> Vec<bool> {
+        let min = |v: f32, limit: Option<f32>| limit.is_none_or(|l| v >= l);
+        let max = |v: f32, limit: Option<f32>| limit.is_none_or(|l| v <= l);
+        (0..self.n_count.len())
+            .map(|c| {
+                let features = self.n_feature[c] as f32;
+                min(self.n_count[c], thresholds.min_count)
+                    && max(self.n_count[c], thresholds.max_count)
+                    && min(features, thresholds.min_features.map(|f| f as f32))
+                    && max(features, thresholds.max_features.map(|f| f as f32))
+                    && max(self.percent_mt[c], thresholds.max_percent_mt)
+                    && max(self.percent_ribo[c], thresholds.max_percent_ribo)
+            })
+            .collect()
+    }
+}
+
+/// The columns of a (rows × columns) matrix where `keep` is true, in the same storage order.
+pub(crate) fn select_cols(mat: &CsMat<f32>, keep: &[bool]) -> CsMat<f32> {
+    let mut new_index = vec![None; mat.cols()];
+    let mut n_kept = 0;
+    for (c, slot) in new_index.iter_mut().enumerate() {
+        if keep.get(c).copied().unwrap_or(false) {
+            *slot = Some(n_kept);
+            n_kept += 1;
+        }
+    }
+    let mut indptr = vec![0];
+    let mut indices = Vec::new();
+    let mut data = Vec::new();
+    if mat.is_csr() {
+        for row in mat.outer_iterator() {
+            for (c, v) in row.iter() {
+                if let Some(nc) = new_index[c] {
+                    indices.push(nc);
+                    data.push(*v);
+                }
+            }
+            indptr.push(indices.len());
+        }
+        CsMat::new((mat.rows(), n_kept), indptr, indices, data)
+    } else {
+        for (c, col) in mat.outer_iterator().enumerate() {
+            if new_index[c].is_some() {
+                indices.extend(col.indices());
+                data.extend(col.data());
+                indptr.push(indices.len());
+            }
+        }
+        CsMat::new_csc((mat.rows(), n_kept), indptr, indices, data)
+    }
+}
+
+impl DataStore {
+    /// nCount, nFeature and the percentage of mitochondrial/ribosomal counts per cell
+    /// (from the raw counts of the primary modality).
+    pub fn qc_metrics(&self, patterns: &QcPatterns) -> Result<QcMetrics, String> {
+        let n_cells = self.cell_names.len();
+        let mut n_count = vec![0.0f32; n_cells];
+        let mut n_feature = vec![0u32; n_cells];
+        let mut mito = vec![0.0f32; n_cells];
+        let mut ribo = vec![0.0f32; n_cells];
+        self.for_each_feature("", |g, v| {
+            let gene = self.gene_names[g].as_str();
+            let (is_mito, is_ribo) = (patterns.is_mito(gene), patterns.is_ribo(gene));
+            for (c, x) in v.iter() {
+                n_count[c] += *x;
+                if *x > 0.0 {
+                    n_feature[c] += 1;
+                }
+                if is_mito {
+                    mito[c] += *x;
+                }
+                if is_ribo {
+                    ribo[c] += *x;
+                }
+            }
+        })?;
+        let percent = |part: Vec<f32>| -> Vec<f32> {
+            part.iter()
+                .zip(&n_count)
+                .map(|(p, total)| if *total > 0.0 { 100.0 * p / total } else { 0.0 })
+                .collect()
+        };
+        let percent_mt = percent(mito);
+        let percent_ribo = percent(ribo);
+        Ok(QcMetrics { n_count, n_feature, percent_mt, percent_ribo })
+    }
+
+    /// Compute the QC metrics and store them as numeric `cell_meta` columns
+    /// (replacing older ones), so they can be used for coloring and gating.
+    pub fn add_qc_metrics(&mut self, patterns: &QcPatterns) -> Result<(), String> {
+        let qc = self.qc_metrics(patterns)?;
+        let numeric = |name: &str, values: Vec<f64>| MetaColumn::Numeric { name: name.to_string(), values };
+        let mut table = MetaTable::from_survival_data(&self.cell_meta, &self.factor_numbers, &self.cell_names)?;
+        table.set(numeric(N_COUNT, qc.n_count.iter().map(|v| *v as f64).collect()))?;
+        table.set(numeric(N_FEATURE, qc.n_feature.iter().map(|v| *v as f64).collect()))?;
+        table.set(numeric(PERCENT_MT, qc.percent_mt.iter().map(|v| *v as f64).collect()))?;
+        table.set(numeric(PERCENT_RIBO, qc.percent_ribo.iter().map(|v| *v as f64).collect()))?;
+        self.factor_numbers = table.numbers.clone();
+        self.cell_meta = table.into_survival_data()?;
+        println!("🧪 QC metrics added for {} cells", self.cell_names.len());
+        Ok(())
+    }
+
+    /// One flag per cell: does it pass the QC `thresholds`?
+    pub fn qc_pass(&self, patterns: &QcPatterns, thresholds: &QcThresholds) -> Result<Vec<bool>, String> {
+        Ok(self.qc_metrics(patterns)?.pass(thresholds))
+    }
+
+    /// Drop all cells where `keep` is false from the counts, layers, modalities,
+    /// projections and cell_meta. Returns the number of cells left.
+    pub fn filter_cells(&mut self, keep: &[bool]) -> Result<usize, String> {
+        let n_cells = self.cell_names.len();
+        if keep.len() != n_cells {
+            return Err(format!("❌ {} QC flags for {} cells", keep.len(), n_cells));
+        }
+        if self.out_of_core.is_some() {
+            return Err("❌ Cells can not be removed from out-of-core counts".to_string());
+        }
+        let rows: Vec<usize> = (0..n_cells).filter(|c| keep[*c]).collect();
+        if rows.len() == n_cells {
+            return Ok(n_cells);
+        }
+        let table = MetaTable::from_survival_data(&self.cell_meta, &self.factor_numbers, &self.cell_names)?;
+        self.cell_meta = table.select_rows(keep).into_survival_data()?;
+
+        self.counts = select_cols(&self.counts, keep);
+        self.counts_csc = OnceLock::new();
+        for modality in self.modalities.values_mut() {
+            *modality = Modality::new(
+                select_cols(&modality.counts, keep),
+                std::mem::take(&mut modality.feature_names),
+            );
+        }
+        for layer in self.layers.values_mut() {
+            *layer = select_cols(layer, keep);
+        }
+        for layer in self.dense_layers.values_mut() {
+            layer.values = layer.values.select(ndarray::Axis(1), &rows);
+        }
+        for drc in self.drcs.values_mut() {
+            *drc = drc.select(ndarray::Axis(0), &rows);
+        }
+        self.neighbors.clear();
+        let removed = self.cell_names.iter().zip(keep).filter(|(_, k)| !**k).map(|(c, _)| c.clone());
+        self.filtered_cells.extend(removed);
+        self.cell_names = rows.iter().map(|c| self.cell_names[*c].clone()).collect();
+        println!("🧹 kept {} of {} cells", rows.len(), n_cells);
+        Ok(rows.len())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_store::data_store::test_store;
+    use ndarray::Array2;
+    use std::collections::HashMap;
+
+    #[test]
+    fn qc_metrics_and_filtering() {
+        // genes × cells: MT-CO1, Rpl13, ACTB over 3 cells
+        let counts = CsMat::new(
+            (3, 3),
+            vec![0, 2, 3, 5],
+            vec![0, 1, 1, 0, 1],
+            vec![5.0, 1.0, 4.0, 5.0, 5.0],
+        );
+        let genes: Vec<String> = ["MT-CO1", "Rpl13", "ACTB"].iter().map(|s| s.to_string()).collect();
+        let cells: Vec<String> = ["a", "b", "c"].iter().map(|s| s.to_string()).collect();
+        let mut drcs = HashMap::new();
+        drcs.insert("umap".to_string(), Array2::from_shape_fn((3, 3), |(r, _)| r as f32));
+        let mut ds = test_store(counts, genes, cells);
+        ds.drcs = drcs;
+
+        let qc = ds.qc_metrics(&QcPatterns::default()).unwrap();
+        assert_eq!(qc.n_count, vec![10.0, 10.0, 0.0]);
+        assert_eq!(qc.n_feature, vec![2, 3, 0]);
+        assert_eq!(qc.percent_mt, vec![50.0, 10.0, 0.0]);
+        assert_eq!(qc.percent_ribo, vec![0.0, 40.0, 0.0]);
+
+        let keep = qc.pass(&QcThresholds { min_count: Some(1.0), max_percent_mt: Some(20.0), ..Default::default() });
+        assert_eq!(keep, vec![false, true, false]);
+        assert_eq!(ds.filter_cells(&keep).unwrap(), 1);
+        assert_eq!(ds.cell_names, vec!["b"]);
+        assert_eq!(ds.counts.to_dense().column(0).to_vec(), vec![1.0, 4.0, 5.0]);
+        assert_eq!(ds.drcs["umap"].row(0).to_vec(), vec![1.0; 3]);
+    }
+}
@@ -0,0 +1,366 @@
+//source.rs
+//! Where dataset files are read from.
+//!
+//! Every file a loader reads goes through `open_text`, which decompresses gzip, zstd and
+//! bzip2 streams on the fly - recognized by their magic bytes, not by the file extension.
+//! Dataset folders may also be packed into a `.tar` (plain or compressed) or `.zip` archive.
+//! A path through the archive, e.g. `pbmc.tar.gz/filtered/matrix.mtx.gz`, is then read
+//! straight from the archive without unpacking it; `exists`, `is_dir` and `list_dir` look
+//! into archives as well.
+use flate2::read::{DeflateDecoder, MultiGzDecoder};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+use zip::{CompressionMethod, ZipArchive};
+
+/// The compression of a stream, detected from its first bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Bzip2,
+}
+
+impl Compression {
+    pub fn detect(magic: &[u8]) -> Self {
+        match magic {
+            [0x1f, 0x8b, ..] => Compression::Gzip,
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Compression::Zstd,
+            [b'B', b'Z', b'h', ..] => Compression::Bzip2,
+            _ => Compression::None,
+        }
+    }
+}
+
+/// Read the first bytes of `reader` and hand them back in front of the rest of the stream.
+fn sniff<'a, R: Read + 'a>(mut reader: R) -> io::Result<(Compression, impl Read + 'a)> {
+    let mut magic = [0u8; 4];
+    let mut n = 0;
+    while n < magic.len() {
+        match reader.read(&mut magic[n..])? {
+            0 => break,
+            k => n += k,
+        }
+    }
+    let head = Cursor::new(magic).take(n as u64);
+    Ok((Compression::detect(&magic[..n]), head.chain(reader)))
+}
+
+/// Wrap `reader` in the decoder its magic bytes ask for (or return it as it is).
+pub fn decompress<'a, R: Read + 'a>(reader: R) -> io::Result<Box<dyn Read + 'a>> {
+    let (compression, reader) = sniff(reader)?;
+    Ok(match compression {
+        // multi member streams: bgzip files are concatenated gzip blocks
+        Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+        Compression::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(reader)),
+        Compression::None => Box::new(reader),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArchiveKind {
+    Tar,
+    Zip,
+}
+
+fn archive_kind(path: &Path) -> Option<ArchiveKind> {
+    let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+    if name.ends_with(".zip") {
+        Some(ArchiveKind::Zip)
+    } else if [".tar", ".tar.gz", ".tgz", ".tar.zst", ".tzst", ".tar.bz2", ".tbz2"]
+        .iter()
+        .any(|ext| name.ends_with(ext))
+    {
+        Some(ArchiveKind::Tar)
+    } else {
+        None
+    }
+}
+
+/// The files of an archive: member path → (position, size). The position is the offset
+/// of the data in the unpacked tar stream, or the entry number of a zip file.
+#[derive(Debug)]
+struct ArchiveIndex {
+    kind: ArchiveKind,
+    stamp: (u64, Option<SystemTime>),
+    files: BTreeMap<PathBuf, (u64, u64)>,
+}
+
+/// `./a//b/` → `a/b`; `..` and absolute parts are dropped.
+fn member_path(name: &Path) -> PathBuf {
+    name.components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect()
+}
+
+fn file_stamp(path: &Path) -> (u64, Option<SystemTime>) {
+    fs::metadata(path)
+        .map(|m| (m.len(), m.modified().ok()))
+        .unwrap_or((0, None))
+}
+
+impl ArchiveIndex {
+    fn build(archive: &Path, kind: ArchiveKind) -> io::Result<Self> {
+        let mut files = BTreeMap::new();
+        match kind {
+            ArchiveKind::Tar => {
+                let mut tar = tar::Archive::new(decompress(File::open(archive)?)?);
+                for entry in tar.entries()? {
+                    let entry = entry?;
+                    if entry.header().entry_type().is_file() {
+                        files.insert(member_path(&entry.path()?), (entry.raw_file_position(), entry.size()));
+                    }
+                }
+            }
+            ArchiveKind::Zip => {
+                let mut zip = ZipArchive::new(File::open(archive)?)?;
+                for i in 0..zip.len() {
+                    let file = zip.by_index_raw(i)?;
+                    if file.is_file() {
+                        files.insert(member_path(Path::new(file.name())), (i as u64, file.size()));
+                    }
+                }
+            }
+        }
+        Ok(Self { kind, stamp: file_stamp(archive), files })
+    }
+
+    fn is_dir(&self, member: &Path) -> bool {
+        member.as_os_str().is_empty()
+            || self
+                .files
+                .keys()
+                .any(|f| f.starts_with(member) && f.as_path() != member)
+    }
+
+    /// Names of the files and folders directly inside `member`.
+    fn children(&self, member: &Path) -> BTreeSet<PathBuf> {
+        self.files
+            .keys()
+            .filter_map(|f| f.strip_prefix(member).ok())
+            .filter_map(|rest| rest.components().next())
+            .map(|c| PathBuf::from(c.as_os_str()))
+            .collect()
+    }
+
+    /// The raw bytes of one member.
+    fn open(&self, archive: &Path, member: &Path) -> io::Result<Box<dyn Read>> {
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, format!("{:?} is not part of the archive", member));
+        let &(position, size) = self.files.get(member).ok_or_else(not_found)?;
+        match self.kind {
+            ArchiveKind::Tar => {
+                let mut stream = decompress(File::open(archive)?)?;
+                io::copy(&mut (&mut stream).take(position), &mut io::sink())?;
+                Ok(Box::new(stream.take(size)))
+            }
+            ArchiveKind::Zip => {
+                let mut zip = ZipArchive::new(File::open(archive)?)?;
+                let (method, start, compressed) = {
+                    let file = zip.by_index_raw(position as usize)?;
+                    (file.compression(), file.data_start(), file.compressed_size())
+                };
+                let mut raw = File::open(archive)?;
+                raw.seek(SeekFrom::Start(start))?;
+                let raw = raw.take(compressed);
+                Ok(match method {
+                    CompressionMethod::Stored => Box::new(raw),
+                    CompressionMethod::Deflated => Box::new(DeflateDecoder::new(raw)),
+                    // rare methods: let the zip crate unpack the member into memory
+                    _ => {
+                        let mut buf = Vec::with_capacity(size as usize);
+                        zip.by_index(position as usize)?.read_to_end(&mut buf)?;
+                        Box::new(Cursor::new(buf))
+                    }
+                })
+            }
+        }
+    }
+}
+
+/// The index of an archive, built on first use and rebuilt if the archive changes.
+fn archive_index(archive: &Path, kind: ArchiveKind) -> io::Result<Arc<ArchiveIndex>> {
+    static INDEXES: OnceLock<Mutex<HashMap<PathBuf, Arc<ArchiveIndex>>>> = OnceLock::new();
+    let mut indexes = INDEXES
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    if let Some(index) = indexes.get(archive).filter(|i| i.stamp == file_stamp(archive)) {
+        return Ok(index.clone());
+    }
+    let index = Arc::new(ArchiveIndex::build(archive, kind)?);
+    println!("📦 {:?}: {} files", archive, index.files.len());
+    indexes.insert(archive.to_path_buf(), index.clone());
+    Ok(index)
+}
+
+/// (archive, path inside it) if `path` is an archive or lies inside one.
+fn split_archive(path: &Path) -> Option<(PathBuf, ArchiveKind, PathBuf)> {
+    path.ancestors().find_map(|a| {
+        let kind = archive_kind(a)?;
+        if !a.is_file() {
+            return None;
+        }
+        let member = member_path(path.strip_prefix(a).ok()?);
+        Some((a.to_path_buf(), kind, member))
+    })
+}
+
+/// Does `path` point into a packed dataset folder (or is it one)?
+pub fn in_archive(path: &Path) -> bool {
+    split_archive(path).is_some()
+}
+
+pub fn exists(path: &Path) -> bool {
+    match split_archive(path) {
+        None => path.exists(),
+        Some((archive, kind, member)) => archive_index(&archive, kind)
+            .map(|i| i.files.contains_key(&member) || i.is_dir(&member))
+            .unwrap_or(false),
+    }
+}
+
+/// A folder on disk, an archive or a folder inside an archive.
+pub fn is_dir(path: &Path) -> bool {
+    match split_archive(path) {
+        None => path.is_dir(),
+        Some((archive, kind, member)) => archive_index(&archive, kind)
+            .map(|i| i.is_dir(&member))
+            .unwrap_or(false),
+    }
+}
+
+pub fn is_file(path: &Path) -> bool {
+    match split_archive(path) {
+        None => path.is_file(),
+        Some((archive, kind, member)) => archive_index(&archive, kind)
+            .map(|i| i.files.contains_key(&member))
+            .unwrap_or(false),
+    }
+}
+
+/// An uncompressed file on disk - what readers that need a real file (HDF5, SQLite, mmap) can open.
+pub fn is_plain_file(path: &Path) -> bool {
+    path.is_file()
+        && File::open(path)
+            .and_then(sniff)
+            .map(|(c, _)| c == Compression::None)
+            .unwrap_or(false)
+}
+
+/// The entries of a folder (on disk or inside an archive), sorted.
+pub fn list_dir(path: &Path) -> Vec<PathBuf> {
+    match split_archive(path) {
+        None => {
+            let mut ret: Vec<PathBuf> = fs::read_dir(path)
+                .map(|entries| entries.flatten().map(|e| e.path()).collect())
+                .unwrap_or_default();
+            ret.sort();
+            ret
+        }
+        Some((archive, kind, member)) => archive_index(&archive, kind)
+            .map(|i| i.children(&member).into_iter().map(|c| path.join(c)).collect())
+            .unwrap_or_default(),
+    }
+}
+
+/// The stored size of a file (compressed bytes for compressed files).
+pub fn len(path: &Path) -> u64 {
+    match split_archive(path) {
+        None => fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+        Some((archive, kind, member)) => archive_index(&archive, kind)
+            .ok()
+            .and_then(|i| i.files.get(&member).map(|(_, size)| *size))
+            .unwrap_or(0),
+    }
+}
+
+/// The bytes of a file as they are stored (not decompressed).
+pub fn open_raw(path: &Path) -> Result<Box<dyn Read>, String> {
+    let failed = |e: io::Error| format!("❌ Failed to open {:?}: {}", path, e);
+    match split_archive(path) {
+        None => Ok(Box::new(File::open(path).map_err(failed)?)),
+        Some((archive, kind, member)) => archive_index(&archive, kind)
+            .and_then(|i| i.open(&archive, &member))
+            .map_err(failed),
+    }
+}
+
+/// Open a (text) file, decompressing it on the fly if it is gzip, zstd or bzip2 compressed.
+pub(crate) fn open_text(path: &Path) -> Result<Box<dyn Read>, String> {
+    decompress(open_raw(path)?).map_err(|e| format!("❌ Failed to open {:?}: {}", path, e))
+}
+
+pub(crate) fn read_to_string(path: &Path) -> Result<String, String> {
+    let mut text = String::new();
+    open_text(path)?
+        .read_to_string(&mut text)
+        .map_err(|e| format!("❌ Failed to read {:?}: {}", path, e))?;
+    Ok(text)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn compressed_files_and_archives() {
+        let dir = std::env::temp_dir().join(format!("pf3d_source_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let text = "AAAC-1\nAAAG-1\n";
+
+        // compression is found from the content, whatever the extension says
+        let zst = dir.join("barcodes.tsv");
+        fs::write(&zst, zstd::encode_all(text.as_bytes(), 3).unwrap()).unwrap();
+        assert_eq!(read_to_string(&zst).unwrap(), text);
+        let mut bz = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        bz.write_all(text.as_bytes()).unwrap();
+        let bz2 = dir.join("barcodes.tsv.bz2");
+        fs::write(&bz2, bz.finish().unwrap()).unwrap();
+        assert_eq!(read_to_string(&bz2).unwrap(), text);
+        assert!(!is_plain_file(&bz2));
+
+        // a gzipped member of a .tar.gz
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(text.as_bytes()).unwrap();
+        let member = gz.finish().unwrap();
+        let mut tar = tar::Builder::new(Vec::new());
+        for (name, data) in [("./pbmc/barcodes.tsv.gz", member.as_slice()), ("./pbmc/meta.tsv", b"barcode\n")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, data).unwrap();
+        }
+        let mut tgz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        tgz.write_all(&tar.into_inner().unwrap()).unwrap();
+        let archive = dir.join("pbmc.tar.gz");
+        fs::write(&archive, tgz.finish().unwrap()).unwrap();
+
+        assert!(is_dir(&archive) && is_dir(&archive.join("pbmc")));
+        assert_eq!(list_dir(&archive.join("pbmc")), vec![
+            archive.join("pbmc/barcodes.tsv.gz"),
+            archive.join("pbmc/meta.tsv"),
+        ]);
+        assert_eq!(read_to_string(&archive.join("pbmc/barcodes.tsv.gz")).unwrap(), text);
+        assert_eq!(read_to_string(&archive.join("pbmc/meta.tsv")).unwrap(), "barcode\n");
+        assert!(!exists(&archive.join("pbmc/features.tsv.gz")));
+
+        // the same member from a .zip
+        let path = dir.join("pbmc.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+        let options = zip::write::SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        zip.start_file("pbmc/barcodes.tsv", options).unwrap();
+        zip.write_all(text.as_bytes()).unwrap();
+        zip.finish().unwrap();
+        assert_eq!(read_to_string(&path.join("pbmc/barcodes.tsv")).unwrap(), text);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
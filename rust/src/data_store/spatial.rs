@@ -0,0 +1,174 @@
+//spatial.rs
+//! 10x Visium (Space Ranger) output folders:
+//!
+//! * `filtered_feature_bc_matrix.h5` or `filtered_feature_bc_matrix/` - the expression
+//! * `spatial/tissue_positions.csv` (`tissue_positions_list.csv` before Space Ranger 2.0)
+//! * `spatial/scalefactors_json.json` and `spatial/tissue_{hires,lowres}_image.png`
+//!
+//! The spot positions become the `spatial` projection in full resolution pixels
+//! (x = column, y = -row so that the tissue is not upside down in VR).
+use crate::data_store::DataStore;
+use crate::data_store::source::{self, open_text};
+use crate::data_store::doctor::{Issue, Severity};
+use crate::data_store::projection::map_rows_by_barcode;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// The `drcs` key of the spot positions.
+pub const SPATIAL_PROJECTION: &str = "spatial";
+
+const POSITION_FILES: [&str; 2] = ["tissue_positions.csv", "tissue_positions_list.csv"];
+const MATRIX_CANDIDATES: [&str; 4] = [
+    "filtered_feature_bc_matrix.h5",
+    "filtered_feature_bc_matrix",
+    "raw_feature_bc_matrix.h5",
+    "raw_feature_bc_matrix",
+];
+
+/// `scalefactors_json.json` as written by Space Ranger.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct ScaleFactors {
+    tissue_hires_scalef: f32,
+    tissue_lowres_scalef: f32,
+    spot_diameter_fullres: f32,
+}
+
+/// The tissue image belonging to the `spatial` projection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpatialInfo {
+    /// the hires (or lowres) tissue image, if there is one
+    pub image: Option<PathBuf>,
+    /// image pixels per full resolution pixel (the `tissue_*_scalef` of the image)
+    pub image_scale: f32,
+    /// the spot diameter in full resolution pixels
+    pub spot_diameter: f32,
+}
+
+/// Is `dir` a Space Ranger output folder (it has a `spatial/tissue_positions*.csv`)?
+pub fn is_visium_folder(dir: &Path) -> bool {
+    POSITION_FILES.iter().any(|f| source::is_file(&dir.join("spatial").join(f)))
+}
+
+impl DataStore {
+    /// Load a Space Ranger output folder: the (filtered) matrix plus the spot positions
+    /// as the `spatial` projection. The z axis defaults to 0 - use `set_projection_axes`
+    /// with `ZAxis::Feature` to lift the spots by the expression of a gene.
+    pub fn from_visium<P: AsRef<Path>>(dir: P) -> Result<Self, String> {
+        let dir = dir.as_ref();
+        let matrix = MATRIX_CANDIDATES
+            .iter()
+            .map(|m| dir.join(m))
+            .find(|p| source::exists(p))
+            .ok_or_else(|| format!("❌ No filtered_feature_bc_matrix(.h5) in {:?}", dir))?;
+        let mut ret = if source::is_dir(&matrix) {
+            Self::from_cellranger_cached(&matrix)?
+        } else {
+            Self::from_10x_h5(&matrix)?
+        };
+        ret.load_spatial(&dir.join("spatial"))?;
+        Ok(ret)
+    }
+
+    /// Read the spot positions, scale factors and tissue image from a `spatial/` folder.
+    pub fn load_spatial(&mut self, dir: &Path) -> Result<(), String> {
+        let positions = POSITION_FILES
+            .iter()
+            .map(|f| dir.join(f))
+            .find(|p| source::is_file(p))
+            .ok_or_else(|| format!("❌ No tissue_positions.csv in {:?}", dir))?;
+        let (barcodes, rows) = read_tissue_positions(&positions)?;
+        let (data, stats) = map_rows_by_barcode(&barcodes, &rows, &self.cell_names);
+        if stats.matched == 0 {
+            return Err(format!("❌ No spot of {:?} is part of the dataset", positions));
+        }
+        println!(
+            "🔬 {} spots matched, {} spots without cells, {} cells without a spot",
+            stats.matched, stats.dropped, stats.missing
+        );
+        if stats.missing > 0 {
+            self.load_issues.push(Issue {
+                severity: Severity::Warning,
+                file: positions.file_name().unwrap_or_default().to_string_lossy().to_string(),
+                row: None,
+                problem: format!("{} cells have no spot position", stats.missing),
+            });
+        }
+        self.drcs.insert(SPATIAL_PROJECTION.to_string(), data);
+        self.axes.remove(SPATIAL_PROJECTION);
+
+        let factors: ScaleFactors = match source::read_to_string(&dir.join("scalefactors_json.json")) {
+            Ok(text) => serde_json::from_str(&text).map_err(|e| format!("❌ Invalid scalefactors_json.json: {}", e))?,
+            Err(_) => {
+                println!("⚠️ No scalefactors_json.json in {:?} - the tissue image can not be placed", dir);
+                ScaleFactors::default()
+            }
+        };
+        let image = [
+            ("tissue_hires_image.png", factors.tissue_hires_scalef),
+            ("tissue_lowres_image.png", factors.tissue_lowres_scalef),
+        ]
+        .into_iter()
+        .map(|(f, scale)| (dir.join(f), scale))
+        // Godot loads the image itself - images inside an archive are not shown
+        .find(|(p, scale)| !source::in_archive(p) && source::is_file(p) && *scale > 0.0);
+
+        self.spatial = Some(SpatialInfo {
+            image_scale: image.as_ref().map(|(_, s)| *s).unwrap_or(1.0),
+            image: image.map(|(p, _)| p),
+            spot_diameter: factors.spot_diameter_fullres,
+        });
+        Ok(())
+    }
+}
+
+/// Barcodes and (x, y) = (pxl_col, -pxl_row) of all spots in a tissue positions file.
+/// Columns: barcode, in_tissue, array_row, array_col, pxl_row_in_fullres, pxl_col_in_fullres
+/// (with a header line since Space Ranger 2.0).
+fn read_tissue_positions(path: &Path) -> Result<(Vec<String>, Vec<Vec<f32>>), String> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(open_text(path)?);
+    let mut barcodes = Vec::new();
+    let mut rows = Vec::new();
+    for (i, record) in rdr.records().enumerate() {
+        let record = record.map_err(|e| format!("❌ Failed to read {:?}: {}", path, e))?;
+        if i == 0 && record.get(0) == Some("barcode") {
+            continue;
+        }
+        if record.len() < 6 {
+            return Err(format!("❌ {:?} line {}: expected 6 columns, found {}", path, i + 1, record.len()));
+        }
+        let px = |c: usize| record[c].trim().parse::<f32>().unwrap_or(f32::NAN);
+        barcodes.push(record[0].to_string());
+        rows.push(vec![px(5), -px(4)]);
+    }
+    Ok((barcodes, rows))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_with_and_without_header() {
+        let dir = std::env::temp_dir().join(format!("printforge3d_visium_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let new = dir.join("tissue_positions.csv");
+        std::fs::write(
+            &new,
+            "barcode,in_tissue,array_row,array_col,pxl_row_in_fullres,pxl_col_in_fullres\nAAA-1,1,0,0,100,200\n",
+        )
+        .unwrap();
+        let old = dir.join("tissue_positions_list.csv");
+        std::fs::write(&old, "AAA-1,1,0,0,100,200\nCCC-1,0,0,2,110,210\n").unwrap();
+
+        let (barcodes, rows) = read_tissue_positions(&new).unwrap();
+        assert_eq!(barcodes, vec!["AAA-1"]);
+        assert_eq!(rows, vec![vec![200.0, -100.0]]);
+        let (barcodes, _) = read_tissue_positions(&old).unwrap();
+        assert_eq!(barcodes.len(), 2);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
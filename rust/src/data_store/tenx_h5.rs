@@ -0,0 +1,180 @@
+//tenx_h5.rs
+use crate::data_store::DataStore;
+use crate::data_store::hdf5_utils::{read_compressed_group, read_strings, read_usize};
+use crate::data_store::modality::{split_by_feature_type, GENE_EXPRESSION};
+use hdf5::{File as H5File, Group};
+use std::collections::HashMap;
+use std::path::Path;
+
+impl DataStore {
+    /// Load a 10x Genomics HDF5 matrix (`filtered_feature_bc_matrix.h5`).
+    ///
+    /// Reads `matrix/data|indices|indptr|shape`, `matrix/barcodes` and
+    /// `matrix/features/{id,name,feature_type}` (Cell Ranger v3+).
+    /// Cell Ranger v2 files (one group per genome with `genes`/`gene_names`) are read as well.
+    ///
+    /// `meta.tsv`/`meta.factors.json` and '*.drc' files next to the .h5 are loaded
+    /// like `from_cellranger` does it (both are optional).
+    pub fn from_10x_h5<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let file = H5File::open(path).map_err(|e| format!("❌ Failed to open {:?}: {}", path, e))?;
+
+        let group = if file.link_exists("matrix") {
+            file.group("matrix")
+        } else {
+            // Cell Ranger v2: the first (usually only) genome group
+            let first = file.member_names().unwrap_or_default().into_iter().next().unwrap_or_default();
+            file.group(&first)
+        }
+        .map_err(|e| format!("❌ No matrix group found in {:?}: {}", path, e))?;
+
+        // --- Genes ---
+        let Features { ids: gene_ids, names: gene_names, types: feature_types } = read_features(&group)?;
+        if gene_names.is_empty() {
+            return Err(format!("❌ No gene names found in {:?}", path));
+        }
+
+        // --- Cell barcodes ---
+        let cell_names = group
+            .dataset("barcodes")
+            .map_err(|e| format!("❌ No barcodes in {:?}: {}", path, e))
+            .and_then(|ds| read_strings(&ds))?;
+        if cell_names.is_empty() {
+            return Err(format!("❌ No cell barcodes found in {:?}", path));
+        }
+
+        // --- Matrix (features × barcodes, stored CSC) ---
+        let shape = group
+            .dataset("shape")
+            .map_err(|e| format!("❌ No matrix shape in {:?}: {}", path, e))
+            .and_then(|ds| read_usize(&ds))?;
+        if shape != [gene_names.len(), cell_names.len()] {
+            return Err(format!(
+                "❌ Matrix shape {:?} does not match {} features × {} barcodes",
+                shape,
+                gene_names.len(),
+                cell_names.len()
+            ));
+        }
+        let counts = read_compressed_group(&group, (shape[0], shape[1]), false)?;
+        if counts.nnz() == 0 {
+            return Err(format!("❌ Matrix {:?} appears empty", path));
+        }
+
+        // --- Split into modalities (gene meta: the features of the primary one) ---
+        let records: Vec<Vec<String>> = gene_ids
+            .iter()
+            .zip(&gene_names)
+            .zip(&feature_types)
+            .map(|((id, name), t)| vec![id.clone(), name.clone(), t.clone()])
+            .collect();
+        let (primary_modality, counts, gene_names, modalities) =
+            split_by_feature_type(counts, gene_names, &feature_types);
+
+        // --- Metadata + projections next to the .h5 ---
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let (cell_meta, factor_numbers) = Self::load_cell_meta(dir, &cell_names)?;
+
+        let mut ret = Self::from_parts(counts, gene_names, cell_names, cell_meta, Default::default(), HashMap::new())?;
+        ret.factor_numbers = factor_numbers;
+        ret.primary_modality = primary_modality;
+        ret.modalities = modalities;
+        ret.set_gene_table_from_features(&records)?;
+        ret.load_projections_in(dir);
+        Ok(ret)
+    }
+}
+
+/// The feature table of a 10x `.h5` matrix, one entry per row of the matrix.
+struct Features {
+    ids: Vec<String>,
+    names: Vec<String>,
+    types: Vec<String>,
+}
+
+/// Read v3 `features/` or v2 `genes`/`gene_names` (which are all gene expression).
+fn read_features(group: &Group) -> Result<Features, String> {
+    let read = |name: &str| -> Result<Vec<String>, String> {
+        group
+            .dataset(name)
+            .map_err(|e| format!("❌ No '{}' in {}: {}", name, group.name(), e))
+            .and_then(|ds| read_strings(&ds))
+    };
+
+    if group.link_exists("features") {
+        let ids = read("features/id")?;
+        let names = read("features/name")?;
+        let types = read("features/feature_type")
+            .unwrap_or_else(|_| vec![GENE_EXPRESSION.to_string(); names.len()]);
+        Ok(Features { ids, names, types })
+    } else {
+        let ids = read("genes")?;
+        let names = read("gene_names")?;
+        let types = vec![GENE_EXPRESSION.to_string(); names.len()];
+        Ok(Features { ids, names, types })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_store::hdf5_utils::fixtures::{write_compressed, write_strings, write_values};
+    use std::path::PathBuf;
+
+    /// A Cell Ranger v3 file with two genes and one antibody over two cells (features × barcodes CSC):
+    /// c0 = (g0: 1, adt: 5), c1 = (g1: 2, adt: 7). `shape` and `indptr` can be overridden.
+    fn write_10x_h5(tag: &str, shape: &[i32], indptr: &[i64]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("printforge3d_10x_{}_{}", tag, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("filtered_feature_bc_matrix.h5");
+        let file = H5File::create(&path).unwrap();
+        let matrix = file.create_group("matrix").unwrap();
+        write_strings(&matrix, "barcodes", &["c0", "c1"]);
+        write_compressed(&matrix, &[1.0, 5.0, 2.0, 7.0], &[0, 2, 1, 2], indptr);
+        write_values(&matrix, "shape", shape);
+        let features = matrix.create_group("features").unwrap();
+        write_strings(&features, "id", &["ENSG0", "ENSG1", "ADT0"]);
+        write_strings(&features, "name", &["g0", "g1", "CD3"]);
+        write_strings(&features, "feature_type", &["Gene Expression", "Gene Expression", "Antibody Capture"]);
+        path
+    }
+
+    fn remove(path: &Path) {
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn matrix_and_features_are_split_by_modality() {
+        let path = write_10x_h5("ok", &[3, 2], &[0, 2, 4]);
+        let ds = DataStore::from_10x_h5(&path);
+        remove(&path);
+        let ds = ds.unwrap();
+
+        assert_eq!(ds.primary_modality, GENE_EXPRESSION);
+        assert_eq!(ds.gene_names, vec!["g0", "g1"]);
+        assert_eq!(ds.gene_ids(), vec!["ENSG0", "ENSG1"]);
+        assert_eq!(ds.cell_names, vec!["c0", "c1"]);
+        assert_eq!(ds.counts.shape(), (2, 2));
+        assert_eq!(ds.counts.get(0, 0), Some(&1.0));
+        assert_eq!(ds.counts.get(1, 1), Some(&2.0));
+        assert_eq!(ds.counts.get(0, 1), None);
+
+        let adt = &ds.modalities["Antibody Capture"];
+        assert_eq!(adt.feature_names, vec!["CD3"]);
+        assert_eq!(adt.counts.get(0, 0), Some(&5.0));
+        assert_eq!(adt.counts.get(0, 1), Some(&7.0));
+    }
+
+    #[test]
+    fn shape_mismatches_are_errors() {
+        // shape says three cells, there are two barcodes
+        let path = write_10x_h5("shape", &[3, 3], &[0, 2, 4]);
+        assert!(DataStore::from_10x_h5(&path).is_err());
+        remove(&path);
+
+        // indptr for one cell
+        let path = write_10x_h5("indptr", &[3, 2], &[0, 4]);
+        assert!(DataStore::from_10x_h5(&path).is_err());
+        remove(&path);
+    }
+}
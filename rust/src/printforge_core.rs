@@ -1,12 +1,13 @@
 use godot::prelude::*;
 use std::collections::{HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::thread;
 
-use crate::data_store::DataStore;
+use crate::data_store::{DataStore, DoctorReport, GeneJoin, HvgFlavor, KnnOptions, Normalization, PcaOptions, ProjectionAxes, QcThresholds, RAW_LAYER, Severity, SpatialInfo, ZAxis, SPATIAL_PROJECTION};
 use crate::umap_graph_3d::UmapGraph3D;
 use godot::classes::Engine;
-use std::path::Path;
-use std::fs;
-use crate::utils::color_to_id;
+use crate::utils::{color_to_id, id_to_color, value_to_color};
 use ordered_float::OrderedFloat;
 
 
@@ -17,83 +18,951 @@ pub struct PrintForgeCore {
     base: Base<Node3D>,
 
     datasets: HashMap<String, DataStore>,
-    projections: Vec<Gd<UmapGraph3D>>,
+    reports: HashMap<String, DoctorReport>,
+    pending: Vec<PendingLoad>,
+}
+
+/// What a loader thread sends back to the main thread.
+enum LoadMessage {
+    Progress(f32, String),
+    Done(Box<DataStore>, DoctorReport),
+    Failed(String),
+    /// A job on a loaded dataset is over (see `start_job`); the dataset comes back with it.
+    JobDone(Box<DataStore>, Result<JobResult, String>),
+}
+
+/// What a job on a worker thread computed, reported by `finish_job`.
+enum JobResult {
+    VariableGenes(Vec<String>),
+    Pca { projection: String, variance_ratio: Vec<f32> },
+    Neighbors { projection: String },
+}
+
+/// A dataset that is loading (or running a job) on a worker thread.
+struct PendingLoad {
+    name: String,
+    /// the #[func] that started the job, `None` for a load
+    job: Option<String>,
+    rx: mpsc::Receiver<LoadMessage>,
+    handle: Option<thread::JoinHandle<()>>,
 }
 
 #[godot_api]
 impl PrintForgeCore {
 
+    /// Emitted from the main thread while a dataset loads in the background
+    /// (`stage` is e.g. "reading", "matrix", "checking", "graphs").
+    #[signal]
+    fn dataset_load_progress(name: GString, fraction: f32, stage: GString);
+
+    /// Emitted once the dataset and its graphs are ready; `summary` holds
+    /// `n_cells`, `n_genes`, `projections`, `modalities`, `errors` and `warnings`.
+    #[signal]
+    fn dataset_loaded(name: GString, summary: Dictionary);
+
+    #[signal]
+    fn dataset_load_failed(name: GString, error: GString);
+
+    /// Emitted when a job on a dataset (e.g. `select_variable_genes`) failed;
+    /// `job` is the name of the function that started it.
+    #[signal]
+    fn dataset_job_failed(name: GString, job: GString, error: GString);
+
+    /// The result of `select_variable_genes`: the selected genes, most variable first.
+    #[signal]
+    fn variable_genes_selected(name: GString, genes: PackedStringArray);
+
+    /// `run_pca` is done and its projection is shown; `variance_ratio` holds the
+    /// explained variance ratio of every component.
+    #[signal]
+    fn pca_finished(name: GString, projection: GString, variance_ratio: PackedFloat32Array);
+
+    /// `build_neighbor_graph` is done; `get_neighbors` can be used on `projection` now.
+    #[signal]
+    fn neighbor_graph_built(name: GString, projection: GString);
+
+    /// Load a dataset on a worker thread. Progress and the result are reported
+    /// through the `dataset_load_*` signals; the graphs are created on the main
+    /// thread (in `process`) once the data is ready.
     #[func]
     pub fn load_dataset_and_projections(&mut self, name: GString, path: GString) {
-        let real_path = path.to_string();
+        let path = path.to_string();
         let name = name.to_string();
+        if self.pending.iter().any(|p| p.name == name) {
+            godot_error!("❌ Dataset '{}' is already loading", name);
+            return;
+        }
+        godot_print!("📂 Rust: Loading dataset '{}' from {}", name, path);
 
-        // 1️⃣ Call your original core loader
-        self.load_dataset(&name, &real_path);
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let progress = |stage: &str, fraction: f32| {
+                let _ = tx.send(LoadMessage::Progress(fraction, stage.to_string()));
+            };
+            let msg = match DataStore::open_with_progress(&path, &progress) {
+                Ok(ds) => {
+                    progress("checking", 0.0);
+                    let report = ds.doctor();
+                    LoadMessage::Done(Box::new(ds), report)
+                }
+                Err(e) => LoadMessage::Failed(format!("Failed to load dataset from {}: {}", path, e)),
+            };
+            let _ = tx.send(msg);
+        });
+        self.pending.push(PendingLoad { name, job: None, rx, handle: Some(handle) });
+        self.base_mut().set_process(true);
+    }
 
-        // 2️⃣ Then search for projections and visualize them
+    /// Run `job` on a worker thread. The dataset is taken out of `datasets` until the job
+    /// is over: calls on it fail with "not loaded" meanwhile and `handle_selection` skips it.
+    /// `process` puts it back and reports the result. A panic in `run` is reported as a
+    /// failed job; the dataset still comes back, as far as the job got with it.
+    /// Returns false if the dataset is not loaded or busy.
+    fn start_job(
+        &mut self,
+        name: &str,
+        job: &str,
+        run: impl FnOnce(&mut DataStore) -> Result<JobResult, String> + Send + 'static,
+    ) -> bool {
+        if self.pending.iter().any(|p| p.name == name) {
+            godot_error!("❌ Dataset '{}' is busy", name);
+            return false;
+        }
+        let Some(mut ds) = self.datasets.remove(name) else {
+            godot_error!("❌ Dataset '{}' not loaded", name);
+            return false;
+        };
+        godot_print!("⚙️ Rust: {} on '{}' started", job, name);
+        let (tx, rx) = mpsc::channel();
+        let job_name = job.to_string();
+        let handle = thread::spawn(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| run(&mut ds))).unwrap_or_else(|e| {
+                let reason = e
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| e.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                Err(format!("❌ {} panicked: {}", job_name, reason))
+            });
+            let _ = tx.send(LoadMessage::JobDone(Box::new(ds), result));
+        });
+        self.pending.push(PendingLoad { name: name.to_string(), job: Some(job.to_string()), rx, handle: Some(handle) });
+        self.base_mut().set_process(true);
+        true
+    }
 
-        let dataset_path =  Path::new(&real_path);
-        godot_print!("Initializing 3D graphs");
-        match fs::read_dir(dataset_path) {
-            Ok(entries) => {
-                for entry in entries.flatten() {
-                    let p = entry.path();
-                    let Some(ext) = p.extension().and_then(|e| e.to_str()) else { continue };
-                    if !ext.eq_ignore_ascii_case("drc") { continue }
-
-                    // projection type from file stem, fallback to full name
-                    let proj_name = p.file_stem()
-                        .and_then(|s| Some(format!("{}",s.display())) )
-                        .unwrap_or_else(|| p.to_string_lossy().to_string() )
-                        .to_string();
-
-                    let path_str: String = p.to_string_lossy().into_owned();
-                    godot_print!("I have identiofied a file: '{}'", path_str);
-
-                    let mut graph = UmapGraph3D::new_alloc();
-                    graph.bind_mut().from_projection_data(
-                        (&name).into(),              // dataset_name
-                        (&proj_name).into(),         // projection_type
-                        (&path_str).into(),                  // path to the .drc/.tsv file
-                        Color::from_rgb(0.9, 0.9, 0.9),   // base color
-                    );
-
-                    self.base_mut().add_child(&graph);
-                    //self.projections.push(graph);
+    /// Merge already loaded datasets into a new one (barcodes are prefixed with the
+    /// dataset names, which also become the `sample` factor).
+    /// `intersect` keeps only the genes all samples have, otherwise the union is used.
+    /// `projection` optionally names a .drc file calculated on the merged data.
+    #[func]
+    pub fn merge_datasets(
+        &mut self,
+        name: GString,
+        samples: PackedStringArray,
+        intersect: bool,
+        projection: GString,
+    ) -> bool {
+        let name = name.to_string();
+        let samples: Vec<String> = samples.as_slice().iter().map(|s| s.to_string()).collect();
+        let mut parts = Vec::new();
+        for sample in &samples {
+            let Some(ds) = self.datasets.get(sample) else {
+                godot_error!("❌ Dataset '{}' not loaded", sample);
+                return false;
+            };
+            parts.push((sample.as_str(), ds));
+        }
+        let join = if intersect { GeneJoin::Intersect } else { GeneJoin::Union };
+        let mut ds = match DataStore::merge(&parts, join) {
+            Ok(ds) => ds,
+            Err(e) => {
+                godot_error!("❌ Merging {:?} failed: {}", samples, e);
+                return false;
+            }
+        };
+        let projection = projection.to_string();
+        if !projection.is_empty() {
+            let key = std::path::Path::new(&projection)
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_else(|| "merged".to_string());
+            if let Err(e) = ds.load_projection_from_tsv(&key, &projection) {
+                godot_error!("❌ Failed to load the merged projection {}: {}", projection, e);
+            }
+        }
+        let report = ds.doctor();
+        self.finish_load(&name, ds, report);
+        true
+    }
+
+    /// Forward the messages of all running loads as signals and
+    /// finish the loads and jobs that are done.
+    fn poll_pending_loads(&mut self) {
+        let mut messages = Vec::new();
+        for (i, load) in self.pending.iter().enumerate() {
+            loop {
+                match load.rx.try_recv() {
+                    Ok(msg @ LoadMessage::Progress(..)) => messages.push((i, msg)),
+                    Ok(msg) => {
+                        messages.push((i, msg));
+                        break;
+                    }
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        messages.push((i, LoadMessage::Failed("loader thread stopped unexpectedly".to_string())));
+                        break;
+                    }
+                }
+            }
+        }
+
+        let mut done = Vec::new();
+        for (i, msg) in messages {
+            let name = self.pending[i].name.clone();
+            match msg {
+                LoadMessage::Progress(fraction, stage) => {
+                    self.emit_progress(&name, fraction, &stage);
                 }
+                LoadMessage::Done(ds, report) => {
+                    done.push(i);
+                    self.finish_load(&name, *ds, report);
+                }
+                LoadMessage::Failed(e) => {
+                    done.push(i);
+                    godot_error!("❌ {}: {}", name, e);
+                    match self.pending[i].job.clone() {
+                        None => {
+                            self.base_mut().emit_signal(
+                                "dataset_load_failed",
+                                &[GString::from(&name).to_variant(), GString::from(&e).to_variant()],
+                            );
+                        }
+                        Some(job) => {
+                            // the worker thread died without sending the dataset back
+                            self.remove_graphs(&name);
+                            self.emit_job_failed(&name, &job, &e);
+                        }
+                    }
+                }
+                LoadMessage::JobDone(ds, result) => {
+                    done.push(i);
+                    let job = self.pending[i].job.clone().unwrap_or_default();
+                    self.finish_job(&name, &job, *ds, result);
+                }
+            }
+        }
+
+        for i in done.into_iter().rev() {
+            let mut load = self.pending.remove(i);
+            if let Some(handle) = load.handle.take() {
+                let _ = handle.join();
             }
+        }
+        if self.pending.is_empty() {
+            self.base_mut().set_process(false);
+        }
+    }
+
+    /// Main thread part of a job: put the dataset back and report the result.
+    fn finish_job(&mut self, name: &str, job: &str, ds: DataStore, result: Result<JobResult, String>) {
+        self.datasets.insert(name.to_string(), ds);
+        let result = match result {
+            Ok(result) => result,
             Err(e) => {
-                godot_error!("❌ Could not read directory '{}': {}", dataset_path.display(), e);
+                godot_error!("{}", e);
+                self.emit_job_failed(name, job, &e);
+                return;
+            }
+        };
+        godot_print!("✅ {} on '{}' finished", job, name);
+        match result {
+            JobResult::VariableGenes(genes) => {
+                let mut selected = PackedStringArray::new();
+                for g in &genes {
+                    selected.push(g.as_str());
+                }
+                self.base_mut().emit_signal(
+                    "variable_genes_selected",
+                    &[GString::from(name).to_variant(), selected.to_variant()],
+                );
             }
+            JobResult::Pca { projection, variance_ratio } => {
+                if self.graphs_of_projection(name, &projection).is_empty() {
+                    self.add_graph(name, &projection);
+                } else {
+                    self.refresh_projection(name, &projection);
+                }
+                let ratio: PackedFloat32Array = variance_ratio.into_iter().collect();
+                self.base_mut().emit_signal(
+                    "pca_finished",
+                    &[
+                        GString::from(name).to_variant(),
+                        GString::from(&projection).to_variant(),
+                        ratio.to_variant(),
+                    ],
+                );
+            }
+            JobResult::Neighbors { projection } => {
+                self.base_mut().emit_signal(
+                    "neighbor_graph_built",
+                    &[GString::from(name).to_variant(), GString::from(&projection).to_variant()],
+                );
+            }
+        }
+    }
+
+    fn emit_job_failed(&mut self, name: &str, job: &str, error: &str) {
+        self.base_mut().emit_signal(
+            "dataset_job_failed",
+            &[
+                GString::from(name).to_variant(),
+                GString::from(job).to_variant(),
+                GString::from(error).to_variant(),
+            ],
+        );
+    }
+
+    fn emit_progress(&mut self, name: &str, fraction: f32, stage: &str) {
+        self.base_mut().emit_signal(
+            "dataset_load_progress",
+            &[
+                GString::from(name).to_variant(),
+                fraction.to_variant(),
+                GString::from(stage).to_variant(),
+            ],
+        );
+    }
+
+    /// Main thread part of a load: store the dataset, build the graphs and report.
+    /// A dataset loaded again under the same name replaces the old one and its graphs.
+    fn finish_load(&mut self, name: &str, ds: DataStore, report: DoctorReport) {
+        godot_print!("✅ Dataset '{}' loaded", name);
+        let mut summary = Dictionary::new();
+        summary.set("n_cells", ds.cell_names.len() as i64);
+        summary.set("n_genes", ds.gene_names.len() as i64);
+        summary.set("projections", ds.drcs.len() as i64);
+        let mut modalities = PackedStringArray::new();
+        for m in ds.modality_names() {
+            modalities.push(m.as_str());
+        }
+        summary.set("modalities", modalities);
+        summary.set("errors", report.errors().count() as i64);
+        summary.set("warnings", report.warnings().count() as i64);
+
+        self.report_dataset(name, report);
+        if self.datasets.insert(name.to_string(), ds).is_some() {
+            godot_print!("♻️ Dataset '{}' replaced - rebuilding its graphs", name);
+            self.remove_graphs(name);
+        }
+
+        // visualize all projections the loader found
+        // (the *.drc files of a folder or the embeddings of a single file)
+        self.emit_progress(name, 0.0, "graphs");
+        godot_print!("Initializing 3D graphs");
+        self.add_graphs_from_store(name);
+
+        // default coloring from the dataset.toml
+        let color_by = self.datasets.get(name).and_then(|ds| ds.manifest.color_by.clone());
+        if let Some(column) = color_by {
+            self.color_by_meta(name.into(), (&column).into());
         }
+        self.emit_progress(name, 1.0, "graphs");
         godot_print!("Finished");
 
+        self.base_mut().emit_signal(
+            "dataset_loaded",
+            &[GString::from(name).to_variant(), summary.to_variant()],
+        );
     }
 
 
-    fn load_dataset(&mut self, name: &str, path: &str) -> Option<Vec<String>> {
-        godot_print!("📂 Rust: Loading dataset '{}' from {}", name, path);
-        match DataStore::from_cellranger(path) {
-            Ok(ds) => {
-                godot_print!("✅ Dataset '{}' loaded", name);
-                self.datasets.insert(name.to_string(), ds);
-                self.datasets.get(name)
-                    .and_then(|stored_ds| {
-                        stored_ds.cell_meta
-                            .factors
-                            .get("barcode")
-                            .map(|f| f.get_levels().to_vec())
-                    })
+    /// Create one UmapGraph3D per projection stored in the dataset's `drcs`.
+    fn add_graphs_from_store(&mut self, name: &str) {
+        let Some(ds) = self.datasets.get(name) else {
+            return;
+        };
+        let keys: Vec<String> = ds.drcs.keys().cloned().collect();
+        for key in keys {
+            self.add_graph(name, &key);
+        }
+    }
+
+    /// Remove and free all graphs of a dataset.
+    fn remove_graphs(&mut self, name: &str) {
+        for mut graph in self.graphs_of(name) {
+            self.base_mut().remove_child(&graph);
+            graph.queue_free();
+        }
+    }
+
+    /// Add a graph showing the `drcs` entry `projection` of a dataset.
+    /// The title and base color come from the dataset.toml if it has them.
+    fn add_graph(&mut self, name: &str, projection: &str) -> bool {
+        let Some(ds) = self.datasets.get(name) else {
+            godot_error!("❌ Dataset '{}' not loaded", name);
+            return false;
+        };
+        let entry = ds.manifest.projection(projection);
+        let base_color = entry
+            .and_then(|p| p.color.as_deref())
+            .map(id_to_color)
+            .unwrap_or(Color::from_rgb(0.9, 0.9, 0.9));
+        let title = entry
+            .and_then(|p| p.title.clone())
+            .unwrap_or_else(|| projection.to_string());
+
+        let mut graph = UmapGraph3D::new_alloc();
+        let built = graph.bind_mut().from_store(name, ds, projection, base_color);
+        if let Err(e) = built {
+            godot_error!("{}", e);
+            graph.free();
+            return false;
+        }
+        graph.bind_mut().label = (&title).into();
+        if projection == SPATIAL_PROJECTION {
+            if let Some(SpatialInfo { image: Some(image), image_scale, .. }) = &ds.spatial {
+                graph.bind_mut().show_tissue_image(&image.to_string_lossy(), *image_scale);
             }
+        }
+        self.base_mut().add_child(&graph);
+        true
+    }
+
+    /// Show a projection of a dataset in one more graph.
+    #[func]
+    pub fn add_projection_graph(&mut self, dataset: GString, projection: GString) -> bool {
+        self.add_graph(&dataset.to_string(), &projection.to_string())
+    }
+
+    /// (Re)load a projection file into the `drcs` of a dataset. All graphs showing it
+    /// are moved to the new coordinates; a new projection gets a graph.
+    #[func]
+    pub fn load_projection(&mut self, dataset: GString, projection: GString, path: GString) -> bool {
+        let (dataset, projection) = (dataset.to_string(), projection.to_string());
+        let Some(ds) = self.datasets.get_mut(&dataset) else {
+            godot_error!("❌ Dataset '{}' not loaded", dataset);
+            return false;
+        };
+        match ds.load_projection_from_tsv(&projection, &path.to_string()) {
+            Ok(stats) if !stats.is_complete() => godot_warn!(
+                "⚠️ Projection '{}' covers {} cells, {} have no coordinates",
+                projection,
+                stats.matched,
+                stats.missing
+            ),
+            Ok(_) => {}
             Err(e) => {
-                godot_error!("❌ Failed to load dataset '{}' from {}: {}", name, path, e);
-                None
-            },
+                godot_error!("❌ Failed to load projection '{}' from {}: {}", projection, path, e);
+                return false;
+            }
         }
+        if self.graphs_of_projection(&dataset, &projection).is_empty() {
+            self.add_graph(&dataset, &projection)
+        } else {
+            self.refresh_projection(&dataset, &projection)
+        }
+    }
 
+    /// Move all graphs of a projection to its current coordinates in the dataset.
+    fn refresh_projection(&mut self, dataset: &str, projection: &str) -> bool {
+        let Some(ds) = self.datasets.get(dataset) else {
+            godot_error!("❌ Dataset '{}' not loaded", dataset);
+            return false;
+        };
+        let view = match ds.projection_view(projection) {
+            Ok(view) => view,
+            Err(e) => {
+                godot_error!("{}", e);
+                return false;
+            }
+        };
+        for mut graph in self.graphs_of_projection(dataset, projection) {
+            graph.bind_mut().update_positions(&view);
+        }
+        true
     }
 
+    /// Log a doctor report and keep it for `get_dataset_report`.
+    fn report_dataset(&mut self, name: &str, report: DoctorReport) {
+        for issue in &report.issues {
+            match issue.severity {
+                Severity::Error => godot_error!("🩺 {}: {}", name, issue),
+                Severity::Warning => godot_warn!("🩺 {}: {}", name, issue),
+            }
+        }
+        godot_print!(
+            "🩺 Dataset '{}': {} errors, {} warnings",
+            name,
+            report.errors().count(),
+            report.warnings().count()
+        );
+        self.reports.insert(name.to_string(), report);
+    }
+
+    /// Re-run the consistency checks of a loaded dataset; returns true if there are no errors.
+    #[func]
+    pub fn check_dataset(&mut self, dataset: GString) -> bool {
+        let name = dataset.to_string();
+        let Some(report) = self.datasets.get(&name).map(|ds| ds.doctor()) else {
+            godot_error!("❌ Dataset '{}' not loaded", name);
+            return false;
+        };
+        let ok = report.is_ok();
+        self.report_dataset(&name, report);
+        ok
+    }
+
+    /// The consistency report of a dataset: one Dictionary per problem with
+    /// `severity` ("error"/"warning"), `file`, `row` (-1 if not row specific) and `problem`.
+    #[func]
+    pub fn get_dataset_report(&self, dataset: GString) -> Array<Dictionary> {
+        let mut ret = Array::new();
+        let Some(report) = self.reports.get(&dataset.to_string()) else {
+            return ret;
+        };
+        for issue in &report.issues {
+            let mut entry = Dictionary::new();
+            entry.set("severity", issue.severity.to_string());
+            entry.set("file", issue.file.clone());
+            entry.set("row", issue.row.map(|r| r as i64).unwrap_or(-1));
+            entry.set("problem", issue.problem.clone());
+            ret.push(&entry);
+        }
+        ret
+    }
+
+    /// Choose the embedding dimensions (0-based) a projection shows as x, y and z,
+    /// e.g. 1, 2, 3 for PC2/PC3/PC4. Updates all graphs of that projection.
+    #[func]
+    pub fn set_projection_dims(&mut self, dataset: GString, projection: GString, x: i64, y: i64, z: i64) -> bool {
+        if x < 0 || y < 0 || z < 0 {
+            godot_error!("❌ Dimensions must not be negative");
+            return false;
+        }
+        let axes = ProjectionAxes { x: x as usize, y: y as usize, z: ZAxis::Dim(z as usize) };
+        self.set_axes(&dataset.to_string(), &projection.to_string(), axes)
+    }
+
+    /// Set the z axis of a projection (keeping x and y): a number for a constant,
+    /// `dim:<i>` for another embedding dimension, `meta:<column>` or `gene:<name>`.
+    #[func]
+    pub fn set_projection_z(&mut self, dataset: GString, projection: GString, z: GString) -> bool {
+        let (dataset, projection) = (dataset.to_string(), projection.to_string());
+        let z = match ZAxis::parse(&z.to_string()) {
+            Ok(z) => z,
+            Err(e) => {
+                godot_error!("{}", e);
+                return false;
+            }
+        };
+        let Some(axes) = self.datasets.get(&dataset).and_then(|ds| ds.projection_axes(&projection)) else {
+            godot_error!("❌ Projection '{}' of dataset '{}' not found", projection, dataset);
+            return false;
+        };
+        self.set_axes(&dataset, &projection, ProjectionAxes { z, ..axes })
+    }
+
+    fn set_axes(&mut self, dataset: &str, projection: &str, axes: ProjectionAxes) -> bool {
+        let Some(ds) = self.datasets.get_mut(dataset) else {
+            godot_error!("❌ Dataset '{}' not loaded", dataset);
+            return false;
+        };
+        if let Err(e) = ds.set_projection_axes(projection, axes) {
+            godot_error!("{}", e);
+            return false;
+        }
+        self.refresh_projection(dataset, projection)
+    }
+
+    /// Write the counts of a loaded folder dataset into its `counts.pf3dgenes`;
+    /// the next load of the folder keeps the matrix on disk.
+    #[func]
+    pub fn write_out_of_core(&self, dataset: GString) -> bool {
+        let Some(ds) = self.datasets.get(&dataset.to_string()) else {
+            godot_error!("❌ Dataset '{}' not loaded", dataset);
+            return false;
+        };
+        let Some(dir) = ds.source.as_deref().filter(|p| p.is_dir()) else {
+            godot_error!("❌ Dataset '{}' was not loaded from a folder", dataset);
+            return false;
+        };
+        match ds.write_out_of_core(dir) {
+            Ok(path) => {
+                godot_print!("💾 '{}' written to {:?}", dataset, path);
+                true
+            }
+            Err(e) => {
+                godot_error!("{}", e);
+                false
+            }
+        }
+    }
+
+    /// Convert the matrix.mtx(.gz) of a dataset folder into its `counts.pf3dgenes` without
+    /// loading it (for matrices larger than the RAM). Blocks until the file is written.
+    #[func]
+    pub fn convert_folder_to_out_of_core(&self, path: GString) -> bool {
+        let path = path.to_string();
+        let last = std::cell::Cell::new(-1i32);
+        let progress = |f: f32| {
+            let step = (f * 10.0) as i32;
+            if step != last.replace(step) {
+                godot_print!("📦 converting {}: {:.0}%", path, f * 100.0);
+            }
+        };
+        match DataStore::convert_to_out_of_core(std::path::Path::new(&path), &progress) {
+            Ok(out) => {
+                godot_print!("💾 {} converted to {:?}", path, out);
+                true
+            }
+            Err(e) => {
+                godot_error!("{}", e);
+                false
+            }
+        }
+    }
+
+    /// Show or hide the tissue image under the spots of a spatial dataset.
+    #[func]
+    pub fn show_tissue(&mut self, dataset: GString, visible: bool) {
+        for mut graph in self.graphs_of_projection(&dataset.to_string(), SPATIAL_PROJECTION) {
+            graph.bind_mut().set_tissue_visible(visible);
+        }
+    }
+
+    /// Display settings of a dataset (from its dataset.toml):
+    /// `name`, `modality`, `color_by` and `titles` (projection -> title).
+    #[func]
+    pub fn get_dataset_info(&self, dataset: GString) -> Dictionary {
+        let mut info = Dictionary::new();
+        let name = dataset.to_string();
+        let Some(ds) = self.datasets.get(&name) else {
+            return info;
+        };
+        let manifest = &ds.manifest;
+        info.set("name", manifest.name.clone().unwrap_or(name));
+        info.set("modality", ds.primary_modality.clone());
+        info.set("color_by", manifest.color_by.clone().unwrap_or_default());
+        let mut titles = Dictionary::new();
+        for key in ds.drcs.keys() {
+            let title = manifest.projection(key).and_then(|p| p.title.clone()).unwrap_or_else(|| key.clone());
+            titles.set(key.clone(), title);
+        }
+        info.set("titles", titles);
+        info
+    }
+
+    /// Color all graphs of a dataset by a cell_meta column:
+    /// factors get one hue per level, numeric columns the grey → red gradient.
+    /// Fails while a job (variable genes, PCA, neighbor graph) runs on the dataset.
+    #[func]
+    pub fn color_by_meta(&mut self, dataset: GString, column: GString) {
+        let dataset = dataset.to_string();
+        let column = column.to_string();
+        let Some(ds) = self.datasets.get(&dataset) else {
+            godot_error!("❌ Dataset '{}' not loaded", dataset);
+            return;
+        };
+        if !ds.cell_meta.headers.contains(&column) {
+            godot_error!("❌ Column '{}' not found in the meta data of '{}'", column, dataset);
+            return;
+        }
+        let missing = Color::from_rgb(0.5, 0.5, 0.5);
+        let colors: Vec<Color> = match ds.meta_factor(&column) {
+            Some((levels, codes)) => {
+                let n = levels.len().max(1) as f32;
+                codes
+                    .iter()
+                    .map(|c| c.map_or(missing, |c| Color::from_hsv(c as f32 / n, 0.75, 0.9)))
+                    .collect()
+            }
+            None => {
+                let values = ds.cell_meta.as_vec_f64(&column);
+                let max = values.iter().copied().filter(|v| !v.is_nan()).fold(0.0f64, f64::max) as f32;
+                values
+                    .iter()
+                    .map(|v| if v.is_nan() { missing } else { value_to_color(*v as f32, max) })
+                    .collect()
+            }
+        };
+
+        for mut graph in self.graphs_of(&dataset) {
+            graph.bind_mut().set_colors(&colors);
+        }
+        godot_print!("🎨 Colored '{}' by '{}'", dataset, column);
+    }
+
+    /// All candidates for a gene symbol, Ensembl id or alias (`index`, `symbol`, `id`).
+    #[func]
+    pub fn find_gene(&self, dataset: GString, query: GString) -> Array<Dictionary> {
+        let mut ret = Array::new();
+        let Some(ds) = self.datasets.get(&dataset.to_string()) else {
+            godot_error!("❌ Dataset '{}' not loaded", dataset);
+            return ret;
+        };
+        let ids = ds.gene_ids();
+        for gene in ds.resolve_gene(&query.to_string()) {
+            let mut entry = Dictionary::new();
+            entry.set("index", gene as i64);
+            entry.set("symbol", ds.gene_names[gene].as_str());
+            entry.set("id", ids[gene].as_str());
+            ret.push(&entry);
+        }
+        ret
+    }
+
+    /// All feature types (modalities) of a dataset, the primary one first.
+    #[func]
+    pub fn get_modalities(&self, dataset: GString) -> PackedStringArray {
+        let mut ret = PackedStringArray::new();
+        if let Some(ds) = self.datasets.get(&dataset.to_string()) {
+            for name in ds.modality_names() {
+                ret.push(name.as_str());
+            }
+        }
+        ret
+    }
+
+    /// Color all graphs of a dataset by the values of one feature of a modality
+    /// (pass an empty modality for the primary one, usually "Gene Expression").
+    /// `layer` picks an expression layer of the primary modality ("" for the raw counts).
+    /// Fails while a job runs on the dataset.
+    #[func]
+    pub fn color_by_feature(&mut self, dataset: GString, modality: GString, feature: GString, layer: GString) {
+        let dataset = dataset.to_string();
+        let Some(ds) = self.datasets.get(&dataset) else {
+            godot_error!("❌ Dataset '{}' not loaded", dataset);
+            return;
+        };
+        let layer = layer.to_string();
+        let values = if layer.is_empty() || layer == RAW_LAYER {
+            ds.feature_values(&modality.to_string(), &feature.to_string())
+        } else {
+            ds.layer_values(&layer, &feature.to_string())
+        };
+        let values = match values {
+            Ok(v) => v,
+            Err(e) => {
+                godot_error!("❌ {}", e);
+                return;
+            }
+        };
+        // scaled layers and residuals are negative as well - color from the minimum
+        let min = values.iter().copied().fold(0.0f32, f32::min);
+        let max = values.iter().copied().fold(0.0f32, f32::max) - min;
+        let colors: Vec<Color> = values.iter().map(|v| value_to_color(*v - min, max)).collect();
+
+        for mut graph in self.graphs_of(&dataset) {
+            graph.bind_mut().set_colors(&colors);
+        }
+        godot_print!("🎨 Colored '{}' by '{}' (max {:.2})", dataset, feature, max);
+    }
+
+    /// Compute an expression layer, e.g. `library_size`, `cpm`, `log1p:<layer>`,
+    /// `pearson[:theta]` or `scaled:<layer>[:max]` (see `Normalization::parse`).
+    /// `genes` limits the dense layers (Pearson residuals, scaled) to these genes.
+    #[func]
+    pub fn add_expression_layer(&mut self, dataset: GString, name: GString, method: GString, genes: PackedStringArray) -> bool {
+        let Some(ds) = self.datasets.get_mut(&dataset.to_string()) else {
+            godot_error!("❌ Dataset '{}' not loaded", dataset);
+            return false;
+        };
+        let how = match Normalization::parse(&method.to_string()) {
+            Ok(how) => how,
+            Err(e) => {
+                godot_error!("{}", e);
+                return false;
+            }
+        };
+        let mut rows = Vec::new();
+        for gene in genes.as_slice() {
+            match ds.gene_index(&gene.to_string()) {
+                Ok(g) => rows.push(g),
+                Err(e) => godot_warn!("⚠️ {} - skipped", e),
+            }
+        }
+        let genes = if genes.is_empty() { None } else { Some(rows.as_slice()) };
+        match ds.add_layer(&name.to_string(), &how, genes) {
+            Ok(()) => true,
+            Err(e) => {
+                godot_error!("{}", e);
+                false
+            }
+        }
+    }
+
+    /// The expression layers of a dataset, the raw counts first.
+    #[func]
+    pub fn get_layers(&self, dataset: GString) -> PackedStringArray {
+        let mut ret = PackedStringArray::new();
+        if let Some(ds) = self.datasets.get(&dataset.to_string()) {
+            for name in ds.layer_names() {
+                ret.push(name.as_str());
+            }
+        }
+        ret
+    }
+
+    /// Add the QC metrics (nCount, nFeature, percent_mt, percent_ribo) to the cell_meta
+    /// of a dataset - out-of-core datasets do not get them on load.
+    #[func]
+    pub fn add_qc_metrics(&mut self, dataset: GString) -> bool {
+        let Some(ds) = self.datasets.get_mut(&dataset.to_string()) else {
+            godot_error!("❌ Dataset '{}' not loaded", dataset);
+            return false;
+        };
+        let patterns = ds.manifest.qc.clone();
+        match ds.add_qc_metrics(&patterns) {
+            Ok(()) => true,
+            Err(e) => {
+                godot_error!("{}", e);
+                false
+            }
+        }
+    }
+
+    /// Remove the cells failing QC from a dataset and rebuild its graphs.
+    /// `thresholds` may hold min_count, max_count, min_features, max_features,
+    /// max_percent_mt and max_percent_ribo; missing keys are not checked.
+    /// Returns the number of cells left (-1 on errors).
+    #[func]
+    pub fn filter_cells_by_qc(&mut self, dataset: GString, thresholds: Dictionary) -> i64 {
+        let name = dataset.to_string();
+        let Some(ds) = self.datasets.get_mut(&name) else {
+            godot_error!("❌ Dataset '{}' not loaded", dataset);
+            return -1;
+        };
+        let limit = |key: &str| thresholds.get(key).and_then(|v| v.try_to::<f64>().ok());
+        let thresholds = QcThresholds {
+            min_count: limit("min_count").map(|v| v as f32),
+            max_count: limit("max_count").map(|v| v as f32),
+            min_features: limit("min_features").map(|v| v as u32),
+            max_features: limit("max_features").map(|v| v as u32),
+            max_percent_mt: limit("max_percent_mt").map(|v| v as f32),
+            max_percent_ribo: limit("max_percent_ribo").map(|v| v as f32),
+        };
+        let patterns = ds.manifest.qc.clone();
+        let kept = ds.qc_pass(&patterns, &thresholds).and_then(|keep| ds.filter_cells(&keep));
+        let kept = match kept {
+            Ok(kept) => kept,
+            Err(e) => {
+                godot_error!("{}", e);
+                return -1;
+            }
+        };
+        // the point count changed - the graphs are built again
+        self.remove_graphs(&name);
+        self.add_graphs_from_store(&name);
+        godot_print!("🧹 {} cells of '{}' passed QC", kept, name);
+        kept as i64
+    }
+
+    /// Select the `n_top` highly variable genes of a dataset (`method`: seurat_v3 or
+    /// dispersion; `batch`: an optional cell_meta factor) and store the results in its
+    /// gene_meta. Runs on a worker thread; the selected genes (most variable first) are
+    /// sent with `variable_genes_selected`. Returns false if the job did not start.
+    #[func]
+    pub fn select_variable_genes(&mut self, dataset: GString, method: GString, n_top: i64, batch: GString) -> bool {
+        let (method, batch) = (method.to_string(), batch.to_string());
+        self.start_job(&dataset.to_string(), "select_variable_genes", move |ds| {
+            let flavor = HvgFlavor::parse(&method)?;
+            let genes = ds.select_hvgs(flavor, n_top.max(0) as usize, Some(batch.as_str()))?;
+            Ok(JobResult::VariableGenes(genes.iter().map(|g| ds.gene_names[*g].clone()).collect()))
+        })
+    }
+
+    /// Compute a PCA of the highly variable genes and show it as the projection `name`.
+    /// `layer` is a sparse expression layer (empty: log normalized counts); with a `group`
+    /// column only its cells (of `level`, or of any level if that is empty) are used.
+    /// Runs on a worker thread; `pca_finished` reports the explained variance ratio of
+    /// every component once the graph is shown. Returns false if the job did not start.
+    #[func]
+    pub fn run_pca(
+        &mut self,
+        dataset: GString,
+        name: GString,
+        n_components: i64,
+        layer: GString,
+        group: GString,
+        level: GString,
+    ) -> bool {
+        let name = name.to_string();
+        let (layer, group, level) = (layer.to_string(), group.to_string(), level.to_string());
+        self.start_job(&dataset.to_string(), "run_pca", move |ds| {
+            let cells = match group.as_str() {
+                "" => None,
+                _ => Some(ds.cells_in_group(&group, &level)?),
+            };
+            let opts = PcaOptions {
+                n_components: n_components.max(1) as usize,
+                layer: Some(layer).filter(|l| !l.is_empty()),
+                cells,
+                ..Default::default()
+            };
+            let result = ds.run_pca(&name, &opts)?;
+            let variance_ratio = result.variance_ratio.iter().map(|v| *v as f32).collect();
+            Ok(JobResult::Pca { projection: name, variance_ratio })
+        })
+    }
+
+    /// Build the kNN and SNN graphs of a dataset over a projection (e.g. the PCA scores),
+    /// using its first `n_dims` dimensions (all if 0). Runs on a worker thread and emits
+    /// `neighbor_graph_built` when done. Returns false if the job did not start.
+    #[func]
+    pub fn build_neighbor_graph(&mut self, dataset: GString, projection: GString, k: i64, n_dims: i64) -> bool {
+        let projection = projection.to_string();
+        let opts = KnnOptions {
+            k: k.max(1) as usize,
+            n_dims: Some(n_dims as usize).filter(|d| *d > 0),
+            ..Default::default()
+        };
+        self.start_job(&dataset.to_string(), "build_neighbor_graph", move |ds| {
+            ds.build_neighbors(&projection, &opts)?;
+            Ok(JobResult::Neighbors { projection })
+        })
+    }
+
+    /// The neighbors of a cell in the graph over `projection`: its k nearest
+    /// (nearest first) or, with `shared`, its SNN neighbors (strongest first).
+    #[func]
+    pub fn get_neighbors(&self, dataset: GString, projection: GString, cell: i64, shared: bool) -> PackedInt32Array {
+        let Some(ds) = self.datasets.get(&dataset.to_string()) else {
+            godot_error!("❌ Dataset '{}' not loaded", dataset);
+            return PackedInt32Array::new();
+        };
+        let projection = projection.to_string();
+        let cell = cell.max(0) as usize;
+        let neighbors = if shared { ds.snn_of(&projection, cell) } else { ds.knn_of(&projection, cell) };
+        match neighbors {
+            Ok(neighbors) => neighbors.iter().map(|(c, _)| *c as i32).collect(),
+            Err(e) => {
+                godot_error!("{}", e);
+                PackedInt32Array::new()
+            }
+        }
+    }
+
+    /// All UmapGraph3D children showing the given dataset.
+    fn graphs_of(&self, dataset: &str) -> Vec<Gd<UmapGraph3D>> {
+        self.base()
+            .get_children()
+            .iter_shared()
+            .filter_map(|child| child.try_cast::<UmapGraph3D>().ok())
+            .filter(|g| g.bind().dataset_name.to_string() == dataset)
+            .collect()
+    }
+
+    /// All UmapGraph3D children showing one projection of a dataset.
+    fn graphs_of_projection(&self, dataset: &str, projection: &str) -> Vec<Gd<UmapGraph3D>> {
+        self.graphs_of(dataset)
+            .into_iter()
+            .filter(|g| g.bind().projection_type.to_string() == projection)
+            .collect()
+    }
+
+    /// Select the cells inside a sphere (world coordinates) in every graph it touches
+    /// and color them in all graphs of their dataset. Datasets running a job are skipped.
     #[func]
     pub fn handle_selection(&mut self, center_data: Vector3, radius_data: f32, color: Color) {
         godot_print!(
@@ -103,46 +972,36 @@ impl PrintForgeCore {
         );
 
         let group_id = color_to_id(&color);
+        let names: Vec<String> = self.datasets.keys().cloned().collect();
 
-
-        for (dataset_name, ds) in self.datasets.iter_mut() {
+        for dataset_name in names {
+            let graphs = self.graphs_of(&dataset_name);
+            let Some(ds) = self.datasets.get_mut(&dataset_name) else {
+                continue;
+            };
             // Collect unique cell IDs across all projections for this dataset
             let mut all_selected: HashSet<i32> = HashSet::new();
 
             // 1️⃣ Iterate over all projections belonging to this dataset
-            for   graph_gd in self.projections.iter() {
-                let  graph = graph_gd.bind();
-
-                if graph.dataset_name.to_string() != *dataset_name {
-                    continue;
-                }
+            for graph_gd in &graphs {
+                let graph = graph_gd.bind();
 
                 // Convert VR → data space for this graph
                 let (data_center, data_radius) =
                     graph.world_selection_to_data_selection(center_data, radius_data);
 
-                // Select once per dataset (if not yet computed)
-                let pos = [center_data.x, center_data.y, center_data.z];
-                let selected = ds.select_in_sphere(
-                    &(graph.projection_type.to_string()),
-                    &group_id,
-                    &pos,
-                    data_radius,
-
-                );
-                while let Ok(ref v) = selected{
-                    all_selected.extend(v);
+                let pos = [data_center.x, data_center.y, data_center.z];
+                match ds.select_in_sphere(&graph.projection_type.to_string(), &group_id, &pos, data_radius) {
+                    Ok(selected) => all_selected.extend(selected),
+                    Err(e) => godot_error!("❌ Selection in '{}' failed: {}", graph.projection_type, e),
                 }
             }
 
             // 2️⃣ Apply coloring to ALL UmapGraph3D belonging to this dataset
             if !all_selected.is_empty() {
                 let selected_vec: Vec<i32> = all_selected.iter().copied().collect();
-                for mut graph_gd in self.projections.iter_mut() {
-                    let mut graph = graph_gd.bind_mut();
-                    if graph.dataset_name.to_string() == *dataset_name {
-                        graph.set_to_color(&selected_vec, color);
-                    }
+                for mut graph_gd in graphs {
+                    graph_gd.bind_mut().set_to_color(&selected_vec, color);
                 }
             }
         }
@@ -160,4 +1019,11 @@ impl INode3D for PrintForgeCore {
     fn ready(&mut self) {
         godot_print!("🧠 PrintForgeCore ready — awaiting dataset load...");
     }
+
+    fn process(&mut self, _delta: f64) {
+        // loads and jobs
+        if !self.pending.is_empty() {
+            self.poll_pending_loads();
+        }
+    }
 }
\ No newline at end of file
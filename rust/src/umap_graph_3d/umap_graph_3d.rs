@@ -3,12 +3,12 @@ use godot::classes::{
     ArrayMesh, MeshInstance3D, MultiMeshInstance3D, RenderingServer, Shader, ShaderMaterial,
     Image, ImageTexture, MultiMesh,StandardMaterial3D,
     mesh::{PrimitiveType, ArrayType},
+    base_material_3d::{CullMode, ShadingMode, TextureParam},
 };
-use ndarray::{Array2, s};
+use ndarray::Array2;
 use rayon::iter::IntoParallelIterator;
 use godot::classes::multi_mesh::TransformFormat;
-use rust_data_table::SurvivalData;
-use std::collections::HashSet;
+use crate::data_store::DataStore;
 use godot::classes::QuadMesh;
 use godot::classes::SphereMesh;
 use godot::classes::{Area3D, CollisionShape3D, BoxShape3D};
@@ -45,53 +45,61 @@ pub struct UmapGraph3D {
     #[export]
     radius: f32,
 
+    /// The color of points without any other coloring
+    #[init(val = Color::from_rgb(0.9, 0.9, 0.9))]
+    base_color: Color,
+
     /// The re-centered center of the drc data.
     #[export]
     center: Vector3,
 
     /// The scale_factor the original data was scaled to fit into VR
     #[export]
-    scale_factor:f32
+    scale_factor:f32,
+
+    /// The lowest z of all points (data space / 10), the tissue image is drawn below it
+    floor_z: f32,
+
+    /// Spatial datasets: the tissue image drawn as a plane under the spots
+    tissue: Option<Gd<MeshInstance3D>>,
+
+    /// The size of the tissue image in data units
+    tissue_size: Vector2,
 
 }
 
 
 #[godot_api]
 impl UmapGraph3D {
-    #[func]
-    pub fn from_projection_data(
+    /// Build the graph from the `drcs` entry `projection` of a loaded dataset,
+    /// in the dimensions `DataStore::projection_view` selects.
+    /// Graphs are created by `PrintForgeCore`, which keeps them in sync with its datasets.
+    pub fn from_store(
+        &mut self,
+        dataset_name: &str,
+        ds: &DataStore,
+        projection: &str,
+        base_color: Color,
+    ) -> Result<(), String> {
+        let view = ds.projection_view(projection)?;
+        godot_print!("📊 building projection with {} points", view.nrows());
+        self.from_projection_view(dataset_name.into(), projection.into(), &view, base_color);
+        Ok(())
+    }
+
+    /// Build the graph from an in-memory projection (cells × 3).
+    fn from_projection_view(
         &mut self,
         dataset_name: GString,
         projection_type: GString,
-        path: GString,
+        view: &Array2<f32>,
         base_color: Color,
     ) {
         self.dataset_name = dataset_name.clone();
         self.projection_type = projection_type.clone();
         self.id = format!("{}::{}", dataset_name, projection_type);
 
-        let path = path.to_string();
-
-        let ds = match SurvivalData::from_tsv(
-                &path,           // file
-                b'\t',               // delimiter
-                HashSet::new(),      // exclude set
-                String::new(),       // dataset name or id if you use it
-            ) {
-            Ok(ds) => ds,
-            Err(e) => {
-                godot_error!("❌ Failed to read '{}': {}", path, e);
-                return
-            }
-        };
-        let n = ds.numeric_data.shape()[0];
-        godot_print!("📊 building projection with {} points", n);
-        let n_cols = ds.numeric_data.shape()[1];
-        if n_cols < 4 {
-            godot_print!("Dataset must have at least 3 numeric columns (x, y, z) + rownames;n_col = {}\n{path}\n{:?}",n_cols,ds.numeric_data.row(0) );
-        }
-        let view = ds.numeric_data.slice(s![.., 1..4]).mapv(|v| v as f32).to_owned();
-        
+        let n = view.shape()[0];
 
         // ─── prepare MultiMesh
         let mut multimesh = MultiMesh::new_gd();
@@ -111,46 +119,9 @@ impl UmapGraph3D {
 
         
 
-        let mut min = Vector3::new(f32::MAX, f32::MAX, f32::MAX);
-        let mut max = Vector3::new(f32::MIN, f32::MIN, f32::MIN);
-
-        for (i, row) in view.axis_iter(ndarray::Axis(0)).enumerate() {
-            if row[0].is_nan() || row[1].is_nan() || row[2].is_nan() {
-                continue;
-            }
-            let pos = Vector3::new(row[0] /10.0, row[1]/10.0, row[2]/10.0);
-
-            // update bounds
-            min.x = min.x.min(pos.x);
-            min.y = min.y.min(pos.y);
-            min.z = min.z.min(pos.z);
-
-            max.x = max.x.max(pos.x);
-            max.y = max.y.max(pos.y);
-            max.z = max.z.max(pos.z);
-        }
-
-        // compute normalization
-        // 2️⃣ compute center for X/Z and bottom-aligned Y
-        self.center = Vector3::new((min.x + max.x) * 0.5, min.y, (min.z + max.z) * 0.5);
-
-        // 3️⃣ compute uniform scale (fit in ~3 m space)
-        let extent = (max - min).length();
-        self.scale_factor = 3.0 / extent;
-
-        for (i, row) in view.axis_iter(ndarray::Axis(0)).enumerate() {
-            if row[0].is_nan() || row[1].is_nan() || row[2].is_nan() {
-                multimesh.set_instance_color(i as i32, Color::from_rgba(0.0, 0.0, 0.0, 0.0)); // transparent
-                multimesh.set_instance_transform(i as i32, Transform3D::IDENTITY.scaled(Vector3::ZERO)); // collapsed
-                continue;
-            }
-            let pos = Vector3::new(row[0] /10.0, row[1]/10.0, row[2]/10.0);
-            let pos_norm = (pos - self.center) * self.scale_factor * 3.0; // 3.0 = target size in meters
-
-            let t = Transform3D::IDENTITY.translated(pos_norm);
-            multimesh.set_instance_transform(i as i32, t);
-            multimesh.set_instance_color(i as i32, base_color);
-        }
+        let (min, max) = self.fit_to_view(view);
+        self.base_color = base_color;
+        self.place_points(&mut multimesh, view, true);
         
         // ─── shader for round discs
         let mut mat: Gd<ShaderMaterial> = load("res://materials/umap_cells_std.tres");
@@ -187,6 +158,117 @@ impl UmapGraph3D {
     }
 
 
+    /// Compute `center` and `scale_factor` for a (cells × 3) view; returns the data bounds.
+    fn fit_to_view(&mut self, view: &Array2<f32>) -> (Vector3, Vector3) {
+        let (min, max) = view_bounds(view);
+        (self.center, self.scale_factor) = fit_bounds(min, max);
+        self.floor_z = min.z;
+        (min, max)
+    }
+
+    /// Data coordinates → position in this node.
+    fn to_local(&self, data: Vector3) -> Vector3 {
+        data_to_local(data, self.center, self.scale_factor)
+    }
+
+    /// Move all instances to the positions of `view` (NaN rows are hidden).
+    /// With `reset_colors` every visible point gets the base color, otherwise
+    /// existing colors are kept and only points that were hidden get it.
+    fn place_points(&self, multimesh: &mut Gd<MultiMesh>, view: &Array2<f32>, reset_colors: bool) {
+        for (i, row) in view.axis_iter(ndarray::Axis(0)).enumerate() {
+            if row[0].is_nan() || row[1].is_nan() || row[2].is_nan() {
+                multimesh.set_instance_color(i as i32, Color::from_rgba(0.0, 0.0, 0.0, 0.0)); // transparent
+                multimesh.set_instance_transform(i as i32, Transform3D::IDENTITY.scaled(Vector3::ZERO)); // collapsed
+                continue;
+            }
+            let t = Transform3D::IDENTITY.translated(self.to_local(Vector3::new(row[0], row[1], row[2])));
+            multimesh.set_instance_transform(i as i32, t);
+            if reset_colors || multimesh.get_instance_color(i as i32).a == 0.0 {
+                multimesh.set_instance_color(i as i32, self.base_color);
+            }
+        }
+    }
+
+    /// Show new coordinates for the same cells (e.g. other dimensions of the embedding).
+    /// Colors are kept.
+    pub fn update_positions(&mut self, view: &Array2<f32>) {
+        let Some(inst) = self.meshes.first().cloned() else {
+            godot_warn!("⚠️ No MultiMesh found in UmapGraph3D '{}'", self.dataset_name);
+            return;
+        };
+        let Some(mut mm) = inst.get_multimesh() else { return };
+        if mm.get_instance_count() as usize != view.nrows() {
+            godot_error!(
+                "❌ {} rows for {} points in '{}'",
+                view.nrows(),
+                mm.get_instance_count(),
+                self.id
+            );
+            return;
+        }
+        self.fit_to_view(view);
+        self.place_points(&mut mm, view, false);
+        self.place_tissue();
+    }
+
+    /// Draw a tissue image under the points. The image pixel (px, py) is at the data
+    /// position (px / image_scale, -py / image_scale), which is how spatial projections
+    /// store the spot positions.
+    pub fn show_tissue_image(&mut self, path: &str, image_scale: f32) {
+        let Some(image) = Image::load_from_file(path) else {
+            godot_error!("❌ Failed to load the tissue image '{}'", path);
+            return;
+        };
+        let Some(texture) = ImageTexture::create_from_image(&image) else {
+            godot_error!("❌ Failed to create a texture from '{}'", path);
+            return;
+        };
+        self.tissue_size = Vector2::new(image.get_width() as f32, image.get_height() as f32) / image_scale;
+
+        let mut mat = StandardMaterial3D::new_gd();
+        mat.set_texture(TextureParam::ALBEDO, &texture);
+        mat.set_shading_mode(ShadingMode::UNSHADED);
+        mat.set_cull_mode(CullMode::DISABLED);
+
+        let mut plane = MeshInstance3D::new_alloc();
+        plane.set_mesh(&QuadMesh::new_gd());
+        plane.set_material_override(&mat);
+        let name = GString::from("TissueImage");
+        plane.set_name(name.arg());
+        if let Some(mut old) = self.tissue.replace(plane.clone()) {
+            old.queue_free();
+        }
+        self.base_mut().add_child(&plane);
+        self.place_tissue();
+        godot_print!("🔬 tissue image '{}' added to '{}'", path, self.id);
+    }
+
+    /// Fit the tissue plane to the current center/scale_factor.
+    fn place_tissue(&mut self) {
+        let Some(mut plane) = self.tissue.clone() else {
+            return;
+        };
+        let size = self.tissue_size / 10.0 * self.scale_factor * 3.0;
+        if let Some(mut quad) = plane.get_mesh().and_then(|m| m.try_cast::<QuadMesh>().ok()) {
+            quad.set_size(size);
+        }
+        let mut pos = self.to_local(Vector3::new(self.tissue_size.x * 0.5, -self.tissue_size.y * 0.5, 0.0));
+        // slightly below the lowest point so the spots are never hidden
+        pos.z = (self.floor_z - self.center.z) * self.scale_factor * 3.0 - 0.01;
+        plane.set_position(pos);
+    }
+
+    /// Show or hide the tissue image (if there is one).
+    #[func]
+    pub fn set_tissue_visible(&mut self, visible: bool) {
+        if let Some(plane) = self.tissue.as_mut() {
+            plane.set_visible(visible);
+        }
+    }
+
+
+    /// A selection sphere in world space → center and radius in the data
+    /// coordinates of the displayed projection (the inverse of `to_local`).
     pub fn world_selection_to_data_selection(
         &self,
         center_vr: Vector3,
@@ -200,11 +282,13 @@ impl UmapGraph3D {
 
         // Convert world (VR) → local (graph) coordinates
         let transform = self.base().get_global_transform();
-        let center_local = transform.affine_inverse().xform_inv(center_vr);
+        let center_local = transform.affine_inverse() * center_vr;
+        let node_scale = transform.basis.get_scale();
+        let radius_local = radius_vr / ((node_scale.x + node_scale.y + node_scale.z) / 3.0);
 
         // Convert graph local → data coordinate space
-        let center_data = (center_local / self.scale_factor) + self.center;
-        let radius_data = radius_vr / self.scale_factor;
+        let center_data = local_to_data(center_local, self.center, self.scale_factor);
+        let radius_data = radius_local * local_to_data_scale(self.scale_factor);
 
         godot_print!(
             "📈 mapped to data-space: center={:?}, radius={:.4}",
@@ -245,6 +329,22 @@ impl UmapGraph3D {
         );
     }
 
+    /// Set one color per cell (e.g. expression of a gene); hidden (NaN) cells stay hidden.
+    pub fn set_colors(&mut self, colors: &[Color]) {
+        let Some(inst) = self.meshes.first() else {
+            godot_warn!("⚠️ No MultiMesh found in UmapGraph3D '{}'", self.dataset_name);
+            return;
+        };
+        if let Some(mut mm) = inst.get_multimesh() {
+            let n = (mm.get_instance_count() as usize).min(colors.len());
+            for (idx, color) in colors.iter().enumerate().take(n) {
+                if mm.get_instance_color(idx as i32).a != 0.0 {
+                    mm.set_instance_color(idx as i32, *color);
+                }
+            }
+        }
+    }
+
 
     /* // needs Godot update to get there!
     #[func]
@@ -343,3 +443,105 @@ impl INode3D for UmapGraph3D {
     }
 }
 
+
+/// The bounds (data space / 10) of the rows of a (cells × 3) view, NaN rows are skipped.
+fn view_bounds(view: &Array2<f32>) -> (Vector3, Vector3) {
+    let mut min = Vector3::new(f32::MAX, f32::MAX, f32::MAX);
+    let mut max = Vector3::new(f32::MIN, f32::MIN, f32::MIN);
+
+    for row in view.axis_iter(ndarray::Axis(0)) {
+        if row[0].is_nan() || row[1].is_nan() || row[2].is_nan() {
+            continue;
+        }
+        let pos = Vector3::new(row[0] /10.0, row[1]/10.0, row[2]/10.0);
+
+        // update bounds
+        min.x = min.x.min(pos.x);
+        min.y = min.y.min(pos.y);
+        min.z = min.z.min(pos.z);
+
+        max.x = max.x.max(pos.x);
+        max.y = max.y.max(pos.y);
+        max.z = max.z.max(pos.z);
+    }
+    (min, max)
+}
+
+/// `center` and `scale_factor` of a graph showing data within these bounds.
+fn fit_bounds(min: Vector3, max: Vector3) -> (Vector3, f32) {
+    // center for X/Z and bottom-aligned Y
+    let center = Vector3::new((min.x + max.x) * 0.5, min.y, (min.z + max.z) * 0.5);
+    // uniform scale (fit in ~3 m space)
+    let extent = (max - min).length();
+    (center, 3.0 / extent)
+}
+
+/// Data coordinates → node coordinates of a graph fitted to `center` / `scale_factor`.
+fn data_to_local(data: Vector3, center: Vector3, scale_factor: f32) -> Vector3 {
+    (data / 10.0 - center) * scale_factor * 3.0 // 3.0 = target size in meters
+}
+
+/// The length in data coordinates of one unit in node coordinates.
+fn local_to_data_scale(scale_factor: f32) -> f32 {
+    10.0 / (scale_factor * 3.0)
+}
+
+/// Node coordinates → data coordinates (the inverse of `data_to_local`).
+fn local_to_data(local: Vector3, center: Vector3, scale_factor: f32) -> Vector3 {
+    local * local_to_data_scale(scale_factor) + center * 10.0
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_store::test_store;
+    use sprs::TriMat;
+
+    #[test]
+    fn local_coordinates_map_back_to_the_data() {
+        let (center, scale_factor) = (Vector3::new(1.5, -2.0, 0.25), 0.4);
+        let a = Vector3::new(12.0, -7.0, 3.0);
+        let b = Vector3::new(-4.0, 20.0, 9.5);
+        let (la, lb) = (data_to_local(a, center, scale_factor), data_to_local(b, center, scale_factor));
+
+        assert!((local_to_data(la, center, scale_factor) - a).length() < 1e-4);
+        // a sphere through b around a has that radius in the data as well
+        let radius = la.distance_to(lb) * local_to_data_scale(scale_factor);
+        assert!((radius - a.distance_to(b)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn reloaded_projection_refits_to_the_same_box() {
+        let cells: Vec<String> = ["c1", "c2", "c3"].iter().map(|s| s.to_string()).collect();
+        let mut ds = test_store(TriMat::<f32>::new((1, 3)).to_csr(), vec!["g1".into()], cells);
+        let path = std::env::temp_dir().join(format!("printforge3d_graph_{}.drc", std::process::id()));
+        let path_str = path.to_string_lossy().to_string();
+        std::fs::write(&path, "cell\tx\ty\tz\nc1\t0\t0\t0\nc2\t10\t0\t0\nc3\t0\t10\t10\n").unwrap();
+        ds.load_projection_from_tsv("umap", &path_str).unwrap();
+        // what from_store shows
+        let before = ds.projection_view("umap").unwrap();
+
+        // PrintForgeCore::load_projection reloads the drcs entry and hands the new view to update_positions
+        std::fs::write(&path, "cell\tx\ty\tz\nc1\t100\t200\t0\nc2\t300\t200\t0\nc3\t100\t400\t200\n").unwrap();
+        ds.load_projection_from_tsv("umap", &path_str).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let after = ds.projection_view("umap").unwrap();
+        assert_ne!(before, after);
+        assert_eq!(after.row(2).to_vec(), vec![100.0, 400.0, 200.0]);
+
+        // the refit keeps the moved points in the same ~3 m box
+        let (min, max) = view_bounds(&after);
+        let (center, scale_factor) = fit_bounds(min, max);
+        assert_eq!(center, Vector3::new(20.0, 20.0, 10.0));
+        let fitted = |b: &Array2<f32>, (center, scale_factor): (Vector3, f32)| -> Vec<Vector3> {
+            b.rows().into_iter().map(|r| data_to_local(Vector3::new(r[0], r[1], r[2]), center, scale_factor)).collect()
+        };
+        let (old_min, old_max) = view_bounds(&before);
+        let old = fitted(&before, fit_bounds(old_min, old_max));
+        let new = fitted(&after, (center, scale_factor));
+        for (o, n) in old.iter().zip(&new) {
+            assert!((*o - *n).length() < 1e-4);
+        }
+    }
+}
@@ -44,6 +44,18 @@ pub fn id_to_color(id: &str) -> Color {
 }
 
 
+/// Map an expression value onto a light grey → red gradient (`max` is the top of the scale).
+pub fn value_to_color(value: f32, max: f32) -> Color {
+    let t = if max > 0.0 { (value / max).clamp(0.0, 1.0) } else { 0.0 };
+    let low = Color::from_rgb(0.85, 0.85, 0.85);
+    let high = Color::from_rgb(0.8, 0.0, 0.0);
+    Color::from_rgb(
+        low.r + (high.r - low.r) * t,
+        low.g + (high.g - low.g) * t,
+        low.b + (high.b - low.b) * t,
+    )
+}
+
 
 #[cfg(test)]
 mod tests {